@@ -1,150 +1,1486 @@
 use std::any::Any;
-use std::io::ErrorKind;
-use std::net::SocketAddr;
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use anyhow::{bail, Context};
+use axum::body::Body;
 use axum::extract::rejection::JsonRejection;
-use axum::extract::{self, Query, State};
+use axum::extract::{
+    self, DefaultBodyLimit, FromRef, FromRequest, OriginalUri, Query, Request, State,
+};
+use axum::http::header::{
+    ACCEPT, ACCEPT_ENCODING, AUTHORIZATION, CONTENT_DISPOSITION, CONTENT_LENGTH, CONTENT_TYPE,
+    ETAG, IF_MATCH, IF_MODIFIED_SINCE, IF_NONE_MATCH, IF_RANGE, RANGE, RETRY_AFTER,
+    WWW_AUTHENTICATE,
+};
+use axum::http::{HeaderMap, HeaderName, HeaderValue, StatusCode};
 use axum::response::IntoResponse;
 use axum::response::Response;
 use axum::{Json, Router};
 use serde::{Deserialize, Serialize};
+use tower::{Layer, ServiceExt};
 use tower_http::catch_panic::CatchPanicLayer;
+use tower_http::cors::{self, AllowOrigin, CorsLayer};
+use tower_http::normalize_path::NormalizePath;
 use tower_http::request_id::{MakeRequestUuid, PropagateRequestIdLayer, SetRequestIdLayer};
-use tower_http::trace::TraceLayer;
+use tower_http::trace::{DefaultOnRequest, DefaultOnResponse, TraceLayer};
 
 use crate::hash;
+use crate::index;
 use crate::location;
+use crate::logging;
 use crate::metadata;
 use crate::metrics::{
     self, register_build_info_metrics, register_process_metrics, HttpMetrics, RepositoryMetrics,
 };
-use crate::outpack_file::OutpackFile;
+use crate::openapi;
+use crate::outpack_file::{self, OutpackFile};
+use crate::ratelimit::{self, RateLimiter};
 use crate::responses::{OutpackError, OutpackSuccess};
+use crate::resumable::{self, UploadStatus};
+use crate::schema;
+use crate::shutdown;
+use crate::signing;
 use crate::store;
+use crate::sync;
+use crate::timeout;
 use crate::upload::{Upload, UploadLayer};
-use crate::{config, git};
+use crate::utils;
+use crate::worktree::Worktrees;
+use crate::zip;
+use crate::{config, git, query};
+
+type OutpackResult<T> = Result<OutpackSuccess<T>, OutpackError>;
+
+/// State shared by all request handlers.
+///
+/// Most handlers only need the path to the root, and can extract it directly using
+/// `State<PathBuf>`.
+#[derive(Clone)]
+struct AppState {
+    root: PathBuf,
+
+    /// The repository's configuration, as read at startup or during the last reload.
+    config: Arc<RwLock<config::Config>>,
+
+    /// Held while the configuration is being changed, so that changes are made one at a time
+    /// without holding the lock on the configuration itself while it is written to disk.
+    config_changes: Arc<tokio::sync::Mutex<()>>,
+
+    /// Worktrees of the branches of the repository's git remote, and the routes serving each,
+    /// along with the commit they were built for.
+    worktrees: Arc<Worktrees>,
+    branch_routes: Arc<Mutex<HashMap<String, (git2::Oid, Router)>>>,
+}
+
+impl AppState {
+    fn new(root: &Path, config: config::Config) -> AppState {
+        AppState {
+            root: root.to_owned(),
+            config: Arc::new(RwLock::new(config)),
+            config_changes: Arc::new(tokio::sync::Mutex::new(())),
+            worktrees: Arc::new(Worktrees::new(root)),
+            branch_routes: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+}
+
+impl FromRef<AppState> for PathBuf {
+    fn from_ref(state: &AppState) -> PathBuf {
+        state.root.clone()
+    }
+}
+
+// This mostly exists to smooth over a difference with original
+// version, which used Root as the object; soon we will update this to
+// report actual versions back.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ApiRoot {
+    pub schema_version: String,
+}
+
+fn internal_error(_err: Box<dyn Any + Send + 'static>) -> Response {
+    OutpackError::new(
+        "UNKNOWN_ERROR",
+        "Something went wrong",
+        StatusCode::INTERNAL_SERVER_ERROR,
+    )
+    .into_response()
+}
+
+async fn not_found() -> OutpackError {
+    OutpackError::new(
+        "NOT_FOUND",
+        "This route does not exist",
+        StatusCode::NOT_FOUND,
+    )
+}
+
+async fn index() -> OutpackResult<ApiRoot> {
+    Ok(OutpackSuccess::from(ApiRoot {
+        schema_version: String::from("0.1.1"),
+    }))
+}
+
+/// The media type of newline-delimited JSON, in which each line is a complete JSON value.
+const NDJSON: &str = "application/x-ndjson";
+
+#[derive(Deserialize)]
+struct Format {
+    format: Option<String>,
+}
+
+/// Decide whether a list should be returned as newline-delimited JSON rather than a single JSON
+/// array. This is requested with either the `format` query parameter or the Accept header, and
+/// the query parameter takes precedence if both are present.
+fn wants_ndjson(format: &Format, headers: &HeaderMap) -> Result<bool, OutpackError> {
+    match format.format.as_deref() {
+        Some("ndjson") => Ok(true),
+        Some("json") => Ok(false),
+        Some(other) => Err(OutpackError::new(
+            "BAD_REQUEST",
+            format!("Unknown format '{}', expected 'json' or 'ndjson'", other),
+            StatusCode::BAD_REQUEST,
+        )),
+        None => Ok(headers
+            .get(ACCEPT)
+            .and_then(|v| v.to_str().ok())
+            .is_some_and(|v| v.split(',').any(|t| t.trim().starts_with(NDJSON)))),
+    }
+}
+
+/// Stream a list as newline-delimited JSON. Unlike the usual response, there is no envelope
+/// around the items.
+///
+/// The items are serialised on a blocking thread, and handed to the response through a bounded
+/// channel, so that a long list is neither serialised on the async runtime nor held in memory
+/// as JSON all at once. Serialising stops early if the client goes away.
+fn ndjson_response<T: Serialize + Send + 'static>(items: Vec<T>) -> Response {
+    let (tx, mut rx) = tokio::sync::mpsc::channel(16);
+    tokio::task::spawn_blocking(move || {
+        for item in items {
+            let line = serde_json::to_vec(&item).map(|mut line| {
+                line.push(b'\n');
+                line
+            });
+            let failed = line.is_err();
+            if tx.blocking_send(line).is_err() || failed {
+                break;
+            }
+        }
+    });
+    let lines = futures::stream::poll_fn(move |cx| rx.poll_recv(cx));
+    ([(CONTENT_TYPE, NDJSON)], Body::from_stream(lines)).into_response()
+}
+
+#[derive(Deserialize)]
+struct ListOptions {
+    files: Option<bool>,
+}
+
+/// List the packets known to each location. If the `files` query parameter is true, each entry
+/// includes the hashes of the packet's files.
+async fn list_location_metadata(
+    state: State<AppState>,
+    query: Query<ListOptions>,
+    format: Query<Format>,
+    headers: HeaderMap,
+) -> Result<Response, OutpackError> {
+    let now = SystemTime::now();
+    let ndjson = wants_ndjson(&format, &headers)?;
+    let config = state.config.read().unwrap();
+    let mut entries = location::read_locations(&state.root, &config.location)?;
+    if query.files.unwrap_or(false) {
+        location::add_file_hashes(&state.root, &mut entries)?;
+    }
+    if ndjson {
+        Ok(ndjson_response(entries))
+    } else {
+        Ok(OutpackSuccess::list(entries, now).into_response())
+    }
+}
+
+#[derive(Deserialize)]
+struct KnownSince {
+    known_since: Option<utils::Timestamp>,
+    known_until: Option<utils::Timestamp>,
+}
+
+/// Parse the If-Modified-Since header, if present, as seconds since the epoch. As required by
+/// RFC 9110, a header which is not a valid date is ignored.
+fn get_if_modified_since(headers: &HeaderMap) -> Option<utils::Timestamp> {
+    let value = headers.get(IF_MODIFIED_SINCE)?.to_str().ok()?;
+    let time = httpdate::parse_http_date(value).ok()?;
+    Some(utils::Timestamp::from(time))
+}
+
+/// List the metadata of packets added since a given time.
+///
+/// The time may be given either as the `known_since` query parameter or an If-Modified-Since
+/// header. The query parameter takes precedence if both are present. When the header is used and
+/// no packets have been added since, an empty 304 response is returned. The optional
+/// `known_until` query parameter excludes packets added at or after it, and makes `known_since`
+/// inclusive.
+async fn get_metadata_since(
+    state: State<AppState>,
+    query: Query<KnownSince>,
+    format: Query<Format>,
+    headers: HeaderMap,
+) -> Result<Response, OutpackError> {
+    let now = SystemTime::now();
+    let ndjson = wants_ndjson(&format, &headers)?;
+    let if_modified_since = match query.known_since {
+        Some(_) => None,
+        None => get_if_modified_since(&headers),
+    };
+    let locations = state.config.read().unwrap().location.clone();
+    let packets = metadata::get_packit_metadata_from_date(
+        &state.root,
+        &locations,
+        query.known_since.or(if_modified_since),
+        query.known_until,
+    )?;
+    if if_modified_since.is_some() && packets.is_empty() {
+        Ok(StatusCode::NOT_MODIFIED.into_response())
+    } else if ndjson {
+        Ok(ndjson_response(packets))
+    } else {
+        Ok(OutpackSuccess::list(packets, now).into_response())
+    }
+}
+
+/// The header in which the hash of a packet's metadata is returned.
+const METADATA_HASH: &str = "outpack-metadata-hash";
+
+/// Get headers giving the hash of a packet's metadata, as reported by `/metadata/list`. If the
+/// packet is known to several locations, the hash from the first of them is used.
+fn metadata_hash_headers(state: &AppState, id: &str) -> Result<HeaderMap, OutpackError> {
+    let config = state.config.read().unwrap();
+    let entry = location::read_locations(&state.root, &config.location)?
+        .into_iter()
+        .find(|entry| entry.packet == id);
+
+    let mut headers = HeaderMap::new();
+    if let Some(entry) = entry {
+        // Hashes only ever contain characters which are valid in a header.
+        headers.insert(METADATA_HASH, entry.hash.parse().unwrap());
+    }
+    Ok(headers)
+}
+
+async fn get_metadata_by_id(
+    state: State<AppState>,
+    id: extract::Path<String>,
+) -> Result<Response, OutpackError> {
+    let metadata = metadata::get_metadata_by_id(&state.root, &id)?;
+    let headers = metadata_hash_headers(&state, &id)?;
+    Ok((headers, OutpackSuccess::from(metadata)).into_response())
+}
+
+async fn get_packet_parameters(
+    root: State<PathBuf>,
+    id: extract::Path<String>,
+) -> OutpackResult<HashMap<String, serde_json::Value>> {
+    let id = metadata::get_valid_id(&id)?;
+    metadata::get_packet_parameters(&root, &id)
+        .map_err(OutpackError::from)
+        .map(OutpackSuccess::from)
+}
+
+async fn get_packet_size(
+    state: State<AppState>,
+    id: extract::Path<String>,
+) -> OutpackResult<metadata::PacketSize> {
+    let id = metadata::get_valid_id(&id)?;
+    let use_file_store = state.config.read().unwrap().core.use_file_store;
+    metadata::get_packet_size(&state.root, &id, use_file_store)
+        .map_err(OutpackError::from)
+        .map(OutpackSuccess::from)
+}
+
+/// Sends everything written to it as chunks of a response body, failing once the response has
+/// been dropped.
+struct BodyWriter(tokio::sync::mpsc::Sender<io::Result<Vec<u8>>>);
+
+impl io::Write for BodyWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0
+            .blocking_send(Ok(buf.to_vec()))
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "The client went away"))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Download a packet's metadata and all of its files as a single zip archive. The metadata is
+/// stored where it would be in a repository, at `.outpack/metadata/<id>`, and each file under its
+/// path in the packet.
+///
+/// Every file is checked to be in the store, and its path to stay within the archive, before
+/// anything is sent. The archive is then written as it is sent, so a failure after that point can
+/// only be reported by ending the response early.
+async fn download_packet(
+    root: State<PathBuf>,
+    id: extract::Path<String>,
+) -> Result<Response, OutpackError> {
+    let root = root.0;
+    // The id is used in the archive and its filename, as well as to find the packet.
+    let id = metadata::get_valid_id(&id)?;
+    let contents = {
+        let (root, id) = (root.clone(), id.clone());
+        tokio::task::spawn_blocking(move || metadata::get_packet_contents(&root, &id))
+            .await
+            .unwrap()?
+    };
+    for (name, _) in &contents.files {
+        zip::check_name(name)?;
+    }
+
+    let (tx, mut rx) = tokio::sync::mpsc::channel(4);
+    let disposition = format!("attachment; filename=\"{}.zip\"", id);
+    tokio::task::spawn_blocking(move || {
+        let name = format!(".outpack/metadata/{}", id);
+        let writer = io::BufWriter::with_capacity(64 * 1024, BodyWriter(tx.clone()));
+        let mut archive = zip::ZipWriter::new(writer);
+        let result = std::fs::File::open(&contents.metadata)
+            .and_then(|mut file| archive.add(&name, &mut file))
+            .and_then(|_| {
+                contents.files.iter().try_for_each(|(name, path)| {
+                    std::fs::File::open(path).and_then(|mut file| archive.add(name, &mut file))
+                })
+            })
+            .and_then(|_| archive.finish())
+            // Taking the writer out of its buffer flushes it, reporting any failure to do so.
+            .and_then(|writer| writer.into_inner().map(drop).map_err(|e| e.into_error()));
+        if let Err(e) = result {
+            if e.kind() != io::ErrorKind::BrokenPipe {
+                tracing::error!("failed to write packet {} as a zip: {}", id, e);
+                let _ = tx.blocking_send(Err(e));
+            }
+        }
+    });
+
+    let chunks = futures::stream::poll_fn(move |cx| rx.poll_recv(cx));
+    Ok((
+        [
+            (CONTENT_TYPE, String::from("application/zip")),
+            (CONTENT_DISPOSITION, disposition),
+        ],
+        Body::from_stream(chunks),
+    )
+        .into_response())
+}
+
+/// Serve one of the schemas that the server's requests and responses conform to.
+async fn get_schema(name: extract::Path<String>) -> Result<Response, OutpackError> {
+    let contents = schema::get_schema_text(&name)?;
+    Ok(([(CONTENT_TYPE, mime::APPLICATION_JSON.as_ref())], contents).into_response())
+}
+
+#[derive(Deserialize)]
+struct Recursive {
+    recursive: Option<bool>,
+}
+
+async fn get_dependencies(
+    root: State<PathBuf>,
+    id: extract::Path<String>,
+    query: Query<Recursive>,
+) -> OutpackResult<Vec<String>> {
+    metadata::get_dependencies(&root, &id, query.recursive.unwrap_or(false))
+        .map_err(OutpackError::from)
+        .map(OutpackSuccess::from)
+}
+
+/// Check whether an If-None-Match header lists the given entity tag. Weak tags are compared as if
+/// they were strong, as required of If-None-Match by RFC 9110.
+fn if_none_match(headers: &HeaderMap, etag: &str) -> bool {
+    headers
+        .get_all(IF_NONE_MATCH)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(|tag| tag.trim())
+        .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag)
+}
+
+/// Get the metadata of a packet exactly as it was written, which is the text its hash was
+/// computed from. The hash is also given as the ETag, so that clients can revalidate cheaply.
+async fn get_metadata_raw(
+    state: State<AppState>,
+    id: extract::Path<String>,
+    request_headers: HeaderMap,
+) -> Result<Response, OutpackError> {
+    let metadata = metadata::get_metadata_text(&state.root, &id)?;
+    let mut headers = metadata_hash_headers(&state, &id)?;
+    if let Some(hash) = headers.get(METADATA_HASH).and_then(|v| v.to_str().ok()) {
+        let etag = format!("\"{}\"", hash);
+        let matches = if_none_match(&request_headers, &etag);
+        headers.insert(ETAG, etag.parse().unwrap());
+        if matches {
+            return Ok((StatusCode::NOT_MODIFIED, headers).into_response());
+        }
+    }
+    headers.insert(
+        CONTENT_TYPE,
+        HeaderValue::from_static(mime::APPLICATION_JSON.as_ref()),
+    );
+    Ok((headers, metadata).into_response())
+}
+
+#[derive(Deserialize)]
+struct BatchIds {
+    ids: Vec<String>,
+}
+
+/// The result of looking up one of the packets requested from `/metadata/batch`.
+#[derive(Serialize)]
+#[serde(rename_all = "lowercase")]
+enum BatchEntry {
+    Metadata(serde_json::Value),
+    Error(OutpackError),
+}
+
+/// Get the metadata of several packets at once. Failing to read the metadata of any one packet,
+/// such as because it does not exist, does not cause the whole request to fail, but asking for
+/// something which is not a packet id does.
+async fn get_metadata_batch(
+    state: State<AppState>,
+    body: Result<Json<BatchIds>, JsonRejection>,
+) -> OutpackResult<BTreeMap<String, BatchEntry>> {
+    let Json(body) = body?;
+    let max_batch_size = state.config.read().unwrap().server.max_batch_size;
+    if body.ids.len() > max_batch_size {
+        return Err(OutpackError::new(
+            "BAD_REQUEST",
+            format!(
+                "Requested {} packets, but at most {} may be requested at once",
+                body.ids.len(),
+                max_batch_size
+            ),
+            StatusCode::BAD_REQUEST,
+        ));
+    }
+    for id in &body.ids {
+        metadata::get_valid_id(id)?;
+    }
+
+    tokio::task::spawn_blocking(move || {
+        let entries: BTreeMap<String, BatchEntry> = body
+            .ids
+            .into_iter()
+            .map(|id| {
+                let entry = match metadata::get_metadata_by_id(&state.root, &id) {
+                    Ok(metadata) => BatchEntry::Metadata(metadata),
+                    Err(e) => BatchEntry::Error(OutpackError::from(e)),
+                };
+                (id, entry)
+            })
+            .collect();
+        Ok(OutpackSuccess::from(entries))
+    })
+    .await
+    .unwrap()
+}
+
+#[derive(Deserialize)]
+struct MetadataSearch {
+    query: Option<String>,
+    fields: Vec<String>,
+}
+
+/// Pick some fields out of the metadata of every packet, or of those matching a query. Packets
+/// are returned in order of their id.
+async fn search_metadata(
+    root: State<PathBuf>,
+    body: Result<Json<MetadataSearch>, JsonRejection>,
+) -> OutpackResult<Vec<serde_json::Map<String, serde_json::Value>>> {
+    let Json(body) = body?;
+    if body.fields.is_empty() {
+        return Err(OutpackError::new(
+            "BAD_REQUEST",
+            "At least one field must be given",
+            StatusCode::BAD_REQUEST,
+        ));
+    }
+    tokio::task::spawn_blocking(move || {
+        let mut ids = match &body.query {
+            Some(query) => query::find_packet_ids(&root, query)?,
+            None => index::get_packet_index(&root)?
+                .packets
+                .iter()
+                .map(|packet| packet.id.clone())
+                .collect(),
+        };
+        ids.sort();
+        let found = metadata::search_metadata(&root, &ids, &body.fields)?;
+        Ok(OutpackSuccess::from(found))
+    })
+    .await
+    .unwrap()
+}
+
+#[derive(Deserialize)]
+struct PacketName {
+    name: String,
+}
+
+/// Get the id of the most recent packet with the given name.
+async fn get_latest_packet(
+    root: State<PathBuf>,
+    query: Query<PacketName>,
+) -> OutpackResult<String> {
+    let name = query.0.name;
+    tokio::task::spawn_blocking(move || {
+        let id = query::find_latest_id(&root, &name)?.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("No packets named '{}'", name),
+            )
+        })?;
+        Ok(OutpackSuccess::from(id))
+    })
+    .await
+    .unwrap()
+}
+
+#[derive(Deserialize)]
+struct SummaryQuery {
+    #[serde(default)]
+    sort: metadata::SummaryOrder,
+    limit: Option<usize>,
+    offset: Option<usize>,
+}
+
+/// Summarise the packets of each name, ordered by the `sort` query parameter. The `limit` and
+/// `offset` query parameters select a page of the summaries.
+async fn get_packet_summary(
+    root: State<PathBuf>,
+    query: Query<SummaryQuery>,
+) -> OutpackResult<Vec<metadata::NameSummary>> {
+    let now = SystemTime::now();
+    tokio::task::spawn_blocking(move || {
+        let summary = metadata::get_packet_summary(&root, query.sort)?;
+        let page = summary
+            .into_iter()
+            .skip(query.offset.unwrap_or(0))
+            .take(query.limit.unwrap_or(usize::MAX))
+            .collect();
+        Ok(OutpackSuccess::list(page, now))
+    })
+    .await
+    .unwrap()
+}
+
+#[derive(Deserialize)]
+struct Filename {
+    filename: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Signature {
+    sig: Option<String>,
+    exp: Option<u64>,
+}
+
+fn forbidden(detail: impl Into<String>) -> OutpackError {
+    OutpackError::new("FORBIDDEN", detail, StatusCode::FORBIDDEN)
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+/// Check the signature of a download url, if it has one. Requests without a signature are left
+/// to whatever authentication is in front of the server.
+fn check_signature(state: &AppState, hash: &str, query: &Signature) -> Result<(), OutpackError> {
+    if query.sig.is_none() && query.exp.is_none() {
+        return Ok(());
+    }
+    let (Some(sig), Some(exp)) = (&query.sig, query.exp) else {
+        return Err(forbidden("Signed urls must have both 'sig' and 'exp'"));
+    };
+    let config = state.config.read().unwrap();
+    let Some(secret) = &config.server.url_signing_secret else {
+        return Err(forbidden("Signed urls are not enabled on this server"));
+    };
+    signing::verify(secret, hash, exp, sig, unix_now()).map_err(|e| forbidden(e.to_string()))
+}
+
+/// Whether a request for part of a file should be honoured. An If-Range header makes this
+/// conditional on the client's copy being the one that would be served, so that a download is
+/// never resumed with the bytes of a different file. Its tag must match strongly, so a weak tag
+/// never matches, and a date must be the file's modification time.
+fn if_range_matches(headers: &HeaderMap, file: &OutpackFile) -> bool {
+    let value = match headers.get(IF_RANGE) {
+        None => return true,
+        Some(value) => value.to_str().unwrap_or_default().trim(),
+    };
+    if value.starts_with('"') {
+        value == file.etag()
+    } else {
+        httpdate::parse_http_date(value)
+            .is_ok_and(|date| Some(httpdate::fmt_http_date(date)) == file.last_modified())
+    }
+}
+
+/// Serve a file, or only the part of it given by a Range header.
+async fn serve_file(file: OutpackFile, headers: &HeaderMap) -> io::Result<Response> {
+    let range = headers
+        .get(RANGE)
+        .and_then(|v| v.to_str().ok())
+        .filter(|_| if_range_matches(headers, &file))
+        .and_then(|range| outpack_file::parse_range(range, file.size()));
+    Ok(match range {
+        Some(outpack_file::Range::Satisfiable(start, end)) => {
+            file.with_range(start, end).await?.into_response()
+        }
+        Some(outpack_file::Range::Unsatisfiable) => file.range_not_satisfiable(),
+        None => file.into_response(),
+    })
+}
+
+/// Download a file, serving its gzip-compressed copy instead if there is one and the client
+/// accepts gzip encoding. A conditional request whose If-None-Match lists the file's tag gets an
+/// empty 304 response instead. A single range of bytes may be requested with a Range header,
+/// along with an If-Range header to only get the range if the file is unchanged.
+async fn get_file(
+    state: State<AppState>,
+    hash: extract::Path<String>,
+    query: Query<Filename>,
+    signature: Query<Signature>,
+    headers: HeaderMap,
+) -> Result<Response, OutpackError> {
+    // Files are stored, and urls signed, by the hex form of their hash, whichever encoding the
+    // request uses.
+    let hash = hash::Hash::decode(&hash)?.0.to_string();
+    check_signature(&state, &hash, &signature)?;
+    let root = &state.root;
+    let path = store::file_path(root, &hash)?;
+    let accepts_gzip = headers
+        .get(ACCEPT_ENCODING)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(outpack_file::accepts_gzip);
+    let compressed = if accepts_gzip {
+        let path = store::compressed_file_path(root, &hash)?;
+        OutpackFile::open_compressed(hash.to_owned(), path).await?
+    } else {
+        None
+    };
+    let file = match compressed {
+        Some(file) => Ok(file),
+        None => OutpackFile::open(hash.to_owned(), path).await,
+    };
+    let response = match file {
+        // The tag of each encoding of the file is different, so a request can only match the
+        // encoding it would be served.
+        Ok(file) if if_none_match(&headers, &file.etag()) => Ok(file.not_modified()),
+        Ok(file) => serve_file(file.with_filename(query.0.filename), &headers).await,
+        Err(e) => Err(e),
+    };
+    response.map_err(|e| match e.kind() {
+        // Any other failure to open a file that should be in the store, such as a permission
+        // error, is a problem with the server rather than the request.
+        io::ErrorKind::NotFound => {
+            OutpackError::new("NOT_FOUND", e.to_string(), StatusCode::NOT_FOUND)
+        }
+        _ => OutpackError::from(e),
+    })
+}
+
+#[derive(Deserialize)]
+struct ExpiresIn {
+    expires_in: Option<u64>,
+}
+
+#[derive(Serialize)]
+struct SignedUrl {
+    url: String,
+    expires: u64,
+}
+
+/// Create a url from which the file can be downloaded, without any other credentials, until it
+/// expires. The url includes whatever prefix the request was made under, such as `/roots/<name>`
+/// when several roots are served, so that it leads back to the same root, and gives the hex form
+/// of the hash, whichever encoding the request uses.
+async fn get_signed_url(
+    state: State<AppState>,
+    hash: extract::Path<String>,
+    query: Query<ExpiresIn>,
+    OriginalUri(original_uri): OriginalUri,
+    uri: axum::http::Uri,
+) -> OutpackResult<SignedUrl> {
+    let config = state.config.read().unwrap();
+    let secret = config.server.url_signing_secret.as_ref().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            "Signed urls are not enabled; set 'url_signing_secret' in the server configuration",
+        )
+    })?;
+    let hash = hash::Hash::decode(&hash)?.0.to_string();
+    if !store::file_exists(&state.root, &hash)? {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("Hash '{}' not found", hash),
+        )
+        .into());
+    }
+    let expires_in = query.expires_in.unwrap_or(3600);
+    let max_expiry = config.server.max_signed_url_expiry;
+    let expires = unix_now()
+        .checked_add(expires_in)
+        .filter(|_| expires_in <= max_expiry)
+        .ok_or_else(|| {
+            OutpackError::new(
+                "BAD_REQUEST",
+                format!(
+                    "Signed urls may be valid for at most {} seconds, not {}",
+                    max_expiry, expires_in
+                ),
+                StatusCode::BAD_REQUEST,
+            )
+        })?;
+    let sig = signing::sign(secret, &hash, expires);
+    let prefix = original_uri
+        .path()
+        .trim_end_matches('/')
+        .strip_suffix(uri.path())
+        .unwrap_or("");
+    Ok(OutpackSuccess::from(SignedUrl {
+        url: format!("{}/file/{}?exp={}&sig={}", prefix, hash, expires, sig),
+        expires,
+    }))
+}
+
+#[derive(Deserialize)]
+struct Algorithm {
+    alg: Option<String>,
+    #[serde(default)]
+    encoding: hash::HashEncoding,
+}
+
+async fn get_checksum(state: State<AppState>, query: Query<Algorithm>) -> OutpackResult<String> {
+    let Query(Algorithm { alg, encoding }) = query;
+    let alg = match alg {
+        Some(alg) => alg.parse()?,
+        None => state.config.read().unwrap().core.hash_algorithm,
+    };
+    let digest: hash::Hash = metadata::get_ids_digest(&state.root, alg)?.parse()?;
+    Ok(OutpackSuccess::from(digest.encode(encoding)?))
+}
+
+/// Write hashes, which are in their hex form, in the given encoding.
+fn encode_hashes(
+    hashes: Vec<String>,
+    encoding: hash::HashEncoding,
+) -> Result<Vec<String>, hash::HashError> {
+    if encoding == hash::HashEncoding::Hex {
+        return Ok(hashes);
+    }
+    hashes
+        .iter()
+        .map(|h| h.parse::<hash::Hash>()?.encode(encoding))
+        .collect()
+}
+
+/// Reject a request to check more than the configured number of ids or hashes at once, telling the
+/// client the limit so that it can split up its request.
+fn check_count(state: &AppState, count: usize, what: &str) -> Result<(), OutpackError> {
+    let max_check_size = state.config.read().unwrap().server.max_check_size;
+    if count > max_check_size {
+        return Err(OutpackError::new(
+            "BAD_REQUEST",
+            format!(
+                "Sent {} {}, but at most {} may be checked at once",
+                count, what, max_check_size
+            ),
+            StatusCode::BAD_REQUEST,
+        ));
+    }
+    Ok(())
+}
+
+async fn get_missing_packets(
+    state: State<AppState>,
+    ids: Result<Json<Ids>, JsonRejection>,
+) -> OutpackResult<Vec<String>> {
+    let Json(ids) = ids?;
+    check_count(&state, ids.ids.len(), "ids")?;
+    let root = &state.root;
+    let wanted = if ids.recursive {
+        metadata::get_dependency_closure(root, &ids.ids)?
+    } else {
+        ids.ids
+    };
+    metadata::get_missing_ids(root, &wanted, ids.unpacked)
+        .map_err(OutpackError::from)
+        .map(OutpackSuccess::from)
+}
+
+#[derive(Deserialize)]
+struct SyncPlanRequest {
+    ids: Vec<String>,
+    #[serde(default)]
+    unpacked: bool,
+    #[serde(default)]
+    recursive: bool,
+    #[serde(default)]
+    incomplete: bool,
+}
+
+/// List the missing packets and the files needed for them at once, saving a client which is about
+/// to sync from making separate requests to `/packets/missing` and `/files/missing`.
+async fn get_sync_plan(
+    state: State<AppState>,
+    request: Result<Json<SyncPlanRequest>, JsonRejection>,
+) -> OutpackResult<metadata::SyncPlan> {
+    let Json(request) = request?;
+    check_count(&state, request.ids.len(), "ids")?;
+    let root = state.root.clone();
+    tokio::task::spawn_blocking(move || {
+        let wanted = if request.recursive {
+            metadata::get_dependency_closure(&root, &request.ids)?
+        } else {
+            request.ids
+        };
+        metadata::get_sync_plan(&root, &wanted, request.unpacked, request.incomplete)
+            .map_err(OutpackError::from)
+            .map(OutpackSuccess::from)
+    })
+    .await
+    .unwrap()
+}
+
+#[derive(Deserialize)]
+struct Verify {
+    verify: Option<bool>,
+}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+enum MissingFiles {
+    Missing(Vec<String>),
+    Checked(store::FileCheck),
+}
+
+async fn get_missing_files(
+    state: State<AppState>,
+    query: Query<Verify>,
+    hashes: Result<Json<Hashes>, JsonRejection>,
+) -> OutpackResult<MissingFiles> {
+    let hashes = hashes?;
+    check_count(&state, hashes.hashes.len(), "hashes")?;
+    // Hashes may be sent in any one encoding, and are returned in the same one.
+    let (hashes, encoding) = hash::decode_hashes(&hashes.hashes)?;
+    let root = state.root.clone();
+    if query.verify.unwrap_or(false) {
+        tokio::task::spawn_blocking(move || {
+            let check = store::check_files(&root, &hashes)?;
+            Ok(OutpackSuccess::from(MissingFiles::Checked(
+                store::FileCheck {
+                    missing: encode_hashes(check.missing, encoding)?,
+                    corrupt: encode_hashes(check.corrupt, encoding)?,
+                },
+            )))
+        })
+        .await
+        .unwrap()
+    } else {
+        let missing = store::get_missing_files(&root, &hashes)?;
+        Ok(OutpackSuccess::from(MissingFiles::Missing(encode_hashes(
+            missing, encoding,
+        )?)))
+    }
+}
+
+/// Get the size and modification time of each of the given files, without downloading them. The
+/// files are keyed by their hashes as they were sent, in whichever encoding that was.
+async fn get_file_info(
+    state: State<AppState>,
+    hashes: Result<Json<Hashes>, JsonRejection>,
+) -> OutpackResult<BTreeMap<String, store::FileInfo>> {
+    let Json(Hashes { hashes }) = hashes?;
+    check_count(&state, hashes.len(), "hashes")?;
+    let (decoded, _) = hash::decode_hashes(&hashes)?;
+    let mut info = store::get_file_info(&state.root, &decoded)?;
+    let info: BTreeMap<String, store::FileInfo> = hashes
+        .into_iter()
+        .zip(decoded)
+        .filter_map(|(hash, decoded)| Some((hash, info.remove(&decoded)?)))
+        .collect();
+    Ok(OutpackSuccess::from(info))
+}
+
+#[derive(Deserialize)]
+struct Page {
+    limit: Option<usize>,
+    offset: Option<usize>,
+}
+
+/// List the hashes of the files in the store, in sorted order. The `limit` and `offset` query
+/// parameters select a page of the list.
+async fn list_files(root: State<PathBuf>, query: Query<Page>) -> OutpackResult<Vec<String>> {
+    let now = SystemTime::now();
+    tokio::task::spawn_blocking(move || {
+        let hashes = store::list_hashes(&root)?;
+        let page = hashes
+            .into_iter()
+            .skip(query.offset.unwrap_or(0))
+            .take(query.limit.unwrap_or(usize::MAX))
+            .collect();
+        Ok(OutpackSuccess::list(page, now))
+    })
+    .await
+    .unwrap()
+}
+
+async fn verify_store(root: State<PathBuf>) -> OutpackResult<Vec<store::InvalidFile>> {
+    // Verifying the store reads every file in it, which can take a long time. If the client goes
+    // away, this handler's future is dropped along with the receiving end of the channel, which
+    // the blocking task notices before moving on to the next file.
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+    tokio::task::spawn_blocking(move || {
+        for entry in store::enumerate_files(&root) {
+            if tx.is_closed() {
+                break;
+            }
+            if let Some(result) = store::verify_entry(&root, &entry).transpose() {
+                let _ = tx.send(result);
+            }
+        }
+    });
+
+    let mut invalid = Vec::new();
+    while let Some(result) = rx.recv().await {
+        invalid.push(result?);
+    }
+    Ok(OutpackSuccess::from(invalid))
+}
+
+#[derive(Deserialize)]
+struct Top {
+    top: Option<usize>,
+}
+
+/// Report how many packets use each file in the store, and so how much space is saved by storing
+/// each file only once. The `top` query parameter sets how many of the most used files are listed.
+async fn get_store_references(
+    root: State<PathBuf>,
+    query: Query<Top>,
+) -> OutpackResult<store::ReferenceReport> {
+    let top = query.top.unwrap_or(10);
+    tokio::task::spawn_blocking(move || store::reference_report(&root, top))
+        .await
+        .unwrap()
+        .map_err(OutpackError::from)
+        .map(OutpackSuccess::from)
+}
+
+#[derive(Deserialize)]
+struct DryRun {
+    dry_run: Option<bool>,
+}
+
+/// Remove every file which is not used by any packet, and is older than the configured
+/// `gc_grace_period`, or only report which would be removed in a dry run.
+async fn gc_store(state: State<AppState>, query: Query<DryRun>) -> OutpackResult<store::GcResult> {
+    let dry_run = query.dry_run.unwrap_or(false);
+    let grace_period = state.config.read().unwrap().server.gc_grace_period;
+    let grace_period = Duration::from_secs(grace_period);
+    let root = state.root.clone();
+    tokio::task::spawn_blocking(move || store::gc(&root, dry_run, grace_period))
+        .await
+        .unwrap()
+        .map_err(OutpackError::from)
+        .map(OutpackSuccess::from)
+}
+
+#[derive(Deserialize)]
+struct AddFile {
+    dry_run: Option<bool>,
+    verify: Option<bool>,
+}
+
+/// Add a file to the store. In a dry run, the file is only hashed, and the result of comparing
+/// that to the expected hash is returned.
+///
+/// If the store already has a file with the hash, the upload succeeds without any of its body
+/// being read, unless the `verify` query parameter asks for it to be checked as usual. Either way
+/// the stored file is kept from `gc` for the grace period, as a newly uploaded one would be.
+async fn add_file(
+    root: State<PathBuf>,
+    hash: extract::Path<String>,
+    query: Query<AddFile>,
+    request: Request,
+) -> Result<Response, OutpackError> {
+    let dry_run = query.dry_run.unwrap_or(false);
+    if !dry_run && !query.verify.unwrap_or(false) && store::want_file(&root, &hash)? {
+        return Ok(OutpackSuccess::from(()).into_response());
+    }
+    let file = match Upload::from_request(request, &()).await {
+        Ok(file) => file,
+        Err(rejection) => return Ok(rejection),
+    };
+
+    tokio::task::spawn_blocking(move || {
+        if dry_run {
+            let check = store::check_file_hash(file, &hash)?;
+            Ok(OutpackSuccess::from(check).into_response())
+        } else {
+            store::put_file(&root, file, &hash)?;
+            Ok(OutpackSuccess::from(()).into_response())
+        }
+    })
+    .await
+    .unwrap()
+}
+
+async fn start_upload(root: State<PathBuf>) -> OutpackResult<UploadStatus> {
+    resumable::start_upload(&root)
+        .map_err(OutpackError::from)
+        .map(OutpackSuccess::from)
+}
+
+async fn get_upload(
+    root: State<PathBuf>,
+    id: extract::Path<String>,
+) -> OutpackResult<UploadStatus> {
+    resumable::get_upload(&root, &id)
+        .map_err(OutpackError::from)
+        .map(OutpackSuccess::from)
+}
+
+fn get_upload_offset(headers: &HeaderMap) -> Result<u64, OutpackError> {
+    headers
+        .get("upload-offset")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse().ok())
+        .ok_or_else(|| {
+            OutpackError::new(
+                "BAD_REQUEST",
+                "Expected a valid Upload-Offset header",
+                StatusCode::BAD_REQUEST,
+            )
+        })
+}
+
+async fn append_upload(
+    state: State<AppState>,
+    id: extract::Path<String>,
+    headers: HeaderMap,
+    body: Body,
+) -> OutpackResult<UploadStatus> {
+    let offset = get_upload_offset(&headers)?;
+    let max_size = state.config.read().unwrap().server.max_upload_size;
+    resumable::append_chunk(&state.root, &id, offset, body.into_data_stream(), max_size)
+        .await
+        .map_err(OutpackError::from)
+        .map(OutpackSuccess::from)
+}
+
+#[derive(Deserialize)]
+struct FinishUpload {
+    hash: String,
+}
+
+async fn finish_upload(
+    root: State<PathBuf>,
+    id: extract::Path<String>,
+    body: Result<Json<FinishUpload>, JsonRejection>,
+) -> OutpackResult<()> {
+    let body = body?;
+    resumable::finish_upload(&root, &id, &body.hash)
+        .await
+        .map_err(OutpackError::from)
+        .map(OutpackSuccess::from)
+}
+
+async fn cancel_upload(root: State<PathBuf>, id: extract::Path<String>) -> OutpackResult<()> {
+    resumable::cancel_upload(&root, &id)
+        .await
+        .map_err(OutpackError::from)
+        .map(OutpackSuccess::from)
+}
+
+async fn add_metadata(state: State<AppState>, metadata: String) -> OutpackResult<String> {
+    let root = state.root.clone();
+    let hash_algorithm = state.config.read().unwrap().core.hash_algorithm;
+    tokio::task::spawn_blocking(move || {
+        metadata::add_validated_metadata(&root, &metadata, hash_algorithm)
+    })
+    .await
+    .unwrap()
+    .map(|hash| hash.to_string())
+    .map_err(OutpackError::from)
+    .map(OutpackSuccess::from)
+}
+
+/// Check which of the dependencies of some metadata are missing, so that a client can pull them
+/// before adding it.
+async fn validate_metadata(root: State<PathBuf>, metadata: String) -> OutpackResult<Vec<String>> {
+    tokio::task::spawn_blocking(move || metadata::get_missing_dependencies(&root, &metadata))
+        .await
+        .unwrap()
+        .map_err(OutpackError::from)
+        .map(OutpackSuccess::from)
+}
+
+async fn add_packet(
+    root: State<PathBuf>,
+    hash: extract::Path<String>,
+    packet: String,
+) -> Result<OutpackSuccess<()>, OutpackError> {
+    let hash = hash.parse::<hash::Hash>().map_err(OutpackError::from)?;
+    metadata::add_packet(&root, &packet, &hash)
+        .map_err(OutpackError::from)
+        .map(OutpackSuccess::from)
+}
+
+/// Replace the configuration with the one now on disk. Settings which are only read at startup
+/// cannot be changed this way, and a configuration which changes any of them is rejected rather
+/// than being applied in part.
+async fn reload_config(state: State<AppState>) -> OutpackResult<()> {
+    let _guard = state.config_changes.lock().await;
+    let config = load_config(&state.root).map_err(|e| {
+        OutpackError::new(
+            "INVALID_CONFIG",
+            format!("{:#}", e),
+            StatusCode::BAD_REQUEST,
+        )
+    })?;
+    let mut current = state.config.write().unwrap();
+    let changed = config.server.changed_startup_settings(&current.server);
+    if !changed.is_empty() {
+        return Err(OutpackError::new(
+            "INVALID_CONFIG",
+            format!(
+                "The settings {} are only read at startup; restart the server to change them",
+                changed.join(", ")
+            ),
+            StatusCode::BAD_REQUEST,
+        ));
+    }
+    index::use_index_threads(&state.root, config.server.index_threads());
+    *current = config;
+    Ok(OutpackSuccess::from(()))
+}
+
+#[derive(Serialize, Deserialize)]
+struct ReindexResult {
+    packets: usize,
+}
+
+async fn reindex(root: State<PathBuf>) -> OutpackResult<ReindexResult> {
+    tokio::task::spawn_blocking(move || {
+        index::rebuild(&root)
+            .map(|packets| ReindexResult { packets })
+            .map_err(OutpackError::from)
+            .map(OutpackSuccess::from)
+    })
+    .await
+    .unwrap()
+}
 
-type OutpackResult<T> = Result<OutpackSuccess<T>, OutpackError>;
+#[derive(Deserialize)]
+struct CleanTemp {
+    max_age: Option<u64>,
+}
 
-// This mostly exists to smooth over a difference with original
-// version, which used Root as the object; soon we will update this to
-// report actual versions back.
-#[derive(Serialize, Deserialize, Debug)]
-pub struct ApiRoot {
-    pub schema_version: String,
+/// Remove old temporary files left behind by uploads. Files must have gone unmodified for the
+/// `max_age` query parameter, in seconds, or else the configured `temp_max_age`.
+async fn clean_temp(
+    state: State<AppState>,
+    query: Query<CleanTemp>,
+) -> OutpackResult<store::CleanTempResult> {
+    let config = state.config.read().unwrap().clone();
+    let max_age = Duration::from_secs(query.max_age.unwrap_or(config.server.temp_max_age));
+    let upload_dir = config.server.upload_dir(&state.root);
+    let root = state.root.clone();
+    tokio::task::spawn_blocking(move || store::clean_temp(&root, &upload_dir, max_age))
+        .await
+        .unwrap()
+        .map_err(OutpackError::from)
+        .map(OutpackSuccess::from)
 }
 
-fn internal_error(_err: Box<dyn Any + Send + 'static>) -> Response {
-    OutpackError {
-        error: String::from("UNKNOWN_ERROR"),
-        detail: String::from("Something went wrong"),
-        kind: Some(ErrorKind::Other),
+/// Remove old temporary files from the repository every `interval`, for as long as the server
+/// runs, starting straight away.
+async fn clean_temp_periodically(
+    root: PathBuf,
+    upload_dir: PathBuf,
+    interval: Duration,
+    max_age: Duration,
+) {
+    let mut ticks = tokio::time::interval(interval);
+    loop {
+        ticks.tick().await;
+        let root = root.clone();
+        let upload_dir = upload_dir.clone();
+        let result =
+            tokio::task::spawn_blocking(move || store::clean_temp(&root, &upload_dir, max_age))
+                .await
+                .unwrap();
+        match result {
+            Ok(result) if !result.removed.is_empty() => tracing::info!(
+                "removed {} temporary files, freeing {} bytes",
+                result.removed.len(),
+                result.bytes
+            ),
+            Ok(_) => {}
+            Err(e) => tracing::warn!("failed to remove temporary files: {}", e),
+        }
     }
-    .into_response()
 }
 
-async fn not_found() -> OutpackError {
-    OutpackError {
-        error: String::from("NOT_FOUND"),
-        detail: String::from("This route does not exist"),
-        kind: Some(ErrorKind::NotFound),
+/// Reject requests to the admin endpoints which do not have the configured admin token, if there
+/// is one.
+async fn require_admin_token(
+    state: State<AppState>,
+    request: Request,
+    next: axum::middleware::Next,
+) -> Response {
+    let admin_token = state.config.read().unwrap().server.admin_token.clone();
+    if let Some(admin_token) = admin_token {
+        let token = request
+            .headers()
+            .get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+        if !token.is_some_and(|token| signing::constant_time_eq(&admin_token, token)) {
+            let mut response = OutpackError::new(
+                "UNAUTHORIZED",
+                "This endpoint requires the admin token",
+                StatusCode::UNAUTHORIZED,
+            )
+            .into_response();
+            response
+                .headers_mut()
+                .insert(WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
+            return response;
+        }
     }
+    next.run(request).await
 }
 
-async fn index() -> OutpackResult<ApiRoot> {
-    Ok(OutpackSuccess::from(ApiRoot {
-        schema_version: String::from("0.1.1"),
-    }))
+/// Reject requests to endpoints which change the configured locations or make the server fetch
+/// from other hosts unless they have the admin token. Unlike the other admin endpoints, these are
+/// disabled entirely when no admin token is configured, as otherwise anyone could make the server
+/// fetch from a host of their choosing.
+async fn require_configured_admin_token(
+    state: State<AppState>,
+    request: Request,
+    next: axum::middleware::Next,
+) -> Response {
+    if state.config.read().unwrap().server.admin_token.is_none() {
+        return OutpackError::new(
+            "FORBIDDEN",
+            "This endpoint is disabled unless an admin token is configured",
+            StatusCode::FORBIDDEN,
+        )
+        .into_response();
+    }
+    require_admin_token(state, request, next).await
 }
 
-async fn list_location_metadata(
-    root: State<PathBuf>,
-) -> OutpackResult<Vec<location::LocationEntry>> {
-    location::read_locations(&root)
-        .map_err(OutpackError::from)
-        .map(OutpackSuccess::from)
+/// The configured locations, as they are listed. Any credentials used to access a location are
+/// omitted.
+fn listed_locations(config: &config::Config) -> Vec<config::Location> {
+    config
+        .location
+        .iter()
+        .cloned()
+        .map(|mut location| {
+            if let Some(args) = location.args.as_object_mut() {
+                args.remove("token");
+            }
+            location
+        })
+        .collect()
 }
 
-#[derive(Deserialize)]
-struct KnownSince {
-    known_since: Option<f64>,
+/// The entity tag of the configured locations, which changes whenever any of them is added,
+/// removed or changed, including when only their credentials change. Credentials only contribute
+/// through a digest of the full configuration, so the tag does not reveal them.
+fn locations_etag(config: &config::Config) -> HeaderValue {
+    let full = serde_json::to_vec(&config.location).unwrap();
+    let full = hash::hash_data(&full, hash::HashAlgorithm::Sha256);
+    let mut locations = serde_json::to_vec(&listed_locations(config)).unwrap();
+    locations.extend_from_slice(full.to_string().as_bytes());
+    let hash = hash::hash_data(&locations, hash::HashAlgorithm::Sha256);
+    format!("\"{}\"", hash).parse().unwrap()
 }
 
-async fn get_metadata_since(
-    root: State<PathBuf>,
-    query: Query<KnownSince>,
-) -> OutpackResult<Vec<metadata::PackitPacket>> {
-    metadata::get_packit_metadata_from_date(&root, query.known_since)
-        .map_err(OutpackError::from)
-        .map(OutpackSuccess::from)
+/// Check an If-Match header against the current entity tag of a resource before changing it, so
+/// that a client cannot overwrite changes it has not seen. Requests without the header are always
+/// allowed. Weak tags never match, as If-Match uses strong comparison.
+fn check_if_match(headers: &HeaderMap, etag: &HeaderValue) -> Result<(), OutpackError> {
+    let mut tags = headers
+        .get_all(IF_MATCH)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(|tag| tag.trim())
+        .peekable();
+    if tags.peek().is_none() || tags.any(|tag| tag == "*" || tag.as_bytes() == etag.as_bytes()) {
+        Ok(())
+    } else {
+        Err(OutpackError::new(
+            "PRECONDITION_FAILED",
+            String::from("The resource has changed since it was read, so must be read again first"),
+            StatusCode::PRECONDITION_FAILED,
+        ))
+    }
 }
 
-async fn get_metadata_by_id(
-    root: State<PathBuf>,
-    id: extract::Path<String>,
-) -> OutpackResult<serde_json::Value> {
-    metadata::get_metadata_by_id(&root, &id)
-        .map_err(OutpackError::from)
-        .map(OutpackSuccess::from)
+/// Respond to a change to the locations, giving their new entity tag.
+fn locations_changed(config: &config::Config) -> Response {
+    let mut response = OutpackSuccess::from(()).into_response();
+    response.headers_mut().insert(ETAG, locations_etag(config));
+    response
 }
 
-async fn get_metadata_raw(
-    root: State<PathBuf>,
-    id: extract::Path<String>,
-) -> Result<String, OutpackError> {
-    metadata::get_metadata_text(&root, &id).map_err(OutpackError::from)
+/// List the configured locations. Any credentials used to access a location are omitted.
+async fn list_locations(state: State<AppState>) -> Result<Response, OutpackError> {
+    let config = state.config.read().unwrap();
+    let mut response = OutpackSuccess::from(listed_locations(&config)).into_response();
+    response.headers_mut().insert(ETAG, locations_etag(&config));
+    Ok(response)
 }
 
-async fn get_file(
-    root: State<PathBuf>,
-    hash: extract::Path<String>,
-) -> Result<OutpackFile, OutpackError> {
-    let path = store::file_path(&root, &hash);
-    OutpackFile::open(hash.to_owned(), path?)
+#[derive(Deserialize)]
+struct AddLocation {
+    name: String,
+    #[serde(flatten)]
+    args: config::HttpLocationArgs,
+}
+
+async fn add_location(
+    state: State<AppState>,
+    headers: HeaderMap,
+    body: Result<Json<AddLocation>, JsonRejection>,
+) -> Result<Response, OutpackError> {
+    let Json(body) = body?;
+    change_locations(&state, &headers, move |root, config| {
+        location::add_remote_location(root, config, &body.name, body.args)
+    })
+    .await
+}
+
+async fn remove_location(
+    state: State<AppState>,
+    name: extract::Path<String>,
+    headers: HeaderMap,
+) -> Result<Response, OutpackError> {
+    change_locations(&state, &headers, move |root, config| {
+        location::remove_location(root, config, &name)
+    })
+    .await
+}
+
+/// Change the configured locations, if they have not changed since the client last read them,
+/// and start using the new configuration once it has been written.
+async fn change_locations(
+    state: &AppState,
+    headers: &HeaderMap,
+    change: impl FnOnce(&Path, &config::Config) -> io::Result<config::Config> + Send + 'static,
+) -> Result<Response, OutpackError> {
+    let _guard = state.config_changes.lock().await;
+    let config = state.config.read().unwrap().clone();
+    check_if_match(headers, &locations_etag(&config))?;
+    let root = state.root.clone();
+    let config = tokio::task::spawn_blocking(move || change(&root, &config))
         .await
-        .map_err(OutpackError::from)
+        .unwrap()?;
+    let response = locations_changed(&config);
+    *state.config.write().unwrap() = config;
+    Ok(response)
 }
 
 #[derive(Deserialize)]
-struct Algorithm {
-    alg: Option<String>,
+struct Pull {
+    location: String,
+    ids: Option<Vec<String>>,
 }
 
-async fn get_checksum(root: State<PathBuf>, query: Query<Algorithm>) -> OutpackResult<String> {
-    metadata::get_ids_digest(&root, query.0.alg)
-        .map_err(OutpackError::from)
-        .map(OutpackSuccess::from)
+async fn sync_pull(
+    state: State<AppState>,
+    body: Result<Json<Pull>, JsonRejection>,
+) -> OutpackResult<sync::PullResult> {
+    let Json(body) = body?;
+    let config = state.config.read().unwrap().clone();
+    tokio::task::spawn_blocking(move || {
+        sync::pull_packets(&state.root, &config, &body.location, body.ids.as_deref())
+            .map_err(OutpackError::from)
+            .map(OutpackSuccess::from)
+    })
+    .await
+    .unwrap()
 }
 
-async fn get_missing_packets(
-    root: State<PathBuf>,
-    ids: Result<Json<Ids>, JsonRejection>,
-) -> OutpackResult<Vec<String>> {
-    let ids = ids?;
-    metadata::get_missing_ids(&root, &ids.ids, ids.unpacked)
-        .map_err(OutpackError::from)
-        .map(OutpackSuccess::from)
+#[derive(Deserialize)]
+struct SyncDiff {
+    ids: Option<Vec<String>>,
+    location: Option<String>,
+    #[serde(default)]
+    unpacked: bool,
 }
 
-async fn get_missing_files(
-    root: State<PathBuf>,
-    hashes: Result<Json<Hashes>, JsonRejection>,
-) -> OutpackResult<Vec<String>> {
-    let hashes = hashes?;
-    store::get_missing_files(&root, &hashes.hashes)
-        .map_err(OutpackError::from)
-        .map(OutpackSuccess::from)
+/// Compare the packets of this repository with another's, given either as a list of ids or as
+/// the name of a location to fetch the list from.
+async fn sync_diff(
+    state: State<AppState>,
+    body: Result<Json<SyncDiff>, JsonRejection>,
+) -> OutpackResult<metadata::PacketDiff> {
+    let Json(body) = body?;
+    if let Some(ids) = &body.ids {
+        check_count(&state, ids.len(), "ids")?;
+    }
+    let config = state.config.read().unwrap().clone();
+    tokio::task::spawn_blocking(move || {
+        let remote = match (body.ids, body.location) {
+            (Some(ids), None) => ids,
+            (None, Some(location)) => sync::get_location_ids(&config, &location)?,
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "Exactly one of 'ids' and 'location' must be given",
+                ))
+            }
+        };
+        metadata::get_packet_diff(&state.root, &remote, body.unpacked)
+    })
+    .await
+    .unwrap()
+    .map_err(OutpackError::from)
+    .map(OutpackSuccess::from)
 }
 
-async fn add_file(
-    root: State<PathBuf>,
+#[derive(Deserialize)]
+struct PullFile {
+    url: String,
+}
+
+/// Download a file into the store from another server, rather than have a client download and
+/// upload it again.
+async fn pull_file(
+    state: State<AppState>,
     hash: extract::Path<String>,
-    file: Upload,
-) -> Result<OutpackSuccess<()>, OutpackError> {
+    body: Result<Json<PullFile>, JsonRejection>,
+) -> OutpackResult<()> {
+    let Json(body) = body?;
+    let config = state.config.read().unwrap().clone();
     tokio::task::spawn_blocking(move || {
-        store::put_file(&root, file, &hash)
+        sync::pull_file(&state.root, &config, &body.url, &hash)
             .map_err(OutpackError::from)
             .map(OutpackSuccess::from)
     })
@@ -152,20 +1488,81 @@ async fn add_file(
     .unwrap()
 }
 
-async fn add_packet(
-    root: State<PathBuf>,
-    hash: extract::Path<String>,
-    packet: String,
-) -> Result<OutpackSuccess<()>, OutpackError> {
-    let hash = hash.parse::<hash::Hash>().map_err(OutpackError::from)?;
-    metadata::add_packet(&root, &packet, &hash)
-        .map_err(OutpackError::from)
-        .map(OutpackSuccess::from)
+#[derive(Deserialize)]
+struct Remote {
+    remote: Option<String>,
+}
+
+async fn git_fetch(
+    state: State<AppState>,
+    query: Query<Remote>,
+) -> Result<OutpackSuccess<git::FetchResult>, OutpackError> {
+    let remote = query
+        .0
+        .remote
+        .unwrap_or_else(|| git::DEFAULT_REMOTE.to_owned());
+    let depth = state.config.read().unwrap().server.git_fetch_depth;
+    tokio::task::spawn_blocking(move || {
+        git::git_fetch(&state.root, &remote, depth)
+            .map_err(OutpackError::from)
+            .map(OutpackSuccess::from)
+    })
+    .await
+    .unwrap()
 }
 
-async fn git_fetch(root: State<PathBuf>) -> Result<OutpackSuccess<()>, OutpackError> {
+/// Fetch from the repository's git remote every `schedule.interval`, starting straight away, for
+/// as long as the API serving `state` exists. A fetch which fails is logged, and does not stop
+/// the next one from being tried.
+fn schedule_git_fetch(state: &AppState, schedule: config::GitFetchSchedule) {
+    let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+        tracing::warn!("git fetches cannot be scheduled outside of an async runtime");
+        return;
+    };
+    // Only weak references are kept, so that the task ends once the API has been dropped.
+    let config = Arc::downgrade(&state.config);
+    let worktrees = Arc::downgrade(&state.worktrees);
+    let root = state.root.clone();
+    let remote = schedule
+        .remote
+        .unwrap_or_else(|| git::DEFAULT_REMOTE.to_owned());
+
+    runtime.spawn(async move {
+        let mut ticks = tokio::time::interval(Duration::from_secs(schedule.interval));
+        loop {
+            ticks.tick().await;
+            let (Some(config), Some(worktrees)) = (config.upgrade(), worktrees.upgrade()) else {
+                return;
+            };
+            let depth = config.read().unwrap().server.git_fetch_depth;
+            let root = root.clone();
+            let fetch_remote = remote.clone();
+            let checkout = schedule.checkout.clone();
+            let result = tokio::task::spawn_blocking(move || {
+                let result = git::git_fetch(&root, &fetch_remote, depth)?;
+                if let Some(branch) = checkout {
+                    worktrees.checkout(&branch)?;
+                }
+                Ok::<_, git2::Error>(result)
+            })
+            .await
+            .unwrap();
+            match result {
+                Ok(result) if !result.refs_updated.is_empty() => tracing::info!(
+                    "fetched from git remote '{}', updating {}",
+                    remote,
+                    result.refs_updated.join(", ")
+                ),
+                Ok(_) => {}
+                Err(e) => tracing::warn!("failed to fetch from git remote '{}': {}", remote, e),
+            }
+        }
+    });
+}
+
+async fn git_status(root: State<PathBuf>) -> Result<OutpackSuccess<git::GitStatus>, OutpackError> {
     tokio::task::spawn_blocking(move || {
-        git::git_fetch(&root)
+        git::git_status(&root)
             .map_err(OutpackError::from)
             .map(OutpackSuccess::from)
     })
@@ -173,11 +1570,97 @@ async fn git_fetch(root: State<PathBuf>) -> Result<OutpackSuccess<()>, OutpackEr
     .unwrap()
 }
 
+/// Serve a request for a branch of the repository's git remote, as though its latest commit were
+/// a root of its own. The branch is checked out into a worktree the first time it is used, and
+/// moved to the latest commit whenever it has changed since.
+async fn serve_branch(
+    state: State<AppState>,
+    extract::Path((branch, _)): extract::Path<(String, String)>,
+    request: Request,
+) -> Result<Response, OutpackError> {
+    let worktrees = state.worktrees.clone();
+    let name = branch.clone();
+    let (root, commit) = tokio::task::spawn_blocking(move || worktrees.checkout(&name))
+        .await
+        .unwrap()?;
+
+    // The routes, and the configuration they were built from, are rebuilt whenever the branch
+    // has moved on, as its configuration may have changed with it.
+    let router = {
+        let mut branch_routes = state.branch_routes.lock().unwrap();
+        match branch_routes.get(&branch) {
+            Some((built_for, router)) if *built_for == commit => router.clone(),
+            _ => {
+                let config = load_config(&root).map_err(|e| {
+                    OutpackError::new(
+                        "NOT_FOUND",
+                        format!("Branch '{}' is not an outpack repository: {:#}", branch, e),
+                        StatusCode::NOT_FOUND,
+                    )
+                })?;
+                let router = routes(AppState::new(&root, config), prometheus::Registry::new())
+                    .layer(axum::middleware::map_response(json_error_response));
+                branch_routes.insert(branch, (commit, router.clone()));
+                router
+            }
+        }
+    };
+
+    // The path is taken from the request as sent, rather than as decoded by the extractor, so
+    // that anything escaped in it stays escaped when it is matched against the branch's routes.
+    let path = request
+        .uri()
+        .path()
+        .trim_start_matches('/')
+        .splitn(3, '/')
+        .nth(2)
+        .unwrap_or("");
+    let uri = match request.uri().query() {
+        Some(query) => format!("/{}?{}", path, query),
+        None => format!("/{}", path),
+    };
+    let uri: axum::http::Uri = uri.parse().map_err(|e| {
+        OutpackError::new(
+            "BAD_REQUEST",
+            format!("Invalid path '{}': {}", uri, e),
+            StatusCode::BAD_REQUEST,
+        )
+    })?;
+    // The request is rebuilt rather than modified, as its extensions hold the parameters matched
+    // by this route, which would confuse those of the branch's routes.
+    let (parts, body) = request.into_parts();
+    let mut forwarded = Request::new(body);
+    *forwarded.method_mut() = parts.method;
+    *forwarded.uri_mut() = uri;
+    *forwarded.headers_mut() = parts.headers;
+    Ok(router.oneshot(forwarded).await.unwrap())
+}
+
+#[derive(Deserialize)]
+struct MaxAge {
+    max_age_days: Option<u64>,
+}
+
 async fn git_list_branches(
     root: State<PathBuf>,
+    query: Query<MaxAge>,
 ) -> Result<OutpackSuccess<git::BranchResponse>, OutpackError> {
+    let max_age = query
+        .max_age_days
+        .map(|days| {
+            days.checked_mul(24 * 60 * 60)
+                .map(Duration::from_secs)
+                .ok_or_else(|| {
+                    OutpackError::new(
+                        "BAD_REQUEST",
+                        format!("max_age_days of {} is too large", days),
+                        StatusCode::BAD_REQUEST,
+                    )
+                })
+        })
+        .transpose()?;
     tokio::task::spawn_blocking(move || {
-        git::git_list_branches(&root)
+        git::git_list_branches(&root, max_age)
             .map_err(OutpackError::from)
             .map(OutpackSuccess::from)
     })
@@ -188,7 +1671,10 @@ async fn git_list_branches(
 #[derive(Serialize, Deserialize)]
 struct Ids {
     ids: Vec<String>,
+    #[serde(default)]
     unpacked: bool,
+    #[serde(default)]
+    recursive: bool,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -211,16 +1697,17 @@ pub fn check_config(config: &config::Config) -> anyhow::Result<()> {
             config.core.hash_algorithm
         );
     }
-    if config.core.path_archive.is_some() {
+    if let Some(path_archive) = &config.core.path_archive {
         bail!(
             "Outpack must be configured to *not* use an archive, but your path_archive is '{}'",
-            config.core.path_archive.as_ref().unwrap()
+            path_archive
         );
     }
     Ok(())
 }
 
-pub fn preflight(root: &Path) -> anyhow::Result<()> {
+/// Read and validate the configuration of the repository at the given root.
+fn load_config(root: &Path) -> anyhow::Result<config::Config> {
     if !root.join(".outpack").exists() {
         bail!("Outpack root not found at '{}'", root.display());
     }
@@ -229,13 +1716,25 @@ pub fn preflight(root: &Path) -> anyhow::Result<()> {
         .with_context(|| format!("Failed to read outpack config from '{}'", root.display()))?;
 
     check_config(&config)?;
+    cors_layer(&config.server.cors)?;
+    Ok(config)
+}
+
+pub fn preflight(root: &Path) -> anyhow::Result<()> {
+    load_config(root)?;
     Ok(())
 }
 
 fn make_request_span(request: &axum::extract::Request) -> tracing::span::Span {
-    let request_id = String::from_utf8_lossy(request.headers()["x-request-id"].as_bytes());
+    // The request id is set by an outer layer, so should always be present. Avoid panicking if
+    // that ever stops being the case, since this runs for every request, including failing ones.
+    let request_id = request
+        .headers()
+        .get("x-request-id")
+        .map(|id| String::from_utf8_lossy(id.as_bytes()).into_owned())
+        .unwrap_or_default();
     tracing::span!(
-        tracing::Level::DEBUG,
+        tracing::Level::INFO,
         "request",
         method = tracing::field::display(request.method()),
         uri = tracing::field::display(request.uri()),
@@ -244,65 +1743,656 @@ fn make_request_span(request: &axum::extract::Request) -> tracing::span::Span {
     )
 }
 
-pub fn api(root: &Path) -> anyhow::Result<Router> {
-    use axum::routing::{get, post};
+/// Error responses produced by axum itself, rather than by a handler, usually have a plain text
+/// body. For the statuses a client might reasonably cause, such as by sending a query parameter
+/// of the wrong type, the body is replaced with the usual json failure response.
+async fn json_error_response(response: Response) -> Response {
+    let error = match response.status() {
+        StatusCode::BAD_REQUEST => "BAD_REQUEST",
+        StatusCode::PAYLOAD_TOO_LARGE => "PAYLOAD_TOO_LARGE",
+        StatusCode::UNSUPPORTED_MEDIA_TYPE => "UNSUPPORTED_MEDIA_TYPE",
+        StatusCode::UNPROCESSABLE_ENTITY => "UNPROCESSABLE_ENTITY",
+        _ => return response,
+    };
+    let is_json = response
+        .headers()
+        .get(CONTENT_TYPE)
+        .is_some_and(|v| v.as_bytes().starts_with(b"application/json"));
+    if is_json {
+        return response;
+    }
 
-    let registry = prometheus::Registry::new();
-    register_process_metrics(&registry).expect("process metrics registered");
-    register_build_info_metrics(&registry).expect("build info metrics registered");
-    RepositoryMetrics::register(&registry, root).expect("repository metrics registered");
-    let http_metrics = HttpMetrics::register(&registry).expect("http metrics registered");
+    let (parts, body) = response.into_parts();
+    // These bodies are short messages, so there is no need to read more than a little of them.
+    let detail = match axum::body::to_bytes(body, 64 * 1024).await {
+        Ok(bytes) if !bytes.is_empty() => String::from_utf8_lossy(&bytes).into_owned(),
+        _ => parts
+            .status
+            .canonical_reason()
+            .unwrap_or_default()
+            .to_owned(),
+    };
+    let mut response = OutpackError::new(String::from(error), detail, parts.status).into_response();
+    // Keep any other headers, such as the request id.
+    for (name, value) in parts.headers.iter() {
+        if name != CONTENT_TYPE && name != CONTENT_LENGTH {
+            response.headers_mut().insert(name.clone(), value.clone());
+        }
+    }
+    response
+}
+
+#[derive(Deserialize)]
+struct Pretty {
+    #[serde(default)]
+    pretty: bool,
+}
+
+/// Responses larger than this are left compact even if `?pretty=true` is given, so that indenting
+/// them does not mean holding the whole of a large response in memory.
+const PRETTY_PRINT_LIMIT: usize = 8 * 1024 * 1024;
+
+/// Indent json responses to requests with `?pretty=true`, so that they are easier to read while
+/// debugging. The response is parsed and written out again, so its content is unchanged but the
+/// keys of objects are sorted.
+async fn pretty_print_json(request: Request, next: axum::middleware::Next) -> Response {
+    let pretty = Query::<Pretty>::try_from_uri(request.uri()).is_ok_and(|query| query.pretty);
+    let response = next.run(request).await;
+    let is_json = response
+        .headers()
+        .get(CONTENT_TYPE)
+        .is_some_and(|v| v.as_bytes().starts_with(b"application/json"));
+    let too_large = response
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok()?.parse::<usize>().ok())
+        .is_some_and(|length| length > PRETTY_PRINT_LIMIT);
+    if !pretty || !is_json || too_large {
+        return response;
+    }
+
+    let (mut parts, body) = response.into_parts();
+    match indent_json(body, PRETTY_PRINT_LIMIT).await {
+        Ok(body) => {
+            parts.headers.remove(CONTENT_LENGTH);
+            Response::from_parts(parts, body)
+        }
+        Err(e) => OutpackError::from(io::Error::new(io::ErrorKind::Other, e)).into_response(),
+    }
+}
+
+/// Indent a json body. A body found to be more than `limit` bytes long is passed on unchanged,
+/// starting with the part of it which had already been read.
+async fn indent_json(body: Body, limit: usize) -> Result<Body, axum::Error> {
+    use futures::StreamExt;
+    let mut stream = body.into_data_stream();
+    let mut chunks = Vec::new();
+    let mut size = 0;
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk?;
+        size += chunk.len();
+        chunks.push(chunk);
+        if size > limit {
+            let read = futures::stream::iter(chunks.into_iter().map(Ok));
+            return Ok(Body::from_stream(read.chain(stream)));
+        }
+    }
+    let bytes = chunks.concat();
+    Ok(match serde_json::from_slice::<serde_json::Value>(&bytes) {
+        Ok(value) => serde_json::to_vec_pretty(&value).unwrap().into(),
+        Err(_) => bytes.into(),
+    })
+}
+
+/// Report a failed request as a 503 if the root has become unavailable, such as because it was
+/// unmounted or deleted while the server was running. The failure is then caused by the server's
+/// environment rather than by the request, even if it looked like a missing file.
+///
+/// Successful responses are left alone, so this only costs a filesystem access when something has
+/// already gone wrong.
+async fn check_root_available(State(root): State<PathBuf>, response: Response) -> Response {
+    let status = response.status();
+    if status != StatusCode::NOT_FOUND && !status.is_server_error() {
+        return response;
+    }
+    if std::fs::read_dir(root.join(".outpack")).is_ok() {
+        return response;
+    }
+    tracing::error!("the root at {} is unavailable", root.display());
+    OutpackError::new(
+        "SERVICE_UNAVAILABLE",
+        format!("The repository at '{}' is unavailable", root.display()),
+        StatusCode::SERVICE_UNAVAILABLE,
+    )
+    .into_response()
+}
 
-    preflight(root)?;
+/// Tell clients how long to wait before retrying a request which failed because the server is
+/// unavailable or is limiting their requests. Other responses are left alone.
+async fn add_retry_after(
+    State(retry_after): State<HeaderValue>,
+    mut response: Response,
+) -> Response {
+    let status = response.status();
+    if status == StatusCode::SERVICE_UNAVAILABLE || status == StatusCode::TOO_MANY_REQUESTS {
+        let headers = response.headers_mut();
+        if !headers.contains_key(RETRY_AFTER) {
+            headers.insert(RETRY_AFTER, retry_after);
+        }
+    }
+    response
+}
+
+/// The version of the API's contract with its clients, sent with every response in the
+/// `Outpack-Api-Version` header. This changes whenever a response changes in a way that could
+/// break existing clients.
+pub const API_VERSION: &str = "1";
+
+const API_VERSION_HEADER: HeaderName = HeaderName::from_static("outpack-api-version");
+
+async fn add_api_version(mut response: Response) -> Response {
+    response
+        .headers_mut()
+        .insert(API_VERSION_HEADER, HeaderValue::from_static(API_VERSION));
+    response
+}
+
+type SecurityHeaders = Arc<Vec<(HeaderName, HeaderValue)>>;
+
+/// Check the configured security headers, returning the names and values of those enabled.
+fn security_headers(config: &config::SecurityHeaders) -> anyhow::Result<SecurityHeaders> {
+    let headers = config
+        .enabled()
+        .into_iter()
+        .map(|(name, value)| {
+            let value = HeaderValue::from_str(value)
+                .with_context(|| format!("Invalid value for header '{}'", name))?;
+            Ok((HeaderName::from_static(name), value))
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    Ok(Arc::new(headers))
+}
+
+/// Add the security headers to a response, unless it already has a value for them.
+async fn add_security_headers(
+    State(headers): State<SecurityHeaders>,
+    mut response: Response,
+) -> Response {
+    for (name, value) in headers.iter() {
+        if !response.headers().contains_key(name) {
+            response.headers_mut().insert(name.clone(), value.clone());
+        }
+    }
+    response
+}
+
+/// Build the layer handling cross-origin requests, or `None` if no origins are allowed.
+///
+/// Preflight requests are answered by the layer itself, and only these responses include the
+/// `Access-Control-Max-Age` header.
+fn cors_layer(config: &config::Cors) -> anyhow::Result<Option<CorsLayer>> {
+    if config.allowed_origins.is_empty() {
+        return Ok(None);
+    }
+    let origins = if config.allowed_origins.iter().any(|o| o == "*") {
+        AllowOrigin::any()
+    } else {
+        let origins = config
+            .allowed_origins
+            .iter()
+            .map(|origin| {
+                HeaderValue::from_str(origin)
+                    .with_context(|| format!("Invalid CORS origin '{}'", origin))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        AllowOrigin::list(origins)
+    };
+    Ok(Some(
+        CorsLayer::new()
+            .allow_origin(origins)
+            .allow_methods(cors::Any)
+            .allow_headers(cors::Any)
+            .expose_headers([API_VERSION_HEADER])
+            .max_age(Duration::from_secs(config.max_age)),
+    ))
+}
 
-    let routes = Router::new()
+/// Apply the CORS settings of the configuration currently in use, so that reloading the
+/// configuration changes which origins are allowed.
+async fn apply_cors(
+    State(config): State<Arc<RwLock<config::Config>>>,
+    request: axum::extract::Request,
+    next: axum::middleware::Next,
+) -> Response {
+    // The settings were checked when the configuration was loaded, so cannot be invalid here.
+    let cors = cors_layer(&config.read().unwrap().server.cors).unwrap_or_default();
+    match cors {
+        Some(cors) => cors.layer(next).oneshot(request).await.into_response(),
+        None => next.run(request).await,
+    }
+}
+
+/// The routes of the API, serving the repository whose state is given.
+fn routes(state: AppState, registry: prometheus::Registry) -> Router {
+    use axum::routing::{delete, get, post};
+
+    // Json bodies are read into memory, so are limited separately from, and usually more tightly
+    // than, uploaded files.
+    let max_json_size = state.config.read().unwrap().server.max_json_size;
+    let json_limit = || DefaultBodyLimit::max(max_json_size);
+
+    // Endpoints which change the repository other than by adding packets to it are restricted to
+    // holders of the admin token if there is one.
+    let admin = Router::new()
+        .route("/admin/reload", post(reload_config))
+        .route("/admin/reindex", post(reindex))
+        .route("/admin/clean-temp", post(clean_temp))
+        .route("/store/gc", post(gc_store))
+        .route_layer(axum::middleware::from_fn_with_state(
+            state.clone(),
+            require_admin_token,
+        ));
+
+    // Those which change the locations or make the server contact other hosts always are.
+    let remote = Router::new()
+        .route("/file/:hash/pull", post(pull_file).layer(json_limit()))
+        .route("/locations", post(add_location).layer(json_limit()))
+        .route("/locations/:name", delete(remove_location))
+        .route("/sync/pull", post(sync_pull).layer(json_limit()))
+        .route("/git/fetch", post(git_fetch))
+        .route_layer(axum::middleware::from_fn_with_state(
+            state.clone(),
+            require_configured_admin_token,
+        ));
+
+    Router::new()
+        .merge(admin)
+        .merge(remote)
         .route("/", get(index))
         .route("/metadata/list", get(list_location_metadata))
+        .route("/metadata", post(add_metadata))
+        .route("/metadata/validate", post(validate_metadata))
+        .route(
+            "/metadata/search",
+            post(search_metadata).layer(json_limit()),
+        )
+        .route(
+            "/metadata/batch",
+            post(get_metadata_batch).layer(json_limit()),
+        )
         .route("/metadata/:id/json", get(get_metadata_by_id))
         .route("/metadata/:id/text", get(get_metadata_raw))
+        .route("/metadata/:id/parameters", get(get_packet_parameters))
+        .route("/metadata/:id/dependencies", get(get_dependencies))
+        .route("/metadata/:id/size", get(get_packet_size))
+        .route("/metadata/:id/download", get(download_packet))
         .route("/checksum", get(get_checksum))
-        .route("/packets/missing", post(get_missing_packets))
-        .route("/files/missing", post(get_missing_files))
+        .route(
+            "/packets/missing",
+            post(get_missing_packets).layer(json_limit()),
+        )
+        .route("/packets/latest", get(get_latest_packet))
+        .route("/packets/summary", get(get_packet_summary))
+        .route("/files", get(list_files))
+        .route(
+            "/files/missing",
+            post(get_missing_files).layer(json_limit()),
+        )
+        .route("/files/info", post(get_file_info).layer(json_limit()))
+        .route("/store/verify", get(verify_store))
+        .route("/store/references", get(get_store_references))
         .route("/packit/metadata", get(get_metadata_since))
         .route("/file/:hash", get(get_file).post(add_file))
+        .route("/file/:hash/signed-url", get(get_signed_url))
         .route("/packet/:hash", post(add_packet))
-        .route("/git/fetch", post(git_fetch))
+        .route("/upload", post(start_upload))
+        .route(
+            "/upload/:id",
+            get(get_upload).patch(append_upload).delete(cancel_upload),
+        )
+        .route(
+            "/upload/:id/finish",
+            post(finish_upload).layer(json_limit()),
+        )
+        .route("/locations", get(list_locations))
+        .route("/sync/plan", post(get_sync_plan).layer(json_limit()))
+        .route("/sync/diff", post(sync_diff).layer(json_limit()))
         .route("/git/branches", get(git_list_branches))
+        .route("/git/status", get(git_status))
+        .route("/git/:branch/*path", get(serve_branch))
         .route("/metrics", get(|| async move { metrics::render(registry) }))
+        .route("/openapi.json", get(|| async { Json(openapi::document()) }))
+        .route("/schema/:name", get(get_schema))
         .fallback(not_found)
-        .with_state(root.to_owned());
+        .with_state(state)
+}
+
+pub fn api(root: &Path) -> anyhow::Result<Router> {
+    let registry = prometheus::Registry::new();
+    register_process_metrics(&registry).expect("process metrics registered");
+    register_build_info_metrics(&registry).expect("build info metrics registered");
+    RepositoryMetrics::register(&registry, root).expect("repository metrics registered");
+    let http_metrics = HttpMetrics::register(&registry).expect("http metrics registered");
+
+    let config = load_config(root)?;
+    store::use_layout(root, config.server.store_layout);
+    index::use_index_threads(root, config.server.index_threads());
+    // The upload limit is only read at startup, and is not affected by reloading the config.
+    let max_upload_size = config.server.max_upload_size;
+    let read_timeout = config.server.read_timeout.map(Duration::from_secs);
+    let upload_dir = config.server.upload_dir(root);
+    std::fs::create_dir_all(&upload_dir).with_context(|| {
+        format!(
+            "Could not create upload directory '{}'",
+            upload_dir.display()
+        )
+    })?;
+    store::use_temp_dir(root, &upload_dir)?;
+    let security_headers = security_headers(&config.server.security_headers)?;
+    let retry_after = config.server.retry_after.map(HeaderValue::from);
+    let rate_limiter = RateLimiter::new(&config.server.rate_limit)?;
+    let git_fetch_schedule = config.server.git_fetch_schedule.clone();
+    let state = AppState::new(root, config);
+    if let Err(e) = state.worktrees.prune_stale() {
+        tracing::warn!("failed to prune stale git worktrees: {}", e);
+    }
+    if let Some(schedule) = git_fetch_schedule.filter(|s| s.interval > 0) {
+        schedule_git_fetch(&state, schedule);
+    }
+    let shared_config = state.config.clone();
+    let routes = routes(state, registry);
 
-    Ok(routes
-        .layer(UploadLayer::new(root.join(".outpack").join("files")))
-        .layer(TraceLayer::new_for_http().make_span_with(make_request_span))
+    let routes = routes
+        .layer(axum::middleware::map_response(json_error_response))
+        .layer(axum::middleware::from_fn(pretty_print_json))
+        .layer(axum::middleware::from_fn_with_state(
+            shared_config,
+            apply_cors,
+        ));
+    let routes = match read_timeout {
+        Some(read_timeout) => routes.layer(axum::middleware::map_request_with_state(
+            read_timeout,
+            timeout::limit_request_body,
+        )),
+        None => routes,
+    };
+    let routes = routes.layer(UploadLayer::new(upload_dir).with_max_size(max_upload_size));
+    // Limited requests are rejected before anything is done with them, such as reading an upload.
+    let routes = match rate_limiter {
+        Some(rate_limiter) => routes.layer(axum::middleware::from_fn_with_state(
+            Arc::new(rate_limiter),
+            ratelimit::limit_rate,
+        )),
+        None => routes,
+    };
+    let routes = routes
+        .layer(
+            TraceLayer::new_for_http()
+                .make_span_with(make_request_span)
+                .on_request(DefaultOnRequest::new().level(tracing::Level::INFO))
+                .on_response(DefaultOnResponse::new().level(tracing::Level::INFO)),
+        )
         .layer(PropagateRequestIdLayer::x_request_id())
         .layer(SetRequestIdLayer::x_request_id(MakeRequestUuid))
         .layer(CatchPanicLayer::custom(internal_error))
-        .layer(http_metrics.layer()))
+        .layer(axum::middleware::map_response_with_state(
+            root.to_owned(),
+            check_root_available,
+        ));
+    let routes = match retry_after {
+        Some(retry_after) => routes.layer(axum::middleware::map_response_with_state(
+            retry_after,
+            add_retry_after,
+        )),
+        None => routes,
+    };
+    let app = routes
+        .layer(axum::middleware::map_response_with_state(
+            security_headers,
+            add_security_headers,
+        ))
+        .layer(axum::middleware::map_response(add_api_version))
+        .layer(http_metrics.layer());
+
+    // Routing happens before any of the router's own layers are applied, so a trailing slash can
+    // only be removed by wrapping the whole application. This makes `/metadata/list/` equivalent
+    // to `/metadata/list`, while leaving `/` alone.
+    Ok(Router::new().fallback_service(NormalizePath::trim_trailing_slash(app)))
+}
+
+async fn list_roots(State(names): State<Arc<Vec<String>>>) -> OutpackResult<Vec<String>> {
+    Ok(OutpackSuccess::from(names.to_vec()))
+}
+
+/// Check that the name of a root can be used as a single segment of a path.
+fn check_root_name(name: &str) -> anyhow::Result<()> {
+    let valid = !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.');
+    if !valid || name == "." || name == ".." {
+        bail!(
+            "Invalid root name '{}': names may only contain letters, digits, '-', '_' and '.'",
+            name
+        );
+    }
+    Ok(())
+}
+
+/// Build the API for several repositories, keyed by name. Each is served under
+/// `/roots/<name>` exactly as `api` would serve it on its own, with its own configuration, and
+/// `GET /roots` lists their names.
+pub fn api_with_roots(roots: &BTreeMap<String, PathBuf>) -> anyhow::Result<Router> {
+    if roots.is_empty() {
+        bail!("At least one root must be given");
+    }
+    let names: Vec<String> = roots.keys().cloned().collect();
+    let mut router = Router::new()
+        .route("/roots", axum::routing::get(list_roots))
+        .with_state(Arc::new(names));
+    for (name, root) in roots {
+        check_root_name(name)?;
+        let api = api(root).with_context(|| format!("Failed to serve root '{}'", name))?;
+        router = router.nest_service(&format!("/roots/{}", name), api);
+    }
+    let app = router
+        .fallback(not_found)
+        .layer(axum::middleware::map_response(json_error_response))
+        .layer(axum::middleware::map_response(add_api_version));
+    Ok(Router::new().fallback_service(NormalizePath::trim_trailing_slash(app)))
+}
+
+/// Options for running an instance of the API server.
+#[derive(Debug, Clone)]
+pub struct ApiConfig {
+    /// The address of the interface to listen on.
+    pub address: IpAddr,
+
+    /// The port to listen on. If zero, a free port is chosen by the operating system.
+    pub port: u16,
+}
+
+impl Default for ApiConfig {
+    fn default() -> Self {
+        ApiConfig {
+            address: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            port: 8000,
+        }
+    }
+}
+
+impl From<SocketAddr> for ApiConfig {
+    fn from(addr: SocketAddr) -> Self {
+        ApiConfig {
+            address: addr.ip(),
+            port: addr.port(),
+        }
+    }
+}
+
+/// An instance of the API server, bound to an address but not yet accepting requests.
+pub struct Api {
+    router: Router,
+    listener: tokio::net::TcpListener,
+    grace_period: Duration,
+}
+
+impl Api {
+    /// The address the server is listening on.
+    pub fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Serve requests until the `shutdown` future completes.
+    ///
+    /// Requests that are in progress at that point are given the grace period configured for
+    /// the repository to complete.
+    pub async fn run(
+        self,
+        shutdown: impl std::future::Future<Output = ()> + Send + 'static,
+    ) -> anyhow::Result<()> {
+        shutdown::serve_with_shutdown(self.listener, self.router, shutdown, self.grace_period)
+            .await?;
+        Ok(())
+    }
+}
+
+/// Build the API server for the repository at `root`, and bind it to the address given by
+/// `config`.
+///
+/// Unlike `api`, this allows several servers to run in the same process, each serving its own
+/// repository on its own address.
+pub async fn api_with_config(root: &Path, config: &ApiConfig) -> anyhow::Result<Api> {
+    let repository_config = load_config(root)?;
+    let router = api(root)?;
+    let grace_period = Duration::from_secs(repository_config.server.shutdown_grace_period);
+    bind(router, grace_period, config).await
+}
+
+/// Build the API server for several repositories, as `api_with_roots`, and bind it to the
+/// address given by `config`. Requests in progress when the server shuts down are given the
+/// longest of the grace periods configured for the repositories.
+pub async fn api_with_roots_and_config(
+    roots: &BTreeMap<String, PathBuf>,
+    config: &ApiConfig,
+) -> anyhow::Result<Api> {
+    let mut grace_period = Duration::ZERO;
+    for root in roots.values() {
+        let repository_config = load_config(root)?;
+        let root_grace_period = Duration::from_secs(repository_config.server.shutdown_grace_period);
+        grace_period = grace_period.max(root_grace_period);
+    }
+    let router = api_with_roots(roots)?;
+    bind(router, grace_period, config).await
+}
+
+async fn bind(router: Router, grace_period: Duration, config: &ApiConfig) -> anyhow::Result<Api> {
+    let listener = tokio::net::TcpListener::bind((config.address, config.port))
+        .await
+        .with_context(|| format!("Failed to listen on {}:{}", config.address, config.port))?;
+
+    Ok(Api {
+        router,
+        listener,
+        grace_period,
+    })
 }
 
-pub fn serve(root: &Path, addr: &SocketAddr) -> anyhow::Result<()> {
-    tracing_subscriber::fmt()
-        .with_max_level(tracing::Level::TRACE)
-        .init();
+/// The repositories served by `serve`.
+pub enum Roots {
+    /// A single repository, served at the top level.
+    Single(PathBuf),
 
-    let app = api(root)?;
+    /// Several repositories, each served under `/roots/<name>`.
+    Named(BTreeMap<String, PathBuf>),
+}
+
+impl Roots {
+    fn paths(&self) -> Vec<&Path> {
+        match self {
+            Roots::Single(root) => vec![root],
+            Roots::Named(roots) => roots.values().map(PathBuf::as_path).collect(),
+        }
+    }
+}
+
+pub fn serve(roots: &Roots, addr: &SocketAddr) -> anyhow::Result<()> {
+    // This needs to happen before any other threads are started.
+    let shutdown = shutdown::shutdown_signal();
+
+    // Logging is shared by every root, so takes its settings from the first of them.
+    let log = roots
+        .paths()
+        .first()
+        .and_then(|root| config::read_config(root).ok())
+        .map(|config| config.server.log)
+        .unwrap_or_default();
+    logging::init(&log)?;
+
+    // Build the index ahead of the first request, so that it does not have to wait for it.
+    // Errors are reported again to whichever request needs the index.
+    for root in roots.paths() {
+        if let Err(e) = index::get_packet_index(root).and_then(|_| index::get_location_index(root))
+        {
+            tracing::warn!("failed to build index for '{}': {}", root.display(), e);
+        }
+    }
 
-    tokio::runtime::Builder::new_multi_thread()
+    let runtime = tokio::runtime::Builder::new_multi_thread()
         .enable_all()
-        .build()?
-        .block_on(async {
-            let listener = tokio::net::TcpListener::bind(addr).await?;
-            tracing::info!("listening on {}", listener.local_addr().unwrap());
-            axum::serve(listener, app).await?;
-            Ok(())
-        })
+        .build()?;
+
+    let result = runtime.block_on(async {
+        // Like the upload directory, how often temporary files are removed is only read at
+        // startup.
+        for root in roots.paths() {
+            let Ok(config) = config::read_config(root) else {
+                continue;
+            };
+            if let Some(interval) = config.server.clean_temp_interval.filter(|&i| i > 0) {
+                tokio::spawn(clean_temp_periodically(
+                    root.to_path_buf(),
+                    config.server.upload_dir(root),
+                    Duration::from_secs(interval),
+                    Duration::from_secs(config.server.temp_max_age),
+                ));
+            }
+        }
+
+        let config = ApiConfig::from(*addr);
+        let api = match roots {
+            Roots::Single(root) => api_with_config(root, &config).await?,
+            Roots::Named(roots) => api_with_roots_and_config(roots, &config).await?,
+        };
+        tracing::info!("listening on {}", api.local_addr()?);
+        api.run(shutdown).await
+    });
+
+    // Abort any requests that did not complete within the grace period, without waiting for
+    // any blocking operations they started.
+    runtime.shutdown_background();
+    result
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[tokio::test]
+    async fn large_bodies_are_not_indented() {
+        let json = r#"{"a":[1,2,3]}"#;
+        let body = indent_json(Body::from(json), 1024).await.unwrap();
+        let bytes = axum::body::to_bytes(body, usize::MAX).await.unwrap();
+        assert_eq!(bytes, "{\n  \"a\": [\n    1,\n    2,\n    3\n  ]\n}");
+
+        // The body is streamed in parts, all of which are passed on once it is too large.
+        let parts = ["{\"a\":", "[1,2", ",3]}"].map(Ok::<_, io::Error>);
+        let body = Body::from_stream(futures::stream::iter(parts));
+        let body = indent_json(body, 8).await.unwrap();
+        let bytes = axum::body::to_bytes(body, usize::MAX).await.unwrap();
+        assert_eq!(bytes, json);
+    }
+
     fn make_config(
         hash_algorithm: hash::HashAlgorithm,
         path_archive: Option<String>,
@@ -315,8 +2405,14 @@ mod tests {
             path_archive,
             use_file_store,
             require_complete_tree,
+            extra: serde_json::Map::new(),
         };
-        config::Config { location, core }
+        config::Config {
+            location,
+            core,
+            server: config::Server::default(),
+            extra: serde_json::Map::new(),
+        }
     }
 
     #[test]
@@ -1,3 +1,4 @@
+use outpack::hash::HashAlgorithm;
 use std::net::SocketAddr;
 use std::path::PathBuf;
 
@@ -10,6 +11,10 @@ pub struct Args {
 #[derive(clap::Subcommand, Debug)]
 pub enum Command {
     /// Initialize a new outpack repository
+    ///
+    /// If none of `--path-archive`, `--use-file-store` and `--require-complete-tree` is given, a
+    /// repository with a file store and a complete tree is created, as required by the API server,
+    /// as long as the path does not already contain packets.
     Init {
         path: PathBuf,
 
@@ -24,24 +29,89 @@ pub enum Command {
         /// Require a complete tree.
         #[arg(long)]
         require_complete_tree: bool,
+
+        /// Algorithm used to hash files and metadata.
+        #[arg(long, default_value = "sha256", value_parser = parse_hash_algorithm)]
+        hash_algorithm: HashAlgorithm,
     },
 
-    /// Search for a packet in a repository
-    Search {
+    /// Search for packets in a repository, printing their ids
+    #[command(alias = "search")]
+    Query {
         #[arg(short, long)]
         root: PathBuf,
         query: String,
+
+        /// Print the ids as a JSON array.
+        #[arg(long)]
+        json: bool,
     },
 
     /// Parse an outpack query, without evaluating it
     Parse { query: String },
 
-    /// Start the outpack API server
-    StartServer {
+    /// Move the files of a repository's file store into a new layout
+    ///
+    /// Files are stored under `depth` nested directories, named after successive
+    /// `width`-character chunks of their hash. The server must not be running while the store is
+    /// migrated.
+    MigrateStore {
+        #[arg(short, long)]
+        root: PathBuf,
+
+        /// Number of nested directories each file is stored under.
+        #[arg(long)]
+        depth: usize,
+
+        /// Number of characters of the hash used to name each directory.
+        #[arg(long)]
+        width: usize,
+    },
+
+    /// Add every file in a directory to a repository's file store
+    ///
+    /// Subdirectories are imported too. Files whose contents are already in the store are
+    /// skipped, and the source directory is left unchanged.
+    ImportFiles {
         #[arg(short, long)]
         root: PathBuf,
 
+        /// The directory containing the files to import.
+        source: PathBuf,
+    },
+
+    /// Re-hash every file in a repository's file store, listing any which are corrupt or misnamed
+    ///
+    /// Exits with an error if any files are listed.
+    VerifyStore {
+        #[arg(short, long)]
+        root: PathBuf,
+    },
+
+    /// Start the outpack API server
+    StartServer {
+        #[arg(short, long, required_unless_present = "named_root")]
+        root: Option<PathBuf>,
+
+        /// Serve a repository under `/roots/<name>`, given as `<name>=<path>`. May be repeated to
+        /// serve several repositories from the same server, instead of using `--root`.
+        #[arg(long, value_parser = parse_named_root, conflicts_with = "root")]
+        named_root: Vec<(String, PathBuf)>,
+
         #[arg(long, default_value = "0.0.0.0:8000")]
         listen: SocketAddr,
     },
 }
+
+fn parse_named_root(value: &str) -> Result<(String, PathBuf), String> {
+    match value.split_once('=') {
+        Some((name, path)) => Ok((name.to_owned(), PathBuf::from(path))),
+        None => Err(String::from("expected <name>=<path>")),
+    }
+}
+
+fn parse_hash_algorithm(value: &str) -> Result<HashAlgorithm, String> {
+    value
+        .parse()
+        .map_err(|e: outpack::hash::HashError| e.explanation)
+}
@@ -2,8 +2,12 @@ mod args;
 use args::{Args, Command};
 
 use clap::Parser;
-use outpack::init::outpack_init;
-use outpack::query::{parse_query, run_query};
+use outpack::api::Roots;
+use outpack::config::StoreLayout;
+use outpack::init::{create_root, outpack_init_with_hash_algorithm};
+use outpack::query::{find_packet_ids, parse_query, run_query};
+use outpack::store::{import_dir, migrate_layout, verify_all};
+use std::collections::BTreeMap;
 
 fn main() -> anyhow::Result<()> {
     let cli = Args::parse();
@@ -13,13 +17,31 @@ fn main() -> anyhow::Result<()> {
             path_archive,
             use_file_store,
             require_complete_tree,
+            hash_algorithm,
         } => {
-            outpack_init(&path, path_archive, use_file_store, require_complete_tree)?;
+            // The default only applies when no storage options are given. Otherwise they are
+            // checked as they always were, so `--require-complete-tree` alone is still an error.
+            if path_archive.is_none() && !use_file_store && !require_complete_tree {
+                create_root(&path, hash_algorithm)?;
+            } else {
+                outpack_init_with_hash_algorithm(
+                    &path,
+                    path_archive,
+                    use_file_store,
+                    require_complete_tree,
+                    hash_algorithm,
+                )?;
+            }
         }
 
-        Command::Search { root, query } => {
-            let result = run_query(&root, &query)?;
-            println!("{}", result);
+        Command::Query { root, query, json } => {
+            if json {
+                let ids = find_packet_ids(&root, &query)?;
+                println!("{}", serde_json::to_string(&ids)?);
+            } else {
+                let result = run_query(&root, &query)?;
+                println!("{}", result);
+            }
         }
 
         Command::Parse { query } => {
@@ -27,8 +49,50 @@ fn main() -> anyhow::Result<()> {
             println!("{:?}", result);
         }
 
-        Command::StartServer { root, listen } => {
-            outpack::api::serve(&root, &listen)?;
+        Command::MigrateStore { root, depth, width } => {
+            let moved = migrate_layout(&root, StoreLayout { depth, width })?;
+            println!("Moved {} files", moved);
+        }
+
+        Command::ImportFiles { root, source } => {
+            let result = import_dir(&root, &source)?;
+            println!(
+                "Imported {} files, skipped {} already present",
+                result.imported, result.skipped
+            );
+        }
+
+        Command::VerifyStore { root } => {
+            let invalid = verify_all(&root)?;
+            for file in &invalid {
+                match &file.found {
+                    Some(found) => println!("{}: contents have hash {}", file.path, found),
+                    None => println!("{}: not a valid location in the store", file.path),
+                }
+            }
+            if !invalid.is_empty() {
+                anyhow::bail!("Found {} invalid files in the store", invalid.len());
+            }
+        }
+
+        Command::StartServer {
+            root,
+            named_root,
+            listen,
+        } => {
+            let roots = match root {
+                Some(root) => Roots::Single(root),
+                None => {
+                    let mut roots = BTreeMap::new();
+                    for (name, path) in named_root {
+                        if roots.insert(name.clone(), path).is_some() {
+                            anyhow::bail!("Root '{}' was given more than once", name);
+                        }
+                    }
+                    Roots::Named(roots)
+                }
+            };
+            outpack::api::serve(&roots, &listen)?;
         }
     }
     Ok(())
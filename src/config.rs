@@ -1,32 +1,414 @@
 use serde::{Deserialize, Serialize};
 use std::fs;
-use std::io::Error;
-use std::path::Path;
+use std::io::{Error, Write};
+use std::num::NonZeroU32;
+use std::path::{Path, PathBuf};
 use std::result::Result;
 
 use crate::hash::HashAlgorithm;
 
-#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
 pub struct Location {
-    // Practically, doing anything with locations (therefore needing
-    // access to the "type" and "args" fields) is going to require we
-    // know how to deserialise into a union type; for example
-    // https://stackoverflow.com/q/66964692
     pub name: String,
+
+    // The contents of "args" depend on the type of location. Rather than
+    // deserialising into a union type, which would have to know about every
+    // type used by other outpack implementations, the arguments are kept as
+    // plain json and only interpreted for the types the server understands.
+    #[serde(rename = "type")]
+    pub location_type: String,
+    pub args: serde_json::Value,
+}
+
+/// The arguments of a location of type "http", which is another outpack server.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct HttpLocationArgs {
+    pub url: String,
+
+    /// A bearer token sent with every request made to the server, if it requires authentication.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+}
+
+impl Location {
+    pub fn local() -> Location {
+        Location {
+            name: String::from("local"),
+            location_type: String::from("local"),
+            args: serde_json::json!({}),
+        }
+    }
+
+    pub fn http(name: &str, args: HttpLocationArgs) -> Location {
+        Location {
+            name: String::from(name),
+            location_type: String::from("http"),
+            args: serde_json::to_value(args).expect("Location args can be serialised"),
+        }
+    }
+
+    /// The arguments of this location, if it is of type "http".
+    pub fn http_args(&self) -> Option<HttpLocationArgs> {
+        if self.location_type == "http" {
+            serde_json::from_value(self.args.clone()).ok()
+        } else {
+            None
+        }
+    }
 }
 
-#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
 pub struct Core {
     pub hash_algorithm: HashAlgorithm,
     pub path_archive: Option<String>,
     pub use_file_store: bool,
     pub require_complete_tree: bool,
+
+    /// Any other fields, which the server does not use but keeps, so that writing the
+    /// configuration does not lose settings of other outpack implementations.
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>,
+}
+
+/// Settings that only affect the behaviour of the API server.
+///
+/// This section is specific to the server and is not used by other outpack implementations. It
+/// may be omitted entirely, in which case the defaults are used.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+#[serde(default)]
+pub struct Server {
+    /// How long to wait for in-flight requests to complete when shutting down, in seconds.
+    pub shutdown_grace_period: u64,
+
+    /// The largest file that may be uploaded, in bytes. Uploads are unlimited if this is `None`.
+    pub max_upload_size: Option<u64>,
+
+    /// The most packets whose metadata may be requested from `/metadata/batch` at once.
+    pub max_batch_size: usize,
+
+    /// The most ids or hashes that may be checked by `/packets/missing` or `/files/missing` at
+    /// once.
+    pub max_check_size: usize,
+
+    /// The largest json request body accepted, in bytes. This does not apply to uploaded files.
+    pub max_json_size: usize,
+
+    /// Headers added to every response, to harden the server when it is used from a browser.
+    pub security_headers: SecurityHeaders,
+
+    /// How files are arranged in the file store.
+    pub store_layout: StoreLayout,
+
+    /// Which browser origins may make cross-origin requests to the server.
+    pub cors: Cors,
+
+    /// The number of threads used to read metadata when building the packet index. If `None`,
+    /// one thread is used for each available CPU.
+    pub index_threads: Option<usize>,
+
+    /// Where uploaded files are written before being moved into the store. Relative paths are
+    /// resolved against the root. If `None`, the store's own directory is used.
+    pub upload_dir: Option<PathBuf>,
+
+    /// The secret used to sign time-limited download urls. Signed urls cannot be created unless
+    /// this is set.
+    pub url_signing_secret: Option<String>,
+
+    /// The longest time for which a signed url may be valid, in seconds.
+    pub max_signed_url_expiry: u64,
+
+    /// How long to wait for more of a request's body to arrive before giving up on the request,
+    /// in seconds. If `None`, clients may take as long as they like.
+    pub read_timeout: Option<u64>,
+
+    /// How many commits of history to fetch from the repository's git remote. If `None`, all of
+    /// its history is fetched.
+    pub git_fetch_depth: Option<NonZeroU32>,
+
+    /// How long clients are asked to wait before retrying a request which failed because the
+    /// server is unavailable or busy, in seconds. If `None`, clients are not told how long to wait.
+    pub retry_after: Option<u64>,
+
+    /// The bearer token required by the `/admin` endpoints, `/store/gc`, and the endpoints which
+    /// change the locations or make the server fetch from other hosts. If `None`, the former need
+    /// no token and the latter are disabled.
+    pub admin_token: Option<String>,
+
+    /// How many requests each client may make. Requests are unlimited unless this is configured.
+    pub rate_limit: RateLimit,
+
+    /// How verbose the server's logs are, and how they are formatted.
+    pub log: Log,
+
+    /// Hosts, besides those of the repository's http locations, from which single files may be
+    /// pulled into the store.
+    pub pull_hosts: Vec<String>,
+
+    /// How long a temporary file left behind by an upload must go unmodified before it is
+    /// removed by `/admin/clean-temp`, in seconds.
+    pub temp_max_age: u64,
+
+    /// How long an unreferenced file must have been in the store before `/store/gc` removes it,
+    /// in seconds, so that the files of a packet which is being pushed are left alone.
+    pub gc_grace_period: u64,
+
+    /// How often to remove old temporary files, in seconds. If `None`, they are only removed when
+    /// `/admin/clean-temp` is called.
+    pub clean_temp_interval: Option<u64>,
+
+    /// Fetching from the repository's git remote on a schedule. If `None`, the remote is only
+    /// fetched from when `/git/fetch` is called.
+    pub git_fetch_schedule: Option<GitFetchSchedule>,
 }
 
-#[derive(Serialize, Deserialize, Debug, PartialEq)]
+/// How often to fetch from the repository's git remote in the background, and what to do after
+/// each fetch.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct GitFetchSchedule {
+    /// How often to fetch, in seconds.
+    pub interval: u64,
+
+    /// The remote to fetch from. If `None`, `origin` is used.
+    #[serde(default)]
+    pub remote: Option<String>,
+
+    /// A branch of the remote to check out after each fetch, so that it is up to date before it
+    /// is next served from `/git/<branch>`.
+    #[serde(default)]
+    pub checkout: Option<String>,
+}
+
+/// The settings for the server's logs. These are read when the server starts, and can be
+/// overridden by the `OUTPACK_LOG_LEVEL` and `OUTPACK_LOG_FORMAT` environment variables.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Default)]
+#[serde(default)]
+pub struct Log {
+    /// The least important messages which are logged.
+    pub level: LogLevel,
+    pub format: LogFormat,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    #[default]
+    Trace,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// One json object per line.
+    #[default]
+    Json,
+    /// Human-readable lines of text.
+    Plain,
+}
+
+/// Limits on how often each client may make requests, kept separately for requests which read
+/// from the repository and those which change it. Either kind is unlimited if its limit is `None`.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Default)]
+#[serde(default)]
+pub struct RateLimit {
+    /// The limit on `GET`, `HEAD` and `OPTIONS` requests.
+    pub reads: Option<Rate>,
+
+    /// The limit on all other requests.
+    pub writes: Option<Rate>,
+
+    /// Whether clients are told apart by the bearer token they send, rather than by their IP
+    /// address. This should only be used if tokens are checked before requests reach the server,
+    /// or clients could avoid the limit by sending a different token each time.
+    pub by_token: bool,
+}
+
+/// A rate of `requests` per `per_seconds`. A client may make up to `requests` at once, after
+/// which it may make more as the allowance is refilled at that rate.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy)]
+pub struct Rate {
+    pub requests: u32,
+    pub per_seconds: f64,
+}
+
+/// Cross-origin resource sharing, for browser applications served from another origin. This is
+/// disabled unless at least one origin is allowed.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+#[serde(default)]
+pub struct Cors {
+    /// The allowed origins, such as `https://example.com`, or `*` to allow any origin.
+    pub allowed_origins: Vec<String>,
+
+    /// How long browsers may cache the result of a preflight request, in seconds.
+    pub max_age: u64,
+}
+
+impl Default for Cors {
+    fn default() -> Self {
+        Cors {
+            allowed_origins: Vec::new(),
+            max_age: 600,
+        }
+    }
+}
+
+/// The arrangement of files in the file store.
+///
+/// Each file is stored under `depth` nested directories, named after successive `width`-character
+/// chunks of its hash, with the rest of the hash as its filename. Other outpack implementations
+/// only understand the default layout, of one level of two characters.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy)]
+pub struct StoreLayout {
+    pub depth: usize,
+    pub width: usize,
+}
+
+impl Default for StoreLayout {
+    fn default() -> Self {
+        StoreLayout { depth: 1, width: 2 }
+    }
+}
+
+/// The value of each security header, or `None` if the header should not be sent.
+///
+/// Headers which are omitted from the configuration take their default values, while headers
+/// given as `null` are disabled.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+#[serde(default)]
+pub struct SecurityHeaders {
+    pub x_content_type_options: Option<String>,
+    pub x_frame_options: Option<String>,
+    pub referrer_policy: Option<String>,
+}
+
+impl Default for SecurityHeaders {
+    fn default() -> Self {
+        SecurityHeaders {
+            x_content_type_options: Some(String::from("nosniff")),
+            x_frame_options: Some(String::from("DENY")),
+            referrer_policy: Some(String::from("no-referrer")),
+        }
+    }
+}
+
+impl SecurityHeaders {
+    /// The names and values of the headers which are enabled.
+    pub fn enabled(&self) -> Vec<(&'static str, &str)> {
+        [
+            ("x-content-type-options", &self.x_content_type_options),
+            ("x-frame-options", &self.x_frame_options),
+            ("referrer-policy", &self.referrer_policy),
+        ]
+        .into_iter()
+        .filter_map(|(name, value)| value.as_deref().map(|value| (name, value)))
+        .collect()
+    }
+}
+
+impl Default for Server {
+    fn default() -> Self {
+        Server {
+            shutdown_grace_period: 30,
+            max_upload_size: None,
+            max_batch_size: 1000,
+            max_check_size: 100_000,
+            max_json_size: 1024 * 1024,
+            security_headers: SecurityHeaders::default(),
+            store_layout: StoreLayout::default(),
+            cors: Cors::default(),
+            index_threads: None,
+            upload_dir: None,
+            url_signing_secret: None,
+            max_signed_url_expiry: 7 * 24 * 60 * 60,
+            read_timeout: Some(60),
+            git_fetch_depth: None,
+            retry_after: Some(30),
+            admin_token: None,
+            rate_limit: RateLimit::default(),
+            log: Log::default(),
+            pull_hosts: Vec::new(),
+            temp_max_age: 24 * 60 * 60,
+            gc_grace_period: 60 * 60,
+            clean_temp_interval: None,
+            git_fetch_schedule: None,
+        }
+    }
+}
+
+impl Server {
+    fn is_default(&self) -> bool {
+        *self == Server::default()
+    }
+
+    /// The directory in which uploads to the given root are written. This should be on the same
+    /// filesystem as the store, so that uploads can be moved into it without being copied.
+    pub fn upload_dir(&self, root: &Path) -> PathBuf {
+        match &self.upload_dir {
+            Some(path) => root.join(path),
+            None => root.join(".outpack").join("files"),
+        }
+    }
+
+    /// The number of threads to use when building the packet index.
+    pub fn index_threads(&self) -> usize {
+        self.index_threads.unwrap_or_else(|| {
+            std::thread::available_parallelism()
+                .map(|n| n.get())
+                .unwrap_or(1)
+        })
+    }
+
+    /// The names of the settings which differ from those in `other` but are only read when the
+    /// server starts, so cannot be changed by reloading the configuration.
+    pub fn changed_startup_settings(&self, other: &Server) -> Vec<&'static str> {
+        [
+            (
+                "shutdown_grace_period",
+                self.shutdown_grace_period != other.shutdown_grace_period,
+            ),
+            (
+                "max_upload_size",
+                self.max_upload_size != other.max_upload_size,
+            ),
+            ("max_json_size", self.max_json_size != other.max_json_size),
+            (
+                "security_headers",
+                self.security_headers != other.security_headers,
+            ),
+            ("upload_dir", self.upload_dir != other.upload_dir),
+            ("store_layout", self.store_layout != other.store_layout),
+            ("read_timeout", self.read_timeout != other.read_timeout),
+            ("retry_after", self.retry_after != other.retry_after),
+            ("rate_limit", self.rate_limit != other.rate_limit),
+            ("log", self.log != other.log),
+            (
+                "clean_temp_interval",
+                self.clean_temp_interval != other.clean_temp_interval,
+            ),
+            (
+                "git_fetch_schedule",
+                self.git_fetch_schedule != other.git_fetch_schedule,
+            ),
+        ]
+        .into_iter()
+        .filter_map(|(name, changed)| changed.then_some(name))
+        .collect()
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
 pub struct Config {
     pub core: Core,
     pub location: Vec<Location>,
+    #[serde(default, skip_serializing_if = "Server::is_default")]
+    pub server: Server,
+
+    /// Any other fields, such as `schema_version`, which are kept as they were read.
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>,
 }
 
 impl Config {
@@ -47,25 +429,86 @@ impl Config {
             path_archive,
             use_file_store,
             require_complete_tree,
+            extra: serde_json::Map::new(),
+        };
+        let location = vec![Location::local()];
+        Ok(Config {
+            core,
+            location,
+            server: Server::default(),
+            extra: serde_json::Map::new(),
+        })
+    }
+}
+
+/// The reasons the repository's configuration could not be read.
+///
+/// This converts to an `io::Error` whose kind reflects the problem, so callers can use `?` as with
+/// any other io error. The original `ConfigError` is kept as the error's inner value, from which
+/// the API picks a response status.
+#[derive(Debug)]
+pub enum ConfigError {
+    /// There is no configuration file, or it could not be opened.
+    Missing(Error),
+    /// The configuration file is not valid json.
+    Malformed(serde_json::Error),
+    /// The configuration is valid json, but a field is missing or has the wrong type.
+    InvalidField(serde_json::Error),
+}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigError::Missing(e) => write!(f, "Repository configuration not found: {}", e),
+            ConfigError::Malformed(e) => write!(f, "Repository configuration is malformed: {}", e),
+            ConfigError::InvalidField(e) => {
+                write!(f, "Repository configuration is invalid: {}", e)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ConfigError::Missing(e) => Some(e),
+            ConfigError::Malformed(e) | ConfigError::InvalidField(e) => Some(e),
+        }
+    }
+}
+
+impl From<ConfigError> for Error {
+    fn from(e: ConfigError) -> Self {
+        let kind = match e {
+            ConfigError::Missing(_) => std::io::ErrorKind::NotFound,
+            _ => std::io::ErrorKind::InvalidData,
         };
-        let location: Vec<Location> = Vec::new();
-        Ok(Config { core, location })
+        Error::new(kind, e)
     }
 }
 
-pub fn read_config(root: &Path) -> Result<Config, Error> {
+pub fn read_config(root: &Path) -> Result<Config, ConfigError> {
     let path = root.join(".outpack").join("config.json");
-    let config_file = fs::File::open(path)?;
-    let config: Config = serde_json::from_reader(config_file)?;
-    Ok(config)
+    let config_file = fs::File::open(path).map_err(ConfigError::Missing)?;
+    let reader = std::io::BufReader::new(config_file);
+    serde_json::from_reader(reader).map_err(|e| match e.classify() {
+        serde_json::error::Category::Data => ConfigError::InvalidField(e),
+        _ => ConfigError::Malformed(e),
+    })
 }
 
+/// Write the configuration of the repository at the given root. The new configuration is
+/// written alongside the old one and then moved over it, so that anything reading the
+/// configuration sees either all of the old one or all of the new one.
 pub fn write_config(config: &Config, root: &Path) -> Result<(), Error> {
     // assume .outpack exists
-    let path_config = root.join(".outpack").join("config.json");
-    fs::File::create(&path_config)?;
+    let outpack = root.join(".outpack");
     let json = serde_json::to_string(&config)?;
-    fs::write(path_config, json)?;
+    let mut file = tempfile::NamedTempFile::new_in(&outpack)?;
+    file.write_all(json.as_bytes())?;
+    file.as_file().sync_all()?;
+    file.persist(outpack.join("config.json"))
+        .map_err(|e| e.error)?;
     Ok(())
 }
 
@@ -83,6 +526,115 @@ mod tests {
         assert!(cfg.core.path_archive.is_none());
     }
 
+    #[test]
+    fn server_config_is_optional() {
+        let cfg = read_config(Path::new("tests/example")).unwrap();
+        assert_eq!(cfg.server, Server::default());
+
+        let cfg: Config = serde_json::from_value(serde_json::json!({
+            "core": {
+                "hash_algorithm": "sha256",
+                "path_archive": null,
+                "use_file_store": true,
+                "require_complete_tree": true
+            },
+            "location": [],
+            "server": { "shutdown_grace_period": 5 }
+        }))
+        .unwrap();
+        assert_eq!(cfg.server.shutdown_grace_period, 5);
+        assert_eq!(cfg.server.max_upload_size, None);
+
+        let cfg: Config = serde_json::from_value(serde_json::json!({
+            "core": {
+                "hash_algorithm": "sha256",
+                "path_archive": null,
+                "use_file_store": true,
+                "require_complete_tree": true
+            },
+            "location": [],
+            "server": { "max_upload_size": 1024 }
+        }))
+        .unwrap();
+        assert_eq!(cfg.server.shutdown_grace_period, 30);
+        assert_eq!(cfg.server.max_upload_size, Some(1024));
+        assert_eq!(cfg.server.max_batch_size, 1000);
+        assert_eq!(cfg.server.max_check_size, 100_000);
+        assert_eq!(cfg.server.max_json_size, 1024 * 1024);
+        assert!(cfg.server.cors.allowed_origins.is_empty());
+        assert_eq!(cfg.server.cors.max_age, 600);
+    }
+
+    #[test]
+    fn security_headers_can_be_overridden_or_disabled() {
+        let headers: SecurityHeaders = serde_json::from_value(serde_json::json!({
+            "x_frame_options": "SAMEORIGIN",
+            "referrer_policy": null
+        }))
+        .unwrap();
+        assert_eq!(
+            headers.enabled(),
+            vec![
+                ("x-content-type-options", "nosniff"),
+                ("x-frame-options", "SAMEORIGIN")
+            ]
+        );
+    }
+
+    /// Write a config file to a new repository, returning its root.
+    fn root_with_config(contents: &str) -> tempfile::TempDir {
+        let tmp = tempfile::TempDir::new().unwrap();
+        fs::create_dir_all(tmp.path().join(".outpack")).unwrap();
+        fs::write(tmp.path().join(".outpack").join("config.json"), contents).unwrap();
+        tmp
+    }
+
+    #[test]
+    fn read_config_distinguishes_errors() {
+        let tmp = tempfile::TempDir::new().unwrap();
+        let err = read_config(tmp.path()).unwrap_err();
+        assert!(matches!(err, ConfigError::Missing(_)), "{:?}", err);
+        assert_eq!(Error::from(err).kind(), std::io::ErrorKind::NotFound);
+
+        let tmp = root_with_config("{ \"core\": ");
+        let err = read_config(tmp.path()).unwrap_err();
+        assert!(matches!(err, ConfigError::Malformed(_)), "{:?}", err);
+        assert!(err.to_string().contains("malformed"));
+
+        let mut config = serde_json::to_value(Config::new(None, true, true).unwrap()).unwrap();
+        config["core"]
+            .as_object_mut()
+            .unwrap()
+            .remove("hash_algorithm");
+        let tmp = root_with_config(&config.to_string());
+        let err = read_config(tmp.path()).unwrap_err();
+        assert!(matches!(err, ConfigError::InvalidField(_)), "{:?}", err);
+        assert!(err.to_string().contains("missing field `hash_algorithm`"));
+
+        assert_eq!(Error::from(err).kind(), std::io::ErrorKind::InvalidData);
+        config["core"]["hash_algorithm"] = "sha3".into();
+        let tmp = root_with_config(&config.to_string());
+        let err = read_config(tmp.path()).unwrap_err();
+        assert!(matches!(err, ConfigError::InvalidField(_)), "{:?}", err);
+        assert!(err.to_string().contains(
+            "unsupported hash algorithm 'sha3', expected one of md5, sha1, sha256, sha384, sha512"
+        ));
+    }
+
+    #[test]
+    fn git_fetch_depth_must_be_positive() {
+        let mut config = serde_json::to_value(Config::new(None, true, true).unwrap()).unwrap();
+        config["server"] = serde_json::json!({ "git_fetch_depth": 1 });
+        let tmp = root_with_config(&config.to_string());
+        let depth = read_config(tmp.path()).unwrap().server.git_fetch_depth;
+        assert_eq!(depth.map(NonZeroU32::get), Some(1));
+
+        config["server"] = serde_json::json!({ "git_fetch_depth": 0 });
+        let tmp = root_with_config(&config.to_string());
+        let err = read_config(tmp.path()).unwrap_err();
+        assert!(matches!(err, ConfigError::InvalidField(_)), "{:?}", err);
+    }
+
     #[test]
     fn can_write_config() {
         let cfg = Config::new(None, true, true).unwrap();
@@ -93,6 +645,61 @@ mod tests {
         assert_eq!(read_config(path).unwrap(), cfg);
     }
 
+    #[test]
+    fn writing_config_replaces_the_file() {
+        let tmp = tempfile::TempDir::new().unwrap();
+        let path = tmp.path();
+        fs::create_dir_all(path.join(".outpack")).unwrap();
+        let old = Config::new(None, true, true).unwrap();
+        write_config(&old, path).unwrap();
+
+        // The old file is left as it was, rather than being truncated and written over.
+        let link = path.join("old-config.json");
+        fs::hard_link(path.join(".outpack").join("config.json"), &link).unwrap();
+        let mut new = old.clone();
+        new.core.require_complete_tree = false;
+        write_config(&new, path).unwrap();
+
+        assert_eq!(read_config(path).unwrap(), new);
+        assert_eq!(
+            fs::read_to_string(link).unwrap(),
+            serde_json::to_string(&old).unwrap()
+        );
+        assert_eq!(fs::read_dir(path.join(".outpack")).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn can_read_location_args() {
+        let cfg = read_config(Path::new("tests/example")).unwrap();
+        assert_eq!(cfg.location[0].name, "local");
+        assert_eq!(cfg.location[0].location_type, "local");
+        assert_eq!(cfg.location[0].http_args(), None);
+
+        let args = HttpLocationArgs {
+            url: String::from("http://example.com"),
+            token: None,
+        };
+        let location = Location::http("upstream", args.clone());
+        assert_eq!(
+            location.args,
+            serde_json::json!({ "url": "http://example.com" })
+        );
+        assert_eq!(location.http_args(), Some(args));
+    }
+
+    #[test]
+    fn upload_dir_is_resolved_against_root() {
+        let root = Path::new("/outpack");
+        let mut server = Server::default();
+        assert_eq!(server.upload_dir(root), root.join(".outpack/files"));
+
+        server.upload_dir = Some(PathBuf::from("uploads"));
+        assert_eq!(server.upload_dir(root), root.join("uploads"));
+
+        server.upload_dir = Some(PathBuf::from("/scratch/uploads"));
+        assert_eq!(server.upload_dir(root), Path::new("/scratch/uploads"));
+    }
+
     #[test]
     fn need_some_storage() {
         let cfg = Config::new(None, false, false);
@@ -1,15 +1,161 @@
+use std::num::NonZeroU32;
 use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use git2::{Branch, BranchType, Reference, Repository};
+use git2::{
+    Branch, BranchType, Commit, ErrorClass, ErrorCode, FetchOptions, Reference, RemoteCallbacks,
+    Repository, StatusOptions,
+};
+use lazy_static::lazy_static;
 use serde::{Deserialize, Serialize};
 
-pub fn git_fetch(root: &Path) -> Result<(), git2::Error> {
-    let repo = Repository::open(root)?;
-    let mut remote = repo.find_remote("origin")?;
+/// Open the git repository at the root, with a clearer error than libgit2's if there isn't one.
+pub(crate) fn open_repository(root: &Path) -> Result<Repository, git2::Error> {
+    Repository::open(root).map_err(|e| match e.code() {
+        ErrorCode::NotFound => git2::Error::new(
+            ErrorCode::NotFound,
+            ErrorClass::Repository,
+            format!("'{}' is not a git repository", root.display()),
+        ),
+        _ => e,
+    })
+}
+
+lazy_static! {
+    /// Held for the duration of each fetch. A fetch started while another is in progress waits for
+    /// it to finish, rather than both updating the same refs at once.
+    static ref FETCH_LOCK: Mutex<()> = Mutex::new(());
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct FetchResult {
+    /// The number of objects downloaded from the remote.
+    pub objects_received: usize,
+
+    /// The number of bytes downloaded from the remote.
+    pub bytes_received: usize,
+
+    /// The refs which were created or moved by the fetch, such as `refs/remotes/origin/main`.
+    pub refs_updated: Vec<String>,
+}
+
+/// The remote fetched from when none is named.
+pub const DEFAULT_REMOTE: &str = "origin";
+
+/// The depth passed to libgit2 to fetch the rest of a shallow repository's history.
+const DEPTH_UNSHALLOW: i32 = i32::MAX;
+
+/// The depth to fetch at, given the depth configured and whether the repository is already
+/// shallow. Without a depth, a shallow repository is converted back into one with full history.
+fn fetch_depth(depth: Option<NonZeroU32>, shallow: bool) -> i32 {
+    match depth {
+        Some(depth) => i32::try_from(depth.get()).unwrap_or(DEPTH_UNSHALLOW - 1),
+        None if shallow => DEPTH_UNSHALLOW,
+        None => 0,
+    }
+}
+
+/// Fetch from the remote with the given name, such as [`DEFAULT_REMOTE`]. If `depth` is given,
+/// only that many commits of each branch's history are fetched, leaving the repository shallow.
+/// A later fetch with a greater depth, or none, fetches the history missing.
+///
+/// libgit2 cannot make shallow fetches from remotes on the local filesystem, or over the `git://`
+/// protocol, so those always fail if `depth` is given.
+pub fn git_fetch(
+    root: &Path,
+    remote_name: &str,
+    depth: Option<NonZeroU32>,
+) -> Result<FetchResult, git2::Error> {
+    let repo = open_repository(root)?;
+    let mut remote = find_remote(&repo, remote_name)?;
     let ref_specs_iter = remote.fetch_refspecs()?;
     let ref_specs: Vec<&str> = ref_specs_iter.iter().map(|spec| spec.unwrap()).collect();
-    remote.fetch(&ref_specs, None, None)?;
-    Ok(())
+
+    let mut refs_updated = Vec::new();
+    let mut callbacks = RemoteCallbacks::new();
+    callbacks.update_tips(|name, _, _| {
+        refs_updated.push(name.to_string());
+        true
+    });
+    let mut options = FetchOptions::new();
+    options.remote_callbacks(callbacks);
+    options.depth(fetch_depth(depth, repo.is_shallow()));
+
+    let _lock = FETCH_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    remote.fetch(&ref_specs, Some(&mut options), None)?;
+    let stats = remote.stats();
+    let objects_received = stats.received_objects();
+    let bytes_received = stats.received_bytes();
+    drop(options);
+
+    refs_updated.sort();
+    Ok(FetchResult {
+        objects_received,
+        bytes_received,
+        refs_updated,
+    })
+}
+
+fn find_remote<'a>(repo: &'a Repository, name: &str) -> Result<git2::Remote<'a>, git2::Error> {
+    repo.find_remote(name).map_err(|e| {
+        if e.code() != ErrorCode::NotFound {
+            return e;
+        }
+        let remotes = repo.remotes().ok();
+        let mut available: Vec<&str> = remotes.iter().flatten().flatten().collect();
+        available.sort();
+        let available = if available.is_empty() {
+            String::from("the repository has no remotes")
+        } else {
+            format!("available remotes are '{}'", available.join("', '"))
+        };
+        git2::Error::new(
+            ErrorCode::NotFound,
+            ErrorClass::Reference,
+            format!("Remote '{}' does not exist; {}", name, available),
+        )
+    })
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct GitStatus {
+    /// The commit checked out, or `None` if nothing has been committed yet.
+    pub commit_hash: Option<String>,
+
+    /// The branch checked out, or `None` if HEAD is detached.
+    pub branch: Option<String>,
+
+    /// Whether any tracked files differ from the commit checked out. Untracked files, such as
+    /// those written by the server itself, are not counted.
+    pub dirty: bool,
+}
+
+/// Describe what is checked out in the repository at the root.
+pub fn git_status(root: &Path) -> Result<GitStatus, git2::Error> {
+    let repo = open_repository(root)?;
+    let (commit_hash, branch) = match repo.head() {
+        Ok(head) => {
+            let branch = if head.is_branch() {
+                head.shorthand().map(String::from)
+            } else {
+                None
+            };
+            (Some(head.peel_to_commit()?.id().to_string()), branch)
+        }
+        Err(e) if e.code() == ErrorCode::UnbornBranch => (None, None),
+        Err(e) => return Err(e),
+    };
+
+    let mut options = StatusOptions::new();
+    options.include_untracked(false).include_ignored(false);
+    let dirty = !repo.statuses(Some(&mut options))?.is_empty();
+
+    Ok(GitStatus {
+        commit_hash,
+        branch,
+        dirty,
+    })
 }
 
 #[derive(Serialize, Deserialize)]
@@ -23,7 +169,31 @@ pub struct BranchInfo {
     name: String,
     commit_hash: String,
     time: i64,
-    message: Vec<String>,
+    message: CommitMessage,
+
+    /// Whether this is the branch currently checked out in the repository.
+    current: bool,
+}
+
+/// A commit message, split as git does into its subject and body.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct CommitMessage {
+    /// The first paragraph of the message, with any line breaks replaced by spaces.
+    subject: String,
+
+    /// The rest of the message, after the blank line following the subject, exactly as written
+    /// apart from surrounding whitespace. This is `None` if the message is only a subject.
+    body: Option<String>,
+}
+
+impl CommitMessage {
+    fn from_commit(commit: &Commit) -> CommitMessage {
+        let lossy = |bytes: &[u8]| String::from_utf8_lossy(bytes).into_owned();
+        CommitMessage {
+            subject: commit.summary_bytes().map(lossy).unwrap_or_default(),
+            body: commit.body_bytes().map(lossy),
+        }
+    }
 }
 
 fn get_branch_name(reference: &Reference) -> String {
@@ -38,40 +208,60 @@ fn get_branch_info(branch: Branch) -> Result<BranchInfo, git2::Error> {
     let git_ref = branch.get().resolve()?;
     let name = get_branch_name(&git_ref);
     let branch_commit = git_ref.peel_to_commit()?;
-    let message: Vec<String> = String::from_utf8_lossy(branch_commit.message_bytes())
-        .split_terminator("\n")
-        .map(String::from)
-        .collect();
+    let message = CommitMessage::from_commit(&branch_commit);
     Ok(BranchInfo {
         name,
         commit_hash: branch_commit.id().to_string(),
         time: branch_commit.time().seconds(),
         message,
+        current: false,
     })
 }
 
-pub fn git_list_branches(root: &Path) -> Result<BranchResponse, git2::Error> {
-    let repo = Repository::open(root)?;
+/// List the branches of the repository's remote, except for `HEAD`. If `max_age` is given,
+/// branches whose latest commit is older than that are left out.
+pub fn git_list_branches(
+    root: &Path,
+    max_age: Option<Duration>,
+) -> Result<BranchResponse, git2::Error> {
+    let repo = open_repository(root)?;
 
-    let default_branch = repo
-        .find_branch("origin/HEAD", BranchType::Remote)
-        .ok()
-        .map(|b| -> Result<String, git2::Error> {
-            let git_ref = b.get().resolve()?;
-            Ok(get_branch_name(&git_ref))
-        })
-        .transpose()?;
+    // The remote's HEAD is a symbolic ref pointing at its default branch, rather than a branch of
+    // its own. It is recognised as such instead of by its name or position, which depend on how
+    // the repository was cloned and on the names of the other branches.
+    let mut default_branch = None;
+    let mut branches = Vec::new();
+    for branch in repo.branches(Some(BranchType::Remote))? {
+        let (branch, _) = branch?;
+        if branch.get().symbolic_target_bytes().is_some() {
+            default_branch = Some(get_branch_name(&branch.get().resolve()?));
+        } else {
+            branches.push(get_branch_info(branch)?);
+        }
+    }
 
-    let branches = repo
-        .branches(Some(BranchType::Remote))?
-        .filter(|branch_tuple| -> bool {
-            if let Ok((b, _)) = branch_tuple {
-                return b.name() != Ok(Some("origin/HEAD"));
-            }
-            true
+    // The remote branch with the same name as the local one checked out, if HEAD is not detached.
+    let head = repo.head().ok();
+    let current = head
+        .as_ref()
+        .filter(|head| head.is_branch())
+        .and_then(|head| head.shorthand());
+    let branches: Vec<BranchInfo> = branches
+        .into_iter()
+        .map(|b| BranchInfo {
+            current: current == Some(b.name.as_str()),
+            ..b
         })
-        .map(|branch_tuple| get_branch_info(branch_tuple?.0))
-        .collect::<Result<Vec<BranchInfo>, git2::Error>>()?;
+        .collect();
+
+    let branches = match max_age {
+        Some(max_age) => {
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+            let oldest = now.saturating_sub(max_age).as_secs() as i64;
+            branches.into_iter().filter(|b| b.time >= oldest).collect()
+        }
+        None => branches,
+    };
 
     Ok(BranchResponse {
         default_branch,
@@ -81,7 +271,9 @@ pub fn git_list_branches(root: &Path) -> Result<BranchResponse, git2::Error> {
 
 #[cfg(test)]
 mod tests {
-    use test_utils::{git_get_latest_commit, git_remote_branches, initialise_git_repo};
+    use test_utils::{
+        git_create_branch_at, git_get_latest_commit, git_remote_branches, initialise_git_repo,
+    };
 
     use super::*;
 
@@ -99,7 +291,12 @@ mod tests {
         let initial_branches = git_remote_branches(&test_git.local);
         assert_eq!(initial_branches.count(), 2); // HEAD and main
 
-        git_fetch(&test_git.dir.path().join("local")).unwrap();
+        let result = git_fetch(&test_git.dir.path().join("local"), DEFAULT_REMOTE, None).unwrap();
+        assert!(result.objects_received > 0);
+        assert_eq!(
+            result.refs_updated,
+            vec!["refs/remotes/origin/master", "refs/remotes/origin/other"]
+        );
 
         let post_fetch_ref = git_get_latest_commit(&test_git.local, "refs/remotes/origin/HEAD");
         assert_eq!(
@@ -111,13 +308,77 @@ mod tests {
         assert_eq!(post_fetch_branches.count(), 3); // HEAD, main and other
     }
 
+    #[test]
+    fn fetch_depth_unshallows_when_depth_removed() {
+        let depth = |n| NonZeroU32::new(n);
+        assert_eq!(fetch_depth(None, false), 0);
+        assert_eq!(fetch_depth(None, true), DEPTH_UNSHALLOW);
+        assert_eq!(fetch_depth(depth(1), false), 1);
+        assert_eq!(fetch_depth(depth(10), true), 10);
+        assert_eq!(fetch_depth(depth(u32::MAX), true), DEPTH_UNSHALLOW - 1);
+    }
+
+    #[test]
+    fn can_fetch_from_other_remotes() {
+        let test_git = initialise_git_repo(None);
+        let local_path = test_git.dir.path().join("local");
+        let remote_path = test_git.dir.path().join("remote");
+        test_git
+            .local
+            .remote("upstream", remote_path.to_str().unwrap())
+            .unwrap();
+
+        let result = git_fetch(&local_path, "upstream", None).unwrap();
+        assert_eq!(
+            result.refs_updated,
+            vec![
+                "refs/remotes/upstream/master",
+                "refs/remotes/upstream/other"
+            ]
+        );
+        // Other remotes are left alone.
+        assert!(test_git
+            .local
+            .find_reference("refs/remotes/origin/other")
+            .is_err());
+
+        let err = git_fetch(&local_path, "missing", None).unwrap_err();
+        assert_eq!(err.code(), ErrorCode::NotFound);
+        assert_eq!(
+            err.message(),
+            "Remote 'missing' does not exist; available remotes are 'origin', 'upstream'"
+        );
+    }
+
+    #[test]
+    fn concurrent_fetches_are_serialised() {
+        let test_git = initialise_git_repo(None);
+        let local_path = test_git.dir.path().join("local");
+
+        let results: Vec<FetchResult> = std::thread::scope(|s| {
+            let handles: Vec<_> = (0..2)
+                .map(|_| s.spawn(|| git_fetch(&local_path, DEFAULT_REMOTE, None).unwrap()))
+                .collect();
+            handles.into_iter().map(|h| h.join().unwrap()).collect()
+        });
+
+        // Whichever fetch ran first did all the work, leaving nothing for the other.
+        let mut updated: Vec<usize> = results.iter().map(|r| r.refs_updated.len()).collect();
+        updated.sort();
+        assert_eq!(updated, vec![0, 2]);
+
+        let again = git_fetch(&local_path, DEFAULT_REMOTE, None).unwrap();
+        assert_eq!(again.objects_received, 0);
+        assert!(again.refs_updated.is_empty());
+    }
+
     #[test]
     fn can_list_git_branches() {
         let test_git = initialise_git_repo(None);
         let local_path = &test_git.dir.path().join("local");
-        git_fetch(local_path).unwrap();
+        git_fetch(local_path, DEFAULT_REMOTE, None).unwrap();
 
-        let branch_response = git_list_branches(local_path).unwrap();
+        let branch_response = git_list_branches(local_path, None).unwrap();
         let default_branch = branch_response.default_branch.unwrap();
         let branches_list = branch_response.branches;
 
@@ -125,11 +386,121 @@ mod tests {
 
         assert_eq!(branches_list.len(), 2);
         assert_eq!(branches_list[0].name, String::from("master"));
+        assert_eq!(branches_list[0].message.subject, "Second commit");
+        assert_eq!(branches_list[0].message.body, None);
+        assert_eq!(branches_list[1].name, String::from("other"));
+        assert_eq!(branches_list[1].message.subject, "Third commit");
+
+        assert!(branches_list[0].current);
+        assert!(!branches_list[1].current);
+    }
+
+    #[test]
+    fn can_exclude_stale_branches() {
+        let test_git = initialise_git_repo(None);
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        let days = |n: u64| Duration::from_secs(n * 24 * 60 * 60);
+        let ten_days_ago = (now - days(10)).as_secs() as i64;
+        git_create_branch_at(&test_git.remote, "stale", "Old commit", ten_days_ago);
+
+        let local_path = &test_git.dir.path().join("local");
+        git_fetch(local_path, DEFAULT_REMOTE, None).unwrap();
+
+        let names = |response: BranchResponse| -> Vec<String> {
+            response.branches.into_iter().map(|b| b.name).collect()
+        };
+        let all = git_list_branches(local_path, None).unwrap();
+        assert_eq!(names(all), vec!["master", "other", "stale"]);
+
+        let recent = git_list_branches(local_path, Some(days(7))).unwrap();
+        assert_eq!(names(recent), vec!["master", "other"]);
+
+        let older = git_list_branches(local_path, Some(days(30))).unwrap();
+        assert_eq!(names(older), vec!["master", "other", "stale"]);
+    }
+
+    #[test]
+    fn commit_message_keeps_subject_and_body() {
+        let test_git = initialise_git_repo(None);
+        let message = "Add a feature\n\nThis explains why.\n\n- one\n- two\n\nThe end.\n";
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        git_create_branch_at(&test_git.remote, "feature", message, now.as_secs() as i64);
+
+        let local_path = &test_git.dir.path().join("local");
+        git_fetch(local_path, DEFAULT_REMOTE, None).unwrap();
+        let branches = git_list_branches(local_path, None).unwrap().branches;
+        let feature = branches.iter().find(|b| b.name == "feature").unwrap();
         assert_eq!(
-            branches_list[0].message,
-            vec![String::from("Second commit")]
+            feature.message,
+            CommitMessage {
+                subject: String::from("Add a feature"),
+                body: Some(String::from(
+                    "This explains why.\n\n- one\n- two\n\nThe end."
+                )),
+            }
         );
-        assert_eq!(branches_list[1].name, String::from("other"));
-        assert_eq!(branches_list[1].message, vec![String::from("Third commit")]);
+    }
+
+    #[test]
+    fn detects_default_branch_regardless_of_order() {
+        let test_git = initialise_git_repo(None);
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64;
+        // These sort before and after HEAD, so no branch is in a fixed position.
+        git_create_branch_at(&test_git.remote, "AAA", "First branch", now);
+        git_create_branch_at(&test_git.remote, "zzz", "Last branch", now);
+
+        let local_path = &test_git.dir.path().join("local");
+        git_fetch(local_path, DEFAULT_REMOTE, None).unwrap();
+
+        let response = git_list_branches(local_path, None).unwrap();
+        assert_eq!(response.default_branch, Some(String::from("master")));
+        let names: Vec<&str> = response.branches.iter().map(|b| b.name.as_str()).collect();
+        assert_eq!(names, vec!["AAA", "master", "other", "zzz"]);
+
+        // Without a symbolic HEAD there is no default, and every branch is still listed.
+        test_git
+            .local
+            .find_reference("refs/remotes/origin/HEAD")
+            .unwrap()
+            .delete()
+            .unwrap();
+        let response = git_list_branches(local_path, None).unwrap();
+        assert_eq!(response.default_branch, None);
+        assert_eq!(response.branches.len(), 4);
+    }
+
+    #[test]
+    fn can_get_git_status() {
+        let test_git = initialise_git_repo(None);
+        let local_path = test_git.dir.path().join("local");
+
+        let status = git_status(&local_path).unwrap();
+        let head = git_get_latest_commit(&test_git.local, "HEAD");
+        assert_eq!(status.commit_hash, Some(head.id().to_string()));
+        assert_eq!(status.branch, Some(String::from("master")));
+        assert!(!status.dirty);
+
+        // Untracked files don't count, but changes to tracked ones do.
+        std::fs::write(local_path.join("untracked"), "new").unwrap();
+        assert!(!git_status(&local_path).unwrap().dirty);
+        std::fs::write(local_path.join("new_file"), "changed").unwrap();
+        assert!(git_status(&local_path).unwrap().dirty);
+
+        test_git.local.set_head_detached(head.id()).unwrap();
+        assert_eq!(git_status(&local_path).unwrap().branch, None);
+    }
+
+    #[test]
+    fn reports_missing_repository_clearly() {
+        let tmp = tempfile::TempDir::new().unwrap();
+        let err = git_list_branches(tmp.path(), None).err().unwrap();
+        assert_eq!(err.code(), ErrorCode::NotFound);
+        assert!(err.message().contains("is not a git repository"));
+
+        let err = git_fetch(tmp.path(), DEFAULT_REMOTE, None).unwrap_err();
+        assert_eq!(err.code(), ErrorCode::NotFound);
     }
 }
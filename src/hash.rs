@@ -6,7 +6,7 @@ use std::fmt;
 use std::fmt::LowerHex;
 use std::path::Path;
 
-#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
 #[serde(rename_all = "lowercase")]
 pub enum HashAlgorithm {
     Md5,
@@ -16,7 +16,55 @@ pub enum HashAlgorithm {
     Sha512,
 }
 
-#[derive(Debug, PartialEq)]
+impl HashAlgorithm {
+    pub const ALL: [HashAlgorithm; 5] = [
+        HashAlgorithm::Md5,
+        HashAlgorithm::Sha1,
+        HashAlgorithm::Sha256,
+        HashAlgorithm::Sha384,
+        HashAlgorithm::Sha512,
+    ];
+
+    /// The code identifying the algorithm in a multihash.
+    fn multihash_code(self) -> u64 {
+        match self {
+            HashAlgorithm::Md5 => 0xd5,
+            HashAlgorithm::Sha1 => 0x11,
+            HashAlgorithm::Sha256 => 0x12,
+            HashAlgorithm::Sha384 => 0x20,
+            HashAlgorithm::Sha512 => 0x13,
+        }
+    }
+
+    /// The length of the algorithm's digests, in bytes.
+    fn digest_length(self) -> usize {
+        match self {
+            HashAlgorithm::Md5 => 16,
+            HashAlgorithm::Sha1 => 20,
+            HashAlgorithm::Sha256 => 32,
+            HashAlgorithm::Sha384 => 48,
+            HashAlgorithm::Sha512 => 64,
+        }
+    }
+}
+
+// Written by hand so that a repository configured with an algorithm we don't support is told
+// which algorithm that is, and which ones could be used instead.
+impl<'de> Deserialize<'de> for HashAlgorithm {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let name = String::deserialize(deserializer)?;
+        name.parse().map_err(|_| {
+            let supported: Vec<String> = HashAlgorithm::ALL.iter().map(|a| a.to_string()).collect();
+            serde::de::Error::custom(format!(
+                "unsupported hash algorithm '{}', expected one of {}",
+                name,
+                supported.join(", ")
+            ))
+        })
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
 pub struct Hash {
     pub algorithm: HashAlgorithm,
     pub value: String,
@@ -113,6 +161,193 @@ impl std::str::FromStr for Hash {
     }
 }
 
+/// The ways in which a hash can be written. Hashes are always stored and compared in their hex
+/// form, `<algorithm>:<hex digest>`, and only converted to and from other encodings when they are
+/// sent to or received from clients.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HashEncoding {
+    #[default]
+    Hex,
+    /// A [multihash](https://multiformats.io/multihash/), written in unpadded lowercase base32
+    /// with the multibase prefix `b`, as used by IPFS.
+    Multihash,
+}
+
+impl fmt::Display for HashEncoding {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            Self::Hex => "hex",
+            Self::Multihash => "multihash",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+/// The multibase prefix of lowercase base32, the only base that multihashes are written in.
+const MULTIBASE_BASE32: char = 'b';
+
+const BASE32_ALPHABET: &[u8; 32] = b"abcdefghijklmnopqrstuvwxyz234567";
+
+fn base32_encode(bytes: &[u8]) -> String {
+    let mut out = String::new();
+    let mut buffer: u32 = 0;
+    let mut bits = 0;
+    for &byte in bytes {
+        buffer = (buffer << 8) | byte as u32;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            out.push(BASE32_ALPHABET[((buffer >> bits) & 31) as usize] as char);
+        }
+    }
+    if bits > 0 {
+        out.push(BASE32_ALPHABET[((buffer << (5 - bits)) & 31) as usize] as char);
+    }
+    out
+}
+
+fn base32_decode(s: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::new();
+    let mut buffer: u32 = 0;
+    let mut bits = 0;
+    for c in s.bytes() {
+        let value = BASE32_ALPHABET.iter().position(|&a| a == c)? as u32;
+        buffer = (buffer << 5) | value;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((buffer >> bits) as u8);
+        }
+    }
+    Some(out)
+}
+
+fn write_varint(mut value: u64, out: &mut Vec<u8>) {
+    while value >= 0x80 {
+        out.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+/// Read an unsigned varint from the start of `bytes`, returning it and the bytes after it.
+fn read_varint(bytes: &[u8]) -> Option<(u64, &[u8])> {
+    let mut value = 0;
+    // The multiformats spec limits varints to 9 bytes.
+    for (i, &byte) in bytes.iter().enumerate().take(9) {
+        value |= ((byte & 0x7f) as u64) << (7 * i);
+        if byte & 0x80 == 0 {
+            return Some((value, &bytes[i + 1..]));
+        }
+    }
+    None
+}
+
+fn hex_decode(s: &str) -> Option<Vec<u8>> {
+    if s.len() % 2 != 0 {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+impl Hash {
+    /// Write the hash in the given encoding.
+    pub fn encode(&self, encoding: HashEncoding) -> Result<String, HashError> {
+        match encoding {
+            HashEncoding::Hex => Ok(self.to_string()),
+            HashEncoding::Multihash => {
+                let digest = hex_decode(&self.value).ok_or_else(|| {
+                    HashError::new(
+                        HashErrorKind::InvalidHashFormat,
+                        format!("Hash '{}' cannot be written as a multihash", self),
+                    )
+                })?;
+                let mut bytes = Vec::new();
+                write_varint(self.algorithm.multihash_code(), &mut bytes);
+                write_varint(digest.len() as u64, &mut bytes);
+                bytes.extend(digest);
+                Ok(format!("{}{}", MULTIBASE_BASE32, base32_encode(&bytes)))
+            }
+        }
+    }
+
+    /// Read a hash written in any of the supported encodings, returning it in its hex form along
+    /// with the encoding it was written in.
+    pub fn decode(s: &str) -> Result<(Hash, HashEncoding), HashError> {
+        let trimmed = s.trim();
+        if trimmed.contains(':') {
+            return Ok((trimmed.parse()?, HashEncoding::Hex));
+        }
+        let invalid = || {
+            HashError::new(
+                HashErrorKind::InvalidHashFormat,
+                format!("Invalid hash format '{s}'"),
+            )
+        };
+        let bytes = trimmed
+            .strip_prefix(MULTIBASE_BASE32)
+            .and_then(base32_decode)
+            .ok_or_else(invalid)?;
+        let (code, rest) = read_varint(&bytes).ok_or_else(invalid)?;
+        let (length, digest) = read_varint(rest).ok_or_else(invalid)?;
+        if length == 0 || digest.len() as u64 != length {
+            return Err(invalid());
+        }
+        let algorithm = HashAlgorithm::ALL
+            .into_iter()
+            .find(|a| a.multihash_code() == code)
+            .ok_or_else(|| {
+                HashError::new(
+                    HashErrorKind::InvalidHashAlgorithm,
+                    format!("Unsupported multihash algorithm 0x{:x} in '{s}'", code),
+                )
+            })?;
+        if digest.len() != algorithm.digest_length() {
+            return Err(HashError::new(
+                HashErrorKind::InvalidHashFormat,
+                format!(
+                    "Invalid hash format '{s}': {} digests are {} bytes long, not {}",
+                    algorithm,
+                    algorithm.digest_length(),
+                    digest.len()
+                ),
+            ));
+        }
+        let value = digest.iter().map(|b| format!("{:02x}", b)).collect();
+        Ok((Hash { algorithm, value }, HashEncoding::Multihash))
+    }
+}
+
+/// Read several hashes, returning their hex forms and the encoding they were written in, which
+/// must be the same for all of them. An empty list is taken to be in hex.
+pub fn decode_hashes(hashes: &[String]) -> Result<(Vec<String>, HashEncoding), HashError> {
+    let mut first: Option<(&str, HashEncoding)> = None;
+    let mut decoded = Vec::with_capacity(hashes.len());
+    for s in hashes {
+        let (hash, encoding) = Hash::decode(s)?;
+        match first {
+            Some((first, expected)) if expected != encoding => {
+                return Err(HashError::new(
+                    HashErrorKind::InvalidHashFormat,
+                    format!(
+                        "Hashes must all be in the same encoding, but '{}' is {} and '{}' is {}",
+                        first, expected, s, encoding
+                    ),
+                ));
+            }
+            Some(_) => {}
+            None => first = Some((s, encoding)),
+        }
+        decoded.push(hash.to_string());
+    }
+    let encoding = first.map(|(_, encoding)| encoding).unwrap_or_default();
+    Ok((decoded, encoding))
+}
+
 fn hex_string<T: LowerHex>(digest: T) -> String {
     format!("{:x}", digest)
 }
@@ -329,4 +564,111 @@ mod tests {
         assert!(res.is_err());
         assert_eq!(res.unwrap_err().kind, HashErrorKind::FileReadFailed);
     }
+
+    #[test]
+    fn base32_matches_rfc4648() {
+        for (data, encoded) in [
+            ("", ""),
+            ("f", "my"),
+            ("fo", "mzxq"),
+            ("foo", "mzxw6"),
+            ("foob", "mzxw6yq"),
+            ("fooba", "mzxw6ytb"),
+            ("foobar", "mzxw6ytboi"),
+        ] {
+            assert_eq!(base32_encode(data.as_bytes()), encoded);
+            assert_eq!(base32_decode(encoded).unwrap(), data.as_bytes());
+        }
+        assert_eq!(base32_decode("MZXW6"), None);
+    }
+
+    #[test]
+    fn hashes_round_trip_through_encodings() {
+        for algorithm in HashAlgorithm::ALL {
+            let hash = hash_data(b"hello", algorithm);
+            for encoding in [HashEncoding::Hex, HashEncoding::Multihash] {
+                let encoded = hash.encode(encoding).unwrap();
+                assert_eq!(Hash::decode(&encoded).unwrap(), (hash.clone(), encoding));
+            }
+        }
+
+        let hash = hash_data(b"hello", HashAlgorithm::Sha256);
+        assert_eq!(hash.encode(HashEncoding::Hex).unwrap(), hash.to_string());
+        // A sha256 multihash starts with the bytes 0x12 0x20, which are "ciq" in base32.
+        let multihash = hash.encode(HashEncoding::Multihash).unwrap();
+        assert!(multihash.starts_with("bciq"));
+        assert_eq!(multihash.len(), 1 + (34 * 8 + 4) / 5);
+    }
+
+    #[test]
+    fn rejects_invalid_multihashes() {
+        let hash = hash_data(b"hello", HashAlgorithm::Sha1);
+        let multihash = hash.encode(HashEncoding::Multihash).unwrap();
+        let truncated = &multihash[..multihash.len() - 2];
+        let err = Hash::decode(truncated).unwrap_err();
+        assert_eq!(err.kind, HashErrorKind::InvalidHashFormat);
+
+        // The sha3-256 multihash of nothing, which isn't supported.
+        let mut bytes = vec![0x16, 0x20];
+        bytes.extend([0; 32]);
+        let err = Hash::decode(&format!("b{}", base32_encode(&bytes))).unwrap_err();
+        assert_eq!(err.kind, HashErrorKind::InvalidHashAlgorithm);
+
+        for s in ["", "b", "zQmHash", "bCIQ"] {
+            assert!(Hash::decode(s).is_err());
+        }
+
+        // Digests must be as long as the algorithm's, even if the multihash is self-consistent.
+        for algorithm in HashAlgorithm::ALL {
+            let length = algorithm.digest_length();
+            assert_eq!(hash_data(b"", algorithm).value.len(), length * 2);
+            for wrong in [length - 1, length + 1] {
+                let mut bytes = Vec::new();
+                write_varint(algorithm.multihash_code(), &mut bytes);
+                write_varint(wrong as u64, &mut bytes);
+                bytes.extend(vec![0; wrong]);
+                let multihash = format!("b{}", base32_encode(&bytes));
+                let err = Hash::decode(&multihash).unwrap_err();
+                assert_eq!(err.kind, HashErrorKind::InvalidHashFormat);
+                assert_eq!(
+                    err.explanation,
+                    format!(
+                        "Invalid hash format '{}': {} digests are {} bytes long, not {}",
+                        multihash, algorithm, length, wrong
+                    )
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn decoding_hashes_rejects_mixed_encodings() {
+        let a = hash_data(b"a", HashAlgorithm::Sha256);
+        let b = hash_data(b"b", HashAlgorithm::Sha256);
+        let hex = vec![a.to_string(), b.to_string()];
+        let multihash = vec![
+            a.encode(HashEncoding::Multihash).unwrap(),
+            b.encode(HashEncoding::Multihash).unwrap(),
+        ];
+        assert_eq!(
+            decode_hashes(&hex).unwrap(),
+            (hex.clone(), HashEncoding::Hex)
+        );
+        assert_eq!(
+            decode_hashes(&multihash).unwrap(),
+            (hex.clone(), HashEncoding::Multihash)
+        );
+        assert_eq!(decode_hashes(&[]).unwrap(), (vec![], HashEncoding::Hex));
+
+        let mixed = vec![hex[0].clone(), multihash[1].clone()];
+        let err = decode_hashes(&mixed).unwrap_err();
+        assert_eq!(err.kind, HashErrorKind::InvalidHashFormat);
+        assert_eq!(
+            err.explanation,
+            format!(
+                "Hashes must all be in the same encoding, but '{}' is hex and '{}' is multihash",
+                mixed[0], mixed[1]
+            )
+        );
+    }
 }
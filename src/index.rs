@@ -1,20 +1,144 @@
-use crate::metadata::{get_metadata_from_date, Packet};
+//! In-memory indexes of the packets in a repository.
+//!
+//! Building an index requires reading every metadata or location file, so the result is cached
+//! for each root. A cached index is rebuilt whenever the modification time of any of the
+//! directories it was built from changes, which happens whenever a packet is added to them. Code
+//! which modifies these directories should additionally call `invalidate_index`, in case the
+//! change happens quickly enough that the modification times are unchanged.
+use crate::config;
+use crate::location::{read_location, LocationEntry};
+use crate::metadata::{clear_metadata_cache, read_all_metadata, Packet};
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+use std::fs;
 use std::io;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
 
 #[derive(Clone)]
 pub struct Index {
     pub packets: Vec<Packet>,
 }
 
-pub fn get_packet_index(root: &Path) -> io::Result<Index> {
-    let packets = get_metadata_from_date(root, None)?;
-    Ok(Index { packets })
+/// The entries of each location, keyed by the location's name.
+pub type LocationIndex = HashMap<String, Vec<LocationEntry>>;
+
+/// The modification times of the directories an index was built from.
+type Stamp = Vec<(PathBuf, SystemTime)>;
+
+type Cache<T> = Mutex<HashMap<PathBuf, (Stamp, Arc<T>)>>;
+
+lazy_static! {
+    static ref PACKET_CACHE: Cache<Index> = Mutex::new(HashMap::new());
+    static ref LOCATION_CACHE: Cache<LocationIndex> = Mutex::new(HashMap::new());
+    static ref INDEX_THREADS: Mutex<HashMap<PathBuf, usize>> = Mutex::new(HashMap::new());
+}
+
+fn get_stamp(paths: Vec<PathBuf>) -> io::Result<Stamp> {
+    paths
+        .into_iter()
+        .map(|path| {
+            let modified = fs::metadata(&path)?.modified()?;
+            Ok((path, modified))
+        })
+        .collect()
+}
+
+fn get_cached<T>(
+    cache: &Cache<T>,
+    root: &Path,
+    stamp: Stamp,
+    build: impl FnOnce() -> io::Result<T>,
+) -> io::Result<Arc<T>> {
+    if let Some((cached_stamp, value)) = cache.lock().unwrap().get(root) {
+        if *cached_stamp == stamp {
+            return Ok(value.clone());
+        }
+    }
+
+    let value = Arc::new(build()?);
+    cache
+        .lock()
+        .unwrap()
+        .insert(root.to_owned(), (stamp, value.clone()));
+    Ok(value)
+}
+
+/// Get the index of packet metadata for the repository at the given root, rebuilding it if any
+/// metadata has been added since it was last built.
+///
+/// The metadata is read using the number of threads last given to `use_index_threads`. Until then,
+/// the `index_threads` server setting is read from the configuration on disk, and a repository
+/// whose configuration cannot be read uses the default.
+pub fn get_packet_index(root: &Path) -> io::Result<Arc<Index>> {
+    let stamp = get_stamp(vec![root.join(".outpack").join("metadata")])?;
+    get_cached(&PACKET_CACHE, root, stamp, || {
+        let threads = INDEX_THREADS.lock().unwrap().get(root).copied();
+        let threads = threads.unwrap_or_else(|| {
+            config::read_config(root)
+                .map(|config| config.server)
+                .unwrap_or_default()
+                .index_threads()
+        });
+        let packets = read_all_metadata(root, threads)?;
+        Ok(Index { packets })
+    })
+}
+
+/// Use the given number of threads when rebuilding the packet index of the repository from now on,
+/// such as the number in the configuration the server is currently using.
+pub fn use_index_threads(root: &Path, threads: usize) {
+    INDEX_THREADS
+        .lock()
+        .unwrap()
+        .insert(root.to_owned(), threads);
+}
+
+/// Get the index of location entries for the repository at the given root, rebuilding it if any
+/// location has been modified since it was last built.
+pub fn get_location_index(root: &Path) -> io::Result<Arc<LocationIndex>> {
+    let path = root.join(".outpack").join("location");
+    let locations = fs::read_dir(&path)?
+        .map(|entry| Ok(entry?.path()))
+        .collect::<io::Result<Vec<PathBuf>>>()?;
+
+    let mut paths = locations.clone();
+    paths.push(path);
+    paths.sort();
+
+    get_cached(&LOCATION_CACHE, root, get_stamp(paths)?, || {
+        locations
+            .into_iter()
+            .map(|path| {
+                let name = path.file_name().unwrap().to_string_lossy().into_owned();
+                Ok((name, read_location(path)?))
+            })
+            .collect()
+    })
+}
+
+/// Discard the cached indexes of the given root, forcing them to be rebuilt on next use.
+pub fn invalidate_index(root: &Path) {
+    PACKET_CACHE.lock().unwrap().remove(root);
+    LOCATION_CACHE.lock().unwrap().remove(root);
+}
+
+/// Rebuild the indexes of the given root from scratch, returning the number of packets. Unlike the
+/// usual rebuild when a directory changes, every metadata file is read again, so this also picks
+/// up changes made to files in place.
+pub fn rebuild(root: &Path) -> io::Result<usize> {
+    invalidate_index(root);
+    clear_metadata_cache();
+    get_location_index(root)?;
+    Ok(get_packet_index(root)?.packets.len())
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::metadata::{add_packet, get_ids_digest};
+    use crate::test_utils::tests::{get_empty_outpack_root, get_temp_outpack_root, start_packet};
 
     #[test]
     fn can_get_packet_index() {
@@ -30,4 +154,160 @@ mod tests {
         assert_eq!(ids[2], "20180220-095832-16a4bbed");
         assert_eq!(ids[3], "20180818-164043-7cdcde4b");
     }
+
+    #[test]
+    fn can_get_location_index() {
+        let locations = get_location_index(Path::new("tests/example")).unwrap();
+        assert_eq!(locations.len(), 2);
+        assert_eq!(locations["local"].len(), 1);
+        assert_eq!(locations["another"].len(), 3);
+    }
+
+    #[test]
+    fn index_is_reused_until_modified() {
+        let root = get_temp_outpack_root();
+        let first = get_packet_index(&root).unwrap();
+        let second = get_packet_index(&root).unwrap();
+        assert!(Arc::ptr_eq(&first, &second));
+
+        invalidate_index(&root);
+        let third = get_packet_index(&root).unwrap();
+        assert!(!Arc::ptr_eq(&first, &third));
+    }
+
+    #[test]
+    fn new_packets_are_added_to_index() {
+        let root = get_temp_outpack_root();
+        let digest = get_ids_digest(&root, crate::hash::HashAlgorithm::Sha256).unwrap();
+        assert_eq!(get_packet_index(&root).unwrap().packets.len(), 4);
+
+        let (id, json, hash) = start_packet("data").finish();
+        add_packet(&root, &json, &hash).unwrap();
+
+        let index = get_packet_index(&root).unwrap();
+        assert_eq!(index.packets.len(), 5);
+        assert!(index.packets.iter().any(|p| p.id == id));
+        let locations = get_location_index(&root).unwrap();
+        assert!(locations["local"].iter().any(|e| e.packet == id));
+        assert_ne!(
+            get_ids_digest(&root, crate::hash::HashAlgorithm::Sha256).unwrap(),
+            digest
+        );
+    }
+
+    #[test]
+    fn index_detects_changes_made_outside_the_server() {
+        let root = get_temp_outpack_root();
+        let locations = get_location_index(&root).unwrap();
+        assert_eq!(locations["another"].len(), 3);
+
+        // Simulate another process removing a packet from a location, which does not go through
+        // `invalidate_index`.
+        std::thread::sleep(std::time::Duration::from_millis(10));
+        let path = root.join(".outpack/location/another/20170818-164830-33e0ab01");
+        fs::remove_file(path).unwrap();
+
+        let locations = get_location_index(&root).unwrap();
+        assert_eq!(locations["another"].len(), 2);
+    }
+
+    #[test]
+    fn rebuild_rereads_metadata_changed_in_place() {
+        let root = get_temp_outpack_root();
+        let id = "20170818-164830-33e0ab01";
+        let name = |index: &Index| {
+            let packet = index.packets.iter().find(|p| p.id == id).unwrap();
+            packet.name.clone()
+        };
+        assert_eq!(
+            name(&get_packet_index(&root).unwrap()),
+            "modup-201707-queries1"
+        );
+
+        // Editing a file leaves the modification time of the directory unchanged.
+        let path = root.join(".outpack/metadata").join(id);
+        let contents = fs::read_to_string(&path).unwrap();
+        fs::write(&path, contents.replace("modup-201707-queries1", "renamed")).unwrap();
+        assert_eq!(
+            name(&get_packet_index(&root).unwrap()),
+            "modup-201707-queries1"
+        );
+
+        assert_eq!(rebuild(&root).unwrap(), 4);
+        assert_eq!(name(&get_packet_index(&root).unwrap()), "renamed");
+    }
+
+    /// Create a repository containing a large number of packets, with distinct parameters. The
+    /// packets are the same each time this is called.
+    fn synthetic_root(count: usize) -> PathBuf {
+        let root = get_empty_outpack_root();
+        let metadata = root.join(".outpack").join("metadata");
+        for i in 0..count {
+            let mut builder = start_packet("synthetic");
+            let (id, json, _) = builder.id(format!("20240101-000000-{:08x}", i)).finish();
+            let mut packet: serde_json::Value = serde_json::from_str(&json).unwrap();
+            packet["parameters"] = serde_json::json!({ "i": i });
+            packet["time"] = serde_json::json!({ "start": 1704067200.0, "end": 1704067201.0 });
+            fs::write(metadata.join(id), packet.to_string()).unwrap();
+        }
+        root
+    }
+
+    fn as_json(packets: &[Packet]) -> Vec<serde_json::Value> {
+        packets
+            .iter()
+            .map(|p| serde_json::to_value(p).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn parallel_and_serial_reads_are_identical() {
+        // Metadata is cached by path, so each read uses a separate copy of the repository.
+        let serial = read_all_metadata(&synthetic_root(200), 1).unwrap();
+        assert_eq!(serial.len(), 200);
+        for threads in [2, 7, 64, 1000] {
+            let parallel = read_all_metadata(&synthetic_root(200), threads).unwrap();
+            assert_eq!(as_json(&parallel), as_json(&serial), "{} threads", threads);
+        }
+    }
+
+    #[test]
+    fn index_uses_configured_threads() {
+        let root = synthetic_root(20);
+        let mut config = config::read_config(&root).unwrap();
+        config.server.index_threads = Some(4);
+        config::write_config(&config, &root).unwrap();
+
+        let ids: Vec<String> = get_packet_index(&root)
+            .unwrap()
+            .packets
+            .iter()
+            .map(|p| p.id.clone())
+            .collect();
+        let mut sorted = ids.clone();
+        sorted.sort();
+        assert_eq!(ids.len(), 20);
+        assert_eq!(ids, sorted);
+    }
+
+    /// Compare the time taken to read a large index serially and in parallel. This is slow, so
+    /// is only run when asked for, with `cargo test -- --ignored --nocapture`.
+    #[test]
+    #[ignore]
+    fn benchmark_index_build() {
+        let count = 20000;
+        let threads = std::thread::available_parallelism().unwrap().get();
+        for threads in [1, threads] {
+            let root = synthetic_root(count);
+            let start = std::time::Instant::now();
+            let packets = read_all_metadata(&root, threads).unwrap();
+            assert_eq!(packets.len(), count);
+            println!(
+                "Read {} packets with {} threads in {:?}",
+                count,
+                threads,
+                start.elapsed()
+            );
+        }
+    }
 }
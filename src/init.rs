@@ -3,18 +3,40 @@ use std::fs;
 use std::path::Path;
 
 use crate::config;
+use crate::hash::HashAlgorithm;
 
 pub fn outpack_init(
     path: &Path,
     path_archive: Option<String>,
     use_file_store: bool,
     require_complete_tree: bool,
+) -> anyhow::Result<()> {
+    outpack_init_with_hash_algorithm(
+        path,
+        path_archive,
+        use_file_store,
+        require_complete_tree,
+        HashAlgorithm::Sha256,
+    )
+}
+
+/// Like `outpack_init`, but hashing files and metadata with the given algorithm rather than
+/// sha256.
+pub fn outpack_init_with_hash_algorithm(
+    path: &Path,
+    path_archive: Option<String>,
+    use_file_store: bool,
+    require_complete_tree: bool,
+    hash_algorithm: HashAlgorithm,
 ) -> anyhow::Result<()> {
     let path_outpack = path.join(".outpack");
-    let cfg = config::Config::new(path_archive, use_file_store, require_complete_tree)?;
+    let mut cfg = config::Config::new(path_archive, use_file_store, require_complete_tree)?;
+    cfg.core.hash_algorithm = hash_algorithm;
 
     if path_outpack.exists() {
         let prev = config::read_config(path)?;
+        // Settings which outpack does not know about are kept as they are.
+        cfg.core.extra = prev.core.extra.clone();
         if cfg.core != prev.core {
             bail!("Trying to change config on reinitialisation");
         }
@@ -33,9 +55,26 @@ pub fn outpack_init(
     Ok(())
 }
 
+/// Create a new outpack root at `path`, with a file store and a complete tree, which is the
+/// configuration required by the API server.
+///
+/// Initialising a root that already exists is allowed as long as its configuration is identical
+/// and it does not contain any packets yet.
+pub fn create_root(path: &Path, hash_algorithm: HashAlgorithm) -> anyhow::Result<()> {
+    let path_metadata = path.join(".outpack").join("metadata");
+    if path_metadata.exists() && fs::read_dir(&path_metadata)?.next().is_some() {
+        bail!(
+            "Refusing to initialise '{}', which already contains packets",
+            path.display()
+        );
+    }
+    outpack_init_with_hash_algorithm(path, None, true, true, hash_algorithm)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::test_utils::tests::get_temp_outpack_root;
     use tempfile;
 
     #[test]
@@ -81,4 +120,60 @@ mod tests {
             "Trying to change config on reinitialisation"
         )
     }
+
+    #[test]
+    fn can_choose_hash_algorithm() {
+        let tmp = tempfile::TempDir::new().unwrap();
+        let path = tmp.path();
+        outpack_init_with_hash_algorithm(path, None, true, false, HashAlgorithm::Md5).unwrap();
+        let cfg = config::read_config(path).unwrap();
+        assert_eq!(cfg.core.hash_algorithm, HashAlgorithm::Md5);
+        assert!(!cfg.core.require_complete_tree);
+
+        let res = outpack_init(path, None, true, false);
+        assert_eq!(
+            res.unwrap_err().to_string(),
+            "Trying to change config on reinitialisation"
+        );
+    }
+
+    #[test]
+    fn can_create_root() {
+        let tmp = tempfile::TempDir::new().unwrap();
+        let path = tmp.path();
+        create_root(path, HashAlgorithm::Sha512).unwrap();
+
+        let cfg = config::read_config(path).unwrap();
+        assert_eq!(cfg.core.hash_algorithm, HashAlgorithm::Sha512);
+        assert!(cfg.core.use_file_store);
+        assert!(cfg.core.require_complete_tree);
+        assert_eq!(cfg.core.path_archive, None);
+        assert!(path.join(".outpack/metadata").is_dir());
+        assert!(path.join(".outpack/files").is_dir());
+        assert!(path.join(".outpack/location/local").is_dir());
+
+        // An empty root can be initialised again with the same configuration
+        create_root(path, HashAlgorithm::Sha512).unwrap();
+        let res = create_root(path, HashAlgorithm::Sha256);
+        assert_eq!(
+            res.unwrap_err().to_string(),
+            "Trying to change config on reinitialisation"
+        );
+    }
+
+    #[test]
+    fn create_root_refuses_to_overwrite_existing_packets() {
+        let root = get_temp_outpack_root();
+        let before = config::read_config(&root).unwrap();
+
+        let res = create_root(&root, HashAlgorithm::Sha256);
+        assert_eq!(
+            res.unwrap_err().to_string(),
+            format!(
+                "Refusing to initialise '{}', which already contains packets",
+                root.display()
+            )
+        );
+        assert_eq!(config::read_config(&root).unwrap(), before);
+    }
 }
@@ -3,17 +3,28 @@ mod test_utils;
 
 pub mod api;
 pub mod config;
+pub mod hash;
 pub mod index;
 pub mod init;
 pub mod query;
+pub mod store;
 
 mod git;
-mod hash;
 mod location;
+mod logging;
 mod metadata;
 mod metrics;
+mod openapi;
 mod outpack_file;
+mod ratelimit;
 mod responses;
-mod store;
+mod resumable;
+mod schema;
+mod shutdown;
+mod signing;
+mod sync;
+mod timeout;
 mod upload;
 mod utils;
+mod worktree;
+mod zip;
@@ -1,21 +1,26 @@
 use crate::config::Location;
-use crate::utils::time_as_num;
+use crate::utils::Timestamp;
 use cached::cached_result;
 use cached::instant::SystemTime;
 use serde::{Deserialize, Serialize};
-use std::ffi::OsString;
-use std::fs::DirEntry;
+use std::collections::HashMap;
 use std::path::{Path, PathBuf};
 use std::{fs, io};
 
 use super::config;
+use super::index;
 use super::utils;
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct LocationEntry {
     pub packet: String,
-    pub time: f64,
+    pub time: Timestamp,
     pub hash: String,
+
+    /// The hashes of the packet's files. These are not stored in the location, but may be added
+    /// from the packet's metadata with `add_file_hashes`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub files: Option<Vec<String>>,
 }
 
 cached_result! {
@@ -27,12 +32,13 @@ cached_result! {
     }
 }
 
-fn get_order(location_config: &[Location], entry: &DirEntry) -> usize {
-    let name = entry.file_name();
+/// The position of a location in the configuration. Locations which are not configured, e.g.
+/// because they have since been removed, come after all others.
+fn get_order(location_config: &[Location], name: &str) -> usize {
     location_config
         .iter()
-        .position(|l| OsString::from(&l.name) == name)
-        .unwrap()
+        .position(|l| l.name == name)
+        .unwrap_or(usize::MAX)
 }
 
 pub fn read_location(path: PathBuf) -> io::Result<Vec<LocationEntry>> {
@@ -47,29 +53,45 @@ pub fn read_location(path: PathBuf) -> io::Result<Vec<LocationEntry>> {
     Ok(packets)
 }
 
-pub fn read_locations(root_path: &Path) -> io::Result<Vec<LocationEntry>> {
-    let path = root_path.join(".outpack").join("location");
-
-    let location_config = config::read_config(root_path)?.location;
-
-    let mut locations_sorted = fs::read_dir(path)?
-        .filter_map(|r| r.ok())
-        .collect::<Vec<DirEntry>>();
+/// Read the entries for all locations, ordered according to the given location configuration,
+/// such as the one the server is currently using.
+pub fn read_locations(
+    root_path: &Path,
+    location_config: &[Location],
+) -> io::Result<Vec<LocationEntry>> {
+    let locations = index::get_location_index(root_path)?;
 
-    locations_sorted.sort_by_key(|a| get_order(&location_config, a));
+    let mut locations_sorted: Vec<&String> = locations.keys().collect();
+    locations_sorted.sort_by_key(|name| (get_order(location_config, name), *name));
 
     let packets = locations_sorted
-        .iter()
-        .map(|entry| read_location(entry.path()))
-        // collect any errors at this point into a single result
-        .collect::<io::Result<Vec<Vec<LocationEntry>>>>()?
         .into_iter()
-        .flatten()
+        .flat_map(|name| locations[name].iter().cloned())
         .collect();
 
     Ok(packets)
 }
 
+/// Add the hashes of each packet's files to its location entries, from the packet's metadata.
+/// Entries for packets whose metadata is missing are left without any.
+pub fn add_file_hashes(root: &Path, entries: &mut [LocationEntry]) -> io::Result<()> {
+    let index = index::get_packet_index(root)?;
+    let files: HashMap<&str, Vec<String>> = index
+        .packets
+        .iter()
+        .map(|p| {
+            (
+                p.id.as_str(),
+                p.files.iter().map(|f| f.hash.clone()).collect(),
+            )
+        })
+        .collect();
+    for entry in entries {
+        entry.files = files.get(entry.packet.as_str()).cloned();
+    }
+    Ok(())
+}
+
 pub fn mark_packet_known(
     packet_id: &str,
     location_id: &str,
@@ -79,8 +101,9 @@ pub fn mark_packet_known(
 ) -> io::Result<()> {
     let entry = LocationEntry {
         packet: String::from(packet_id),
-        time: time_as_num(time),
+        time: Timestamp::from(time),
         hash: String::from(hash),
+        files: None,
     };
 
     let location_path = root.join(".outpack").join("location").join(location_id);
@@ -91,10 +114,94 @@ pub fn mark_packet_known(
         fs::File::create(&path)?;
         let json = serde_json::to_string(&entry)?;
         fs::write(path, json)?;
+        index::invalidate_index(root);
     }
     Ok(())
 }
 
+fn validate_location_name(name: &str) -> io::Result<()> {
+    let valid = !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '.')
+        && !name.starts_with('.');
+    if valid {
+        Ok(())
+    } else {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "Invalid location name '{}': names may only contain letters, numbers, '_', '-' \
+                 and '.', and may not start with '.'",
+                name
+            ),
+        ))
+    }
+}
+
+/// Register another outpack server as a location, and persist it to the repository's
+/// configuration. Returns the updated configuration.
+pub fn add_remote_location(
+    root: &Path,
+    config: &config::Config,
+    name: &str,
+    args: config::HttpLocationArgs,
+) -> io::Result<config::Config> {
+    validate_location_name(name)?;
+    if config.location.iter().any(|l| l.name == name) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("A location named '{}' already exists", name),
+        ));
+    }
+    match url::Url::parse(&args.url) {
+        Ok(url) if url.scheme() == "http" || url.scheme() == "https" => {}
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Invalid location url '{}'", args.url),
+            ))
+        }
+    }
+
+    let mut config = config.clone();
+    config.location.push(Location::http(name, args));
+    config::write_config(&config, root)?;
+    Ok(config)
+}
+
+/// Remove a location from the repository's configuration, along with the record of which packets
+/// it provides. Returns the updated configuration.
+pub fn remove_location(
+    root: &Path,
+    config: &config::Config,
+    name: &str,
+) -> io::Result<config::Config> {
+    if name == "local" {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "The local location cannot be removed",
+        ));
+    }
+    if !config.location.iter().any(|l| l.name == name) {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("Location '{}' does not exist", name),
+        ));
+    }
+
+    let mut config = config.clone();
+    config.location.retain(|l| l.name != name);
+    config::write_config(&config, root)?;
+
+    let path = root.join(".outpack").join("location").join(name);
+    if path.exists() {
+        fs::remove_dir_all(path)?;
+    }
+    index::invalidate_index(root);
+    Ok(config)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -103,7 +210,9 @@ mod tests {
 
     #[test]
     fn packets_ordered_by_location_order_then_id() {
-        let entries = read_locations(Path::new("tests/example")).unwrap();
+        let root = Path::new("tests/example");
+        let locations = config::read_config(root).unwrap().location;
+        let entries = read_locations(root, &locations).unwrap();
         assert_eq!(entries[0].packet, "20170818-164847-7574883b");
         assert_eq!(entries[1].packet, "20170818-164830-33e0ab01");
         assert_eq!(entries[2].packet, "20180220-095832-16a4bbed");
@@ -120,20 +229,13 @@ mod tests {
         let entries_b = read_location(loc_b.clone()).unwrap();
         assert!(!entries_b.iter().any(|e| e.packet == entry_a.packet));
         let now = SystemTime::now();
-        mark_packet_known(
-            &entry_a.packet,
-            "local",
-            &entry_a.hash,
-            SystemTime::now(),
-            &root,
-        )
-        .unwrap();
+        mark_packet_known(&entry_a.packet, "local", &entry_a.hash, now, &root).unwrap();
         let entries_b = read_location(loc_b).unwrap();
         let res = entries_b
             .iter()
             .find(|e| e.packet == entry_a.packet)
             .unwrap();
-        assert_eq!(res.time, time_as_num(now));
+        assert_eq!(res.time, Timestamp::from(now));
         assert_eq!(res.packet, entry_a.packet);
         assert_eq!(res.hash, entry_a.hash);
     }
@@ -145,14 +247,7 @@ mod tests {
         let entries_a = read_location(loc_a).unwrap();
         let entry_a = entries_a.first().unwrap();
         let now = SystemTime::now();
-        mark_packet_known(
-            &entry_a.packet,
-            "local",
-            &entry_a.hash,
-            SystemTime::now(),
-            &root,
-        )
-        .unwrap();
+        mark_packet_known(&entry_a.packet, "local", &entry_a.hash, now, &root).unwrap();
 
         let loc_b = root.join(".outpack/location/local");
         let entries_b = read_location(loc_b.clone()).unwrap();
@@ -160,13 +255,13 @@ mod tests {
             .iter()
             .find(|e| e.packet == entry_a.packet)
             .unwrap();
-        assert_eq!(res.time, time_as_num(now));
+        assert_eq!(res.time, Timestamp::from(now));
 
         mark_packet_known(
             &entry_a.packet,
             "local",
             &entry_a.hash,
-            SystemTime::now() + Duration::from_secs(120),
+            now + Duration::from_secs(120),
             &root,
         )
         .unwrap();
@@ -177,6 +272,84 @@ mod tests {
             .find(|e| e.packet == entry_a.packet)
             .unwrap();
         // time known should still be the time it was first added at
-        assert_eq!(res.time, time_as_num(now));
+        assert_eq!(res.time, Timestamp::from(now));
+    }
+
+    fn example_args(url: &str) -> config::HttpLocationArgs {
+        config::HttpLocationArgs {
+            url: String::from(url),
+            token: None,
+        }
+    }
+
+    #[test]
+    fn can_add_and_remove_remote_locations() {
+        let root = get_temp_outpack_root();
+        let cfg = config::read_config(&root).unwrap();
+
+        let cfg = add_remote_location(&root, &cfg, "upstream", example_args("http://a")).unwrap();
+        assert_eq!(cfg.location.len(), 3);
+        assert_eq!(config::read_config(&root).unwrap(), cfg);
+
+        let res = add_remote_location(&root, &cfg, "upstream", example_args("http://b"));
+        assert_eq!(res.unwrap_err().kind(), io::ErrorKind::InvalidInput);
+
+        let cfg = remove_location(&root, &cfg, "another").unwrap();
+        assert_eq!(config::read_config(&root).unwrap(), cfg);
+        let names: Vec<&str> = cfg.location.iter().map(|l| l.name.as_str()).collect();
+        assert_eq!(names, vec!["local", "upstream"]);
+        assert!(!root.join(".outpack/location/another").exists());
+        assert_eq!(read_locations(&root, &cfg.location).unwrap().len(), 1);
+
+        let res = remove_location(&root, &cfg, "another");
+        assert_eq!(res.unwrap_err().kind(), io::ErrorKind::NotFound);
+        let res = remove_location(&root, &cfg, "local");
+        assert_eq!(res.unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn changing_locations_keeps_other_settings() {
+        let root = get_temp_outpack_root();
+        let path = root.join(".outpack").join("config.json");
+        let mut json: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+        json["schema_version"] = "0.0.1".into();
+        json["custom"] = serde_json::json!({ "kept": true });
+        json["core"]["custom"] = "kept".into();
+        fs::write(&path, json.to_string()).unwrap();
+        let cfg = config::read_config(&root).unwrap();
+
+        let cfg = add_remote_location(&root, &cfg, "upstream", example_args("http://a")).unwrap();
+        remove_location(&root, &cfg, "upstream").unwrap();
+
+        let written: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(written["schema_version"], "0.0.1");
+        assert_eq!(written["custom"], serde_json::json!({ "kept": true }));
+        assert_eq!(written["core"]["custom"], "kept");
+        assert_eq!(written["location"], json["location"]);
+    }
+
+    #[test]
+    fn remote_locations_are_validated() {
+        let root = get_temp_outpack_root();
+        let cfg = config::read_config(&root).unwrap();
+        for name in ["", "../x", ".hidden", "a b"] {
+            let res = add_remote_location(&root, &cfg, name, example_args("http://a"));
+            assert_eq!(res.unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        }
+        for url in ["not a url", "ftp://example.com"] {
+            let res = add_remote_location(&root, &cfg, "upstream", example_args(url));
+            assert_eq!(res.unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        }
+        assert_eq!(config::read_config(&root).unwrap(), cfg);
+    }
+
+    #[test]
+    fn unconfigured_locations_are_listed_last() {
+        let root = get_temp_outpack_root();
+        let entries = read_locations(&root, &[]).unwrap();
+        assert_eq!(entries[0].packet, "20170818-164830-33e0ab01");
+        assert_eq!(entries[3].packet, "20170818-164847-7574883b");
     }
 }
@@ -0,0 +1,184 @@
+//! Choosing how verbose the server's logs are, and how they are formatted.
+//!
+//! The settings come from the `log` entry of the `server` section of the configuration, and each of
+//! them can be overridden by an environment variable, so that a deployment can change them without
+//! editing the repository's configuration.
+use std::io;
+use tracing::level_filters::LevelFilter;
+use tracing::Subscriber;
+use tracing_subscriber::fmt::MakeWriter;
+
+use crate::config::{Log, LogFormat, LogLevel};
+
+/// The environment variable overriding the configured log level.
+pub const LEVEL_VARIABLE: &str = "OUTPACK_LOG_LEVEL";
+
+/// The environment variable overriding the configured log format.
+pub const FORMAT_VARIABLE: &str = "OUTPACK_LOG_FORMAT";
+
+fn parse_variable<T: serde::de::DeserializeOwned>(name: &str, value: &str) -> io::Result<T> {
+    serde_json::from_value(serde_json::Value::String(value.to_lowercase())).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Invalid value '{}' for {}", value, name),
+        )
+    })
+}
+
+/// Apply any overrides from the environment to the configured settings, looking variables up
+/// with `var`.
+pub fn with_overrides(log: &Log, var: impl Fn(&str) -> Option<String>) -> io::Result<Log> {
+    let mut log = log.clone();
+    if let Some(value) = var(LEVEL_VARIABLE) {
+        log.level = parse_variable(LEVEL_VARIABLE, &value)?;
+    }
+    if let Some(value) = var(FORMAT_VARIABLE) {
+        log.format = parse_variable(FORMAT_VARIABLE, &value)?;
+    }
+    Ok(log)
+}
+
+impl From<LogLevel> for LevelFilter {
+    fn from(level: LogLevel) -> Self {
+        match level {
+            LogLevel::Error => LevelFilter::ERROR,
+            LogLevel::Warn => LevelFilter::WARN,
+            LogLevel::Info => LevelFilter::INFO,
+            LogLevel::Debug => LevelFilter::DEBUG,
+            LogLevel::Trace => LevelFilter::TRACE,
+        }
+    }
+}
+
+/// A subscriber writing log lines with the given settings to `writer`.
+pub fn subscriber<W>(log: &Log, writer: W) -> Box<dyn Subscriber + Send + Sync>
+where
+    W: for<'a> MakeWriter<'a> + Send + Sync + 'static,
+{
+    let builder = tracing_subscriber::fmt()
+        .with_max_level(LevelFilter::from(log.level))
+        .with_writer(writer);
+    match log.format {
+        LogFormat::Json => Box::new(builder.json().with_current_span(true).finish()),
+        LogFormat::Plain => Box::new(builder.finish()),
+    }
+}
+
+/// Send the server's logs to standard output, with the given settings overridden by the
+/// environment.
+pub fn init(log: &Log) -> io::Result<()> {
+    let log = with_overrides(log, |name| std::env::var(name).ok())?;
+    tracing::subscriber::set_global_default(subscriber(&log, io::stdout))
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Clone, Default)]
+    struct Output(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for Output {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl<'a> MakeWriter<'a> for Output {
+        type Writer = Output;
+
+        fn make_writer(&'a self) -> Self::Writer {
+            self.clone()
+        }
+    }
+
+    fn capture(log: &Log) -> String {
+        let output = Output::default();
+        tracing::subscriber::with_default(subscriber(log, output.clone()), || {
+            tracing::error!("an error");
+            tracing::warn!("a warning");
+            tracing::info!("some information");
+            tracing::debug!("some detail");
+        });
+        let bytes = output.0.lock().unwrap().clone();
+        String::from_utf8(bytes).unwrap()
+    }
+
+    #[test]
+    fn level_suppresses_less_important_messages() {
+        let log = Log {
+            level: LogLevel::Warn,
+            format: LogFormat::Plain,
+        };
+        let output = capture(&log);
+        assert!(output.contains("an error"));
+        assert!(output.contains("a warning"));
+        assert!(!output.contains("some information"));
+        assert!(!output.contains("some detail"));
+
+        let log = Log {
+            level: LogLevel::Debug,
+            ..log
+        };
+        assert!(capture(&log).contains("some detail"));
+    }
+
+    #[test]
+    fn can_log_as_json() {
+        let output = capture(&Log {
+            level: LogLevel::Info,
+            format: LogFormat::Json,
+        });
+        let lines: Vec<serde_json::Value> = output
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0]["level"], "ERROR");
+        assert_eq!(lines[0]["fields"]["message"], "an error");
+
+        let output = capture(&Log {
+            level: LogLevel::Info,
+            format: LogFormat::Plain,
+        });
+        assert!(serde_json::from_str::<serde_json::Value>(output.lines().next().unwrap()).is_err());
+    }
+
+    #[test]
+    fn environment_overrides_configuration() {
+        let log = Log::default();
+        let var = |level: &'static str, format: &'static str| {
+            move |name: &str| match name {
+                LEVEL_VARIABLE => Some(String::from(level)),
+                FORMAT_VARIABLE => Some(String::from(format)),
+                _ => None,
+            }
+        };
+        assert_eq!(with_overrides(&log, |_| None).unwrap(), log);
+        assert_eq!(
+            with_overrides(&log, var("WARN", "plain")).unwrap(),
+            Log {
+                level: LogLevel::Warn,
+                format: LogFormat::Plain,
+            }
+        );
+
+        let err = with_overrides(&log, var("loud", "plain")).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(
+            err.to_string(),
+            "Invalid value 'loud' for OUTPACK_LOG_LEVEL"
+        );
+        let err = with_overrides(&log, var("info", "xml")).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Invalid value 'xml' for OUTPACK_LOG_FORMAT"
+        );
+    }
+}
@@ -1,15 +1,22 @@
+//! Reading and adding packet metadata.
+//!
+//! The hash of a packet's metadata is always the hash of the exact bytes it was uploaded as, which
+//! are also the bytes stored on disk and served by `/metadata/<id>/text`. Metadata is never
+//! re-serialised before being hashed or stored: json has no canonical form, so another
+//! implementation serialising the same metadata could order its keys or lay out its whitespace
+//! differently, and a hash of our serialisation would not match the one it computed. Parsed
+//! metadata is only ever used to read fields from.
+use crate::config::Location;
 use crate::location::read_locations;
-use crate::utils::is_packet_str;
-use crate::{location, store};
+use crate::utils::{is_packet_str, Timestamp};
+use crate::{index, location, schema, store, zip};
 use cached::cached_result;
 use serde::{Deserialize, Serialize};
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::path::{Path, PathBuf};
-use std::str::FromStr;
 use std::time::SystemTime;
 use std::{fs, io};
 
-use super::config;
 use super::hash;
 use super::utils;
 
@@ -20,16 +27,20 @@ pub struct PackitPacket {
     pub parameters: Option<HashMap<String, serde_json::Value>>,
     pub time: PacketTime,
     pub custom: Option<serde_json::Value>,
+    /// Whether the packet is unpacked in this repository, so that its files can be downloaded
+    /// from it, rather than only being known to another location.
+    pub is_local: bool,
 }
 
 impl PackitPacket {
-    fn from(packet: &Packet) -> PackitPacket {
+    fn from(packet: &Packet, is_local: bool) -> PackitPacket {
         PackitPacket {
             id: packet.id.to_string(),
             name: packet.name.to_string(),
             parameters: packet.parameters.clone(),
             time: packet.time.clone(),
             custom: packet.custom.clone(),
+            is_local,
         }
     }
 }
@@ -93,59 +104,118 @@ cached_result! {
     }
 }
 
-fn get_path(root: &Path, id: &str) -> PathBuf {
-    root.join(".outpack").join("metadata").join(id)
+/// Forget the parsed metadata kept by `read_all_metadata`, so that every file is read again. This
+/// is only needed if metadata has been changed in place, which outpack itself never does.
+pub fn clear_metadata_cache() {
+    use cached::Cached;
+    METADATA_CACHE.lock().unwrap().cache_clear();
+}
+
+/// The path of a packet's metadata. Ids are checked first, since anything other than a packet id,
+/// such as `../config.json`, could lead outside the metadata directory.
+fn get_path(root: &Path, id: &str) -> io::Result<PathBuf> {
+    let id = get_valid_id(&id.to_string())?;
+    Ok(root.join(".outpack").join("metadata").join(id))
 }
 
 fn get_metadata_file(root_path: &Path, id: &str) -> io::Result<PathBuf> {
-    let path = get_path(root_path, id);
-    if !path.exists() {
-        Err(io::Error::new(
+    // An invalid id can never be that of a packet in the repository.
+    match get_path(root_path, id) {
+        Ok(path) if path.exists() => Ok(path),
+        _ => Err(io::Error::new(
             io::ErrorKind::NotFound,
             format!("packet with id '{}' does not exist", id),
-        ))
-    } else {
-        Ok(path)
+        )),
     }
 }
 
 pub fn get_packit_metadata_from_date(
     root_path: &Path,
-    from: Option<f64>,
+    locations: &[Location],
+    from: Option<Timestamp>,
+    until: Option<Timestamp>,
 ) -> io::Result<Vec<PackitPacket>> {
-    let packets = get_metadata_from_date(root_path, from)?;
-    Ok(packets.iter().map(PackitPacket::from).collect())
+    let packets = get_metadata_from_date(root_path, locations, from, until)?;
+    let local: HashSet<String> = get_ids(root_path, true)?.into_iter().collect();
+    Ok(packets
+        .iter()
+        .map(|packet| PackitPacket::from(packet, local.contains(&packet.id)))
+        .collect())
 }
 
-pub fn get_metadata_from_date(root_path: &Path, from: Option<f64>) -> io::Result<Vec<Packet>> {
+/// Read the metadata of every packet in the repository, sorted by id.
+///
+/// This always reads from disk, and is used to build the index. Most callers should use
+/// `index::get_packet_index` instead. Up to `threads` threads are used to read and parse the
+/// files, and the result is the same whatever the number of threads.
+pub fn read_all_metadata(root_path: &Path, threads: usize) -> io::Result<Vec<Packet>> {
     let path = root_path.join(".outpack").join("metadata");
 
-    let packets = fs::read_dir(path)?
+    let paths: Vec<PathBuf> = fs::read_dir(path)?
         .filter_map(|e| e.ok())
-        .filter(|e| utils::is_packet(&e.file_name()));
-
-    let mut packets = match from {
-        None => packets
-            .map(|entry| read_metadata(entry.path()))
-            .collect::<io::Result<Vec<Packet>>>()?,
-        Some(time) => {
-            let location_meta = read_locations(root_path)?;
-            packets
-                .filter(|entry| {
-                    location_meta
-                        .iter()
-                        .find(|&e| e.packet == entry.file_name().into_string().unwrap())
-                        .map_or(false, |e| e.time > time)
-                })
-                .map(|entry| read_metadata(entry.path()))
-                .collect::<io::Result<Vec<Packet>>>()?
-        }
+        .filter(|e| utils::is_packet(&e.file_name()))
+        .map(|entry| entry.path())
+        .collect();
+
+    let read_all = |paths: &[PathBuf]| -> io::Result<Vec<Packet>> {
+        paths.iter().cloned().map(read_metadata).collect()
+    };
+
+    let threads = threads.clamp(1, paths.len().max(1));
+    let mut packets = if threads == 1 {
+        read_all(&paths)?
+    } else {
+        let chunk_size = (paths.len() + threads - 1) / threads;
+        let chunks = std::thread::scope(|scope| {
+            let handles: Vec<_> = paths
+                .chunks(chunk_size)
+                .map(|chunk| scope.spawn(move || read_all(chunk)))
+                .collect();
+            handles
+                .into_iter()
+                .map(|handle| handle.join().unwrap())
+                .collect::<io::Result<Vec<Vec<Packet>>>>()
+        })?;
+        chunks.into_iter().flatten().collect()
     };
 
     packets.sort_by(|a, b| a.id.cmp(&b.id));
     Ok(packets)
 }
 
+/// Get the packets which were first known to the repository after `from`, or every packet if no
+/// time is given.
+///
+/// If `until` is given, only packets first known before it are included, and `from` becomes
+/// inclusive: the packets are those in the half-open window from `from` up to but excluding
+/// `until`, so consecutive windows never share or skip a packet. A packet's time is compared with
+/// the bounds exactly.
+pub fn get_metadata_from_date(
+    root_path: &Path,
+    locations: &[Location],
+    from: Option<Timestamp>,
+    until: Option<Timestamp>,
+) -> io::Result<Vec<Packet>> {
+    let packets = index::get_packet_index(root_path)?.packets.clone();
+    if from.is_none() && until.is_none() {
+        return Ok(packets);
+    }
+
+    let location_meta = read_locations(root_path, locations)?;
+    Ok(packets
+        .into_iter()
+        .filter(|packet| {
+            location_meta
+                .iter()
+                .find(|&e| e.packet == packet.id)
+                .is_some_and(|e| match until {
+                    Some(until) => from.map_or(true, |from| e.time >= from) && e.time < until,
+                    None => from.map_or(true, |from| e.time > from),
+                })
+        })
+        .collect())
+}
+
 pub fn get_metadata_by_id(root_path: &Path, id: &str) -> io::Result<serde_json::Value> {
     let path = get_metadata_file(root_path, id)?;
     let file = fs::File::open(path)?;
@@ -153,23 +223,160 @@ pub fn get_metadata_by_id(root_path: &Path, id: &str) -> io::Result<serde_json::
     Ok(packet)
 }
 
+/// Get the parameters of a packet. A packet which was run without parameters has none, rather
+/// than it being an error.
+pub fn get_packet_parameters(
+    root_path: &Path,
+    id: &str,
+) -> io::Result<HashMap<String, serde_json::Value>> {
+    let path = get_metadata_file(root_path, id)?;
+    let file = fs::File::open(path)?;
+    let packet: Packet = serde_json::from_reader(file)?;
+    Ok(packet.parameters.unwrap_or_default())
+}
+
+/// Get the ids of the packets a packet depends on, in the order they are listed in its metadata.
+///
+/// If `recursive` is true, the dependencies of those packets are included too, breadth first, so
+/// that each packet is listed after the one that uses it. Each packet is only listed once, even if
+/// it is used by several others. Dependencies which are not in the repository are listed, but
+/// cannot be followed any further.
+pub fn get_dependencies(root_path: &Path, id: &str, recursive: bool) -> io::Result<Vec<String>> {
+    let index = index::get_packet_index(root_path)?;
+    let packets: HashMap<&str, &Packet> =
+        index.packets.iter().map(|p| (p.id.as_str(), p)).collect();
+    let packet = packets.get(id).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("packet with id '{}' does not exist", id),
+        )
+    })?;
+
+    let mut seen = HashSet::from([id]);
+    let mut result: Vec<String> = Vec::new();
+    let mut queue = vec![*packet];
+    while !queue.is_empty() {
+        let mut next = Vec::new();
+        for packet in queue {
+            for dependency in &packet.depends {
+                // Anything already seen has been listed, which also guards against cycles.
+                if !seen.insert(&dependency.packet) {
+                    continue;
+                }
+                result.push(dependency.packet.clone());
+                if let Some(p) = packets.get(dependency.packet.as_str()) {
+                    next.push(*p);
+                }
+            }
+        }
+        if !recursive {
+            break;
+        }
+        queue = next;
+    }
+    Ok(result)
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PacketSize {
+    /// The total size of the packet's files in bytes, as recorded in its metadata.
+    pub size: u64,
+
+    /// The number of files in the packet.
+    pub files: usize,
+
+    /// The files which are not in the file store, and so cannot be downloaded. Their sizes are
+    /// still included in `size`.
+    pub missing: Vec<PacketFile>,
+}
+
+/// Get the total size of a packet's files. If the repository has a file store, files missing
+/// from it are listed too.
+pub fn get_packet_size(root_path: &Path, id: &str, use_file_store: bool) -> io::Result<PacketSize> {
+    let path = get_metadata_file(root_path, id)?;
+    let file = fs::File::open(path)?;
+    let packet: Packet = serde_json::from_reader(file)?;
+
+    let mut missing = Vec::new();
+    if use_file_store {
+        for file in &packet.files {
+            if !store::file_exists(root_path, &file.hash)? {
+                missing.push(file.clone());
+            }
+        }
+    }
+    Ok(PacketSize {
+        size: packet.files.iter().map(|f| f.size as u64).sum(),
+        files: packet.files.len(),
+        missing,
+    })
+}
+
+/// The files making up a packet, to be downloaded together.
+pub struct PacketContents {
+    /// The path of the packet's metadata.
+    pub metadata: PathBuf,
+
+    /// The path within the packet of each of its files, and where it is in the store.
+    pub files: Vec<(String, PathBuf)>,
+}
+
+/// Find the metadata and every file of a packet. This fails if any of the files is missing from
+/// the store, or if there is more to download than fits in a zip archive.
+pub fn get_packet_contents(root_path: &Path, id: &str) -> io::Result<PacketContents> {
+    let metadata = get_metadata_file(root_path, id)?;
+    let packet: Packet = serde_json::from_reader(fs::File::open(&metadata)?)?;
+
+    let mut size = fs::metadata(&metadata)?.len();
+    let mut files = Vec::new();
+    for file in packet.files {
+        let path = store::file_path(root_path, &file.hash)?;
+        if fs::metadata(&path).is_err() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!(
+                    "File '{}' of packet '{}' is missing from the store",
+                    file.path, id
+                ),
+            ));
+        }
+        size += file.size as u64;
+        files.push((file.path, path));
+    }
+    if size > zip::MAX_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Packet '{}' is too large to download as a zip archive", id),
+        ));
+    }
+    Ok(PacketContents { metadata, files })
+}
+
 pub fn get_metadata_text(root_path: &Path, id: &str) -> io::Result<String> {
     let path = get_metadata_file(root_path, id)?;
     fs::read_to_string(path)
 }
 
 fn get_sorted_id_string(mut ids: Vec<String>) -> String {
+    // Strings are compared byte-wise, which makes the order independent of the locale and of the
+    // order in which the filesystem happens to return the packets.
     ids.sort();
     ids.join("")
 }
 
-pub fn get_ids_digest(root_path: &Path, alg_name: Option<String>) -> io::Result<String> {
-    let hash_algorithm = match alg_name {
-        None => config::read_config(root_path)?.core.hash_algorithm,
-        Some(name) => hash::HashAlgorithm::from_str(&name).map_err(hash::hash_error_to_io_error)?,
-    };
-
-    let ids = get_ids(root_path, false)?;
+/// Compute a digest of the set of packets known to the repository.
+///
+/// Two repositories with the same packets always have the same digest, allowing them to be
+/// compared cheaply. The digest is computed by sorting the packet ids in ascending byte-wise
+/// order, concatenating them without any separator and hashing the resulting ASCII string with
+/// the given algorithm. The result is formatted as `<algorithm>:<lowercase hex digest>`, as for
+/// any other outpack hash.
+pub fn get_ids_digest(root_path: &Path, hash_algorithm: hash::HashAlgorithm) -> io::Result<String> {
+    let ids = index::get_packet_index(root_path)?
+        .packets
+        .iter()
+        .map(|packet| packet.id.clone())
+        .collect();
     let id_string = get_sorted_id_string(ids);
     Ok(hash::hash_data(id_string.as_bytes(), hash_algorithm).to_string())
 }
@@ -209,6 +416,209 @@ pub fn get_missing_ids(root: &Path, wanted: &[String], unpacked: bool) -> io::Re
     Ok(wanted.difference(&known).cloned().collect::<Vec<String>>())
 }
 
+/// How the packets of this repository compare with those of another.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct PacketDiff {
+    pub only_local: Vec<String>,
+    pub only_remote: Vec<String>,
+    pub common: Vec<String>,
+}
+
+/// Compare the packets of this repository with the ids of another's. Each list in the result is
+/// sorted. As for `get_missing_ids`, `unpacked` chooses whether only unpacked packets count as
+/// being in this repository.
+pub fn get_packet_diff(root: &Path, remote: &[String], unpacked: bool) -> io::Result<PacketDiff> {
+    let mut only_remote = get_missing_ids(root, remote, unpacked)?;
+    only_remote.sort();
+    let remote: HashSet<&str> = remote.iter().map(|id| id.trim()).collect();
+
+    let mut local = get_ids(root, unpacked)?;
+    local.sort();
+    let (common, only_local) = local
+        .into_iter()
+        .partition(|id| remote.contains(id.as_str()));
+    Ok(PacketDiff {
+        only_local,
+        only_remote,
+        common,
+    })
+}
+
+/// Look up a dotted path, such as `parameters.year`, in a packet's metadata. Each part of the path
+/// names a field of an object, or is the index of an element of an array.
+fn lookup_path<'a>(value: &'a serde_json::Value, path: &str) -> Option<&'a serde_json::Value> {
+    path.split('.').try_fold(value, |value, part| match value {
+        serde_json::Value::Object(map) => map.get(part),
+        serde_json::Value::Array(items) => items.get(part.parse::<usize>().ok()?),
+        _ => None,
+    })
+}
+
+/// Pick the given fields out of a packet's metadata, keyed by their paths. Fields which the packet
+/// does not have are null.
+pub fn project_metadata(
+    metadata: &serde_json::Value,
+    fields: &[String],
+) -> serde_json::Map<String, serde_json::Value> {
+    fields
+        .iter()
+        .map(|field| {
+            let value = lookup_path(metadata, field).cloned();
+            (field.clone(), value.unwrap_or(serde_json::Value::Null))
+        })
+        .collect()
+}
+
+/// Pick the given fields out of the metadata of each of the packets with the given ids, in order.
+pub fn search_metadata(
+    root: &Path,
+    ids: &[String],
+    fields: &[String],
+) -> io::Result<Vec<serde_json::Map<String, serde_json::Value>>> {
+    ids.iter()
+        .map(|id| Ok(project_metadata(&get_metadata_by_id(root, id)?, fields)))
+        .collect()
+}
+
+/// A summary of the packets with one name.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct NameSummary {
+    pub name: String,
+    /// The number of packets with the name.
+    pub count: usize,
+    /// The id of the most recent of them.
+    pub latest_id: String,
+    /// The time at which the most recent of them was started.
+    pub latest_time: f64,
+}
+
+/// How to order the summaries of packet names.
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SummaryOrder {
+    /// Alphabetically by name.
+    #[default]
+    Name,
+    /// The names with the most packets first.
+    Count,
+    /// The names with the most recent packets first.
+    Latest,
+}
+
+/// Summarise the packets of each name in the index. The most recent packet of each name is the
+/// one `latest(name == "...")` would find: the last to be started, with ties broken by id. Names
+/// which are equal by the chosen order are ordered by name.
+pub fn get_packet_summary(root: &Path, order: SummaryOrder) -> io::Result<Vec<NameSummary>> {
+    let index = index::get_packet_index(root)?;
+    let mut latest: BTreeMap<&str, (usize, &Packet)> = BTreeMap::new();
+    for packet in &index.packets {
+        latest
+            .entry(packet.name.as_str())
+            .and_modify(|(count, latest)| {
+                *count += 1;
+                let newer = packet.time.start.total_cmp(&latest.time.start);
+                if newer.then_with(|| packet.id.cmp(&latest.id)).is_gt() {
+                    *latest = packet;
+                }
+            })
+            .or_insert((1, packet));
+    }
+
+    let mut summary: Vec<NameSummary> = latest
+        .into_iter()
+        .map(|(name, (count, packet))| NameSummary {
+            name: name.to_owned(),
+            count,
+            latest_id: packet.id.clone(),
+            latest_time: packet.time.start,
+        })
+        .collect();
+    // The summaries start in order of name, and the sorts are stable.
+    match order {
+        SummaryOrder::Name => {}
+        SummaryOrder::Count => summary.sort_by_key(|s| std::cmp::Reverse(s.count)),
+        SummaryOrder::Latest => summary.sort_by(|a, b| b.latest_time.total_cmp(&a.latest_time)),
+    }
+    Ok(summary)
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct SyncPlan {
+    /// The wanted packets which are missing, as from `get_missing_ids`.
+    pub packets: Vec<String>,
+    /// The files of those packets which are missing from the store.
+    pub files: Vec<String>,
+    /// The missing packets whose metadata is not known at all, so whose files cannot be listed.
+    pub unknown: Vec<String>,
+}
+
+/// Work out everything needed to make the wanted packets present: which of them are missing, and
+/// which of their files would need to be fetched. With `incomplete`, the files missing from
+/// packets which are already present are listed as well. Packets and files are sorted.
+pub fn get_sync_plan(
+    root: &Path,
+    wanted: &[String],
+    unpacked: bool,
+    incomplete: bool,
+) -> io::Result<SyncPlan> {
+    let mut packets = get_missing_ids(root, wanted, unpacked)?;
+    packets.sort();
+
+    let index = index::get_packet_index(root)?;
+    let known: HashMap<&str, &Packet> = index.packets.iter().map(|p| (p.id.as_str(), p)).collect();
+    let mut unknown = Vec::new();
+    let mut hashes = Vec::new();
+    let mut add_files = |packet: &Packet| {
+        hashes.extend(packet.files.iter().map(|f| f.hash.clone()));
+    };
+    for id in &packets {
+        match known.get(id.as_str()) {
+            Some(packet) => add_files(packet),
+            None => unknown.push(id.clone()),
+        }
+    }
+    if incomplete {
+        let missing: HashSet<&str> = packets.iter().map(String::as_str).collect();
+        wanted
+            .iter()
+            .filter(|id| !missing.contains(id.as_str()))
+            .filter_map(|id| known.get(id.as_str()))
+            .for_each(|packet| add_files(packet));
+    }
+
+    Ok(SyncPlan {
+        files: store::get_missing_files(root, &hashes)?,
+        packets,
+        unknown,
+    })
+}
+
+/// Expand a list of packet ids to include all of their transitive dependencies.
+///
+/// Dependencies are found from the metadata known to this repository. Packets with unknown
+/// metadata are included in the result, but their dependencies cannot be.
+pub fn get_dependency_closure(root: &Path, ids: &[String]) -> io::Result<Vec<String>> {
+    let index = index::get_packet_index(root)?;
+    let packets: HashMap<&str, &Packet> =
+        index.packets.iter().map(|p| (p.id.as_str(), p)).collect();
+
+    let mut result: Vec<String> = Vec::new();
+    let mut seen: HashSet<String> = HashSet::new();
+    let mut pending: Vec<String> = ids.to_vec();
+    while let Some(id) = pending.pop() {
+        // Tracking the packets seen so far guards against cycles, as well as avoiding
+        // visiting shared dependencies more than once.
+        if !seen.insert(id.clone()) {
+            continue;
+        }
+        if let Some(packet) = packets.get(id.as_str()) {
+            pending.extend(packet.depends.iter().map(|d| d.packet.clone()));
+        }
+        result.push(id);
+    }
+    Ok(result)
+}
+
 fn check_missing_files(root: &Path, packet: &Packet) -> Result<(), io::Error> {
     let files = packet
         .files
@@ -251,12 +661,14 @@ fn check_missing_dependencies(root: &Path, packet: &Packet) -> Result<(), io::Er
     Ok(())
 }
 
+/// Store metadata as the bytes it was given as, which are what its hash is checked against.
 fn add_parsed_metadata(root: &Path, data: &str, packet: &Packet, hash: &str) -> io::Result<()> {
     hash::validate_hash_data(data.as_bytes(), hash).map_err(hash::hash_error_to_io_error)?;
-    let path = get_path(root, &packet.id);
+    let path = get_path(root, &packet.id)?;
     if !path.exists() {
         fs::File::create(&path)?;
         fs::write(path, data)?;
+        index::invalidate_index(root);
     }
     Ok(())
 }
@@ -268,20 +680,64 @@ pub fn add_metadata(root: &Path, data: &str, hash: &hash::Hash) -> io::Result<()
     add_parsed_metadata(root, data, &packet, &hash.to_string())
 }
 
+/// Parse metadata, after checking it conforms to the outpack metadata schema.
+fn parse_validated_metadata(data: &str) -> io::Result<Packet> {
+    let value: serde_json::Value = serde_json::from_str(data).map_err(|e| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Metadata is not valid json: {}", e),
+        )
+    })?;
+    schema::validate_metadata(&value)?;
+    Ok(serde_json::from_value(value)?)
+}
+
+/// Add metadata to the repository, after checking it conforms to the outpack metadata schema.
+///
+/// As with `add_packet`, the packet's files and dependencies must already be present in the
+/// repository, and the packet is marked as available locally. Returns the hash of the metadata,
+/// with the given algorithm.
+pub fn add_validated_metadata(
+    root: &Path,
+    data: &str,
+    hash_algorithm: hash::HashAlgorithm,
+) -> io::Result<hash::Hash> {
+    let packet = parse_validated_metadata(data)?;
+    let hash = hash::hash_data(data.as_bytes(), hash_algorithm);
+    add_complete_packet(root, data, &packet, &hash.to_string())?;
+    Ok(hash)
+}
+
+/// Get the dependencies of some metadata which are not unpacked in the repository, without adding
+/// the metadata. The result is in the order the dependencies are listed in the metadata.
+pub fn get_missing_dependencies(root: &Path, data: &str) -> io::Result<Vec<String>> {
+    let packet = parse_validated_metadata(data)?;
+    let depends: Vec<String> = packet.depends.into_iter().map(|d| d.packet).collect();
+    let mut missing: HashSet<String> = get_missing_ids(root, &depends, true)?.into_iter().collect();
+    Ok(depends
+        .into_iter()
+        .filter(|id| missing.remove(id))
+        .collect())
+}
+
 /// Add a packet to the repository.
 ///
 /// The packet's files and dependencies must already be present in the repository.
 pub fn add_packet(root: &Path, data: &str, hash: &hash::Hash) -> io::Result<()> {
     let packet: Packet = serde_json::from_str(data)?;
-    let hash_str = hash.to_string();
+    add_complete_packet(root, data, &packet, &hash.to_string())
+}
 
-    check_missing_files(root, &packet)?;
-    check_missing_dependencies(root, &packet)?;
+/// Store a packet's metadata and mark it as available locally, after checking that its files and
+/// dependencies are present, so that the repository's tree stays complete.
+fn add_complete_packet(root: &Path, data: &str, packet: &Packet, hash: &str) -> io::Result<()> {
+    check_missing_files(root, packet)?;
+    check_missing_dependencies(root, packet)?;
 
-    add_parsed_metadata(root, data, &packet, &hash.to_string())?;
+    add_parsed_metadata(root, data, packet, hash)?;
 
     let time = SystemTime::now();
-    location::mark_packet_known(&packet.id, "local", &hash_str, time, root)?;
+    location::mark_packet_known(&packet.id, "local", hash, time, root)?;
     Ok(())
 }
 
@@ -289,35 +745,311 @@ pub fn add_packet(root: &Path, data: &str, hash: &hash::Hash) -> io::Result<()>
 mod tests {
     use super::*;
     use crate::store::file_exists;
-    use crate::test_utils::tests::{get_temp_outpack_root, start_packet};
-    use crate::utils::time_as_num;
+    use crate::test_utils::tests::{
+        get_empty_outpack_root, get_temp_outpack_root, start_packet, vector_equals, PacketBuilder,
+    };
     use md5::Md5;
     use serde_json::Value;
     use sha2::{Digest, Sha256};
+    use std::time::{Duration, UNIX_EPOCH};
+
+    fn example_locations() -> Vec<Location> {
+        crate::config::read_config(Path::new("tests/example"))
+            .unwrap()
+            .location
+    }
 
     #[test]
     fn can_get_packets_from_date() {
-        let all_packets = get_metadata_from_date(Path::new("tests/example"), None).unwrap();
+        let all_packets =
+            get_metadata_from_date(Path::new("tests/example"), &example_locations(), None, None)
+                .unwrap();
         assert_eq!(all_packets.len(), 4);
-        let recent_packets =
-            get_metadata_from_date(Path::new("tests/example"), Some(1662480556.)).unwrap();
+        let recent_packets = get_metadata_from_date(
+            Path::new("tests/example"),
+            &example_locations(),
+            Some(Timestamp::from_seconds(1662480556.)),
+            None,
+        )
+        .unwrap();
         assert_eq!(recent_packets.len(), 1);
         assert_eq!(
             recent_packets.first().unwrap().id,
             "20170818-164847-7574883b"
         );
 
-        let recent_packets =
-            get_metadata_from_date(Path::new("tests/example"), Some(1662480555.)).unwrap();
+        let recent_packets = get_metadata_from_date(
+            Path::new("tests/example"),
+            &example_locations(),
+            Some(Timestamp::from_seconds(1662480555.)),
+            None,
+        )
+        .unwrap();
         assert_eq!(recent_packets.len(), 4);
     }
 
+    #[test]
+    fn known_since_distinguishes_times_within_a_millisecond() {
+        let root = get_empty_outpack_root();
+        let start = UNIX_EPOCH + Duration::from_micros(1662480555662300);
+        let mut ids = Vec::new();
+        for (id, offset) in [
+            ("20220906-160915-00000001", 0),
+            ("20220906-160915-00000002", 400),
+        ] {
+            let (id, json, hash) = start_packet("data").id(id).finish();
+            add_metadata(&root, &json, &hash).unwrap();
+            let time = start + Duration::from_micros(offset);
+            location::mark_packet_known(&id, "local", &hash.to_string(), time, &root).unwrap();
+            ids.push(id);
+        }
+
+        let since = |seconds: f64| -> Vec<String> {
+            let from = Some(Timestamp::from_seconds(seconds));
+            get_metadata_from_date(&root, &[], from, None)
+                .unwrap()
+                .into_iter()
+                .map(|packet| packet.id)
+                .collect()
+        };
+        assert_eq!(since(1662480555.6622), ids);
+        assert_eq!(since(1662480555.6623), ids[1..]);
+        assert_eq!(since(1662480555.66235), ids[1..]);
+        assert!(since(1662480555.6627).is_empty());
+    }
+
+    #[test]
+    fn consecutive_windows_partition_packets() {
+        let root = get_empty_outpack_root();
+        let start = UNIX_EPOCH + Duration::from_secs(1662480555);
+        let mut ids = Vec::new();
+        for (id, offset) in [
+            ("20220906-160915-00000001", 0),
+            ("20220906-160915-00000002", 1),
+            ("20220906-160915-00000003", 2),
+        ] {
+            let (id, json, hash) = start_packet("data").id(id).finish();
+            add_metadata(&root, &json, &hash).unwrap();
+            let time = start + Duration::from_secs(offset);
+            location::mark_packet_known(&id, "local", &hash.to_string(), time, &root).unwrap();
+            ids.push(id);
+        }
+
+        let between = |from: Option<f64>, until: Option<f64>| -> Vec<String> {
+            let from = from.map(Timestamp::from_seconds);
+            let until = until.map(Timestamp::from_seconds);
+            get_metadata_from_date(&root, &[], from, until)
+                .unwrap()
+                .into_iter()
+                .map(|packet| packet.id)
+                .collect()
+        };
+        // Each packet's time is the lower bound of exactly one of these windows.
+        assert_eq!(between(None, Some(1662480556.)), ids[..1]);
+        assert_eq!(between(Some(1662480556.), Some(1662480557.)), ids[1..2]);
+        assert_eq!(between(Some(1662480557.), Some(1662480558.)), ids[2..]);
+        assert_eq!(between(Some(1662480555.), Some(1662480557.)), ids[..2]);
+        assert!(between(Some(1662480556.), Some(1662480556.)).is_empty());
+        assert_eq!(between(None, None), ids);
+
+        // Without an upper bound, the lower one is exclusive as it always was.
+        assert!(between(Some(1662480557.), None).is_empty());
+        assert_eq!(between(Some(1662480556.), None), ids[2..]);
+    }
+
+    #[test]
+    fn can_project_metadata_fields() {
+        let metadata = serde_json::json!({
+            "name": "data",
+            "parameters": { "year": 2024 },
+            "files": [{ "path": "a.csv" }]
+        });
+        let fields = [
+            "name",
+            "parameters.year",
+            "files.0.path",
+            "files.1.path",
+            "parameters.year.month",
+            "missing",
+        ]
+        .map(String::from);
+        assert_eq!(
+            serde_json::Value::Object(project_metadata(&metadata, &fields)),
+            serde_json::json!({
+                "name": "data",
+                "parameters.year": 2024,
+                "files.0.path": "a.csv",
+                "files.1.path": null,
+                "parameters.year.month": null,
+                "missing": null
+            })
+        );
+    }
+
+    #[test]
+    fn packit_metadata_says_which_packets_are_local() {
+        let root = Path::new("tests/example");
+        let packets =
+            get_packit_metadata_from_date(root, &example_locations(), None, None).unwrap();
+        let local: Vec<&str> = packets
+            .iter()
+            .filter(|p| p.is_local)
+            .map(|p| p.id.as_str())
+            .collect();
+        assert_eq!(packets.len(), 4);
+        assert_eq!(local, vec!["20170818-164847-7574883b"]);
+    }
+
+    #[test]
+    fn can_diff_packets() {
+        let root = Path::new("tests/example");
+        let remote = vec![
+            String::from("20170818-164847-7574883b"),
+            String::from(" 20240101-120000-00000001"),
+        ];
+        let diff = get_packet_diff(root, &remote, false).unwrap();
+        assert_eq!(
+            diff,
+            PacketDiff {
+                only_local: vec![
+                    String::from("20170818-164830-33e0ab01"),
+                    String::from("20180220-095832-16a4bbed"),
+                    String::from("20180818-164043-7cdcde4b"),
+                ],
+                only_remote: vec![String::from("20240101-120000-00000001")],
+                common: vec![String::from("20170818-164847-7574883b")],
+            }
+        );
+
+        // Only one packet is unpacked.
+        let diff = get_packet_diff(root, &remote, true).unwrap();
+        assert!(diff.only_local.is_empty());
+        assert_eq!(diff.common, vec![String::from("20170818-164847-7574883b")]);
+    }
+
+    #[test]
+    fn can_summarise_packets_by_name() {
+        let root = Path::new("tests/example");
+        let summary = get_packet_summary(root, SummaryOrder::Name).unwrap();
+        let queries = NameSummary {
+            name: String::from("modup-201707-queries1"),
+            count: 3,
+            latest_id: String::from("20180818-164043-7cdcde4b"),
+            latest_time: 1503074938.2232,
+        };
+        let params = NameSummary {
+            name: String::from("modup-201707-params1"),
+            count: 1,
+            latest_id: String::from("20180220-095832-16a4bbed"),
+            latest_time: 1519120570.2232,
+        };
+        assert_eq!(summary, vec![params.clone(), queries.clone()]);
+
+        let summary = get_packet_summary(root, SummaryOrder::Count).unwrap();
+        assert_eq!(summary, vec![queries.clone(), params.clone()]);
+        let summary = get_packet_summary(root, SummaryOrder::Latest).unwrap();
+        assert_eq!(summary, vec![params, queries]);
+    }
+
     #[test]
     fn can_get_packet() {
         let _packet =
             get_metadata_by_id(Path::new("tests/example"), "20180818-164043-7cdcde4b").unwrap();
     }
 
+    #[test]
+    fn can_get_packet_parameters() {
+        let root = Path::new("tests/example");
+        let parameters = get_packet_parameters(root, "20180220-095832-16a4bbed").unwrap();
+        assert_eq!(parameters.len(), 4);
+        assert_eq!(parameters["disease"], "YF");
+        assert_eq!(parameters["tolerance"], 0.001);
+
+        let parameters = get_packet_parameters(root, "20170818-164847-7574883b").unwrap();
+        assert!(parameters.is_empty());
+
+        let res = get_packet_parameters(root, "20170818-164847-12345678");
+        assert_eq!(res.unwrap_err().kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn can_get_packet_size() {
+        let size =
+            get_packet_size(Path::new("tests/example"), "20180220-095832-16a4bbed", true).unwrap();
+        assert_eq!(size.size, 8159918);
+        assert_eq!(size.files, 21);
+        assert_eq!(size.missing.len(), 21);
+
+        let root = get_empty_outpack_root();
+        let contents = "contents";
+        let present = hash::hash_data(contents.as_bytes(), hash::HashAlgorithm::Sha256);
+        let path = store::file_path(&root, &present.to_string()).unwrap();
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, contents).unwrap();
+        let absent = hash::hash_data(b"absent", hash::HashAlgorithm::Sha256);
+
+        let (id, json, hash) = start_packet("data")
+            .add_file("present.txt", present.to_string(), contents.len())
+            .add_file("absent.txt", absent.to_string(), 100)
+            .finish();
+        add_metadata(&root, &json, &hash).unwrap();
+
+        let size = get_packet_size(&root, &id, true).unwrap();
+        assert_eq!(size.size, 108);
+        assert_eq!(size.files, 2);
+        assert_eq!(size.missing.len(), 1);
+        assert_eq!(size.missing[0].path, "absent.txt");
+        assert_eq!(size.missing[0].hash, absent.to_string());
+
+        let res = get_packet_size(&root, "20170818-164847-12345678", true);
+        assert_eq!(res.unwrap_err().kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn can_get_dependencies() {
+        let root = get_empty_outpack_root();
+        let add = |builder: &mut PacketBuilder| {
+            let (id, json, hash) = builder.finish();
+            add_packet(&root, &json, &hash).unwrap();
+            id
+        };
+        let a = add(start_packet("a").id("20240101-000000-00000001"));
+        let b = add(start_packet("b")
+            .id("20240101-000000-00000002")
+            .add_dependency(&a, vec![]));
+        let c = add(start_packet("c")
+            .id("20240101-000000-00000003")
+            .add_dependency(&b, vec![]));
+        let d = add(start_packet("d")
+            .id("20240101-000000-00000004")
+            .add_dependency(&c, vec![])
+            .add_dependency(&a, vec![]));
+
+        assert_eq!(
+            get_dependencies(&root, &d, false).unwrap(),
+            vec![c.clone(), a.clone()]
+        );
+        assert_eq!(
+            get_dependencies(&root, &d, true).unwrap(),
+            vec![c.clone(), a.clone(), b.clone()]
+        );
+        assert_eq!(
+            get_dependencies(&root, &c, true).unwrap(),
+            vec![b, a.clone()]
+        );
+        assert!(get_dependencies(&root, &a, true).unwrap().is_empty());
+
+        let res = get_dependencies(&root, "20240101-000000-00000005", false);
+        assert_eq!(res.unwrap_err().kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn dependencies_are_listed_even_if_missing() {
+        let root = Path::new("tests/example");
+        let deps = get_dependencies(root, "20180818-164043-7cdcde4b", true).unwrap();
+        assert_eq!(deps, vec!["20170818-164043-7cdcde4b"]);
+    }
+
     #[test]
     fn ids_are_sorted() {
         let ids = vec![
@@ -335,8 +1067,9 @@ mod tests {
     }
 
     #[test]
-    fn can_get_ids_digest_with_config_alg() {
-        let digest = get_ids_digest(Path::new("tests/example"), None).unwrap();
+    fn can_get_ids_digest() {
+        let digest =
+            get_ids_digest(Path::new("tests/example"), hash::HashAlgorithm::Sha256).unwrap();
         let dat = "20170818-164830-33e0ab0120170818-164847-7574883b20180220-095832-16a4bbed\
         20180818-164043-7cdcde4b";
         let expected = format!("sha256:{:x}", Sha256::digest(dat));
@@ -345,13 +1078,85 @@ mod tests {
 
     #[test]
     fn can_get_ids_digest_with_given_alg() {
-        let digest = get_ids_digest(Path::new("tests/example"), Some(String::from("md5"))).unwrap();
+        let digest = get_ids_digest(Path::new("tests/example"), hash::HashAlgorithm::Md5).unwrap();
         let dat = "20170818-164830-33e0ab0120170818-164847-7574883b20180220-095832-16a4bbed\
         20180818-164043-7cdcde4b";
         let expected = format!("md5:{:x}", Md5::digest(dat));
         assert_eq!(digest, expected);
     }
 
+    #[test]
+    fn can_get_dependency_closure() {
+        let root = get_empty_outpack_root();
+        let (c, c_json, c_hash) = start_packet("c").finish();
+        let (b, b_json, b_hash) = start_packet("b").add_dependency(&c, vec![]).finish();
+        let (a, a_json, a_hash) = start_packet("a").add_dependency(&b, vec![]).finish();
+        add_metadata(&root, &c_json, &c_hash).unwrap();
+        add_metadata(&root, &b_json, &b_hash).unwrap();
+        add_metadata(&root, &a_json, &a_hash).unwrap();
+
+        let closure = get_dependency_closure(&root, std::slice::from_ref(&a)).unwrap();
+        assert!(vector_equals(&closure, &[a.clone(), b.clone(), c.clone()]));
+
+        let closure = get_dependency_closure(&root, std::slice::from_ref(&b)).unwrap();
+        assert!(vector_equals(&closure, &[b.clone(), c.clone()]));
+
+        // None of these have been unpacked, so the whole closure is missing.
+        let missing = get_missing_ids(&root, std::slice::from_ref(&a), true).unwrap();
+        assert_eq!(missing, vec![a.clone()]);
+        let closure = get_dependency_closure(&root, std::slice::from_ref(&a)).unwrap();
+        let missing = get_missing_ids(&root, &closure, true).unwrap();
+        assert!(vector_equals(&missing, &[a, b, c]));
+    }
+
+    #[test]
+    fn dependency_closure_handles_cycles() {
+        let root = get_empty_outpack_root();
+        let a = String::from("20240101-000000-0000000a");
+        let b = String::from("20240101-000000-0000000b");
+        let (_, a_json, a_hash) = start_packet("a").id(&a).add_dependency(&b, vec![]).finish();
+        let (_, b_json, b_hash) = start_packet("b").id(&b).add_dependency(&a, vec![]).finish();
+        add_metadata(&root, &a_json, &a_hash).unwrap();
+        add_metadata(&root, &b_json, &b_hash).unwrap();
+
+        let closure = get_dependency_closure(&root, std::slice::from_ref(&a)).unwrap();
+        assert!(vector_equals(&closure, &[a, b]));
+    }
+
+    #[test]
+    fn dependency_closure_includes_unknown_packets() {
+        let closure = get_dependency_closure(
+            Path::new("tests/example"),
+            &[String::from("20170818-164830-33e0ab01")],
+        )
+        .unwrap();
+        assert!(vector_equals(
+            &closure,
+            &[
+                String::from("20170818-164830-33e0ab01"),
+                String::from("20170818-164043-7cdcde4b")
+            ]
+        ));
+    }
+
+    #[test]
+    fn ids_digest_is_independent_of_insertion_order() {
+        let root = get_empty_outpack_root();
+        for id in [
+            "20240102-000000-00000002",
+            "20230101-000000-00000001",
+            "20240101-000000-00000003",
+        ] {
+            let (_, json, hash) = start_packet("data").id(id).finish();
+            add_packet(&root, &json, &hash).unwrap();
+        }
+
+        let digest = get_ids_digest(&root, hash::HashAlgorithm::Sha256).unwrap();
+        let dat = "20230101-000000-0000000120240101-000000-0000000320240102-000000-00000002";
+        let expected = format!("sha256:{:x}", Sha256::digest(dat));
+        assert_eq!(digest, expected);
+    }
+
     #[test]
     fn can_get_ids() {
         let ids = get_ids(Path::new("tests/example"), false).unwrap();
@@ -517,8 +1322,7 @@ mod tests {
             .unwrap();
         assert_eq!(entry.packet, "20230427-150828-68772cee");
         assert_eq!(entry.hash, hash.to_string());
-        println!("time {} now {}", entry.time, time_as_num(now));
-        assert!(entry.time >= time_as_num(now));
+        assert!(entry.time >= Timestamp::from(now));
     }
 
     #[test]
@@ -0,0 +1,746 @@
+//! An OpenAPI description of the server's API, served at `/openapi.json`.
+//!
+//! The schemas of request and response bodies are the same bundled JSON schemas used elsewhere,
+//! included as components of the document. References between them are rewritten to point at
+//! those components.
+use lazy_static::lazy_static;
+use serde_json::{json, Map, Value};
+
+use crate::schema::{OUTPACK_SCHEMAS, SERVER_SCHEMAS};
+
+/// The outpack schemas included in the document, in addition to all of the server's schemas.
+/// The outpack versions of `hash.json` and `packet-id.json` are identical to the server's.
+const OUTPACK_COMPONENTS: &[&str] = &["metadata.json", "git.json"];
+
+fn component_name(file_name: &str) -> &str {
+    file_name.trim_end_matches(".json")
+}
+
+fn schema_ref(file_name: &str) -> Value {
+    json!({ "$ref": format!("#/components/schemas/{}", component_name(file_name)) })
+}
+
+/// Rewrite a bundled schema so that it can be used as an OpenAPI component.
+fn to_component(schema: &mut Value) {
+    match schema {
+        Value::Object(map) => {
+            map.remove("$schema");
+            if let Some(Value::String(reference)) = map.get("$ref") {
+                let reference = schema_ref(reference)["$ref"].clone();
+                map.insert(String::from("$ref"), reference);
+            }
+            map.values_mut().for_each(to_component);
+        }
+        Value::Array(values) => values.iter_mut().for_each(to_component),
+        _ => {}
+    }
+}
+
+fn components() -> Map<String, Value> {
+    let outpack = OUTPACK_SCHEMAS
+        .iter()
+        .filter(|(name, _)| OUTPACK_COMPONENTS.contains(name));
+    SERVER_SCHEMAS
+        .iter()
+        .chain(outpack)
+        .map(|(name, contents)| {
+            let mut schema: Value =
+                serde_json::from_str(contents).expect("Bundled schema is valid json");
+            to_component(&mut schema);
+            (component_name(name).to_owned(), schema)
+        })
+        .collect()
+}
+
+/// A successful response, whose `data` matches the given schema.
+fn success(data: Value) -> Value {
+    json!({
+        "description": "Success",
+        "content": {
+            "application/json": {
+                "schema": {
+                    "allOf": [
+                        schema_ref("response-success.json"),
+                        { "properties": { "data": data } }
+                    ]
+                }
+            }
+        }
+    })
+}
+
+/// Allow a successful list response to be returned as newline-delimited JSON, with one item of
+/// the given schema per line.
+fn with_ndjson(mut response: Value, item: Value) -> Value {
+    response["content"]["application/x-ndjson"] = json!({ "schema": item });
+    response
+}
+
+fn failure(description: &str) -> Value {
+    json!({
+        "description": description,
+        "content": {
+            "application/json": { "schema": schema_ref("response-failure.json") }
+        }
+    })
+}
+
+fn raw(description: &str, content_type: &str) -> Value {
+    json!({
+        "description": description,
+        "content": { content_type: { "schema": { "type": "string" } } }
+    })
+}
+
+/// Add the header giving the hash of a packet's metadata to a response.
+fn with_metadata_hash(mut response: Value) -> Value {
+    response["headers"] = json!({
+        "Outpack-Metadata-Hash": {
+            "description": "The hash of the packet's metadata, as reported by /metadata/list",
+            "schema": schema_ref("hash.json")
+        }
+    });
+    response
+}
+
+fn json_body(schema: Value) -> Value {
+    json!({
+        "required": true,
+        "content": { "application/json": { "schema": schema } }
+    })
+}
+
+fn raw_body(content_type: &str) -> Value {
+    json!({
+        "required": true,
+        "content": { content_type: { "schema": { "type": "string" } } }
+    })
+}
+
+fn path_param(name: &str, schema: Value) -> Value {
+    json!({ "name": name, "in": "path", "required": true, "schema": schema })
+}
+
+fn query_param(name: &str, schema: Value) -> Value {
+    json!({ "name": name, "in": "query", "required": false, "schema": schema })
+}
+
+/// Describe an operation which returns a successful response or a failure.
+fn operation(summary: &str, response: Value) -> Map<String, Value> {
+    let mut operation = Map::new();
+    operation.insert(String::from("summary"), summary.into());
+    operation.insert(
+        String::from("responses"),
+        json!({
+            "200": response,
+            "400": failure("Invalid request"),
+            "404": failure("Not found"),
+            "500": failure("Internal error")
+        }),
+    );
+    operation
+}
+
+/// Describe a HEAD request for a packet's metadata, which gives the same status and headers as
+/// the corresponding GET request but has no body.
+fn head_metadata(id: Value) -> Value {
+    json!({
+        "summary": "Check whether a packet's metadata exists, without downloading it",
+        "parameters": [id],
+        "responses": {
+            "200": with_metadata_hash(json!({ "description": "The metadata exists" })),
+            "404": { "description": "Not found" }
+        }
+    })
+}
+
+fn with(mut operation: Map<String, Value>, key: &str, value: Value) -> Map<String, Value> {
+    operation.insert(key.to_owned(), value);
+    operation
+}
+
+fn with_response(
+    mut operation: Map<String, Value>,
+    status: &str,
+    value: Value,
+) -> Map<String, Value> {
+    operation["responses"][status] = value;
+    operation
+}
+
+fn paths() -> Value {
+    let id = || path_param("id", schema_ref("packet-id.json"));
+    let hash = || path_param("hash", schema_ref("hash.json"));
+    let upload_id = || path_param("id", json!({ "type": "string" }));
+    let empty = || schema_ref("null-response.json");
+    let if_match = || json!({ "name": "If-Match", "in": "header", "required": false, "schema": { "type": "string" } });
+    let packet_name = || {
+        let mut param = query_param("name", json!({ "type": "string" }));
+        param["required"] = true.into();
+        param
+    };
+    let format = || {
+        query_param(
+            "format",
+            json!({ "type": "string", "enum": ["json", "ndjson"] }),
+        )
+    };
+
+    let metadata = json!({
+        "/": {
+            "get": operation("Get the version of the outpack schema", success(schema_ref("root.json")))
+        },
+        "/metadata/list": {
+            "get": with(
+                operation(
+                    "List the packets known to the repository",
+                    with_ndjson(success(schema_ref("locations.json")), schema_ref("location.json"))
+                ),
+                "parameters", json!([query_param("files", json!({ "type": "boolean" })), format()])
+            )
+        },
+        "/metadata": {
+            "post": with(
+                operation("Add metadata, validating it against the outpack schema", success(schema_ref("hash.json"))),
+                "requestBody", raw_body("text/plain")
+            )
+        },
+        "/metadata/validate": {
+            "post": with(
+                operation("List the dependencies of some metadata which are missing", success(schema_ref("ids.json"))),
+                "requestBody", raw_body("text/plain")
+            )
+        },
+        "/metadata/batch": {
+            "post": with(
+                operation("Get the metadata of several packets", success(schema_ref("metadata-batch.json"))),
+                "requestBody", json_body(json!({
+                    "type": "object",
+                    "properties": { "ids": schema_ref("ids.json") },
+                    "required": ["ids"]
+                }))
+            )
+        },
+        "/metadata/search": {
+            "post": with(
+                operation("Get some fields of the metadata of many packets", success(schema_ref("metadata-search.json"))),
+                "requestBody", json_body(json!({
+                    "type": "object",
+                    "properties": {
+                        "query": { "type": "string" },
+                        "fields": { "type": "array", "items": { "type": "string" }, "minItems": 1 }
+                    },
+                    "required": ["fields"]
+                }))
+            )
+        },
+        "/metadata/{id}/json": {
+            "get": with(
+                operation("Get the metadata of a packet", with_metadata_hash(success(schema_ref("metadata.json")))),
+                "parameters", json!([id()])
+            ),
+            "head": head_metadata(id())
+        },
+        "/metadata/{id}/text": {
+            "get": with(
+                with_response(
+                    operation("Get the metadata of a packet exactly as it is stored", with_metadata_hash(raw("The metadata", "application/json"))),
+                    "304", json!({ "description": "The metadata hash matches If-None-Match" })
+                ),
+                "parameters", json!([
+                    id(),
+                    { "name": "If-None-Match", "in": "header", "required": false, "schema": { "type": "string" } }
+                ])
+            ),
+            "head": head_metadata(id())
+        },
+        "/metadata/{id}/parameters": {
+            "get": with(
+                operation("Get the parameters of a packet", success(schema_ref("parameters.json"))),
+                "parameters", json!([id()])
+            )
+        },
+        "/metadata/{id}/size": {
+            "get": with(
+                operation("Get the total size of a packet's files", success(schema_ref("packet-size.json"))),
+                "parameters", json!([id()])
+            )
+        },
+        "/metadata/{id}/download": {
+            "get": with(
+                operation("Download a packet and all of its files as a zip archive", raw("The zip archive", "application/zip")),
+                "parameters", json!([id()])
+            )
+        },
+        "/metadata/{id}/dependencies": {
+            "get": with(
+                operation("List the packets a packet depends on", success(schema_ref("ids.json"))),
+                "parameters", json!([id(), query_param("recursive", json!({ "type": "boolean" }))])
+            )
+        },
+        "/checksum": {
+            "get": with(
+                operation("Get a hash of the ids of all packets", success(schema_ref("encoded-hash.json"))),
+                "parameters", json!([
+                    query_param("alg", json!({ "type": "string" })),
+                    query_param("encoding", json!({ "enum": ["hex", "multihash"] }))
+                ])
+            )
+        },
+        "/packets/latest": {
+            "get": with(
+                operation("Get the id of the most recent packet with the given name", success(schema_ref("packet-id.json"))),
+                "parameters", json!([packet_name()])
+            )
+        },
+        "/packets/summary": {
+            "get": with(
+                operation("Summarise the packets of each name", success(schema_ref("packet-summary.json"))),
+                "parameters", json!([
+                    query_param("sort", json!({ "type": "string", "enum": ["name", "count", "latest"], "default": "name" })),
+                    query_param("limit", json!({ "type": "integer", "minimum": 0 })),
+                    query_param("offset", json!({ "type": "integer", "minimum": 0 }))
+                ])
+            )
+        },
+        "/packets/missing": {
+            "post": with(
+                operation("List the given packets which are missing", success(schema_ref("ids.json"))),
+                "requestBody", json_body(json!({
+                    "type": "object",
+                    "properties": {
+                        "ids": schema_ref("ids.json"),
+                        "unpacked": { "type": "boolean", "default": false },
+                        "recursive": { "type": "boolean" }
+                    },
+                    "required": ["ids"]
+                }))
+            )
+        }
+    });
+    let files = json!({
+        "/files": {
+            "get": with(
+                operation("List the hashes of the files in the store", success(schema_ref("hashes.json"))),
+                "parameters", json!([
+                    query_param("limit", json!({ "type": "integer", "minimum": 0 })),
+                    query_param("offset", json!({ "type": "integer", "minimum": 0 }))
+                ])
+            )
+        },
+        "/files/missing": {
+            "post": with(
+                with(
+                    operation("List the given files which are missing", success(json!({
+                        "oneOf": [schema_ref("encoded-hashes.json"), schema_ref("file-check.json")]
+                    }))),
+                    "parameters", json!([query_param("verify", json!({ "type": "boolean" }))])
+                ),
+                "requestBody", json_body(json!({
+                    "type": "object",
+                    "properties": { "hashes": schema_ref("encoded-hashes.json") },
+                    "required": ["hashes"]
+                }))
+            )
+        },
+        "/files/info": {
+            "post": with(
+                operation("Get the size and modification time of the given files", success(schema_ref("file-info.json"))),
+                "requestBody", json_body(json!({
+                    "type": "object",
+                    "properties": { "hashes": schema_ref("encoded-hashes.json") },
+                    "required": ["hashes"]
+                }))
+            )
+        },
+        "/store/verify": {
+            "get": operation("Check the contents of every file in the store", success(schema_ref("invalid-files.json")))
+        },
+        "/store/gc": {
+            "post": with(
+                operation("Remove every file which is not used by any packet", success(schema_ref("gc.json"))),
+                "parameters", json!([query_param("dry_run", json!({ "type": "boolean" }))])
+            )
+        },
+        "/store/references": {
+            "get": with(
+                operation("Count the packets using each file in the store", success(schema_ref("references.json"))),
+                "parameters", json!([query_param("top", json!({ "type": "integer", "minimum": 0 }))])
+            )
+        },
+        "/packit/metadata": {
+            "get": with(
+                with_response(
+                    operation(
+                        "List the metadata of packets added since a given time",
+                        with_ndjson(success(schema_ref("list.json")), json!({ "$ref": "#/components/schemas/list/items" }))
+                    ),
+                    "304", json!({ "description": "No packets have been added since If-Modified-Since" })
+                ),
+                "parameters", json!([
+                    query_param("known_since", json!({ "type": "number" })),
+                    query_param("known_until", json!({ "type": "number" })),
+                    format(),
+                    { "name": "If-Modified-Since", "in": "header", "required": false, "schema": { "type": "string" } }
+                ])
+            )
+        },
+        "/file/{hash}": {
+            "get": with(
+                with_response(
+                    with_response(
+                        with_response(
+                            operation("Download a file", raw("The file contents", "application/octet-stream")),
+                            "206", raw("The requested range of the file's contents", "application/octet-stream")
+                        ),
+                        "416", json!({ "description": "The requested range starts beyond the end of the file" })
+                    ),
+                    "403", failure("The url's signature is invalid or has expired")
+                ),
+                "parameters", json!([
+                    hash(),
+                    query_param("filename", json!({ "type": "string" })),
+                    query_param("sig", json!({ "type": "string" })),
+                    query_param("exp", json!({ "type": "integer" }))
+                ])
+            ),
+            "post": with(
+                with(
+                    with_response(
+                        with_response(
+                            operation("Upload a file, or only check its hash in a dry run", success(json!({
+                                "oneOf": [empty(), schema_ref("hash-check.json")]
+                            }))),
+                            "409", failure("A stored copy of the file does not match its hash")
+                        ),
+                        "413", failure("The file is larger than the server allows")
+                    ),
+                    "parameters", json!([
+                        hash(),
+                        query_param("dry_run", json!({ "type": "boolean" })),
+                        query_param("verify", json!({ "type": "boolean" }))
+                    ])
+                ),
+                "requestBody", raw_body("application/octet-stream")
+            )
+        },
+        "/file/{hash}/signed-url": {
+            "get": with(
+                operation("Create a time-limited url for downloading a file", success(schema_ref("signed-url.json"))),
+                "parameters", json!([hash(), query_param("expires_in", json!({ "type": "integer" }))])
+            )
+        },
+        "/file/{hash}/pull": {
+            "post": with(
+                with(
+                    operation("Download a file into the store from another server", success(empty())),
+                    "parameters", json!([hash()])
+                ),
+                "requestBody", json_body(json!({
+                    "type": "object",
+                    "properties": { "url": { "type": "string" } },
+                    "required": ["url"]
+                }))
+            )
+        },
+        "/packet/{hash}": {
+            "post": with(
+                with(operation("Add a packet, whose files must already be present", success(empty())), "parameters", json!([hash()])),
+                "requestBody", raw_body("text/plain")
+            )
+        },
+        "/upload": {
+            "post": operation("Start a resumable upload", success(schema_ref("upload.json")))
+        },
+        "/upload/{id}": {
+            "get": with(
+                operation("Get the progress of a resumable upload", success(schema_ref("upload.json"))),
+                "parameters", json!([upload_id()])
+            ),
+            "patch": with(
+                with(
+                    operation("Append data to a resumable upload", success(schema_ref("upload.json"))),
+                    "parameters", json!([
+                        upload_id(),
+                        { "name": "Upload-Offset", "in": "header", "required": true, "schema": { "type": "integer", "minimum": 0 } }
+                    ])
+                ),
+                "requestBody", raw_body("application/octet-stream")
+            ),
+            "delete": with(
+                operation("Cancel a resumable upload", success(empty())),
+                "parameters", json!([upload_id()])
+            )
+        },
+        "/upload/{id}/finish": {
+            "post": with(
+                with(operation("Add the contents of a resumable upload to the store", success(empty())), "parameters", json!([upload_id()])),
+                "requestBody", json_body(json!({
+                    "type": "object",
+                    "properties": { "hash": schema_ref("hash.json") },
+                    "required": ["hash"]
+                }))
+            )
+        }
+    });
+    let server = json!({
+        "/admin/reload": {
+            "post": operation("Reload the repository's configuration", success(empty()))
+        },
+        "/admin/reindex": {
+            "post": operation("Rebuild the index of the repository's packets from disk", success(schema_ref("reindex.json")))
+        },
+        "/admin/clean-temp": {
+            "post": with(
+                operation("Remove old temporary files left behind by uploads", success(schema_ref("clean-temp.json"))),
+                "parameters", json!([query_param("max_age", json!({ "type": "integer", "minimum": 0 }))])
+            )
+        },
+        "/locations": {
+            "get": operation("List the configured locations", success(schema_ref("location-configs.json"))),
+            "post": with(
+                with(
+                    with_response(
+                        operation("Add another outpack server as a location", success(empty())),
+                        "412", failure("The locations do not match If-Match")
+                    ),
+                    "parameters", json!([if_match()])
+                ),
+                "requestBody", json_body(json!({
+                    "type": "object",
+                    "properties": {
+                        "name": { "type": "string" },
+                        "url": { "type": "string" },
+                        "token": { "type": "string" }
+                    },
+                    "required": ["name", "url"]
+                }))
+            )
+        },
+        "/locations/{name}": {
+            "delete": with(
+                with_response(
+                    operation("Remove a location", success(empty())),
+                    "412", failure("The locations do not match If-Match")
+                ),
+                "parameters", json!([path_param("name", json!({ "type": "string" })), if_match()])
+            )
+        },
+        "/sync/pull": {
+            "post": with(
+                operation("Pull packets from another outpack server", success(schema_ref("pull.json"))),
+                "requestBody", json_body(json!({
+                    "type": "object",
+                    "properties": {
+                        "location": { "type": "string" },
+                        "ids": schema_ref("ids.json")
+                    },
+                    "required": ["location"]
+                }))
+            )
+        },
+        "/sync/diff": {
+            "post": with(
+                operation("Compare this repository's packets with another's", success(schema_ref("sync-diff.json"))),
+                "requestBody", json_body(json!({
+                    "type": "object",
+                    "properties": {
+                        "ids": schema_ref("ids.json"),
+                        "location": { "type": "string" },
+                        "unpacked": { "type": "boolean", "default": false }
+                    }
+                }))
+            )
+        },
+        "/sync/plan": {
+            "post": with(
+                operation("List the given packets which are missing, and the files they need", success(schema_ref("sync-plan.json"))),
+                "requestBody", json_body(json!({
+                    "type": "object",
+                    "properties": {
+                        "ids": schema_ref("ids.json"),
+                        "unpacked": { "type": "boolean", "default": false },
+                        "recursive": { "type": "boolean" },
+                        "incomplete": { "type": "boolean" }
+                    },
+                    "required": ["ids"]
+                }))
+            )
+        },
+        "/git/fetch": {
+            "post": with(
+                operation("Fetch from the repository's git remote", success(schema_ref("git-fetch.json"))),
+                "parameters", json!([query_param("remote", json!({ "type": "string", "default": "origin" }))])
+            )
+        },
+        "/git/branches": {
+            "get": with(
+                operation("List the branches of the repository's git remote", success(schema_ref("branch-response.json"))),
+                "parameters", json!([query_param("max_age_days", json!({ "type": "integer", "minimum": 0 }))])
+            )
+        },
+        "/git/status": {
+            "get": operation("Get the commit checked out in the repository, and whether it has been modified", success(schema_ref("git-status.json")))
+        },
+        "/git/{branch}/{path}": {
+            "get": with(
+                with_response(
+                    operation(
+                        "Make a request of a branch of the repository's git remote, as though it were a root of its own",
+                        json!({ "description": "The response to the request made of the branch" })
+                    ),
+                    "404", failure("The branch does not exist, or is not an outpack repository")
+                ),
+                "parameters", json!([
+                    path_param("branch", json!({ "type": "string" })),
+                    path_param("path", json!({ "type": "string" }))
+                ])
+            )
+        },
+        "/roots": {
+            // This is only served when there are several roots, and is not under any of them.
+            "servers": [{ "url": "/" }],
+            "get": with_response(
+                operation("List the names of the roots served", success(schema_ref("roots.json"))),
+                "404", failure("The server serves a single root")
+            )
+        },
+        "/metrics": {
+            "get": {
+                "summary": "Get metrics in the Prometheus text format",
+                "responses": { "200": raw("The metrics", "text/plain") }
+            }
+        },
+        "/openapi.json": {
+            "get": {
+                "summary": "Get this description of the API",
+                "responses": {
+                    "200": {
+                        "description": "An OpenAPI document",
+                        "content": { "application/json": { "schema": { "type": "object" } } }
+                    }
+                }
+            }
+        },
+        "/schema/{name}": {
+            "get": {
+                "summary": "Get one of the schemas of the server's requests and responses",
+                "parameters": [path_param("name", json!({ "type": "string" }))],
+                "responses": {
+                    "200": {
+                        "description": "A JSON schema",
+                        "content": { "application/json": { "schema": { "type": "object" } } }
+                    }
+                }
+            }
+        }
+    });
+
+    // The paths are split into groups, as a single `json!` is too large to expand.
+    let mut paths = Map::new();
+    for group in [metadata, files, server] {
+        if let Value::Object(group) = group {
+            paths.extend(group);
+        }
+    }
+    Value::Object(paths)
+}
+
+fn build_document() -> Value {
+    json!({
+        "openapi": "3.1.0",
+        "info": {
+            "title": "outpack server",
+            "version": env!("CARGO_PKG_VERSION")
+        },
+        // A server with several roots serves each one under its own prefix, with all of the same
+        // paths.
+        "servers": [
+            { "url": "/", "description": "A server with a single root" },
+            {
+                "url": "/roots/{root}",
+                "description": "One of the roots of a server with several, as listed by /roots",
+                "variables": {
+                    "root": { "default": "root", "description": "The name of the root" }
+                }
+            }
+        ],
+        "paths": paths(),
+        "components": { "schemas": components() }
+    })
+}
+
+lazy_static! {
+    static ref DOCUMENT: Value = build_document();
+}
+
+/// The OpenAPI document describing the server's API.
+pub fn document() -> &'static Value {
+    &DOCUMENT
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn collect_refs<'a>(value: &'a Value, refs: &mut Vec<&'a str>) {
+        match value {
+            Value::Object(map) => {
+                if let Some(Value::String(reference)) = map.get("$ref") {
+                    refs.push(reference);
+                }
+                map.values().for_each(|v| collect_refs(v, refs));
+            }
+            Value::Array(values) => values.iter().for_each(|v| collect_refs(v, refs)),
+            _ => {}
+        }
+    }
+
+    #[test]
+    fn all_references_resolve_to_components() {
+        let document = document();
+        let mut refs = Vec::new();
+        collect_refs(document, &mut refs);
+        assert!(!refs.is_empty());
+        for reference in refs {
+            let name = reference
+                .strip_prefix("#/components/schemas/")
+                .unwrap_or_else(|| panic!("Unexpected reference '{}'", reference));
+            // A reference may point inside a component, such as at the items of a list.
+            assert!(
+                document.pointer(&reference[1..]).is_some(),
+                "Missing component '{}'",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn roots_are_described() {
+        let document = document();
+        let servers: Vec<&str> = document["servers"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|server| server["url"].as_str().unwrap())
+            .collect();
+        assert_eq!(servers, vec!["/", "/roots/{root}"]);
+        assert!(document["servers"][1]["variables"]["root"]["default"].is_string());
+
+        let roots = &document["paths"]["/roots"];
+        assert_eq!(roots["servers"], json!([{ "url": "/" }]));
+        assert_eq!(
+            roots["get"]["responses"]["200"]["content"]["application/json"]["schema"]["allOf"][1]
+                ["properties"]["data"],
+            schema_ref("roots.json")
+        );
+    }
+
+    #[test]
+    fn components_do_not_declare_a_schema_dialect() {
+        let schemas = document()["components"]["schemas"].as_object().unwrap();
+        assert!(schemas.contains_key("metadata"));
+        assert!(schemas.contains_key("response-success"));
+        assert!(schemas.values().all(|s| s.get("$schema").is_none()));
+    }
+}
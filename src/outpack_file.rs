@@ -1,15 +1,121 @@
 use axum::body::Body;
 use axum::response::Response;
 use std::io;
-use std::io::ErrorKind;
+use std::io::{ErrorKind, SeekFrom};
 use std::path::Path;
+use std::time::SystemTime;
 use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
 use tokio_util::io::ReaderStream;
 
 pub struct OutpackFile {
     hash: String,
     file: File,
     size: u64,
+    filename: Option<String>,
+    /// Whether the file is a gzip-compressed copy of the stored file.
+    gzip: bool,
+    /// When the file was added to the store, if the filesystem records it.
+    modified: Option<SystemTime>,
+    /// The first and last bytes to serve, if only part of the file was requested.
+    range: Option<(u64, u64)>,
+}
+
+/// The part of a file requested by a `Range` header.
+#[derive(Debug, PartialEq)]
+pub enum Range {
+    /// The first and last bytes of the range, which lie within the file.
+    Satisfiable(u64, u64),
+    /// A range which starts after the end of the file.
+    Unsatisfiable,
+}
+
+/// Parse a `Range` header for a file of the given size. Only a single range of bytes is
+/// supported: any other header, including a list of several ranges, gives `None`, and should be
+/// ignored by serving the whole file, as RFC 9110 allows.
+pub fn parse_range(header: &str, size: u64) -> Option<Range> {
+    let (unit, range) = header.trim().split_once('=')?;
+    if !unit.trim().eq_ignore_ascii_case("bytes") || range.contains(',') {
+        return None;
+    }
+    let (start, end) = range.trim().split_once('-')?;
+    if start.is_empty() {
+        // A suffix range, giving the number of bytes at the end of the file to serve.
+        let length: u64 = end.parse().ok()?;
+        if length == 0 || size == 0 {
+            return Some(Range::Unsatisfiable);
+        }
+        return Some(Range::Satisfiable(size.saturating_sub(length), size - 1));
+    }
+    let start: u64 = start.parse().ok()?;
+    let end: Option<u64> = match end {
+        "" => None,
+        end => Some(end.parse().ok()?),
+    };
+    if end.is_some_and(|end| end < start) {
+        return None;
+    }
+    if start >= size {
+        return Some(Range::Unsatisfiable);
+    }
+    let end = end.map_or(size - 1, |end| end.min(size - 1));
+    Some(Range::Satisfiable(start, end))
+}
+
+/// Guess the content type of a file from the extension of its name.
+fn content_type_for(filename: &str) -> &'static str {
+    let extension = Path::new(filename)
+        .extension()
+        .map(|e| e.to_string_lossy().to_ascii_lowercase());
+
+    match extension.as_deref() {
+        Some("csv") => "text/csv",
+        Some("tsv") => "text/tab-separated-values",
+        Some("txt") | Some("log") => "text/plain",
+        Some("md") => "text/markdown",
+        Some("html") | Some("htm") => "text/html",
+        Some("css") => "text/css",
+        Some("js") => "text/javascript",
+        Some("json") => "application/json",
+        Some("xml") => "application/xml",
+        Some("pdf") => "application/pdf",
+        Some("zip") => "application/zip",
+        Some("png") => "image/png",
+        Some("jpg") | Some("jpeg") => "image/jpeg",
+        Some("gif") => "image/gif",
+        Some("svg") => "image/svg+xml",
+        _ => mime::APPLICATION_OCTET_STREAM.as_ref(),
+    }
+}
+
+/// Whether an `Accept-Encoding` header allows a gzip-encoded response.
+pub fn accepts_gzip(accept_encoding: &str) -> bool {
+    accept_encoding.split(',').any(|coding| {
+        let mut parts = coding.split(';').map(str::trim);
+        let name = parts.next().unwrap_or_default().to_ascii_lowercase();
+        let quality = parts
+            .find_map(|p| p.strip_prefix("q="))
+            .and_then(|q| q.parse::<f32>().ok())
+            .unwrap_or(1.0);
+        (name == "gzip" || name == "x-gzip") && quality > 0.0
+    })
+}
+
+/// Make a filename safe to include in a quoted `Content-Disposition` header.
+///
+/// Only the last component of the path is kept, and any character that cannot appear in the
+/// header is replaced by an underscore.
+fn sanitize_filename(filename: &str) -> String {
+    let name = filename.rsplit(['/', '\\']).next().unwrap_or_default();
+    name.chars()
+        .map(|c| {
+            if (c.is_ascii_graphic() && c != '"') || c == ' ' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
 }
 
 impl OutpackFile {
@@ -22,22 +128,201 @@ impl OutpackFile {
                 }
                 _ => e,
             })?;
-        let size = file.metadata().await?.len();
-        Ok(OutpackFile { hash, file, size })
+        let metadata = file.metadata().await?;
+        Ok(OutpackFile {
+            hash,
+            file,
+            size: metadata.len(),
+            filename: None,
+            gzip: false,
+            modified: metadata.modified().ok(),
+            range: None,
+        })
+    }
+
+    /// Open the gzip-compressed copy of a file, if there is one.
+    ///
+    /// The file is served with a gzip `Content-Encoding`, so that clients see the same contents
+    /// as they would for the uncompressed file.
+    pub async fn open_compressed<P: AsRef<Path>>(
+        hash: String,
+        path: P,
+    ) -> io::Result<Option<OutpackFile>> {
+        let file = match File::open(path.as_ref()).await {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let metadata = file.metadata().await?;
+        Ok(Some(OutpackFile {
+            hash,
+            file,
+            size: metadata.len(),
+            filename: None,
+            gzip: true,
+            modified: metadata.modified().ok(),
+            range: None,
+        }))
+    }
+
+    /// Set the name the file should be served as, which determines its content type.
+    pub fn with_filename(self, filename: Option<String>) -> OutpackFile {
+        OutpackFile { filename, ..self }
+    }
+
+    /// The entity tag of the file. Files are identified by their hash, so it makes a natural tag.
+    /// The compressed copy has different bytes, so needs a tag of its own, or a cache could
+    /// revalidate one encoding of the file with the tag of the other.
+    pub fn etag(&self) -> String {
+        if self.gzip {
+            format!("\"{}+gzip\"", self.hash)
+        } else {
+            format!("\"{}\"", self.hash)
+        }
+    }
+
+    /// The size of the file, in bytes.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// When the file was last modified, formatted as for a `Last-Modified` header.
+    pub fn last_modified(&self) -> Option<String> {
+        self.modified.map(httpdate::fmt_http_date)
+    }
+
+    /// Serve only the bytes from `start` to `end`, inclusive, which must lie within the file.
+    pub async fn with_range(mut self, start: u64, end: u64) -> io::Result<OutpackFile> {
+        self.file.seek(SeekFrom::Start(start)).await?;
+        Ok(OutpackFile {
+            range: Some((start, end)),
+            ..self
+        })
+    }
+
+    /// The response to a request for a range which lies outside the file.
+    pub fn range_not_satisfiable(self) -> Response {
+        use axum::http::header::*;
+        Response::builder()
+            .status(axum::http::StatusCode::RANGE_NOT_SATISFIABLE)
+            .header(CONTENT_RANGE, format!("bytes */{}", self.size))
+            .header(ETAG, self.etag())
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    /// The response to a conditional request whose copy of the file is still current. This has
+    /// the same validators as the full response would, but no body.
+    pub fn not_modified(self) -> Response {
+        use axum::http::header::*;
+        Response::builder()
+            .status(axum::http::StatusCode::NOT_MODIFIED)
+            .header(ETAG, self.etag())
+            .header(VARY, ACCEPT_ENCODING.as_str())
+            .body(Body::empty())
+            .unwrap()
     }
 }
 
 impl axum::response::IntoResponse for OutpackFile {
     fn into_response(self) -> Response {
         use axum::http::header::*;
-        let stream = ReaderStream::new(self.file);
-        let content_disposition = format!("attachment; filename=\"{}\"", self.hash);
+        let etag = self.etag();
+        let (status, length) = match self.range {
+            Some((start, end)) => (axum::http::StatusCode::PARTIAL_CONTENT, end - start + 1),
+            None => (axum::http::StatusCode::OK, self.size),
+        };
+        let stream = ReaderStream::new(self.file.take(length));
+        let (content_type, filename) = match &self.filename {
+            Some(filename) => (content_type_for(filename), sanitize_filename(filename)),
+            None => (mime::APPLICATION_OCTET_STREAM.as_ref(), self.hash.clone()),
+        };
+        let content_disposition = format!("attachment; filename=\"{}\"", filename);
 
-        Response::builder()
-            .header(CONTENT_TYPE, mime::APPLICATION_OCTET_STREAM.as_ref())
+        let mut builder = Response::builder()
+            .status(status)
+            .header(CONTENT_TYPE, content_type)
             .header(CONTENT_DISPOSITION, content_disposition)
-            .header(CONTENT_LENGTH, self.size)
-            .body(Body::from_stream(stream))
-            .unwrap()
+            .header(CONTENT_LENGTH, length)
+            .header(ACCEPT_RANGES, "bytes")
+            .header(ETAG, etag)
+            .header(VARY, ACCEPT_ENCODING.as_str());
+        if let Some((start, end)) = self.range {
+            builder = builder.header(
+                CONTENT_RANGE,
+                format!("bytes {}-{}/{}", start, end, self.size),
+            );
+        }
+        if self.gzip {
+            builder = builder.header(CONTENT_ENCODING, "gzip");
+        }
+        if let Some(modified) = self.modified {
+            builder = builder.header(LAST_MODIFIED, httpdate::fmt_http_date(modified));
+        }
+        builder.body(Body::from_stream(stream)).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn can_guess_content_type() {
+        assert_eq!(content_type_for("data.csv"), "text/csv");
+        assert_eq!(content_type_for("DATA.CSV"), "text/csv");
+        assert_eq!(content_type_for("dir/plot.png"), "image/png");
+        assert_eq!(content_type_for("model.rds"), "application/octet-stream");
+        assert_eq!(content_type_for("README"), "application/octet-stream");
+    }
+
+    #[test]
+    fn can_parse_accept_encoding() {
+        assert!(accepts_gzip("gzip"));
+        assert!(accepts_gzip("deflate, GZIP;q=0.5"));
+        assert!(accepts_gzip("br, x-gzip"));
+        assert!(!accepts_gzip("deflate, br"));
+        assert!(!accepts_gzip("gzip;q=0"));
+        assert!(!accepts_gzip(""));
+    }
+
+    #[test]
+    fn can_sanitize_filename() {
+        assert_eq!(sanitize_filename("data.csv"), "data.csv");
+        assert_eq!(sanitize_filename("dir/my data.csv"), "my data.csv");
+        assert_eq!(sanitize_filename("..\\a\"b\n.csv"), "a_b_.csv");
+        assert_eq!(sanitize_filename("résumé.txt"), "r_sum_.txt");
+    }
+
+    #[test]
+    fn can_parse_range() {
+        assert_eq!(parse_range("bytes=2-4", 10), Some(Range::Satisfiable(2, 4)));
+        assert_eq!(parse_range("bytes=2-", 10), Some(Range::Satisfiable(2, 9)));
+        assert_eq!(
+            parse_range("bytes=2-100", 10),
+            Some(Range::Satisfiable(2, 9))
+        );
+        assert_eq!(parse_range("bytes=-3", 10), Some(Range::Satisfiable(7, 9)));
+        assert_eq!(parse_range("bytes=-30", 10), Some(Range::Satisfiable(0, 9)));
+        assert_eq!(
+            parse_range("Bytes = 0-0", 10),
+            Some(Range::Satisfiable(0, 0))
+        );
+        assert_eq!(parse_range("bytes=10-", 10), Some(Range::Unsatisfiable));
+        assert_eq!(parse_range("bytes=-0", 10), Some(Range::Unsatisfiable));
+        assert_eq!(parse_range("bytes=-5", 0), Some(Range::Unsatisfiable));
+        assert_eq!(parse_range("bytes=4-2", 10), None);
+        assert_eq!(parse_range("bytes=0-1,4-5", 10), None);
+        assert_eq!(parse_range("lines=0-1", 10), None);
+        assert_eq!(parse_range("bytes=a-b", 10), None);
+    }
+
+    #[tokio::test]
+    async fn reports_missing_file_as_not_found() {
+        let tmp = tempfile::TempDir::new().unwrap();
+        let res = OutpackFile::open(String::from("sha256:abcdef"), tmp.path().join("ab")).await;
+        let err = res.err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+        assert_eq!(err.to_string(), "hash 'sha256:abcdef' not found");
     }
 }
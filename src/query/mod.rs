@@ -8,31 +8,54 @@ mod test_utils_query;
 #[cfg(feature = "python")]
 mod python;
 
-use crate::index::get_packet_index;
-use crate::query::query_eval::eval_query;
+use crate::index::{get_packet_index, Index};
+use crate::query::query_eval::{eval_query, find_latest_with_name};
 use crate::query::query_format::format_query_result;
 pub use crate::query::query_parse::parse_query;
 use crate::query::query_parse::Rule;
+use pest::error::{ErrorVariant, LineColLocation};
+use std::io;
 use std::path::Path;
+use std::sync::Arc;
 
 use thiserror::Error;
 
 pub fn run_query(root: &Path, query: &str) -> Result<String, QueryError> {
-    let index = match get_packet_index(root) {
-        Ok(index) => index,
-        Err(e) => {
-            return Err(QueryError::EvalError(format!(
-                "Could not build outpack index from root at {}: {:?}",
-                root.display(),
-                e
-            )))
-        }
-    };
+    let index = read_index(root)?;
     let parsed = parse_query(query)?;
     let result = eval_query(&index, parsed);
     format_query_result(result)
 }
 
+/// Evaluate a query against the repository at `root`, returning the ids of the matching packets.
+pub fn find_packet_ids(root: &Path, query: &str) -> Result<Vec<String>, QueryError> {
+    let index = read_index(root)?;
+    let parsed = parse_query(query)?;
+    let packets = eval_query(&index, parsed)?;
+    Ok(packets
+        .into_iter()
+        .map(|packet| packet.id.clone())
+        .collect())
+}
+
+/// Find the id of the most recent packet with the given name, if there are any.
+///
+/// This is equivalent to the query `latest(name == "<name>")`, without needing to parse one.
+pub fn find_latest_id(root: &Path, name: &str) -> io::Result<Option<String>> {
+    let index = get_packet_index(root)?;
+    Ok(find_latest_with_name(&index, name).map(|packet| packet.id.clone()))
+}
+
+fn read_index(root: &Path) -> Result<Arc<Index>, QueryError> {
+    get_packet_index(root).map_err(|e| {
+        QueryError::EvalError(format!(
+            "Could not build outpack index from root at {}: {:?}",
+            root.display(),
+            e
+        ))
+    })
+}
+
 // pest's error type is quite large, which would consume a lot of stack space and require moving
 // data around, even in the happy path when an Ok is returned. We want to keep this as small as
 // possible so Box the large error body to force it onto the heap. The heap memory allocation cost
@@ -51,6 +74,34 @@ pub enum QueryError {
     EvalError(String),
 }
 
+impl ParseError {
+    /// The line and column, both starting from 1, at which the error was found.
+    pub fn line_col(&self) -> (usize, usize) {
+        match self.0.line_col {
+            LineColLocation::Pos(pos) => pos,
+            LineColLocation::Span(start, _) => start,
+        }
+    }
+
+    /// The names of the tokens that would have been accepted where the error was found.
+    ///
+    /// This is empty if the query was syntactically valid but could not be interpreted, eg.
+    /// because it uses an unknown function.
+    pub fn expected(&self) -> Vec<String> {
+        match &self.0.variant {
+            ErrorVariant::ParsingError { positives, .. } => {
+                positives.iter().map(|r| format!("{:?}", r)).collect()
+            }
+            ErrorVariant::CustomError { .. } => Vec::new(),
+        }
+    }
+
+    /// A description of the error, without its location.
+    pub fn message(&self) -> String {
+        self.0.variant.message().into_owned()
+    }
+}
+
 impl From<pest::error::Error<Rule>> for ParseError {
     fn from(err: pest::error::Error<Rule>) -> ParseError {
         ParseError(Box::new(err))
@@ -22,20 +22,29 @@ fn eval_latest<'a>(
     index: &'a Index,
     inner: Option<Box<QueryNode>>,
 ) -> Result<Vec<&'a Packet>, QueryError> {
-    if inner.is_some() {
-        let latest = eval_query(index, *inner.unwrap())?;
-        let last = latest.last();
-        match last {
-            Some(packet) => Ok(vec![*packet]),
-            None => Ok(vec![]),
-        }
-    } else {
-        let last = index.packets.last();
-        match last {
-            Some(packet) => Ok(vec![packet]),
-            None => Ok(vec![]),
-        }
-    }
+    let candidates = match inner {
+        Some(inner) => eval_query(index, *inner)?,
+        None => index.packets.iter().collect(),
+    };
+    Ok(find_latest(candidates).into_iter().collect())
+}
+
+/// Find the most recent packet, according to the time at which it was started.
+///
+/// Packets started at the same time are ordered by id, so the result does not depend on the order
+/// of the candidates.
+fn find_latest(candidates: Vec<&Packet>) -> Option<&Packet> {
+    candidates.into_iter().max_by(|a, b| {
+        a.time
+            .start
+            .total_cmp(&b.time.start)
+            .then_with(|| a.id.cmp(&b.id))
+    })
+}
+
+/// Find the most recent packet with the given name, as `latest(name == "...")` would.
+pub fn find_latest_with_name<'a>(index: &'a Index, name: &str) -> Option<&'a Packet> {
+    find_latest(index.packets.iter().filter(|p| p.name == name).collect())
 }
 
 fn eval_single<'a>(index: &'a Index, inner: QueryNode) -> Result<Vec<&'a Packet>, QueryError> {
@@ -51,7 +60,7 @@ fn eval_single<'a>(index: &'a Index, inner: QueryNode) -> Result<Vec<&'a Packet>
 }
 
 fn eval_negation<'a>(index: &'a Index, inner: QueryNode) -> Result<Vec<&'a Packet>, QueryError> {
-    let packets = eval_query(index, inner)?;
+    let packets: HashSet<&Packet> = HashSet::from_iter(eval_query(index, inner)?);
     Ok(index
         .packets
         .iter()
@@ -98,6 +107,9 @@ fn lookup_filter(
             TestOperator::GreaterThan => l > r,
             TestOperator::GreaterThanOrEqual => l >= r,
         },
+        // Values of different types are never equal, but neither are they unequal: comparing
+        // a string parameter against a number doesn't match, whichever operator is used.
+        (_, Some(l), Some(r)) if std::mem::discriminant(&l) != std::mem::discriminant(&r) => false,
         (TestOperator::Equal, Some(l), Some(r)) => l == r,
         (TestOperator::NotEqual, Some(l), Some(r)) => l != r,
         (_, _, _) => false,
@@ -130,7 +142,7 @@ fn evaluate_lookup<'a>(
 }
 
 impl Packet {
-    pub fn lookup_value(&self, lookup: &PacketLookup) -> Option<Literal> {
+    pub fn lookup_value(&self, lookup: &PacketLookup) -> Option<Literal<'_>> {
         match lookup {
             PacketLookup::Id => Some(Literal::String(&self.id)),
             PacketLookup::Name => Some(Literal::String(&self.name)),
@@ -138,7 +150,7 @@ impl Packet {
         }
     }
 
-    pub fn get_parameter(&self, param_name: &str) -> Option<Literal> {
+    pub fn get_parameter(&self, param_name: &str) -> Option<Literal<'_>> {
         if let Some(params) = &self.parameters {
             match params.get(param_name)? {
                 JsonValue::Number(number) => Some(Literal::Number(number.as_f64()?)),
@@ -152,6 +164,11 @@ impl Packet {
     }
 }
 
+/// Evaluate `lhs && rhs` or `lhs || rhs`.
+///
+/// The right hand side is not evaluated if the left hand side alone determines the result, i.e.
+/// when it matches no packets for `&&`, or every packet for `||`. Matching packets are returned in
+/// the order they appear in the index.
 fn eval_boolean_op<'a>(
     index: &'a Index,
     op: BooleanOperator,
@@ -159,19 +176,29 @@ fn eval_boolean_op<'a>(
     rhs: QueryNode,
 ) -> Result<Vec<&'a Packet>, QueryError> {
     let lhs_res = eval_query(index, lhs)?;
-    let rhs_res = eval_query(index, rhs)?;
-    let lhs_set: HashSet<&Packet> = HashSet::from_iter(lhs_res.iter().cloned());
-    let rhs_set: HashSet<&Packet> = HashSet::from_iter(rhs_res.iter().cloned());
     match op {
-        BooleanOperator::And => Ok(lhs_set.intersection(&rhs_set).copied().collect()),
-        BooleanOperator::Or => Ok(lhs_set.union(&rhs_set).copied().collect()),
+        BooleanOperator::And if lhs_res.is_empty() => return Ok(lhs_res),
+        BooleanOperator::Or if lhs_res.len() == index.packets.len() => return Ok(lhs_res),
+        _ => {}
     }
+
+    let rhs_res = eval_query(index, rhs)?;
+    let lhs_set: HashSet<&Packet> = HashSet::from_iter(lhs_res);
+    let rhs_set: HashSet<&Packet> = HashSet::from_iter(rhs_res);
+    Ok(index
+        .packets
+        .iter()
+        .filter(|packet| match op {
+            BooleanOperator::And => lhs_set.contains(packet) && rhs_set.contains(packet),
+            BooleanOperator::Or => lhs_set.contains(packet) || rhs_set.contains(packet),
+        })
+        .collect())
 }
 
 #[cfg(test)]
 mod tests {
     use crate::metadata::get_metadata_from_date;
-    use crate::test_utils::tests::assert_packet_ids_eq;
+    use crate::test_utils::tests::{assert_packet_ids_eq, start_packet};
 
     use super::*;
     use std::path::Path;
@@ -234,7 +261,7 @@ mod tests {
 
         let query = QueryNode::Latest(None);
         let res = eval_query(&index, query).unwrap();
-        assert_packet_ids_eq(res, vec!["20180818-164043-7cdcde4b"]);
+        assert_packet_ids_eq(res, vec!["20180220-095832-16a4bbed"]);
 
         let inner_query = QueryNode::Test(
             TestOperator::Equal,
@@ -255,9 +282,83 @@ mod tests {
         assert_eq!(res.len(), 0);
     }
 
+    fn make_index(packets: Vec<(&str, f64)>) -> Index {
+        let packets = packets
+            .into_iter()
+            .map(|(name, start)| {
+                let (_, json, _) = start_packet(name).start_time(start).finish();
+                serde_json::from_str(&json).unwrap()
+            })
+            .collect();
+        Index { packets }
+    }
+
+    fn name_is(name: &'static str) -> Option<Box<QueryNode<'static>>> {
+        Some(Box::new(QueryNode::Test(
+            TestOperator::Equal,
+            TestValue::Lookup(Lookup::Packet(PacketLookup::Name)),
+            TestValue::Literal(Literal::String(name)),
+        )))
+    }
+
+    #[test]
+    fn query_latest_is_ordered_by_time() {
+        let index = make_index(vec![
+            ("data", 3000.),
+            ("data", 1000.),
+            ("other", 4000.),
+            ("data", 2000.),
+        ]);
+
+        let res = eval_query(&index, QueryNode::Latest(name_is("data"))).unwrap();
+        assert_eq!(res.len(), 1);
+        assert_eq!(res[0].time.start, 3000.);
+        assert_eq!(res[0].name, "data");
+
+        let res = eval_query(&index, QueryNode::Latest(None)).unwrap();
+        assert_eq!(res.len(), 1);
+        assert_eq!(res[0].name, "other");
+
+        let res = eval_query(&index, QueryNode::Latest(name_is("missing"))).unwrap();
+        assert!(res.is_empty());
+    }
+
+    #[test]
+    fn query_latest_breaks_ties_by_id() {
+        let index = make_index(vec![("data", 1000.), ("data", 1000.), ("data", 1000.)]);
+        let expected = index.packets.iter().map(|p| &p.id).max().unwrap();
+
+        let res = eval_query(&index, QueryNode::Latest(name_is("data"))).unwrap();
+        assert_eq!(res.len(), 1);
+        assert_eq!(&res[0].id, expected);
+
+        // The result does not depend on the order in which the candidates were found, which
+        // for boolean expressions is arbitrary.
+        let query = QueryNode::Latest(Some(Box::new(QueryNode::BooleanExpr(
+            BooleanOperator::Or,
+            name_is("data").unwrap(),
+            name_is("other").unwrap(),
+        ))));
+        let res = eval_query(&index, query).unwrap();
+        assert_eq!(&res[0].id, expected);
+    }
+
+    #[test]
+    fn can_find_latest_with_name() {
+        let index = make_index(vec![
+            ("data", 3000.),
+            ("data", 1000.),
+            ("other", 4000.),
+            ("data", 2000.),
+        ]);
+        let expected = eval_query(&index, QueryNode::Latest(name_is("data"))).unwrap();
+        assert_eq!(find_latest_with_name(&index, "data"), Some(expected[0]));
+        assert_eq!(find_latest_with_name(&index, "missing"), None);
+    }
+
     #[test]
     fn can_get_parameter_as_literal() {
-        let packets = get_metadata_from_date(Path::new("tests/example"), None).unwrap();
+        let packets = get_metadata_from_date(Path::new("tests/example"), &[], None, None).unwrap();
         assert_eq!(packets.len(), 4);
 
         let matching_packets: Vec<Packet> = packets
@@ -281,7 +382,7 @@ mod tests {
 
     #[test]
     fn can_test_lookup_filter() {
-        let packets = get_metadata_from_date(Path::new("tests/example"), None).unwrap();
+        let packets = get_metadata_from_date(Path::new("tests/example"), &[], None, None).unwrap();
         assert_eq!(packets.len(), 4);
 
         let matching_packets: Vec<Packet> = packets
@@ -524,6 +625,20 @@ mod tests {
         );
         let res = eval_query(&index, query).unwrap();
         assert_eq!(res.len(), 0);
+        let query = QueryNode::Test(
+            TestOperator::NotEqual,
+            TestValue::Lookup(Lookup::Packet(PacketLookup::Parameter("pull_data"))),
+            TestValue::Literal(Literal::Number(1f64)),
+        );
+        let res = eval_query(&index, query).unwrap();
+        assert_eq!(res.len(), 0);
+        let query = QueryNode::Test(
+            TestOperator::NotEqual,
+            TestValue::Lookup(Lookup::Packet(PacketLookup::Parameter("disease"))),
+            TestValue::Literal(Literal::Bool(true)),
+        );
+        let res = eval_query(&index, query).unwrap();
+        assert_eq!(res.len(), 0);
     }
 
     #[test]
@@ -537,7 +652,7 @@ mod tests {
             vec![
                 "20170818-164830-33e0ab01",
                 "20170818-164847-7574883b",
-                "20180220-095832-16a4bbed",
+                "20180818-164043-7cdcde4b",
             ],
         );
 
@@ -545,7 +660,7 @@ mod tests {
             QueryNode::Latest(None),
         ))));
         let res = eval_query(&index, query).unwrap();
-        assert_packet_ids_eq(res, vec!["20180818-164043-7cdcde4b"]);
+        assert_packet_ids_eq(res, vec!["20180220-095832-16a4bbed"]);
     }
 
     #[test]
@@ -554,13 +669,13 @@ mod tests {
 
         let query = QueryNode::Brackets(Box::new(QueryNode::Latest(None)));
         let res = eval_query(&index, query).unwrap();
-        assert_packet_ids_eq(res, vec!["20180818-164043-7cdcde4b"]);
+        assert_packet_ids_eq(res, vec!["20180220-095832-16a4bbed"]);
 
         let query = QueryNode::Brackets(Box::new(QueryNode::Brackets(Box::new(
             QueryNode::Latest(None),
         ))));
         let res = eval_query(&index, query).unwrap();
-        assert_packet_ids_eq(res, vec!["20180818-164043-7cdcde4b"]);
+        assert_packet_ids_eq(res, vec!["20180220-095832-16a4bbed"]);
 
         let query = QueryNode::Brackets(Box::new(QueryNode::Negation(Box::new(
             QueryNode::Latest(None),
@@ -571,7 +686,7 @@ mod tests {
             vec![
                 "20170818-164830-33e0ab01",
                 "20170818-164847-7574883b",
-                "20180220-095832-16a4bbed",
+                "20180818-164043-7cdcde4b",
             ],
         );
     }
@@ -585,8 +700,8 @@ mod tests {
             Box::new(QueryNode::Latest(None)),
             Box::new(QueryNode::Test(
                 TestOperator::Equal,
-                TestValue::Lookup(Lookup::Packet(PacketLookup::Name)),
-                TestValue::Literal(Literal::String("modup-201707-params1")),
+                TestValue::Lookup(Lookup::Packet(PacketLookup::Id)),
+                TestValue::Literal(Literal::String("20180818-164043-7cdcde4b")),
             )),
         );
         let res = eval_query(&index, query).unwrap();
@@ -600,12 +715,12 @@ mod tests {
             Box::new(QueryNode::Negation(Box::new(QueryNode::Latest(None)))),
             Box::new(QueryNode::Test(
                 TestOperator::Equal,
-                TestValue::Lookup(Lookup::Packet(PacketLookup::Name)),
-                TestValue::Literal(Literal::String("modup-201707-params1")),
+                TestValue::Lookup(Lookup::Packet(PacketLookup::Id)),
+                TestValue::Literal(Literal::String("20180818-164043-7cdcde4b")),
             )),
         );
         let res = eval_query(&index, query).unwrap();
-        assert_packet_ids_eq(res, vec!["20180220-095832-16a4bbed"]);
+        assert_packet_ids_eq(res, vec!["20180818-164043-7cdcde4b"]);
     }
 
     #[test]
@@ -614,7 +729,7 @@ mod tests {
 
         let query = QueryNode::Single(Box::new(QueryNode::Latest(None)));
         let res = eval_query(&index, query).unwrap();
-        assert_packet_ids_eq(res, vec!["20180818-164043-7cdcde4b"]);
+        assert_packet_ids_eq(res, vec!["20180220-095832-16a4bbed"]);
 
         let query = QueryNode::Single(Box::new(QueryNode::Negation(Box::new(QueryNode::Latest(
             None,
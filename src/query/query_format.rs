@@ -26,7 +26,8 @@ mod tests {
     #[test]
     fn query_result_can_be_formatted() {
         let packets =
-            crate::metadata::get_metadata_from_date(Path::new("tests/example"), None).unwrap();
+            crate::metadata::get_metadata_from_date(Path::new("tests/example"), &[], None, None)
+                .unwrap();
         let packet_refs: Vec<&Packet> = packets.iter().collect();
         let one_packet = vec![packet_refs[0]];
 
@@ -10,7 +10,7 @@ use crate::query::ParseError;
 #[grammar = "query/query.pest"]
 struct QueryParser;
 
-pub fn parse_query(query: &str) -> Result<QueryNode, ParseError> {
+pub fn parse_query(query: &str) -> Result<QueryNode<'_>, ParseError> {
     let pairs = QueryParser::parse(Rule::query, query)?;
     let node = parse_toplevel(get_first_inner_pair(pairs.peek().unwrap()))?;
     Ok(node)
@@ -128,6 +128,7 @@ fn parse_expr(query: Pair<Rule>) -> Result<QueryNode, ParseError> {
             let inner = parse_body(expr.peek().unwrap().into_inner())?;
             Ok(QueryNode::Brackets(Box::new(inner)))
         }
+        Rule::unknownFunc => Err(unknown_function_error(get_first_inner_pair(query))),
         _ => unreachable!(),
     }
 }
@@ -177,7 +178,17 @@ fn unknown_infix_error(operator: Pair<Rule>) -> ParseError {
     .into()
 }
 
-fn get_string_inner(rule: Pair<Rule>) -> &str {
+fn unknown_function_error(name: Pair<Rule>) -> ParseError {
+    pest::error::Error::new_from_span(
+        pest::error::ErrorVariant::CustomError {
+            message: format!("Unknown function: {}", name.as_str()),
+        },
+        name.as_span(),
+    )
+    .into()
+}
+
+fn get_string_inner(rule: Pair<'_, Rule>) -> &str {
     get_first_inner_pair(rule).as_str()
 }
 
@@ -826,4 +837,31 @@ mod tests {
             )
         );
     }
+
+    #[test]
+    fn parse_errors_report_location_of_unterminated_string() {
+        let err = parse_query(r#"name == "foo"#).unwrap_err();
+        assert_eq!(err.line_col(), (1, 9));
+        assert_eq!(err.expected(), vec!["lookup", "literal"]);
+        assert_eq!(err.message(), "expected lookup or literal");
+
+        let err = parse_query("latest(name == 'x') ||\n  name == 'foo").unwrap_err();
+        assert_eq!(err.line_col(), (2, 11));
+    }
+
+    #[test]
+    fn parse_errors_report_location_of_unknown_function() {
+        let err = parse_query(r#"name == "x" && foo(name == "y")"#).unwrap_err();
+        assert_eq!(err.line_col(), (1, 16));
+        assert!(err.expected().is_empty());
+        assert_eq!(err.message(), "Unknown function: foo");
+
+        let err = parse_query("frobnicate()").unwrap_err();
+        assert_eq!(err.line_col(), (1, 1));
+        assert_eq!(err.message(), "Unknown function: frobnicate");
+
+        // Known functions with the wrong arguments are reported as syntax errors instead.
+        let err = parse_query("single()").unwrap_err();
+        assert!(!err.expected().is_empty());
+    }
 }
@@ -0,0 +1,288 @@
+//! Limiting how often each client may make requests.
+//!
+//! Every client has a bucket of tokens for each kind of request, holding as many requests as the
+//! client may make at once. Each request takes a token, and the bucket is refilled continuously at
+//! the configured rate. A request made while the bucket is empty is rejected with a 429 status,
+//! and a `Retry-After` header saying how long it will be until a token is available.
+use axum::extract::{ConnectInfo, Request, State};
+use axum::http::header::{AUTHORIZATION, RETRY_AFTER};
+use axum::http::{HeaderValue, Method, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::config::{Rate, RateLimit};
+use crate::hash::{hash_data, HashAlgorithm};
+use crate::responses::OutpackError;
+
+/// The number of clients whose buckets are kept before those which are full again are dropped.
+/// A full bucket is no different from the new one a client would be given. If there are still too
+/// many, the buckets of the clients seen least recently are dropped too.
+const MAX_CLIENTS: usize = 10_000;
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// The buckets of every client for one kind of request.
+struct Limiter {
+    capacity: f64,
+    /// The number of tokens added to each bucket per second.
+    refill: f64,
+    buckets: Mutex<HashMap<String, Bucket>>,
+    max_clients: usize,
+}
+
+impl Limiter {
+    fn new(rate: Rate) -> anyhow::Result<Limiter> {
+        if rate.requests == 0 || !(rate.per_seconds.is_finite() && rate.per_seconds > 0.0) {
+            anyhow::bail!(
+                "Invalid rate limit of {} requests per {} seconds: both must be positive",
+                rate.requests,
+                rate.per_seconds
+            );
+        }
+        Ok(Limiter {
+            capacity: rate.requests as f64,
+            refill: rate.requests as f64 / rate.per_seconds,
+            buckets: Mutex::new(HashMap::new()),
+            max_clients: MAX_CLIENTS,
+        })
+    }
+
+    fn tokens(&self, bucket: &Bucket, now: Instant) -> f64 {
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        (bucket.tokens + elapsed * self.refill).min(self.capacity)
+    }
+
+    /// Take a token from the client's bucket, or return how long it will be until there is one.
+    fn acquire(&self, client: &str, now: Instant) -> Result<(), Duration> {
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= self.max_clients && !buckets.contains_key(client) {
+            buckets.retain(|_, bucket| self.tokens(bucket, now) < self.capacity);
+            if buckets.len() >= self.max_clients {
+                // Drop a tenth of the buckets at once, so that a steady stream of new clients
+                // does not mean scanning every bucket on every request.
+                let mut seen: Vec<Instant> =
+                    buckets.values().map(|bucket| bucket.updated).collect();
+                let keep = self.max_clients - 1 - (self.max_clients - 1) / 10;
+                let cutoff = buckets.len() - keep;
+                let (_, oldest_kept, _) = seen.select_nth_unstable(cutoff - 1);
+                let oldest_kept = *oldest_kept;
+                buckets.retain(|_, bucket| bucket.updated > oldest_kept);
+            }
+        }
+        let bucket = buckets.entry(client.to_owned()).or_insert(Bucket {
+            tokens: self.capacity,
+            updated: now,
+        });
+        bucket.tokens = self.tokens(bucket, now);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / self.refill))
+        }
+    }
+}
+
+pub struct RateLimiter {
+    reads: Option<Limiter>,
+    writes: Option<Limiter>,
+    by_token: bool,
+}
+
+impl RateLimiter {
+    /// Build the rate limiter described by the configuration, or `None` if requests are
+    /// unlimited.
+    pub fn new(config: &RateLimit) -> anyhow::Result<Option<RateLimiter>> {
+        if config.reads.is_none() && config.writes.is_none() {
+            return Ok(None);
+        }
+        Ok(Some(RateLimiter {
+            reads: config.reads.map(Limiter::new).transpose()?,
+            writes: config.writes.map(Limiter::new).transpose()?,
+            by_token: config.by_token,
+        }))
+    }
+
+    fn limiter(&self, method: &Method) -> Option<&Limiter> {
+        if method == Method::GET || method == Method::HEAD || method == Method::OPTIONS {
+            self.reads.as_ref()
+        } else {
+            self.writes.as_ref()
+        }
+    }
+
+    /// The client making a request. Requests whose client cannot be identified share a single
+    /// allowance.
+    fn client(&self, request: &Request) -> Client {
+        let token = request
+            .headers()
+            .get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+        let ip = request
+            .extensions()
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(addr)| format!("ip:{}", addr.ip()));
+        match (token, ip) {
+            // Clients are kept and logged, so are named by a hash of their token, rather than
+            // the token itself.
+            (Some(token), ip) if self.by_token => Client {
+                key: format!(
+                    "token:{}",
+                    hash_data(token.as_bytes(), HashAlgorithm::Sha256)
+                ),
+                label: match ip {
+                    Some(ip) => format!("token holder at {}", ip),
+                    None => String::from("token holder"),
+                },
+            },
+            (_, Some(ip)) => Client {
+                key: ip.clone(),
+                label: ip,
+            },
+            _ => Client {
+                key: String::from("unknown"),
+                label: String::from("unknown"),
+            },
+        }
+    }
+}
+
+struct Client {
+    /// The name the client's buckets are kept under.
+    key: String,
+    /// How the client is described in the log, which never includes their token.
+    label: String,
+}
+
+/// A middleware rejecting requests from clients which have run out of requests.
+pub async fn limit_rate(
+    State(limiter): State<Arc<RateLimiter>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    if let Some(bucket) = limiter.limiter(request.method()) {
+        let client = limiter.client(&request);
+        if let Err(wait) = bucket.acquire(&client.key, Instant::now()) {
+            let seconds = wait.as_secs_f64().ceil().max(1.0) as u64;
+            tracing::warn!("rate limited {}, retry in {}s", client.label, seconds);
+            let mut response = OutpackError::new(
+                "TOO_MANY_REQUESTS",
+                format!("Too many requests, try again in {} seconds", seconds),
+                StatusCode::TOO_MANY_REQUESTS,
+            )
+            .into_response();
+            response
+                .headers_mut()
+                .insert(RETRY_AFTER, HeaderValue::from(seconds));
+            return response;
+        }
+    }
+    next.run(request).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter(requests: u32, per_seconds: f64) -> Limiter {
+        Limiter::new(Rate {
+            requests,
+            per_seconds,
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn buckets_are_refilled_over_time() {
+        let limiter = limiter(2, 10.0);
+        let start = Instant::now();
+        assert_eq!(limiter.acquire("a", start), Ok(()));
+        assert_eq!(limiter.acquire("a", start), Ok(()));
+        assert_eq!(limiter.acquire("a", start), Err(Duration::from_secs(5)));
+
+        // Other clients have allowances of their own.
+        assert_eq!(limiter.acquire("b", start), Ok(()));
+
+        let later = start + Duration::from_secs(4);
+        assert!(limiter.acquire("a", later).is_err());
+        let later = start + Duration::from_secs(5);
+        assert_eq!(limiter.acquire("a", later), Ok(()));
+        assert!(limiter.acquire("a", later).is_err());
+
+        // Buckets never hold more than the burst allowed.
+        let later = start + Duration::from_secs(1000);
+        assert_eq!(limiter.acquire("a", later), Ok(()));
+        assert_eq!(limiter.acquire("a", later), Ok(()));
+        assert!(limiter.acquire("a", later).is_err());
+    }
+
+    #[test]
+    fn least_recently_seen_clients_are_dropped() {
+        let mut limiter = limiter(2, 10.0);
+        limiter.max_clients = 3;
+        let start = Instant::now();
+        for (i, client) in ["a", "b", "c", "d", "e"].iter().enumerate() {
+            let now = start + Duration::from_millis(i as u64);
+            assert_eq!(limiter.acquire(client, now), Ok(()));
+            assert!(limiter.buckets.lock().unwrap().len() <= 3);
+        }
+        let buckets = limiter.buckets.lock().unwrap();
+        assert!(!buckets.contains_key("a"));
+        assert!(!buckets.contains_key("b"));
+        assert!(buckets.contains_key("e"));
+    }
+
+    #[test]
+    fn clients_are_not_named_by_their_token() {
+        let limiter = RateLimiter::new(&RateLimit {
+            reads: Some(Rate {
+                requests: 1,
+                per_seconds: 1.0,
+            }),
+            writes: None,
+            by_token: true,
+        })
+        .unwrap()
+        .unwrap();
+        let request = |token: &str| {
+            let mut request = Request::get("/")
+                .header(AUTHORIZATION, format!("Bearer {}", token))
+                .body(axum::body::Body::empty())
+                .unwrap();
+            request
+                .extensions_mut()
+                .insert(ConnectInfo(SocketAddr::from(([10, 0, 0, 1], 80))));
+            request
+        };
+
+        let client = limiter.client(&request("secret"));
+        assert!(!client.key.contains("secret"));
+        assert!(!client.label.contains("secret"));
+        assert_eq!(client.label, "token holder at ip:10.0.0.1");
+
+        // Each token still has its own allowance.
+        assert_eq!(limiter.client(&request("secret")).key, client.key);
+        assert_ne!(limiter.client(&request("other")).key, client.key);
+    }
+
+    #[test]
+    fn rates_must_be_positive() {
+        let rate = |requests, per_seconds| Rate {
+            requests,
+            per_seconds,
+        };
+        assert!(Limiter::new(rate(0, 1.0)).is_err());
+        assert!(Limiter::new(rate(1, 0.0)).is_err());
+        assert!(Limiter::new(rate(1, f64::NAN)).is_err());
+        assert!(Limiter::new(rate(1, 0.5)).is_ok());
+    }
+}
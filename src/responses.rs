@@ -1,68 +1,186 @@
 use std::io;
 use std::io::ErrorKind;
+use std::time::SystemTime;
 
 use axum::extract::rejection::JsonRejection;
 use axum::http::StatusCode;
 use serde::{Deserialize, Serialize};
 
+use crate::config::ConfigError;
 use crate::hash;
+use crate::query::QueryError;
+use crate::schema::{SchemaError, Violation};
+use crate::timeout::is_read_timeout;
+use crate::upload::TooLarge;
+use crate::utils::time_as_num;
 
-pub struct OutpackSuccess<T>(T);
+pub struct OutpackSuccess<T> {
+    data: T,
+    meta: Option<ListMeta>,
+}
 
 impl<T> From<T> for OutpackSuccess<T> {
-    fn from(inner: T) -> Self {
-        Self(inner)
+    fn from(data: T) -> Self {
+        Self { data, meta: None }
     }
 }
 
+impl<T> OutpackSuccess<Vec<T>> {
+    /// A successful response containing a list, along with its length and the time at which it
+    /// was read.
+    ///
+    /// `server_time` should be taken before reading the list, so that anything added while it is
+    /// being read is included when a client next asks for changes since that time.
+    pub fn list(data: Vec<T>, server_time: SystemTime) -> Self {
+        let meta = ListMeta {
+            count: data.len(),
+            server_time: time_as_num(server_time),
+        };
+        Self {
+            data,
+            meta: Some(meta),
+        }
+    }
+}
+
+/// Additional information included in responses containing a list.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct ListMeta {
+    /// The number of entries in the list.
+    pub count: usize,
+
+    /// The server's current time, in seconds since 1970-01-01.
+    pub server_time: f64,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct OutpackError {
     pub error: String,
     pub detail: String,
 
-    #[serde(skip_serializing, skip_deserializing)]
-    pub kind: Option<ErrorKind>,
+    /// The HTTP status of the response the error is returned in.
+    #[serde(skip, default = "default_status")]
+    pub status: StatusCode,
+
+    /// Where a document sent by the client failed validation, and how.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub violations: Vec<Violation>,
+}
+
+impl OutpackError {
+    pub fn new(
+        error: impl Into<String>,
+        detail: impl Into<String>,
+        status: StatusCode,
+    ) -> OutpackError {
+        OutpackError {
+            error: error.into(),
+            detail: detail.into(),
+            status,
+            violations: Vec::new(),
+        }
+    }
+}
+
+fn default_status() -> StatusCode {
+    StatusCode::INTERNAL_SERVER_ERROR
+}
+
+/// The status used for an I/O error of the given kind, when nothing more specific is known.
+fn status_for_kind(kind: ErrorKind) -> StatusCode {
+    match kind {
+        ErrorKind::NotFound => StatusCode::NOT_FOUND,
+        ErrorKind::InvalidInput => StatusCode::BAD_REQUEST,
+        ErrorKind::UnexpectedEof => StatusCode::BAD_REQUEST,
+        ErrorKind::AlreadyExists => StatusCode::CONFLICT,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+/// The status for a failure to read the repository's configuration. A configuration which is well
+/// formed but lacks a required field is reported separately from one that cannot be parsed.
+fn status_for_config_error(e: &ConfigError) -> StatusCode {
+    match e {
+        ConfigError::Missing(_) => StatusCode::NOT_FOUND,
+        ConfigError::Malformed(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        ConfigError::InvalidField(_) => StatusCode::UNPROCESSABLE_ENTITY,
+    }
 }
 
 impl From<io::Error> for OutpackError {
     fn from(e: io::Error) -> Self {
+        let status = match e.get_ref().and_then(|e| e.downcast_ref::<ConfigError>()) {
+            Some(config_error) => status_for_config_error(config_error),
+            None if e.get_ref().is_some_and(|e| is_read_timeout(e)) => StatusCode::REQUEST_TIMEOUT,
+            None if e.get_ref().is_some_and(|e| e.is::<TooLarge>()) => {
+                StatusCode::PAYLOAD_TOO_LARGE
+            }
+            None => status_for_kind(e.kind()),
+        };
+        let violations = match e.get_ref().and_then(|e| e.downcast_ref::<SchemaError>()) {
+            Some(schema_error) => schema_error.violations.clone(),
+            None => Vec::new(),
+        };
         OutpackError {
-            error: e.kind().to_string(),
-            detail: e.to_string(),
-            kind: Some(e.kind()),
+            violations,
+            ..OutpackError::new(e.kind().to_string(), e.to_string(), status)
         }
     }
 }
 
 impl From<hash::HashError> for OutpackError {
     fn from(e: hash::HashError) -> Self {
-        OutpackError {
-            // later this can be sorted out better; for now keep old
-            // behaviour
-            error: std::io::ErrorKind::InvalidInput.to_string(),
-            detail: e.explanation,
-            kind: Some(std::io::ErrorKind::InvalidInput),
-        }
+        // later this can be sorted out better; for now keep old
+        // behaviour
+        OutpackError::new(
+            std::io::ErrorKind::InvalidInput.to_string(),
+            e.explanation,
+            StatusCode::BAD_REQUEST,
+        )
     }
 }
 
 impl From<JsonRejection> for OutpackError {
     fn from(e: JsonRejection) -> Self {
-        OutpackError {
-            error: e.to_string(),
-            detail: e.body_text(),
-            kind: Some(std::io::ErrorKind::InvalidInput),
-        }
+        // A body that is too large is reported as such, and any other problem with the body as a
+        // bad request.
+        let status = match e.status() {
+            StatusCode::PAYLOAD_TOO_LARGE => StatusCode::PAYLOAD_TOO_LARGE,
+            _ if is_read_timeout(&e) => StatusCode::REQUEST_TIMEOUT,
+            _ => StatusCode::BAD_REQUEST,
+        };
+        OutpackError::new(e.to_string(), e.body_text(), status)
     }
 }
 
 impl From<git2::Error> for OutpackError {
     fn from(e: git2::Error) -> Self {
-        OutpackError {
-            error: e.message().to_string(),
-            detail: format!("{:?}", e.code()),
-            kind: Some(std::io::ErrorKind::Other),
+        // A root which isn't a git repository, or a branch or remote which doesn't exist, is a
+        // problem with the request rather than the server.
+        let missing = matches!(
+            e.class(),
+            git2::ErrorClass::Repository | git2::ErrorClass::Reference
+        );
+        if missing && e.code() == git2::ErrorCode::NotFound {
+            return OutpackError::new("NOT_FOUND", e.message().to_string(), StatusCode::NOT_FOUND);
         }
+        OutpackError::new(
+            e.message().to_string(),
+            format!("{:?}", e.code()),
+            StatusCode::INTERNAL_SERVER_ERROR,
+        )
+    }
+}
+
+impl From<QueryError> for OutpackError {
+    fn from(e: QueryError) -> Self {
+        // A query which cannot be parsed, or which uses something that cannot be evaluated, is a
+        // problem with the request.
+        let error = match e {
+            QueryError::ParseError(..) => "PARSE_ERROR",
+            QueryError::EvalError(..) => "EVAL_ERROR",
+        };
+        OutpackError::new(error, e.to_string(), StatusCode::BAD_REQUEST)
     }
 }
 
@@ -71,6 +189,8 @@ pub struct SuccessResponse<T> {
     pub status: String,
     pub data: T,
     pub errors: Option<Vec<OutpackError>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub meta: Option<ListMeta>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -84,8 +204,9 @@ impl<T: Serialize> axum::response::IntoResponse for OutpackSuccess<T> {
     fn into_response(self) -> axum::http::Response<axum::body::Body> {
         axum::Json(SuccessResponse {
             status: String::from("success"),
-            data: self.0,
+            data: self.data,
             errors: None,
+            meta: self.meta,
         })
         .into_response()
     }
@@ -93,13 +214,7 @@ impl<T: Serialize> axum::response::IntoResponse for OutpackSuccess<T> {
 
 impl axum::response::IntoResponse for OutpackError {
     fn into_response(self) -> axum::http::Response<axum::body::Body> {
-        let status = match self.kind {
-            Some(ErrorKind::NotFound) => StatusCode::NOT_FOUND,
-            Some(ErrorKind::InvalidInput) => StatusCode::BAD_REQUEST,
-            Some(ErrorKind::UnexpectedEof) => StatusCode::BAD_REQUEST,
-            _ => StatusCode::INTERNAL_SERVER_ERROR,
-        };
-
+        let status = self.status;
         let body = axum::Json(FailResponse {
             status: "failure".to_owned(),
             data: None,
@@ -109,3 +224,67 @@ impl axum::response::IntoResponse for OutpackError {
         (status, body).into_response()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::response::IntoResponse;
+
+    fn response_status(e: io::Error) -> StatusCode {
+        OutpackError::from(e).into_response().status()
+    }
+
+    #[test]
+    fn io_errors_have_sensible_default_statuses() {
+        let status = response_status(io::Error::new(ErrorKind::NotFound, "missing"));
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        let status = response_status(io::Error::new(ErrorKind::InvalidInput, "invalid"));
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        let status = response_status(io::Error::new(ErrorKind::AlreadyExists, "conflict"));
+        assert_eq!(status, StatusCode::CONFLICT);
+
+        let status = response_status(io::Error::new(ErrorKind::Other, "unknown"));
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[test]
+    fn config_errors_have_distinct_statuses() {
+        let missing = ConfigError::Missing(io::Error::new(ErrorKind::NotFound, "missing"));
+        assert_eq!(response_status(missing.into()), StatusCode::NOT_FOUND);
+
+        let e = serde_json::from_str::<serde_json::Value>("{").unwrap_err();
+        let malformed = ConfigError::Malformed(e);
+        assert_eq!(
+            response_status(malformed.into()),
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
+
+        let e = serde_json::from_str::<crate::config::Core>("{}").unwrap_err();
+        let invalid = ConfigError::InvalidField(e);
+        assert_eq!(
+            response_status(invalid.into()),
+            StatusCode::UNPROCESSABLE_ENTITY
+        );
+    }
+
+    #[test]
+    fn hash_errors_are_bad_requests() {
+        let e = "notahash".parse::<hash::Hash>().unwrap_err();
+        assert_eq!(OutpackError::from(e).status, StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn status_is_not_serialised() {
+        let e = OutpackError::new(
+            "PAYLOAD_TOO_LARGE",
+            "Too large",
+            StatusCode::PAYLOAD_TOO_LARGE,
+        );
+        assert_eq!(
+            serde_json::to_value(&e).unwrap(),
+            serde_json::json!({ "error": "PAYLOAD_TOO_LARGE", "detail": "Too large" })
+        );
+    }
+}
@@ -0,0 +1,466 @@
+//! Resumable uploads, for files that are too large to reliably send in a single request.
+//!
+//! An upload is started with `start_upload`, which returns a fresh id. Chunks of data are then
+//! appended in order using `append_chunk`, each of which must state the offset at which it
+//! begins; a client that loses its connection can use `get_upload` to find out how much data was
+//! received and carry on from there. Once all the data has been sent, `finish_upload` verifies the
+//! hash of the assembled file and moves it into the file store.
+//!
+//! Partial uploads are kept in `.outpack/uploads`. Uploads that have not received any data for
+//! longer than `UPLOAD_TIMEOUT` are considered abandoned and are removed.
+use axum::body::Bytes;
+use futures::{Stream, TryStreamExt};
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use std::{fs, io};
+use tempfile::TempPath;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::sync::{Mutex, OwnedMutexGuard};
+use tokio_util::io::StreamReader;
+
+use crate::store;
+use crate::upload::{TooLarge, Upload};
+
+pub const UPLOAD_TIMEOUT: Duration = Duration::from_secs(24 * 60 * 60);
+
+lazy_static! {
+    static ref UPLOAD_LOCKS: std::sync::Mutex<HashMap<String, Arc<Mutex<()>>>> =
+        std::sync::Mutex::new(HashMap::new());
+}
+
+/// A held lock on an upload, preventing any other change to it.
+struct UploadGuard {
+    id: String,
+    guard: Option<OwnedMutexGuard<()>>,
+}
+
+impl Drop for UploadGuard {
+    fn drop(&mut self) {
+        // The lock is forgotten once nothing holds or waits for it, so that the locks of
+        // finished uploads are not kept forever.
+        drop(self.guard.take());
+        let mut locks = UPLOAD_LOCKS.lock().unwrap();
+        if locks
+            .get(&self.id)
+            .is_some_and(|l| Arc::strong_count(l) == 1)
+        {
+            locks.remove(&self.id);
+        }
+    }
+}
+
+/// Wait for the lock of the upload with the given id. Each upload has a lock of its own, so
+/// waiting for one upload never holds up another.
+async fn lock_upload(id: &str) -> UploadGuard {
+    let lock = UPLOAD_LOCKS
+        .lock()
+        .unwrap()
+        .entry(id.to_owned())
+        .or_default()
+        .clone();
+    UploadGuard {
+        id: id.to_owned(),
+        guard: Some(lock.lock_owned().await),
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct UploadStatus {
+    pub id: String,
+    pub offset: u64,
+}
+
+fn uploads_directory(root: &Path) -> PathBuf {
+    root.join(".outpack").join("uploads")
+}
+
+fn not_found(id: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("upload '{}' does not exist", id),
+    )
+}
+
+fn is_expired(path: &Path, max_age: Duration) -> io::Result<bool> {
+    let modified = fs::metadata(path)?.modified()?;
+    let age = SystemTime::now()
+        .duration_since(modified)
+        .unwrap_or(Duration::ZERO);
+    Ok(age > max_age)
+}
+
+fn get_upload_path(root: &Path, id: &str) -> io::Result<PathBuf> {
+    // Ids are generated by us and only ever contain alphanumeric characters. Anything else could
+    // be an attempt to escape the uploads directory.
+    if id.is_empty() || !id.chars().all(|c| c.is_ascii_alphanumeric()) {
+        return Err(not_found(id));
+    }
+    let path = uploads_directory(root).join(id);
+    if !path.exists() {
+        return Err(not_found(id));
+    }
+    if is_expired(&path, UPLOAD_TIMEOUT)? {
+        fs::remove_file(&path)?;
+        return Err(not_found(id));
+    }
+    Ok(path)
+}
+
+/// Remove any partial uploads which have not been modified for longer than `max_age`.
+pub fn remove_expired_uploads(root: &Path, max_age: Duration) -> io::Result<()> {
+    let directory = uploads_directory(root);
+    if !directory.exists() {
+        return Ok(());
+    }
+    for entry in fs::read_dir(directory)? {
+        let path = entry?.path();
+        let removed = match is_expired(&path, max_age) {
+            Ok(true) => fs::remove_file(&path),
+            Ok(false) => Ok(()),
+            Err(e) => Err(e),
+        };
+        match removed {
+            // The upload may have been finished or cancelled since the directory was listed.
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            removed => removed?,
+        }
+    }
+    Ok(())
+}
+
+/// Start a new upload, returning its id.
+pub fn start_upload(root: &Path) -> io::Result<UploadStatus> {
+    remove_expired_uploads(root, UPLOAD_TIMEOUT)?;
+
+    let directory = uploads_directory(root);
+    fs::create_dir_all(&directory)?;
+    let (_, path) = tempfile::Builder::new()
+        .prefix("")
+        .rand_bytes(16)
+        .tempfile_in(directory)?
+        .keep()
+        .map_err(|e| e.error)?;
+
+    let id = path.file_name().unwrap().to_string_lossy().into_owned();
+    Ok(UploadStatus { id, offset: 0 })
+}
+
+/// Get the amount of data received so far for an upload.
+pub fn get_upload(root: &Path, id: &str) -> io::Result<UploadStatus> {
+    let path = get_upload_path(root, id)?;
+    Ok(UploadStatus {
+        id: id.to_owned(),
+        offset: fs::metadata(path)?.len(),
+    })
+}
+
+/// Check that a chunk starting at the given offset would come next in an upload, returning the
+/// path of the upload and the amount of data received so far.
+fn check_offset(root: &Path, id: &str, offset: u64) -> io::Result<(PathBuf, u64)> {
+    let path = get_upload_path(root, id)?;
+    let current = fs::metadata(&path)?.len();
+    if offset != current {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "Chunk for upload '{}' starts at offset {}, but expected offset {}",
+                id, offset, current
+            ),
+        ));
+    }
+    Ok((path, current))
+}
+
+/// Append a chunk of data to an upload.
+///
+/// The offset must match the amount of data received so far. This ensures chunks cannot be
+/// applied out of order, or applied twice if a client retries a request which had in fact
+/// succeeded. Each chunk is received into a file of its own before being appended, so a slow
+/// client does not hold up changes to the upload; of two chunks sent for the same offset, the
+/// one received last is rejected. If `max_size` is given, a chunk which would make the upload
+/// larger than that is rejected, and nothing of it is kept.
+pub async fn append_chunk<S>(
+    root: &Path,
+    id: &str,
+    offset: u64,
+    stream: S,
+    max_size: Option<u64>,
+) -> io::Result<UploadStatus>
+where
+    S: Stream<Item = Result<Bytes, axum::Error>> + Unpin,
+{
+    // Chunks which could never be applied are rejected before receiving any of them.
+    check_offset(root, id, offset)?;
+
+    let stream = stream.map_err(|err| io::Error::new(io::ErrorKind::Other, err));
+    let mut reader = StreamReader::new(stream);
+    // The name of the chunk's file is never a valid id, so it cannot be mistaken for an upload.
+    let chunk = tempfile::NamedTempFile::new_in(uploads_directory(root))?.into_temp_path();
+    let mut file = tokio::fs::File::create(&chunk).await?;
+    match max_size {
+        Some(max_size) => {
+            let remaining = max_size.saturating_sub(offset);
+            let written =
+                tokio::io::copy(&mut (&mut reader).take(remaining + 1), &mut file).await?;
+            if written > remaining {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    TooLarge(max_size),
+                ));
+            }
+        }
+        None => {
+            tokio::io::copy(&mut reader, &mut file).await?;
+        }
+    }
+    file.flush().await?;
+    drop(file);
+
+    let _guard = lock_upload(id).await;
+    let (path, current) = check_offset(root, id, offset)?;
+    let mut file = tokio::fs::OpenOptions::new()
+        .append(true)
+        .open(&path)
+        .await?;
+    let copied = tokio::io::copy(&mut tokio::fs::File::open(&chunk).await?, &mut file).await;
+    if let Err(e) = copied {
+        // Keep the upload as it was, so that the chunk can be sent again.
+        file.set_len(current).await?;
+        return Err(e);
+    }
+    file.flush().await?;
+
+    get_upload(root, id)
+}
+
+/// Complete an upload, moving it into the file store.
+///
+/// If the assembled file does not match the expected hash it is discarded, and the upload must
+/// be started again from scratch.
+pub async fn finish_upload(root: &Path, id: &str, hash: &str) -> io::Result<()> {
+    let _guard = lock_upload(id).await;
+    let root = root.to_owned();
+    let id = id.to_owned();
+    let hash = hash.to_owned();
+    tokio::task::spawn_blocking(move || {
+        let path = get_upload_path(&root, &id)?;
+        store::put_file(&root, Upload::File(TempPath::from_path(path)), &hash)
+    })
+    .await
+    .unwrap()
+}
+
+/// Abandon an upload, discarding any data received so far.
+pub async fn cancel_upload(root: &Path, id: &str) -> io::Result<()> {
+    let _guard = lock_upload(id).await;
+    let path = get_upload_path(root, id)?;
+    fs::remove_file(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hash::{hash_data, HashAlgorithm};
+    use crate::test_utils::tests::get_empty_outpack_root;
+
+    fn chunk(data: &'static [u8]) -> impl Stream<Item = Result<Bytes, axum::Error>> + Unpin {
+        futures::stream::iter(vec![Ok(Bytes::from_static(data))])
+    }
+
+    #[tokio::test]
+    async fn can_upload_in_two_chunks() {
+        let root = get_empty_outpack_root();
+        let hash = hash_data(b"Hello, World!", HashAlgorithm::Sha256).to_string();
+
+        let status = start_upload(&root).unwrap();
+        assert_eq!(status.offset, 0);
+
+        let res = append_chunk(&root, &status.id, 0, chunk(b"Hello, "), None)
+            .await
+            .unwrap();
+        assert_eq!(res.offset, 7);
+        let res = append_chunk(&root, &status.id, 7, chunk(b"World!"), None)
+            .await
+            .unwrap();
+        assert_eq!(res.offset, 13);
+        assert_eq!(get_upload(&root, &status.id).unwrap().offset, 13);
+
+        finish_upload(&root, &status.id, &hash).await.unwrap();
+        assert!(store::file_exists(&root, &hash).unwrap());
+        assert_eq!(
+            fs::read(store::file_path(&root, &hash).unwrap()).unwrap(),
+            b"Hello, World!"
+        );
+
+        let res = get_upload(&root, &status.id);
+        assert_eq!(res.unwrap_err().kind(), io::ErrorKind::NotFound);
+    }
+
+    #[tokio::test]
+    async fn rejects_out_of_order_chunks() {
+        let root = get_empty_outpack_root();
+        let status = start_upload(&root).unwrap();
+
+        append_chunk(&root, &status.id, 0, chunk(b"Hello, "), None)
+            .await
+            .unwrap();
+
+        let res = append_chunk(&root, &status.id, 13, chunk(b"World!"), None).await;
+        let err = res.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "Chunk for upload '{}' starts at offset 13, but expected offset 7",
+                status.id
+            )
+        );
+
+        // Retrying a chunk that has already been applied is also rejected.
+        let res = append_chunk(&root, &status.id, 0, chunk(b"Hello, "), None).await;
+        assert_eq!(res.unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(get_upload(&root, &status.id).unwrap().offset, 7);
+    }
+
+    #[tokio::test]
+    async fn limits_size_of_uploads() {
+        let root = get_empty_outpack_root();
+        let status = start_upload(&root).unwrap();
+
+        append_chunk(&root, &status.id, 0, chunk(b"Hello, "), Some(10))
+            .await
+            .unwrap();
+        let res = append_chunk(&root, &status.id, 7, chunk(b"World!"), Some(10)).await;
+        let err = res.unwrap_err();
+        assert_eq!(err.to_string(), "Uploads may not be larger than 10 bytes");
+        assert!(err.get_ref().unwrap().is::<TooLarge>());
+
+        // Nothing of the rejected chunk is kept, so a smaller one can still be sent.
+        assert_eq!(get_upload(&root, &status.id).unwrap().offset, 7);
+        let res = append_chunk(&root, &status.id, 7, chunk(b"Wor"), Some(10))
+            .await
+            .unwrap();
+        assert_eq!(res.offset, 10);
+    }
+
+    #[tokio::test]
+    async fn concurrent_chunks_are_applied_one_at_a_time() {
+        let root = get_empty_outpack_root();
+        let status = start_upload(&root).unwrap();
+
+        // The first chunk is still being received when the second, for the same offset, is
+        // applied. The first is then rejected once it has been received.
+        let (tx, rx) = futures::channel::mpsc::unbounded();
+        let first = append_chunk(&root, &status.id, 0, rx, None);
+        let second = async {
+            let second = append_chunk(&root, &status.id, 0, chunk(b"World!"), None).await;
+            tx.unbounded_send(Ok(Bytes::from_static(b"Hello, ")))
+                .unwrap();
+            tx.close_channel();
+            second
+        };
+        let (first, second) = tokio::join!(first, second);
+
+        assert_eq!(second.unwrap().offset, 6);
+        assert_eq!(first.unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(get_upload(&root, &status.id).unwrap().offset, 6);
+        assert!(UPLOAD_LOCKS.lock().unwrap().get(&status.id).is_none());
+    }
+
+    #[tokio::test]
+    async fn uploads_can_be_cancelled_while_receiving_chunks() {
+        let root = get_empty_outpack_root();
+        let status = start_upload(&root).unwrap();
+
+        let (tx, rx) = futures::channel::mpsc::unbounded();
+        let append = append_chunk(&root, &status.id, 0, rx, None);
+        let cancel = async {
+            let cancel = cancel_upload(&root, &status.id).await;
+            tx.unbounded_send(Ok(Bytes::from_static(b"Hello, ")))
+                .unwrap();
+            tx.close_channel();
+            cancel
+        };
+        let (append, cancel) = tokio::join!(append, cancel);
+
+        cancel.unwrap();
+        assert_eq!(append.unwrap_err().kind(), io::ErrorKind::NotFound);
+        // Only the cancelled upload's chunk was in the uploads directory, and it is removed too.
+        assert_eq!(fs::read_dir(uploads_directory(&root)).unwrap().count(), 0);
+    }
+
+    #[tokio::test]
+    async fn finishing_validates_hash() {
+        let root = get_empty_outpack_root();
+        let hash = hash_data(b"Hello, World!", HashAlgorithm::Sha256).to_string();
+        let status = start_upload(&root).unwrap();
+
+        append_chunk(&root, &status.id, 0, chunk(b"Hello, "), None)
+            .await
+            .unwrap();
+
+        let res = finish_upload(&root, &status.id, &hash).await;
+        assert_regex!(
+            res.unwrap_err().to_string(),
+            "^Expected hash '.*' but found"
+        );
+        assert!(!store::file_exists(&root, &hash).unwrap());
+
+        // The corrupt upload is discarded.
+        let res = get_upload(&root, &status.id);
+        assert_eq!(res.unwrap_err().kind(), io::ErrorKind::NotFound);
+    }
+
+    #[tokio::test]
+    async fn can_cancel_upload() {
+        let root = get_empty_outpack_root();
+        let status = start_upload(&root).unwrap();
+        cancel_upload(&root, &status.id).await.unwrap();
+
+        let res = get_upload(&root, &status.id);
+        assert_eq!(res.unwrap_err().kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn rejects_invalid_ids() {
+        let root = get_empty_outpack_root();
+        let res = get_upload(&root, "../config.json");
+        assert_eq!(
+            res.unwrap_err().to_string(),
+            "upload '../config.json' does not exist"
+        );
+    }
+
+    #[test]
+    fn can_remove_expired_uploads() {
+        let root = get_empty_outpack_root();
+        let old = start_upload(&root).unwrap();
+        let path = uploads_directory(&root).join(&old.id);
+        let mtime = filetime::FileTime::from_system_time(SystemTime::now() - 2 * UPLOAD_TIMEOUT);
+        filetime::set_file_mtime(&path, mtime).unwrap();
+
+        let fresh = start_upload(&root).unwrap();
+
+        assert!(!path.exists());
+        let res = get_upload(&root, &old.id);
+        assert_eq!(res.unwrap_err().kind(), io::ErrorKind::NotFound);
+        assert_eq!(get_upload(&root, &fresh.id).unwrap().offset, 0);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn removing_expired_uploads_ignores_vanished_entries() {
+        let root = get_empty_outpack_root();
+        let directory = uploads_directory(&root);
+        fs::create_dir_all(&directory).unwrap();
+        // An entry whose target is gone, like an upload finished after the directory was listed.
+        std::os::unix::fs::symlink(directory.join("gone"), directory.join("vanished")).unwrap();
+
+        remove_expired_uploads(&root, Duration::ZERO).unwrap();
+        assert!(start_upload(&root).is_ok());
+    }
+}
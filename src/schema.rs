@@ -0,0 +1,336 @@
+//! Validation of documents against the outpack JSON schemas.
+//!
+//! The schemas are bundled into the binary at compile time, so validation does not depend on
+//! the server's working directory.
+use jsonschema::{Draft, JSONSchema, SchemaResolver, SchemaResolverError};
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::io;
+use std::sync::Arc;
+use url::Url;
+
+pub(crate) const OUTPACK_SCHEMAS: &[(&str, &str)] = &[
+    ("config.json", include_str!("../schema/outpack/config.json")),
+    ("git.json", include_str!("../schema/outpack/git.json")),
+    ("hash.json", include_str!("../schema/outpack/hash.json")),
+    (
+        "location.json",
+        include_str!("../schema/outpack/location.json"),
+    ),
+    (
+        "metadata.json",
+        include_str!("../schema/outpack/metadata.json"),
+    ),
+    (
+        "packet-id.json",
+        include_str!("../schema/outpack/packet-id.json"),
+    ),
+];
+
+/// The schemas of the server's responses, which are not part of the outpack schema itself.
+pub(crate) const SERVER_SCHEMAS: &[(&str, &str)] = &[
+    (
+        "branch-response.json",
+        include_str!("../schema/server/branch-response.json"),
+    ),
+    ("branch.json", include_str!("../schema/server/branch.json")),
+    (
+        "clean-temp.json",
+        include_str!("../schema/server/clean-temp.json"),
+    ),
+    (
+        "encoded-hash.json",
+        include_str!("../schema/server/encoded-hash.json"),
+    ),
+    (
+        "encoded-hashes.json",
+        include_str!("../schema/server/encoded-hashes.json"),
+    ),
+    (
+        "error-detail.json",
+        include_str!("../schema/server/error-detail.json"),
+    ),
+    (
+        "file-check.json",
+        include_str!("../schema/server/file-check.json"),
+    ),
+    (
+        "file-info.json",
+        include_str!("../schema/server/file-info.json"),
+    ),
+    ("gc.json", include_str!("../schema/server/gc.json")),
+    (
+        "git-fetch.json",
+        include_str!("../schema/server/git-fetch.json"),
+    ),
+    (
+        "git-status.json",
+        include_str!("../schema/server/git-status.json"),
+    ),
+    (
+        "hash-check.json",
+        include_str!("../schema/server/hash-check.json"),
+    ),
+    ("hash.json", include_str!("../schema/server/hash.json")),
+    ("hashes.json", include_str!("../schema/server/hashes.json")),
+    ("ids.json", include_str!("../schema/server/ids.json")),
+    (
+        "invalid-files.json",
+        include_str!("../schema/server/invalid-files.json"),
+    ),
+    ("list.json", include_str!("../schema/server/list.json")),
+    (
+        "location-config.json",
+        include_str!("../schema/server/location-config.json"),
+    ),
+    (
+        "location-configs.json",
+        include_str!("../schema/server/location-configs.json"),
+    ),
+    (
+        "location.json",
+        include_str!("../schema/server/location.json"),
+    ),
+    (
+        "locations.json",
+        include_str!("../schema/server/locations.json"),
+    ),
+    (
+        "metadata-batch.json",
+        include_str!("../schema/server/metadata-batch.json"),
+    ),
+    (
+        "metadata-search.json",
+        include_str!("../schema/server/metadata-search.json"),
+    ),
+    (
+        "null-response.json",
+        include_str!("../schema/server/null-response.json"),
+    ),
+    (
+        "packet-id.json",
+        include_str!("../schema/server/packet-id.json"),
+    ),
+    (
+        "packet-size.json",
+        include_str!("../schema/server/packet-size.json"),
+    ),
+    (
+        "packet-summary.json",
+        include_str!("../schema/server/packet-summary.json"),
+    ),
+    (
+        "parameters.json",
+        include_str!("../schema/server/parameters.json"),
+    ),
+    ("pull.json", include_str!("../schema/server/pull.json")),
+    (
+        "references.json",
+        include_str!("../schema/server/references.json"),
+    ),
+    (
+        "reindex.json",
+        include_str!("../schema/server/reindex.json"),
+    ),
+    (
+        "response-failure.json",
+        include_str!("../schema/server/response-failure.json"),
+    ),
+    (
+        "response-success.json",
+        include_str!("../schema/server/response-success.json"),
+    ),
+    ("root.json", include_str!("../schema/server/root.json")),
+    ("roots.json", include_str!("../schema/server/roots.json")),
+    (
+        "signed-url.json",
+        include_str!("../schema/server/signed-url.json"),
+    ),
+    (
+        "sync-diff.json",
+        include_str!("../schema/server/sync-diff.json"),
+    ),
+    (
+        "sync-plan.json",
+        include_str!("../schema/server/sync-plan.json"),
+    ),
+    ("upload.json", include_str!("../schema/server/upload.json")),
+];
+
+/// Get the text of a bundled schema, of the server or of outpack itself. References within a
+/// schema are to other schemas by name alone, so resolve relative to wherever it is served from.
+pub fn get_schema_text(name: &str) -> io::Result<&'static str> {
+    if name.is_empty() || name.contains(['/', '\\']) || name.split('.').any(str::is_empty) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Invalid schema name '{}'", name),
+        ));
+    }
+    SERVER_SCHEMAS
+        .iter()
+        .chain(OUTPACK_SCHEMAS)
+        .find(|(n, _)| *n == name)
+        .map(|(_, contents)| *contents)
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("Schema '{}' does not exist", name),
+            )
+        })
+}
+
+fn get_outpack_schema(name: &str) -> Option<Value> {
+    OUTPACK_SCHEMAS
+        .iter()
+        .find(|(n, _)| *n == name)
+        .map(|(_, contents)| serde_json::from_str(contents).expect("Bundled schema is valid json"))
+}
+
+/// Resolves references between schemas, such as `"$ref": "hash.json"`, to the bundled schemas.
+struct BundledSchemaResolver;
+
+impl SchemaResolver for BundledSchemaResolver {
+    fn resolve(
+        &self,
+        _root_schema: &Value,
+        _url: &Url,
+        original_reference: &str,
+    ) -> Result<Arc<Value>, SchemaResolverError> {
+        get_outpack_schema(original_reference)
+            .map(Arc::new)
+            .ok_or_else(|| anyhow::anyhow!("Unknown schema '{}'", original_reference))
+    }
+}
+
+fn compile_outpack_schema(name: &str) -> JSONSchema {
+    let schema = get_outpack_schema(name).expect("Bundled schema exists");
+    JSONSchema::options()
+        .with_draft(Draft::Draft7)
+        .with_resolver(BundledSchemaResolver)
+        .compile(&schema)
+        .expect("Bundled schema is valid")
+}
+
+lazy_static! {
+    static ref METADATA_SCHEMA: JSONSchema = compile_outpack_schema("metadata.json");
+}
+
+/// One way in which a document does not conform to a schema.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Violation {
+    /// The JSON Pointer to the offending part of the document, which is empty for the document
+    /// as a whole.
+    pub pointer: String,
+    pub message: String,
+}
+
+/// A document which does not conform to a schema. This is returned as the inner value of an
+/// `InvalidInput` io error, from which the API picks out the individual violations.
+#[derive(Debug)]
+pub struct SchemaError {
+    description: String,
+    pub violations: Vec<Violation>,
+}
+
+impl std::fmt::Display for SchemaError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:", self.description)?;
+        for violation in &self.violations {
+            let pointer = if violation.pointer.is_empty() {
+                "/"
+            } else {
+                &violation.pointer
+            };
+            write!(f, "\n - {}: {}", pointer, violation.message)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for SchemaError {}
+
+/// Validate packet metadata against the outpack metadata schema.
+///
+/// If the metadata is invalid, the error lists every violation found along with its location
+/// in the document.
+pub fn validate_metadata(metadata: &Value) -> io::Result<()> {
+    METADATA_SCHEMA.validate(metadata).map_err(|errors| {
+        let violations = errors
+            .map(|e| Violation {
+                pointer: e.instance_path.to_string(),
+                message: e.to_string(),
+            })
+            .collect();
+        let error = SchemaError {
+            description: String::from("Metadata does not conform to the outpack schema"),
+            violations,
+        };
+        io::Error::new(io::ErrorKind::InvalidInput, error)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn read_example_metadata() -> Value {
+        let contents =
+            fs::read_to_string("tests/example/.outpack/metadata/20170818-164830-33e0ab01").unwrap();
+        serde_json::from_str(&contents).unwrap()
+    }
+
+    #[test]
+    fn can_get_schema_text() {
+        assert!(get_schema_text("root.json")
+            .unwrap()
+            .contains("schema_version"));
+        assert!(get_schema_text("metadata.json").is_ok());
+
+        let err = get_schema_text("missing.json").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        for name in [
+            "",
+            "../config.json",
+            "..",
+            "outpack/hash.json",
+            "a\\b.json",
+            ".json",
+        ] {
+            let err = get_schema_text(name).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput, "{}", name);
+        }
+    }
+
+    #[test]
+    fn can_validate_metadata() {
+        validate_metadata(&read_example_metadata()).unwrap();
+    }
+
+    #[test]
+    fn reports_all_violations() {
+        let mut metadata = read_example_metadata();
+        metadata.as_object_mut().unwrap().remove("name");
+        metadata["files"][0]["hash"] = "badhash".into();
+
+        let err = validate_metadata(&metadata).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(
+            err.to_string(),
+            "Metadata does not conform to the outpack schema:\n\
+             \x20- /files/0/hash: \"badhash\" does not match \
+             \"^(md5|sha1|sha256|sha384|sha512):([0-9a-f]{16,})$\"\n\
+             \x20- /: \"name\" is a required property"
+        );
+
+        let violations = &err
+            .get_ref()
+            .unwrap()
+            .downcast_ref::<SchemaError>()
+            .unwrap()
+            .violations;
+        let pointers: Vec<&str> = violations.iter().map(|v| v.pointer.as_str()).collect();
+        assert_eq!(pointers, vec!["/files/0/hash", ""]);
+    }
+}
@@ -0,0 +1,166 @@
+//! Graceful shutdown of the API server.
+//!
+//! When asked to stop, the server stops accepting new connections but gives requests that are
+//! already in progress, such as a large upload or download, some time to complete. Any requests
+//! still running once the grace period has elapsed are aborted.
+use axum::Router;
+use std::future::{Future, IntoFuture};
+use std::io;
+use std::time::Duration;
+use tokio::net::TcpListener;
+
+/// Wait for the process to receive a SIGINT or SIGTERM.
+///
+/// This must be called before any other threads are started, as it blocks delivery of these
+/// signals to the calling thread and to any threads subsequently started from it. The signals are
+/// then received by a dedicated thread instead.
+#[cfg(unix)]
+pub fn shutdown_signal() -> impl Future<Output = ()> {
+    let (tx, rx) = tokio::sync::oneshot::channel();
+
+    // SAFETY: the signal set is fully initialised by sigemptyset before use, and the pointers
+    // passed to these functions are valid for the duration of each call.
+    unsafe {
+        let mut set: libc::sigset_t = std::mem::zeroed();
+        libc::sigemptyset(&mut set);
+        libc::sigaddset(&mut set, libc::SIGINT);
+        libc::sigaddset(&mut set, libc::SIGTERM);
+        libc::pthread_sigmask(libc::SIG_BLOCK, &set, std::ptr::null_mut());
+
+        std::thread::spawn(move || {
+            let mut signal = 0;
+            libc::sigwait(&set, &mut signal);
+            let _ = tx.send(signal);
+        });
+    }
+
+    async move {
+        if let Ok(signal) = rx.await {
+            tracing::info!("received signal {}, shutting down", signal);
+        }
+    }
+}
+
+#[cfg(not(unix))]
+pub fn shutdown_signal() -> impl Future<Output = ()> {
+    std::future::pending()
+}
+
+/// Serve the application until the `shutdown` future completes, then wait up to `grace_period`
+/// for in-flight requests to finish.
+///
+/// Connections are handled by tasks of their own, so any requests still in progress when this
+/// returns are only aborted once the runtime is shut down.
+pub async fn serve_with_shutdown(
+    listener: TcpListener,
+    app: Router,
+    shutdown: impl Future<Output = ()> + Send + 'static,
+    grace_period: Duration,
+) -> io::Result<()> {
+    let (started_tx, started_rx) = tokio::sync::oneshot::channel();
+    // The address of each client is made available to the application, for rate limiting.
+    let app = app.into_make_service_with_connect_info::<std::net::SocketAddr>();
+    let server = axum::serve(listener, app).with_graceful_shutdown(async move {
+        shutdown.await;
+        let _ = started_tx.send(());
+    });
+
+    let deadline = async move {
+        match started_rx.await {
+            Ok(()) => tokio::time::sleep(grace_period).await,
+            // The server stopped without being asked to, so there is nothing to wait for.
+            Err(_) => std::future::pending().await,
+        }
+    };
+
+    tokio::select! {
+        result = server.into_future() => result,
+        _ = deadline => {
+            tracing::warn!(
+                "requests still in progress after {:?}, aborting them",
+                grace_period
+            );
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::routing::get;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+    use tokio::sync::oneshot;
+
+    async fn slow_handler() -> &'static str {
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        "done"
+    }
+
+    /// Start a server with a single slow endpoint, returning its address, a channel used to
+    /// trigger its shutdown and a handle to the running server.
+    async fn start_server(
+        grace_period: Duration,
+    ) -> (
+        std::net::SocketAddr,
+        oneshot::Sender<()>,
+        tokio::task::JoinHandle<io::Result<()>>,
+    ) {
+        let app = Router::new().route("/slow", get(slow_handler));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (tx, rx) = oneshot::channel();
+        let shutdown = async move {
+            let _ = rx.await;
+        };
+        let handle = tokio::spawn(serve_with_shutdown(listener, app, shutdown, grace_period));
+        (addr, tx, handle)
+    }
+
+    async fn start_request(addr: std::net::SocketAddr) -> TcpStream {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(b"GET /slow HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        stream
+    }
+
+    async fn read_response(mut stream: TcpStream) -> String {
+        let mut response = String::new();
+        let _ = stream.read_to_string(&mut response).await;
+        response
+    }
+
+    #[tokio::test]
+    async fn in_flight_requests_complete_during_grace_period() {
+        let (addr, shutdown, server) = start_server(Duration::from_secs(10)).await;
+
+        let stream = start_request(addr).await;
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        shutdown.send(()).unwrap();
+
+        let response = read_response(stream).await;
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+        assert!(response.ends_with("done"), "{}", response);
+
+        server.await.unwrap().unwrap();
+
+        // No new connections are accepted once the server has shut down.
+        assert!(TcpStream::connect(addr).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn does_not_wait_longer_than_grace_period() {
+        let (addr, shutdown, server) = start_server(Duration::from_millis(50)).await;
+
+        let _stream = start_request(addr).await;
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let start = std::time::Instant::now();
+        shutdown.send(()).unwrap();
+
+        server.await.unwrap().unwrap();
+        assert!(start.elapsed() < Duration::from_millis(300));
+    }
+}
@@ -1,19 +1,127 @@
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::ffi::OsStr;
+use std::hash::{Hash, Hasher};
 use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
 use std::{fs, io};
-use tempfile::tempdir_in;
 use walkdir::{DirEntry, WalkDir};
 
+use crate::config::{self, StoreLayout};
 use crate::hash;
+use crate::index;
 use crate::upload::Upload;
+use crate::utils::time_as_num;
 
-pub fn file_path(root: &Path, hash: &str) -> io::Result<PathBuf> {
-    let parsed: hash::Hash = hash.parse().map_err(hash::hash_error_to_io_error)?;
-    Ok(root
+lazy_static! {
+    static ref LAYOUT_CACHE: Mutex<HashMap<PathBuf, StoreLayout>> = Mutex::new(HashMap::new());
+    static ref TEMP_DIRS: Mutex<HashMap<PathBuf, PathBuf>> = Mutex::new(HashMap::new());
+    /// When stored files, by path, were last wanted again after being added, which `gc` treats as
+    /// if they had just been added. A file's modification time is when it was added to the store
+    /// and is served as such, so it is not changed.
+    static ref WANTED: Mutex<HashMap<PathBuf, SystemTime>> = Mutex::new(HashMap::new());
+}
+
+/// Get the layout of the repository's file store, from its configuration.
+///
+/// The configuration is only read the first time the layout is needed. Changing the layout by
+/// editing the configuration would leave every file where it no longer belongs, so afterwards only
+/// `migrate_layout` and `use_layout` change it. A repository without any configuration uses the
+/// default layout.
+fn get_layout(root: &Path) -> io::Result<StoreLayout> {
+    if let Some(layout) = LAYOUT_CACHE.lock().unwrap().get(root) {
+        return Ok(*layout);
+    }
+
+    let layout = if root.join(".outpack").join("config.json").exists() {
+        config::read_config(root)?.server.store_layout
+    } else {
+        StoreLayout::default()
+    };
+    use_layout(root, layout);
+    Ok(layout)
+}
+
+/// Use the given layout for the repository's file store from now on, such as the one in the
+/// configuration the server was started with.
+pub fn use_layout(root: &Path, layout: StoreLayout) {
+    LAYOUT_CACHE.lock().unwrap().insert(root.to_owned(), layout);
+}
+
+/// Write the temporary files of the repository's file store in the given directory from now on,
+/// such as the configured `upload_dir`. Files are moved from there into the store, so the directory
+/// must be on the same filesystem as it, which is checked by moving a file across.
+pub fn use_temp_dir(root: &Path, directory: &Path) -> io::Result<()> {
+    let store = root.join(".outpack").join("files");
+    fs::create_dir_all(&store)?;
+    let probe = tempfile::NamedTempFile::new_in(directory)?.into_temp_path();
+    let target = tempfile::NamedTempFile::new_in(&store)?.into_temp_path();
+    fs::rename(&probe, &target).map_err(|e| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "Files cannot be moved from '{}' into the file store, which must be on the same filesystem: {}",
+                directory.display(),
+                e
+            ),
+        )
+    })?;
+    TEMP_DIRS
+        .lock()
+        .unwrap()
+        .insert(root.to_owned(), directory.to_owned());
+    Ok(())
+}
+
+/// The directory in which temporary files are written before being moved into the store, which
+/// is the store's own directory unless `use_temp_dir` has been called. Temporary files are never
+/// written to the root, which belongs to the user.
+pub fn temp_dir(root: &Path) -> io::Result<PathBuf> {
+    if let Some(directory) = TEMP_DIRS.lock().unwrap().get(root) {
+        return Ok(directory.clone());
+    }
+    let store = root.join(".outpack").join("files");
+    fs::create_dir_all(&store)?;
+    Ok(store)
+}
+
+fn layout_path(root: &Path, layout: StoreLayout, hash: &hash::Hash) -> io::Result<PathBuf> {
+    let prefix_length = layout.depth * layout.width;
+    if hash.value.len() <= prefix_length || !hash.value.is_ascii() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Hash '{}' is too short for the file store's layout", hash),
+        ));
+    }
+
+    let mut path = root
         .join(".outpack")
         .join("files")
-        .join(parsed.algorithm.to_string())
-        .join(&parsed.value[..2])
-        .join(&parsed.value[2..]))
+        .join(hash.algorithm.to_string());
+    for i in 0..layout.depth {
+        path.push(&hash.value[i * layout.width..(i + 1) * layout.width]);
+    }
+    path.push(&hash.value[prefix_length..]);
+    Ok(path)
+}
+
+pub fn file_path(root: &Path, hash: &str) -> io::Result<PathBuf> {
+    let parsed: hash::Hash = hash.parse().map_err(hash::hash_error_to_io_error)?;
+    layout_path(root, get_layout(root)?, &parsed)
+}
+
+/// The extension of the optional gzip-compressed copy of a file, kept alongside it in the store.
+const COMPRESSED_EXTENSION: &str = "gz";
+
+/// Get the path of the gzip-compressed copy of a file, which may or may not exist.
+///
+/// Compressed copies are not created by the server itself, but if one is present it may be
+/// served in place of the file to clients that accept gzip encoding.
+pub fn compressed_file_path(root: &Path, hash: &str) -> io::Result<PathBuf> {
+    Ok(file_path(root, hash)?.with_extension(COMPRESSED_EXTENSION))
 }
 
 pub fn file_exists(root: &Path, hash: &str) -> io::Result<bool> {
@@ -21,10 +129,40 @@ pub fn file_exists(root: &Path, hash: &str) -> io::Result<bool> {
     Ok(std::fs::metadata(path).is_ok())
 }
 
+/// Check whether a file is in the store, and if it is, mark it as wanted, so that it is protected
+/// from `gc` for the grace period as if it had been uploaded again.
+pub fn want_file(root: &Path, hash: &str) -> io::Result<bool> {
+    let path = file_path(root, hash)?;
+    let _guard = file_lock(&path).lock().unwrap_or_else(|e| e.into_inner());
+    if fs::metadata(&path).is_err() {
+        return Ok(false);
+    }
+    mark_wanted(&path);
+    Ok(true)
+}
+
+/// Record that the stored file at `path` is wanted now. The caller must hold the file's lock, so
+/// that `gc` cannot remove the file between its being found and its being marked.
+fn mark_wanted(path: &Path) {
+    WANTED
+        .lock()
+        .unwrap()
+        .insert(path.to_owned(), SystemTime::now());
+}
+
+/// Get the wanted files that are missing from the store.
+///
+/// The result is sorted and free of duplicates, whatever order the wanted hashes are given in.
+/// The files which are present are marked as wanted, since a client which is told that it need not
+/// upload them will go on to add a packet using them, and `gc` must not remove them in between.
 pub fn get_missing_files(root: &Path, wanted: &[String]) -> io::Result<Vec<String>> {
+    let mut wanted = wanted.to_vec();
+    wanted.sort();
+    wanted.dedup();
+
     wanted
         .iter()
-        .filter_map(|h| match file_exists(root, h) {
+        .filter_map(|h| match want_file(root, h) {
             Ok(false) => Some(Ok(h.clone())),
             Ok(true) => None,
             Err(e) => Some(Err(e)),
@@ -32,18 +170,149 @@ pub fn get_missing_files(root: &Path, wanted: &[String]) -> io::Result<Vec<Strin
         .collect()
 }
 
+#[derive(Serialize, Deserialize, Debug, PartialEq, Default)]
+pub struct FileCheck {
+    pub missing: Vec<String>,
+    pub corrupt: Vec<String>,
+}
+
+/// Check which of the wanted files are missing from the store, and which are present but have
+/// contents that no longer match their hash.
+///
+/// As with `get_missing_files`, both lists are sorted and free of duplicates. Unlike it, this
+/// needs to re-hash every file that is present, so it is considerably more expensive.
+pub fn check_files(root: &Path, wanted: &[String]) -> io::Result<FileCheck> {
+    let mut wanted = wanted.to_vec();
+    wanted.sort();
+    wanted.dedup();
+    let mut result = FileCheck::default();
+    for h in &wanted {
+        let path = file_path(root, h)?;
+        if !path.exists() {
+            result.missing.push(h.clone());
+            continue;
+        }
+        match hash::validate_hash_file(&path, h) {
+            Ok(()) => {}
+            Err(e) if e.kind == hash::HashErrorKind::HashesDontMatch => {
+                result.corrupt.push(h.clone());
+            }
+            Err(e) => return Err(hash::hash_error_to_io_error(e)),
+        }
+    }
+    Ok(result)
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct FileInfo {
+    pub present: bool,
+    /// The size of the file in bytes, if it is present.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub size: Option<u64>,
+    /// When the file was last modified, in seconds since the epoch, if it is present.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub modified: Option<f64>,
+}
+
+/// Get the size and modification time of each of the wanted files, recording those which are not
+/// in the store as absent. This only reads the files' metadata, not their contents.
+pub fn get_file_info(root: &Path, wanted: &[String]) -> io::Result<BTreeMap<String, FileInfo>> {
+    wanted
+        .iter()
+        .map(|h| {
+            let info = match fs::metadata(file_path(root, h)?) {
+                Ok(metadata) => FileInfo {
+                    present: true,
+                    size: Some(metadata.len()),
+                    modified: Some(time_as_num(metadata.modified()?)),
+                },
+                Err(e) if e.kind() == io::ErrorKind::NotFound => FileInfo {
+                    present: false,
+                    size: None,
+                    modified: None,
+                },
+                Err(e) => return Err(e),
+            };
+            Ok((h.clone(), info))
+        })
+        .collect()
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct HashCheck {
+    pub expected: String,
+    pub found: String,
+    pub matches: bool,
+}
+
+/// Hash an uploaded file and compare it to the expected hash, without adding it to the store.
+///
+/// The upload is discarded afterwards, whether or not the hashes match.
+pub fn check_file_hash(file: impl Into<Upload>, hash: &str) -> io::Result<HashCheck> {
+    let expected: hash::Hash = hash.parse().map_err(hash::hash_error_to_io_error)?;
+    let found = match file.into() {
+        Upload::Buffered(data) => hash::hash_data(data, expected.algorithm),
+        Upload::File(path) => hash::hash_file(&path, expected.algorithm)?,
+    };
+    Ok(HashCheck {
+        matches: found == expected,
+        expected: expected.to_string(),
+        found: found.to_string(),
+    })
+}
+
+/// The number of locks used to serialise writes to the store.
+const LOCK_COUNT: usize = 64;
+
+lazy_static! {
+    static ref FILE_LOCKS: Vec<Mutex<()>> = (0..LOCK_COUNT).map(|_| Mutex::new(())).collect();
+}
+
+/// Get the lock guarding writes to the given path in the store.
+///
+/// Rather than keeping a lock per file, paths share a fixed number of locks, so unrelated writes
+/// occasionally wait on each other.
+fn file_lock(path: &Path) -> &'static Mutex<()> {
+    let mut hasher = DefaultHasher::new();
+    path.hash(&mut hasher);
+    &FILE_LOCKS[hasher.finish() as usize % LOCK_COUNT]
+}
+
 pub fn put_file(root: &Path, file: impl Into<Upload>, hash: &str) -> io::Result<()> {
-    let temp_dir = tempdir_in(root)?;
-    let temp_path = temp_dir.path().join("data");
+    // The temporary file may be in the store's own directory, but its name is never that of a
+    // stored file.
+    let temp_path = tempfile::NamedTempFile::new_in(temp_dir(root)?)?.into_temp_path();
 
     file.into().persist(&temp_path)?;
 
     hash::validate_hash_file(&temp_path, hash).map_err(hash::hash_error_to_io_error)?;
 
+    // Concurrent uploads of the same file are serialised, so that only the first one moves its
+    // copy into the store and the others find it already present. This only protects against
+    // writes from within this process.
     let path = file_path(root, hash)?;
-    if !file_exists(root, hash)? {
+    let _guard = file_lock(&path).lock().unwrap_or_else(|e| e.into_inner());
+    if file_exists(root, hash)? {
+        // The new copy is known to be good, so a stored copy with the same hash should be
+        // identical. If it isn't, the store has been corrupted and this is reported rather than
+        // quietly replacing the evidence. Failing to read the stored copy is not a sign of
+        // corruption, and is reported as the error it is.
+        let expected: hash::Hash = hash.parse().map_err(hash::hash_error_to_io_error)?;
+        if hash::hash_file(&path, expected.algorithm)? != expected {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!(
+                    "A file with hash '{}' already exists in the store, but its contents do not match its hash",
+                    hash
+                ),
+            ));
+        }
+        // The file is wanted again, perhaps by a packet about to be added, so `gc` must give it
+        // a new grace period.
+        mark_wanted(&path);
+    } else {
         fs::create_dir_all(path.parent().unwrap())?;
-        fs::rename(temp_path, path)?;
+        temp_path.persist(path).map_err(|e| e.error)?;
     }
     Ok(())
 }
@@ -51,17 +320,426 @@ pub fn put_file(root: &Path, file: impl Into<Upload>, hash: &str) -> io::Result<
 pub fn enumerate_files(root: &Path) -> impl Iterator<Item = DirEntry> {
     let directory = root.join(".outpack").join("files");
 
+    // Stored files have no extension, so this only skips compressed copies.
     WalkDir::new(directory)
         .into_iter()
         .filter_map(|r| r.ok())
         .filter(|p| p.file_type().is_file())
+        .filter(|p| p.path().extension() != Some(OsStr::new(COMPRESSED_EXTENSION)))
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct InvalidFile {
+    /// Path of the file, relative to the file store.
+    pub path: String,
+    /// The hash implied by the file's location, or None if the location is not a valid hash.
+    pub expected: Option<String>,
+    /// The hash of the file's contents, or None if the expected hash could not be determined.
+    pub found: Option<String>,
+}
+
+/// Reconstruct the hash of a stored file from its location, relative to the file store.
+fn hash_from_relative_path(path: &Path, layout: StoreLayout) -> Option<hash::Hash> {
+    let parts: Vec<&str> = path.iter().map(|p| p.to_str()).collect::<Option<_>>()?;
+    let (algorithm, rest) = parts.split_first()?;
+    let (value, prefixes) = rest.split_last()?;
+    if prefixes.len() != layout.depth || prefixes.iter().any(|p| p.len() != layout.width) {
+        return None;
+    }
+    format!("{}:{}{}", algorithm, prefixes.concat(), value)
+        .parse()
+        .ok()
+}
+
+/// List the hashes of every file in the store, in sorted order.
+///
+/// Files which are not where they belong in the store, such as those left behind by an
+/// interrupted upload, are skipped.
+pub fn list_hashes(root: &Path) -> io::Result<Vec<String>> {
+    let store = root.join(".outpack").join("files");
+    let layout = get_layout(root)?;
+    let mut hashes: Vec<String> = enumerate_files(root)
+        .filter_map(|entry| {
+            let relative = entry.path().strip_prefix(&store).ok()?;
+            hash_from_relative_path(relative, layout).map(|h| h.to_string())
+        })
+        .collect();
+    hashes.sort();
+    hashes.dedup();
+    Ok(hashes)
+}
+
+/// Check a single file from `enumerate_files`, returning details if its contents do not match
+/// its location in the store. Temporary files, such as those of uploads in progress, are skipped.
+pub fn verify_entry(root: &Path, entry: &DirEntry) -> io::Result<Option<InvalidFile>> {
+    if is_temp_name(entry.file_name()) {
+        return Ok(None);
+    }
+    let store = root.join(".outpack").join("files");
+    let relative = entry.path().strip_prefix(&store).unwrap_or(entry.path());
+    let path = relative
+        .iter()
+        .map(|p| p.to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/");
+
+    let Some(expected) = hash_from_relative_path(relative, get_layout(root)?) else {
+        return Ok(Some(InvalidFile {
+            path,
+            expected: None,
+            found: None,
+        }));
+    };
+
+    let found = hash::hash_file(entry.path(), expected.algorithm)?;
+    if found == expected {
+        Ok(None)
+    } else {
+        Ok(Some(InvalidFile {
+            path,
+            expected: Some(expected.to_string()),
+            found: Some(found.to_string()),
+        }))
+    }
+}
+
+/// Move every file in the store to where it belongs in a new layout, and record the new layout
+/// in the repository's configuration. Returns the number of files moved.
+///
+/// Files which are not where they belong in the current layout are left alone. The server must
+/// not be running while the store is migrated.
+pub fn migrate_layout(root: &Path, layout: StoreLayout) -> io::Result<usize> {
+    if layout.depth > 0 && layout.width == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "The width of a store layout must be positive",
+        ));
+    }
+
+    let mut config = config::read_config(root)?;
+    let current = config.server.store_layout;
+    if current == layout {
+        return Ok(0);
+    }
+
+    let directory = root.join(".outpack").join("files");
+    // Compressed copies of files are moved along with them.
+    let entries: Vec<PathBuf> = WalkDir::new(&directory)
+        .into_iter()
+        .filter_map(|r| r.ok())
+        .filter(|e| e.file_type().is_file())
+        .map(|e| e.into_path())
+        .collect();
+
+    let mut moved = 0;
+    for path in entries {
+        let compressed = path.extension() == Some(OsStr::new(COMPRESSED_EXTENSION));
+        let relative = path.strip_prefix(&directory).unwrap_or(&path);
+        let relative = if compressed {
+            relative.with_extension("")
+        } else {
+            relative.to_owned()
+        };
+        let Some(hash) = hash_from_relative_path(&relative, current) else {
+            continue;
+        };
+
+        let mut destination = layout_path(root, layout, &hash)?;
+        if compressed {
+            destination.set_extension(COMPRESSED_EXTENSION);
+        } else {
+            moved += 1;
+        }
+        fs::create_dir_all(destination.parent().unwrap())?;
+        fs::rename(&path, &destination)?;
+    }
+
+    // Remove the directories of the old layout, which are now empty.
+    for entry in WalkDir::new(&directory)
+        .min_depth(2)
+        .contents_first(true)
+        .into_iter()
+        .filter_map(|r| r.ok())
+        .filter(|e| e.file_type().is_dir())
+    {
+        // This fails for any directory which is not empty, which is left in place.
+        let _ = fs::remove_dir(entry.path());
+    }
+
+    config.server.store_layout = layout;
+    config::write_config(&config, root)?;
+    use_layout(root, layout);
+    Ok(moved)
+}
+
+/// Re-hash every file in the store, returning those which are corrupt or misnamed.
+///
+/// This reads the entire contents of the store and may take a long time on large repositories.
+/// Callers that need to be able to abandon the check part-way through should iterate over
+/// `enumerate_files` and call `verify_entry` themselves.
+pub fn verify_all(root: &Path) -> io::Result<Vec<InvalidFile>> {
+    enumerate_files(root)
+        .filter_map(|entry| verify_entry(root, &entry).transpose())
+        .collect()
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Default)]
+pub struct ImportResult {
+    /// The number of files added to the store.
+    pub imported: usize,
+    /// The number of files skipped, because the store already had a file with the same contents.
+    pub skipped: usize,
+}
+
+/// Add every file in a directory, and its subdirectories, to the store. Each file is hashed with
+/// the repository's hash algorithm, and the source directory is left unchanged.
+pub fn import_dir(root: &Path, source: &Path) -> io::Result<ImportResult> {
+    let algorithm = config::read_config(root)?.core.hash_algorithm;
+    let mut result = ImportResult::default();
+    for entry in WalkDir::new(source).sort_by_file_name() {
+        let entry = entry?;
+        if !entry.file_type().is_file() {
+            continue;
+        }
+        let hash = hash::hash_file(entry.path(), algorithm)?.to_string();
+        if file_exists(root, &hash)? {
+            result.skipped += 1;
+            continue;
+        }
+        // The file is copied alongside the store first, so that the copy can be moved into it.
+        let copy = tempfile::NamedTempFile::new_in(temp_dir(root)?)?;
+        fs::copy(entry.path(), copy.path())?;
+        put_file(root, Upload::File(copy.into_temp_path()), &hash)?;
+        result.imported += 1;
+    }
+    Ok(result)
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Default)]
+pub struct GcResult {
+    /// The unreferenced files which were removed, or which would be removed in a dry run.
+    pub removed: Vec<String>,
+    /// The number of files kept, because they are used by at least one packet or were added too
+    /// recently to be removed.
+    pub kept: usize,
+    /// The space freed by removing the unreferenced files, including any compressed copies.
+    pub bytes: u64,
+}
+
+/// Remove every file from the store which is not used by any packet.
+///
+/// Files are uploaded before the metadata of the packet that uses them, so files added or wanted
+/// again within the last `grace_period` are kept, to leave alone those of packets part way through
+/// being pushed. The index is rebuilt first, so that no file is removed because a packet added by
+/// another program has not been seen yet. In a dry run, the files which would be removed are
+/// reported but nothing is deleted.
+pub fn gc(root: &Path, dry_run: bool, grace_period: Duration) -> io::Result<GcResult> {
+    index::rebuild(root)?;
+    let index = index::get_packet_index(root)?;
+    let referenced: HashSet<&str> = index
+        .packets
+        .iter()
+        .flat_map(|p| p.files.iter().map(|f| f.hash.as_str()))
+        .collect();
+
+    let mut result = GcResult::default();
+    for hash in list_hashes(root)? {
+        let path = file_path(root, &hash)?;
+        if referenced.contains(hash.as_str()) {
+            // A packet now holds on to the file, so whether it was wanted no longer matters.
+            WANTED.lock().unwrap().remove(&path);
+            result.kept += 1;
+            continue;
+        }
+        let _guard = file_lock(&path).lock().unwrap_or_else(|e| e.into_inner());
+        let added = match fs::metadata(&path).and_then(|metadata| metadata.modified()) {
+            Ok(added) => added,
+            // Another program has removed the file since the store was listed.
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        };
+        let wanted = WANTED.lock().unwrap().get(&path).copied();
+        let last_wanted = wanted.map_or(added, |wanted| wanted.max(added));
+        if last_wanted.elapsed().unwrap_or_default() < grace_period {
+            result.kept += 1;
+            continue;
+        }
+        if !dry_run {
+            WANTED.lock().unwrap().remove(&path);
+        }
+        for path in [path.clone(), compressed_file_path(root, &hash)?] {
+            let Ok(metadata) = fs::metadata(&path) else {
+                continue;
+            };
+            if !dry_run {
+                match fs::remove_file(&path) {
+                    Ok(()) => {}
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                    Err(e) => return Err(e),
+                }
+            }
+            result.bytes += metadata.len();
+        }
+        result.removed.push(hash);
+    }
+    Ok(result)
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Default)]
+pub struct CleanTempResult {
+    /// The temporary files which were removed, relative to the root.
+    pub removed: Vec<String>,
+    /// The space freed by removing them.
+    pub bytes: u64,
+}
+
+/// Temporary files are created by the `tempfile` crate, whose names are all this followed by
+/// `TEMP_RANDOM_LENGTH` random letters and digits.
+const TEMP_PREFIX: &str = ".tmp";
+const TEMP_RANDOM_LENGTH: usize = 6;
+
+fn is_temp_name(name: &OsStr) -> bool {
+    match name.to_str().and_then(|n| n.strip_prefix(TEMP_PREFIX)) {
+        Some(random) => {
+            random.len() == TEMP_RANDOM_LENGTH && random.chars().all(|c| c.is_ascii_alphanumeric())
+        }
+        None => false,
+    }
+}
+
+/// Remove the temporary files left behind by uploads which never completed, such as those in
+/// progress when the server was stopped, which have not been modified for longer than `max_age`.
+///
+/// Only the top level of the store's own directory and of `upload_dir` are searched, and only for
+/// files named as temporary files are, so stored files are never touched, nor is anything else in
+/// the root. Uploads still in progress keep being modified, so are left alone as long as `max_age`
+/// is longer than any pause in an upload.
+pub fn clean_temp(
+    root: &Path,
+    upload_dir: &Path,
+    max_age: Duration,
+) -> io::Result<CleanTempResult> {
+    let store = root.join(".outpack").join("files");
+    let mut directories = vec![store.clone()];
+    if upload_dir != store {
+        directories.push(upload_dir.to_path_buf());
+    }
+
+    let now = SystemTime::now();
+    let mut result = CleanTempResult::default();
+    for directory in directories {
+        let entries = match fs::read_dir(&directory) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        };
+        for entry in entries {
+            let entry = entry?;
+            if !is_temp_name(&entry.file_name()) || !entry.file_type()?.is_file() {
+                continue;
+            }
+            let path = entry.path();
+            let metadata = match entry.metadata() {
+                Ok(metadata) => metadata,
+                // The upload may have finished, and moved its file away, since the directory
+                // was listed.
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e),
+            };
+            let modified = metadata.modified()?;
+            if now.duration_since(modified).unwrap_or(Duration::ZERO) <= max_age {
+                continue;
+            }
+            match fs::remove_file(&path) {
+                Ok(()) => {}
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e),
+            }
+            let relative = path.strip_prefix(root).unwrap_or(&path);
+            result.removed.push(relative.to_string_lossy().into_owned());
+            result.bytes += metadata.len();
+        }
+    }
+    result.removed.sort();
+    Ok(result)
+}
+
+/// Count the packets using each file in the store. A packet which contains the same file more than
+/// once counts only once, and files which are not used by any packet have a count of zero.
+pub fn reference_counts(root: &Path) -> io::Result<BTreeMap<String, usize>> {
+    let mut counts: BTreeMap<String, usize> = list_hashes(root)?
+        .into_iter()
+        .map(|hash| (hash, 0))
+        .collect();
+    for packet in &index::get_packet_index(root)?.packets {
+        let hashes: HashSet<&str> = packet.files.iter().map(|f| f.hash.as_str()).collect();
+        for hash in hashes {
+            if let Some(count) = counts.get_mut(hash) {
+                *count += 1;
+            }
+        }
+    }
+    Ok(counts)
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct ReferencedFile {
+    pub hash: String,
+    pub packets: usize,
+    pub size: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct ReferenceReport {
+    /// The number of packets using each file in the store.
+    pub counts: BTreeMap<String, usize>,
+    /// The files used by the most packets, most used first.
+    pub most_referenced: Vec<ReferencedFile>,
+    /// The number of files not used by any packet.
+    pub orphans: usize,
+    /// The space that would be needed to store a separate copy of a file for each packet using
+    /// it, on top of the space actually used.
+    pub bytes_saved: u64,
+}
+
+/// Report how much space is saved by storing each file once, however many packets use it, and
+/// which `top` files are used the most. Files used by the same number of packets are ordered by
+/// hash.
+pub fn reference_report(root: &Path, top: usize) -> io::Result<ReferenceReport> {
+    let counts = reference_counts(root)?;
+    let mut bytes_saved = 0;
+    let mut referenced = Vec::new();
+    for (hash, &packets) in &counts {
+        if packets == 0 {
+            continue;
+        }
+        let size = fs::metadata(file_path(root, hash)?)?.len();
+        bytes_saved += size * (packets as u64 - 1);
+        referenced.push(ReferencedFile {
+            hash: hash.clone(),
+            packets,
+            size,
+        });
+    }
+    // The sort is stable, so files with the same count stay in order of their hash.
+    referenced.sort_by_key(|file| std::cmp::Reverse(file.packets));
+    referenced.truncate(top);
+
+    Ok(ReferenceReport {
+        orphans: counts.values().filter(|&&n| n == 0).count(),
+        counts,
+        most_referenced: referenced,
+        bytes_saved,
+    })
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::hash::{hash_data, HashAlgorithm};
-    use crate::test_utils::tests::{get_temp_outpack_root, vector_equals};
+    use crate::test_utils::tests::{
+        get_empty_outpack_root, get_temp_outpack_root, start_packet, vector_equals,
+    };
     use std::ffi::OsString;
 
     #[test]
@@ -79,6 +757,106 @@ mod tests {
         );
     }
 
+    #[test]
+    fn depth_two_store_round_trips_file() {
+        let root = get_empty_outpack_root();
+        let mut config = config::read_config(&root).unwrap();
+        config.server.store_layout = StoreLayout { depth: 2, width: 2 };
+        config::write_config(&config, &root).unwrap();
+
+        let data = b"Testing 123.";
+        let hash = hash_data(data, HashAlgorithm::Sha256);
+        put_file(&root, data, &hash.to_string()).unwrap();
+
+        let path = file_path(&root, &hash.to_string()).unwrap();
+        assert_eq!(
+            path,
+            root.join(".outpack/files/sha256")
+                .join(&hash.value[..2])
+                .join(&hash.value[2..4])
+                .join(&hash.value[4..])
+        );
+        assert_eq!(fs::read(&path).unwrap(), data);
+        assert!(file_exists(&root, &hash.to_string()).unwrap());
+        assert_eq!(verify_all(&root).unwrap(), vec![]);
+    }
+
+    #[test]
+    fn layout_is_not_changed_by_editing_config() {
+        let root = get_empty_outpack_root();
+        let data = b"Testing 123.";
+        let hash = hash_data(data, HashAlgorithm::Sha256).to_string();
+        put_file(&root, data, &hash).unwrap();
+        let path = file_path(&root, &hash).unwrap();
+
+        let mut config = config::read_config(&root).unwrap();
+        config.server.store_layout = StoreLayout { depth: 2, width: 2 };
+        config::write_config(&config, &root).unwrap();
+        assert_eq!(file_path(&root, &hash).unwrap(), path);
+        assert!(file_exists(&root, &hash).unwrap());
+    }
+
+    #[test]
+    fn migrating_store_layout_keeps_other_settings() {
+        let root = get_empty_outpack_root();
+        let path = root.join(".outpack").join("config.json");
+        let mut json: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+        json["schema_version"] = "0.0.1".into();
+        json["core"]["custom"] = "kept".into();
+        fs::write(&path, json.to_string()).unwrap();
+
+        migrate_layout(&root, StoreLayout { depth: 2, width: 2 }).unwrap();
+
+        let written: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(written["schema_version"], "0.0.1");
+        assert_eq!(written["core"]["custom"], "kept");
+        assert_eq!(written["server"]["store_layout"]["depth"], 2);
+    }
+
+    #[test]
+    fn can_migrate_store_layout() {
+        let root = get_empty_outpack_root();
+        let hashes: Vec<hash::Hash> = ["a", "b", "c"]
+            .iter()
+            .map(|s| {
+                let hash = hash_data(s.as_bytes(), HashAlgorithm::Sha256);
+                put_file(&root, s.as_bytes(), &hash.to_string()).unwrap();
+                hash
+            })
+            .collect();
+        let old_paths: Vec<PathBuf> = hashes
+            .iter()
+            .map(|h| file_path(&root, &h.to_string()).unwrap())
+            .collect();
+        fs::write(
+            compressed_file_path(&root, &hashes[0].to_string()).unwrap(),
+            b"compressed",
+        )
+        .unwrap();
+
+        let layout = StoreLayout { depth: 2, width: 2 };
+        assert_eq!(migrate_layout(&root, layout).unwrap(), 3);
+        assert_eq!(
+            config::read_config(&root).unwrap().server.store_layout,
+            layout
+        );
+
+        for (hash, old_path) in hashes.iter().zip(old_paths) {
+            assert!(!old_path.exists());
+            let path = file_path(&root, &hash.to_string()).unwrap();
+            assert_eq!(path, layout_path(&root, layout, hash).unwrap());
+            assert!(path.exists());
+        }
+        let compressed = compressed_file_path(&root, &hashes[0].to_string()).unwrap();
+        assert_eq!(fs::read(compressed).unwrap(), b"compressed");
+        assert_eq!(verify_all(&root).unwrap(), vec![]);
+
+        // Migrating to the layout already in use does nothing.
+        assert_eq!(migrate_layout(&root, layout).unwrap(), 0);
+    }
+
     #[test]
     fn path_propagates_error_on_invalid_hash() {
         let hash = "sha256";
@@ -105,6 +883,88 @@ mod tests {
         assert!(res.is_ok());
     }
 
+    #[test]
+    fn put_file_rejects_corrupt_existing_file() {
+        let root = get_empty_outpack_root();
+        let data = b"Original contents";
+        let hash = hash_data(data, HashAlgorithm::Sha256).to_string();
+        put_file(&root, data, &hash).unwrap();
+
+        let path = file_path(&root, &hash).unwrap();
+        fs::write(&path, b"corrupted").unwrap();
+
+        let res = put_file(&root, data, &hash);
+        let err = res.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+        assert!(err.to_string().contains("do not match its hash"));
+        assert_eq!(fs::read(path).unwrap(), b"corrupted");
+    }
+
+    #[test]
+    fn put_file_reports_unreadable_existing_file() {
+        let root = get_empty_outpack_root();
+        let data = b"Original contents";
+        let hash = hash_data(data, HashAlgorithm::Sha256).to_string();
+        let path = file_path(&root, &hash).unwrap();
+        fs::create_dir_all(&path).unwrap();
+
+        let err = put_file(&root, data, &hash).unwrap_err();
+        assert_ne!(err.kind(), io::ErrorKind::AlreadyExists);
+        assert!(!err.to_string().contains("do not match its hash"));
+    }
+
+    #[test]
+    fn concurrent_writes_of_same_file_succeed() {
+        let root = get_empty_outpack_root();
+        let data = b"Written by many threads";
+        let hash = hash_data(data, HashAlgorithm::Sha256).to_string();
+
+        let handles: Vec<_> = (0..8)
+            .map(|_| {
+                let root = root.clone();
+                let hash = hash.clone();
+                std::thread::spawn(move || put_file(&root, data, &hash))
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap().unwrap();
+        }
+
+        let files: Vec<_> = enumerate_files(&root).collect();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].path(), file_path(&root, &hash).unwrap());
+        assert_eq!(fs::read(files[0].path()).unwrap(), data);
+    }
+
+    #[test]
+    fn can_check_file_hash_without_storing() {
+        let root = get_empty_outpack_root();
+        let data = b"Testing 123.";
+        let hash = hash_data(data, HashAlgorithm::Sha256).to_string();
+
+        let res = check_file_hash(data, &hash).unwrap();
+        assert_eq!(
+            res,
+            HashCheck {
+                expected: hash.clone(),
+                found: hash,
+                matches: true,
+            }
+        );
+
+        let res = check_file_hash(data, "md5:abcde").unwrap();
+        assert_eq!(
+            res,
+            HashCheck {
+                expected: String::from("md5:abcde"),
+                found: String::from("md5:6df8571d7b178e6fbb982ad0f5cd3bc1"),
+                matches: false,
+            }
+        );
+
+        assert_eq!(enumerate_files(&root).count(), 0);
+    }
+
     #[test]
     fn put_file_validates_hash_format() {
         let root = get_temp_outpack_root();
@@ -127,6 +987,95 @@ mod tests {
         );
     }
 
+    #[test]
+    fn missing_files_are_sorted_and_deduplicated() {
+        let root = get_temp_outpack_root();
+        let present = hash_data(b"present", HashAlgorithm::Sha256).to_string();
+        put_file(&root, b"present", &present).unwrap();
+
+        let mut absent: Vec<String> = ["a", "b", "c"]
+            .iter()
+            .map(|s| hash_data(s.as_bytes(), HashAlgorithm::Sha256).to_string())
+            .collect();
+        let wanted = vec![
+            absent[2].clone(),
+            present.clone(),
+            absent[0].clone(),
+            absent[2].clone(),
+            absent[1].clone(),
+            absent[0].clone(),
+        ];
+
+        absent.sort();
+        assert_eq!(get_missing_files(&root, &wanted).unwrap(), absent);
+    }
+
+    #[test]
+    fn missing_files_validates_hash_format() {
+        let root = get_temp_outpack_root();
+        let res = get_missing_files(&root, &[String::from("badhash")]);
+        let err = res.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(err.to_string(), "Invalid hash format 'badhash'");
+    }
+
+    #[test]
+    fn can_check_files() {
+        let root = get_temp_outpack_root();
+        let intact = hash_data(b"intact", HashAlgorithm::Sha256).to_string();
+        let truncated = hash_data(b"truncated", HashAlgorithm::Sha256).to_string();
+        let absent = hash_data(b"absent", HashAlgorithm::Sha256).to_string();
+
+        put_file(&root, b"intact", &intact).unwrap();
+        put_file(&root, b"truncated", &truncated).unwrap();
+        fs::write(file_path(&root, &truncated).unwrap(), b"trunc").unwrap();
+
+        let res = check_files(&root, &[intact, truncated.clone(), absent.clone()]).unwrap();
+        assert_eq!(
+            res,
+            FileCheck {
+                missing: vec![absent.clone()],
+                corrupt: vec![truncated.clone()],
+            }
+        );
+
+        // Each hash is reported once, in sorted order, however it is asked for.
+        let other_absent = hash_data(b"other absent", HashAlgorithm::Sha256).to_string();
+        let other_truncated = hash_data(b"other truncated", HashAlgorithm::Sha256).to_string();
+        put_file(&root, b"other truncated", &other_truncated).unwrap();
+        fs::write(file_path(&root, &other_truncated).unwrap(), b"other").unwrap();
+        let wanted = [
+            other_absent.clone(),
+            truncated.clone(),
+            absent.clone(),
+            other_truncated.clone(),
+            absent.clone(),
+            truncated.clone(),
+        ];
+        let res = check_files(&root, &wanted).unwrap();
+        let sorted = |mut v: Vec<String>| {
+            v.sort();
+            v
+        };
+        assert_eq!(
+            res,
+            FileCheck {
+                missing: sorted(vec![absent, other_absent]),
+                corrupt: sorted(vec![truncated, other_truncated]),
+            }
+        );
+    }
+
+    #[test]
+    fn check_files_validates_hash_format() {
+        let root = get_temp_outpack_root();
+        let res = check_files(&root, &[String::from("badhash")]);
+        assert_eq!(
+            res.unwrap_err().to_string(),
+            "Invalid hash format 'badhash'"
+        );
+    }
+
     #[test]
     fn enumerate_files_works() {
         let root = get_temp_outpack_root();
@@ -145,4 +1094,388 @@ mod tests {
             files
         );
     }
+
+    #[test]
+    fn enumerate_files_skips_compressed_copies() {
+        let root = get_empty_outpack_root();
+        let hash = hash_data(b"data", HashAlgorithm::Sha256).to_string();
+        put_file(&root, b"data", &hash).unwrap();
+        fs::write(compressed_file_path(&root, &hash).unwrap(), b"compressed").unwrap();
+
+        let files: Vec<_> = enumerate_files(&root).map(|e| e.into_path()).collect();
+        assert_eq!(files, vec![file_path(&root, &hash).unwrap()]);
+        assert_eq!(verify_all(&root).unwrap(), vec![]);
+    }
+
+    #[test]
+    fn can_list_hashes() {
+        let root = get_empty_outpack_root();
+        let mut expected: Vec<String> = ["a", "b", "c"]
+            .iter()
+            .map(|s| {
+                let hash = hash_data(s.as_bytes(), HashAlgorithm::Sha256).to_string();
+                put_file(&root, s.as_bytes(), &hash).unwrap();
+                hash
+            })
+            .collect();
+        expected.sort();
+
+        // Neither compressed copies nor stray files are included.
+        fs::write(compressed_file_path(&root, &expected[0]).unwrap(), b"a").unwrap();
+        fs::write(root.join(".outpack/files/tmp123"), b"partial upload").unwrap();
+
+        assert_eq!(list_hashes(&root).unwrap(), expected);
+    }
+
+    #[test]
+    fn verify_all_reports_corrupt_files() {
+        let root = get_empty_outpack_root();
+        let good = hash_data(b"good", HashAlgorithm::Sha256).to_string();
+        let bad = hash_data(b"bad", HashAlgorithm::Sha256);
+
+        put_file(&root, b"good", &good).unwrap();
+        put_file(&root, b"bad", &bad.to_string()).unwrap();
+        fs::write(file_path(&root, &bad.to_string()).unwrap(), b"corrupted").unwrap();
+
+        let res = verify_all(&root).unwrap();
+        assert_eq!(
+            res,
+            vec![InvalidFile {
+                path: format!("sha256/{}/{}", &bad.value[..2], &bad.value[2..]),
+                expected: Some(bad.to_string()),
+                found: Some(hash_data(b"corrupted", HashAlgorithm::Sha256).to_string()),
+            }]
+        );
+    }
+
+    #[test]
+    fn verify_all_reports_misnamed_files() {
+        let root = get_empty_outpack_root();
+        let directory = root.join(".outpack").join("files").join("sha3");
+        fs::create_dir_all(&directory).unwrap();
+        fs::write(directory.join("data"), b"data").unwrap();
+        // Files still being written are not reported.
+        fs::write(directory.join(".tmpupload"), b"partial").unwrap();
+
+        let res = verify_all(&root).unwrap();
+        assert_eq!(
+            res,
+            vec![InvalidFile {
+                path: String::from("sha3/data"),
+                expected: None,
+                found: None,
+            }]
+        );
+    }
+
+    /// Add a packet containing a single file with the given contents, returning the file's hash.
+    fn add_packet_with_file(root: &Path, contents: &'static [u8]) -> String {
+        let hash = hash_data(contents, HashAlgorithm::Sha256).to_string();
+        put_file(root, contents, &hash).unwrap();
+        let (_, json, metadata_hash) = start_packet("data")
+            .add_file("data.txt", &hash, contents.len())
+            .finish();
+        crate::metadata::add_packet(root, &json, &metadata_hash).unwrap();
+        hash
+    }
+
+    #[test]
+    fn gc_dry_run_reports_unreferenced_files() {
+        let root = get_empty_outpack_root();
+        let kept = add_packet_with_file(&root, b"kept");
+        let orphan = hash_data(b"orphan", HashAlgorithm::Sha256).to_string();
+        put_file(&root, b"orphan", &orphan).unwrap();
+
+        let res = gc(&root, true, Duration::ZERO).unwrap();
+        assert_eq!(
+            res,
+            GcResult {
+                removed: vec![orphan.clone()],
+                kept: 1,
+                bytes: 6,
+            }
+        );
+        assert!(file_exists(&root, &orphan).unwrap());
+        assert!(file_exists(&root, &kept).unwrap());
+    }
+
+    #[test]
+    fn can_count_references() {
+        let root = get_empty_outpack_root();
+        let shared = add_packet_with_file(&root, b"shared");
+        add_packet_with_file(&root, b"shared");
+        let single = add_packet_with_file(&root, b"single");
+        let orphan = hash_data(b"orphan", HashAlgorithm::Sha256).to_string();
+        put_file(&root, b"orphan", &orphan).unwrap();
+
+        let counts = reference_counts(&root).unwrap();
+        assert_eq!(
+            counts,
+            BTreeMap::from([(shared.clone(), 2), (single.clone(), 1), (orphan, 0)])
+        );
+
+        let report = reference_report(&root, 1).unwrap();
+        assert_eq!(report.counts, counts);
+        assert_eq!(
+            report.most_referenced,
+            vec![ReferencedFile {
+                hash: shared,
+                packets: 2,
+                size: 6,
+            }]
+        );
+        assert_eq!(report.orphans, 1);
+        assert_eq!(report.bytes_saved, 6);
+    }
+
+    #[test]
+    fn gc_removes_unreferenced_files() {
+        let root = get_empty_outpack_root();
+        let kept = add_packet_with_file(&root, b"kept");
+        let orphan = hash_data(b"orphan", HashAlgorithm::Sha256).to_string();
+        put_file(&root, b"orphan", &orphan).unwrap();
+        fs::write(compressed_file_path(&root, &orphan).unwrap(), b"gz").unwrap();
+
+        let res = gc(&root, false, Duration::ZERO).unwrap();
+        assert_eq!(res.removed, vec![orphan.clone()]);
+        assert_eq!(res.bytes, 8);
+        assert!(!file_exists(&root, &orphan).unwrap());
+        assert!(!compressed_file_path(&root, &orphan).unwrap().exists());
+        assert_eq!(list_hashes(&root).unwrap(), vec![kept]);
+
+        let res = gc(&root, false, Duration::ZERO).unwrap();
+        assert!(res.removed.is_empty());
+        assert_eq!(res.kept, 1);
+    }
+
+    #[test]
+    fn gc_keeps_recent_and_temporary_files() {
+        let root = get_empty_outpack_root();
+        let recent = hash_data(b"recent", HashAlgorithm::Sha256).to_string();
+        put_file(&root, b"recent", &recent).unwrap();
+        let old = hash_data(b"old", HashAlgorithm::Sha256).to_string();
+        put_file(&root, b"old", &old).unwrap();
+        let a_day_ago = filetime::FileTime::from_system_time(
+            SystemTime::now() - Duration::from_secs(24 * 60 * 60),
+        );
+        filetime::set_file_mtime(file_path(&root, &old).unwrap(), a_day_ago).unwrap();
+
+        // An upload in progress, written next to where its file will go.
+        let temp = file_path(&root, &old).unwrap().with_file_name(".tmpupload");
+        fs::write(&temp, b"partial").unwrap();
+        filetime::set_file_mtime(&temp, a_day_ago).unwrap();
+
+        let res = gc(&root, false, Duration::from_secs(60 * 60)).unwrap();
+        assert_eq!(res.removed, vec![old.clone()]);
+        assert_eq!(res.kept, 1);
+        assert!(file_exists(&root, &recent).unwrap());
+        assert!(!file_exists(&root, &old).unwrap());
+        assert!(temp.exists());
+    }
+
+    #[test]
+    fn gc_keeps_files_uploaded_again() {
+        let root = get_empty_outpack_root();
+        let a_day_ago = filetime::FileTime::from_system_time(
+            SystemTime::now() - Duration::from_secs(24 * 60 * 60),
+        );
+        let mut hashes = Vec::new();
+        for data in ["uploaded", "wanted", "listed"] {
+            let hash = hash_data(data.as_bytes(), HashAlgorithm::Sha256).to_string();
+            put_file(&root, data.as_bytes(), &hash).unwrap();
+            filetime::set_file_mtime(file_path(&root, &hash).unwrap(), a_day_ago).unwrap();
+            hashes.push(hash);
+        }
+        let [uploaded, wanted, listed] = &hashes[..] else {
+            unreachable!()
+        };
+
+        put_file(&root, b"uploaded", uploaded).unwrap();
+        assert!(want_file(&root, wanted).unwrap());
+        let absent = hash_data(b"absent", HashAlgorithm::Sha256).to_string();
+        assert!(!want_file(&root, &absent).unwrap());
+        let missing = get_missing_files(&root, &[listed.clone(), absent.clone()]).unwrap();
+        assert_eq!(missing, vec![absent.clone()]);
+
+        let res = gc(&root, false, Duration::from_secs(60 * 60)).unwrap();
+        assert!(res.removed.is_empty());
+        assert!(!file_exists(&root, &absent).unwrap());
+        for hash in &hashes {
+            // The files are still dated from when they were first added.
+            let path = file_path(&root, hash).unwrap();
+            let modified =
+                filetime::FileTime::from_last_modification_time(&fs::metadata(path).unwrap());
+            assert_eq!(modified, a_day_ago);
+        }
+
+        // Once the grace period has passed since they were last wanted, they can go.
+        let res = gc(&root, false, Duration::ZERO).unwrap();
+        assert_eq!(res.removed.len(), 3);
+    }
+
+    #[test]
+    fn gc_sees_packets_added_by_other_programs() {
+        let root = get_empty_outpack_root();
+        let hash = hash_data(b"used", HashAlgorithm::Sha256).to_string();
+        put_file(&root, b"used", &hash).unwrap();
+        assert_eq!(
+            gc(&root, true, Duration::ZERO).unwrap().removed,
+            vec![hash.clone()]
+        );
+
+        // Written straight into the metadata directory, without updating the index.
+        let (id, json, _) = start_packet("data").add_file("data.txt", &hash, 4).finish();
+        fs::write(root.join(".outpack").join("metadata").join(id), json).unwrap();
+
+        let res = gc(&root, false, Duration::ZERO).unwrap();
+        assert!(res.removed.is_empty());
+        assert!(file_exists(&root, &hash).unwrap());
+    }
+
+    #[test]
+    fn clean_temp_only_removes_old_temporary_files() {
+        let root = get_empty_outpack_root();
+        let store = root.join(".outpack").join("files");
+        let hash = hash_data(b"stored", HashAlgorithm::Sha256).to_string();
+        put_file(&root, b"stored", &hash).unwrap();
+
+        fs::write(store.join(".tmpold123"), b"partial").unwrap();
+        fs::write(store.join(".tmpnew123"), b"recent").unwrap();
+        // Only names which temporary files are given are matched.
+        fs::write(store.join(".tmpfile"), b"other").unwrap();
+        fs::create_dir(store.join(".tmpdir12")).unwrap();
+
+        let old = filetime::FileTime::from_system_time(
+            SystemTime::now() - Duration::from_secs(2 * 60 * 60),
+        );
+        let paths = [
+            store.join(".tmpold123"),
+            store.join(".tmpfile"),
+            store.join(".tmpdir12"),
+            file_path(&root, &hash).unwrap(),
+        ];
+        for path in paths {
+            filetime::set_file_mtime(path, old).unwrap();
+        }
+
+        let res = clean_temp(&root, &store, Duration::from_secs(60 * 60)).unwrap();
+        assert_eq!(res.removed, vec![String::from(".outpack/files/.tmpold123")]);
+        assert_eq!(res.bytes, 7);
+        assert!(store.join(".tmpnew123").exists());
+        assert!(store.join(".tmpfile").exists());
+        assert!(store.join(".tmpdir12").exists());
+        assert!(file_exists(&root, &hash).unwrap());
+        assert_eq!(list_hashes(&root).unwrap(), vec![hash]);
+    }
+
+    #[test]
+    fn clean_temp_leaves_the_root_alone() {
+        let root = get_empty_outpack_root();
+        let store = root.join(".outpack").join("files");
+        fs::create_dir(root.join(".tmp")).unwrap();
+        fs::write(root.join(".tmp").join("cache"), b"cache").unwrap();
+        fs::write(root.join(".tmpAbC123"), b"user file").unwrap();
+
+        let old = filetime::FileTime::from_system_time(
+            SystemTime::now() - Duration::from_secs(2 * 60 * 60),
+        );
+        for path in [
+            root.join(".tmp"),
+            root.join(".tmp").join("cache"),
+            root.join(".tmpAbC123"),
+        ] {
+            filetime::set_file_mtime(path, old).unwrap();
+        }
+
+        let res = clean_temp(&root, &store, Duration::ZERO).unwrap();
+        assert!(res.removed.is_empty());
+        assert!(root.join(".tmp").join("cache").exists());
+        assert!(root.join(".tmpAbC123").exists());
+    }
+
+    #[test]
+    fn temporary_files_are_not_written_to_the_root() {
+        let root = get_empty_outpack_root();
+        assert_eq!(
+            temp_dir(&root).unwrap(),
+            root.join(".outpack").join("files")
+        );
+
+        let before: Vec<_> = fs::read_dir(&root)
+            .unwrap()
+            .map(|e| e.unwrap().path())
+            .collect();
+        let hash = hash_data(b"data", HashAlgorithm::Sha256).to_string();
+        let copy = tempfile::NamedTempFile::new_in(temp_dir(&root).unwrap()).unwrap();
+        fs::write(copy.path(), b"data").unwrap();
+        put_file(&root, Upload::File(copy.into_temp_path()), &hash).unwrap();
+        let after: Vec<_> = fs::read_dir(&root)
+            .unwrap()
+            .map(|e| e.unwrap().path())
+            .collect();
+        assert_eq!(before, after);
+        assert!(file_exists(&root, &hash).unwrap());
+    }
+
+    #[test]
+    fn can_import_directory() {
+        let root = get_empty_outpack_root();
+        let source = tempfile::TempDir::new().unwrap();
+        fs::write(source.path().join("a.txt"), b"a").unwrap();
+        fs::write(source.path().join("b.txt"), b"b").unwrap();
+        fs::create_dir(source.path().join("nested")).unwrap();
+        fs::write(source.path().join("nested").join("c.txt"), b"c").unwrap();
+
+        let res = import_dir(&root, source.path()).unwrap();
+        assert_eq!(
+            res,
+            ImportResult {
+                imported: 3,
+                skipped: 0
+            }
+        );
+        for contents in [b"a", b"b", b"c"] {
+            let hash = hash_data(contents, HashAlgorithm::Sha256).to_string();
+            assert_eq!(
+                fs::read(file_path(&root, &hash).unwrap()).unwrap(),
+                contents
+            );
+        }
+        assert!(source.path().join("nested").join("c.txt").exists());
+
+        fs::write(source.path().join("copy.txt"), b"a").unwrap();
+        let res = import_dir(&root, source.path()).unwrap();
+        assert_eq!(
+            res,
+            ImportResult {
+                imported: 0,
+                skipped: 4
+            }
+        );
+    }
+
+    #[test]
+    fn can_get_file_info() {
+        let root = get_empty_outpack_root();
+        let present = hash_data(b"present", HashAlgorithm::Sha256).to_string();
+        let absent = hash_data(b"absent", HashAlgorithm::Sha256).to_string();
+        put_file(&root, b"present", &present).unwrap();
+
+        let res = get_file_info(&root, &[present.clone(), absent.clone()]).unwrap();
+        assert_eq!(res.len(), 2);
+        assert!(res[&present].present);
+        assert_eq!(res[&present].size, Some(7));
+        assert!(res[&present].modified.unwrap() > 0.0);
+        assert_eq!(
+            res[&absent],
+            FileInfo {
+                present: false,
+                size: None,
+                modified: None
+            }
+        );
+
+        let res = get_file_info(&root, &[String::from("notahash")]);
+        assert_eq!(res.unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    }
 }
@@ -0,0 +1,377 @@
+//! Pulling packets from another outpack server.
+//!
+//! The files of a packet are downloaded before its metadata, and a packet is only added once all
+//! of its files and dependencies are present. If a pull is interrupted it can simply be retried:
+//! packets and files which were already downloaded are not fetched again.
+use reqwest::blocking::{Client, Response};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::path::Path;
+use std::time::SystemTime;
+use url::Url;
+
+use crate::config::{Config, HttpLocationArgs};
+use crate::hash;
+use crate::location::{self, LocationEntry};
+use crate::metadata::{self, Packet};
+use crate::store;
+use crate::upload::Upload;
+use crate::utils;
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct PullResult {
+    /// The packets added to the repository, with dependencies listed before the packets which use
+    /// them.
+    pub packets: Vec<String>,
+
+    /// The number of files downloaded.
+    pub files: usize,
+}
+
+/// A client for the API of another outpack server.
+struct Remote {
+    name: String,
+    args: HttpLocationArgs,
+    client: Client,
+}
+
+impl Remote {
+    fn new(name: &str, args: HttpLocationArgs) -> io::Result<Remote> {
+        // Redirects are not followed, as they could lead anywhere, not just to the location or
+        // host which files were allowed to be pulled from.
+        let client = Client::builder()
+            .redirect(reqwest::redirect::Policy::none())
+            .build()
+            .map_err(|e| {
+                io::Error::new(
+                    io::ErrorKind::Other,
+                    format!("Failed to create http client: {}", e),
+                )
+            })?;
+        Ok(Remote {
+            name: name.to_owned(),
+            args,
+            client,
+        })
+    }
+
+    fn get(&self, path: &str) -> io::Result<Response> {
+        let url = format!("{}/{}", self.args.url.trim_end_matches('/'), path);
+        self.get_url(&url)
+    }
+
+    fn get_url(&self, url: &str) -> io::Result<Response> {
+        let mut request = self.client.get(url);
+        if let Some(token) = &self.args.token {
+            request = request.bearer_auth(token);
+        }
+
+        let error = |detail: String| {
+            io::Error::new(
+                io::ErrorKind::Other,
+                format!(
+                    "Request to '{}' for location '{}' failed: {}",
+                    url, self.name, detail
+                ),
+            )
+        };
+
+        let response = request.send().map_err(|e| error(e.to_string()))?;
+        if response.status().is_success() {
+            Ok(response)
+        } else {
+            let status = response.status();
+            // The body of a failed request is usually an outpack error response, whose detail is
+            // more useful than the status alone.
+            let detail = response
+                .json::<serde_json::Value>()
+                .ok()
+                .and_then(|body| body["errors"][0]["detail"].as_str().map(String::from))
+                .unwrap_or_else(|| status.to_string());
+            Err(error(detail))
+        }
+    }
+
+    fn get_json<T: DeserializeOwned>(&self, path: &str) -> io::Result<T> {
+        let body: serde_json::Value = self.get(path)?.json().map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Invalid response from location '{}': {}", self.name, e),
+            )
+        })?;
+        Ok(serde_json::from_value(body["data"].clone())?)
+    }
+
+    fn get_text(&self, path: &str) -> io::Result<String> {
+        self.get(path)?.text().map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Invalid response from location '{}': {}", self.name, e),
+            )
+        })
+    }
+
+    /// Download a file into the repository's store, checking that its contents match the hash.
+    fn download_file(&self, root: &Path, hash: &str) -> io::Result<()> {
+        let response = self.get(&format!("file/{}", hash))?;
+        store_response(root, response, hash)
+    }
+}
+
+/// Stream the body of a response into the repository's store, checking that it matches the hash.
+fn store_response(root: &Path, mut response: Response, hash: &str) -> io::Result<()> {
+    // The temporary file is created where the store keeps its temporary files, so it can be moved
+    // into the store without copying.
+    let mut file = tempfile::NamedTempFile::new_in(store::temp_dir(root)?)?;
+    io::copy(&mut response, &mut file)?;
+    store::put_file(root, Upload::File(file.into_temp_path()), hash)
+}
+
+/// Find where a file pulled from the given url comes from: the http location at the same origin,
+/// so that its token is used, or otherwise the url's host if it is one of the configured
+/// `pull_hosts`. Returns `None` if files may not be pulled from the url.
+fn allowed_source(config: &Config, url: &Url) -> Option<(String, HttpLocationArgs)> {
+    let location = config.location.iter().find_map(|location| {
+        let args = location.http_args()?;
+        let origin = Url::parse(&args.url).ok()?.origin();
+        (origin == url.origin()).then(|| (location.name.clone(), args))
+    });
+    location.or_else(|| {
+        let host = url.host_str()?;
+        config.server.pull_hosts.iter().any(|h| h == host).then(|| {
+            let args = HttpLocationArgs {
+                url: url.origin().ascii_serialization(),
+                token: None,
+            };
+            (host.to_owned(), args)
+        })
+    })
+}
+
+/// Download a single file from a url into the repository's store, checking that its contents
+/// match the hash. The url must be served by one of the repository's http locations, or by one of
+/// the configured `pull_hosts`. Nothing is downloaded if the store already has the file.
+pub fn pull_file(root: &Path, config: &Config, url: &str, hash: &str) -> io::Result<()> {
+    if store::file_exists(root, hash)? {
+        return Ok(());
+    }
+    let url = Url::parse(url).map_err(|e| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Invalid url '{}': {}", url, e),
+        )
+    })?;
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "Files can only be pulled over http or https, not '{}'",
+                url.scheme()
+            ),
+        ));
+    }
+    let (name, args) = allowed_source(config, &url).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "Files may not be pulled from '{}', which is not a location or an allowed host",
+                url.host_str().unwrap_or_default()
+            ),
+        )
+    })?;
+    let remote = Remote::new(&name, args)?;
+    let response = remote.get_url(url.as_str())?;
+    store_response(root, response, hash)
+}
+
+/// A packet whose metadata has been downloaded, but which hasn't been added yet.
+struct PendingPacket {
+    packet: Packet,
+    metadata: String,
+    hash: String,
+}
+
+/// Download the metadata of a packet and, recursively, of any of its dependencies which aren't
+/// yet present in the repository. Packets are appended to `pending` after their dependencies.
+///
+/// Nothing is written to the repository here, so a location giving an invalid id or metadata for
+/// the wrong packet causes the whole pull to fail before any packet is added.
+fn fetch_metadata(
+    remote: &Remote,
+    id: &str,
+    available: &HashMap<String, String>,
+    skip: &mut HashSet<String>,
+    pending: &mut Vec<PendingPacket>,
+) -> io::Result<()> {
+    // Ids come from the location, either from its list of packets or from the dependencies in
+    // its metadata, and are used to name files in the repository.
+    if !utils::is_packet_str(id) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "Location '{}' refers to invalid packet id '{}'",
+                remote.name, id
+            ),
+        ));
+    }
+    if !skip.insert(id.to_owned()) {
+        return Ok(());
+    }
+
+    let hash = available.get(id).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            format!(
+                "Packet '{}' is not available from location '{}'",
+                id, remote.name
+            ),
+        )
+    })?;
+    let metadata = remote.get_text(&format!("metadata/{}/text", id))?;
+    hash::validate_hash_data(metadata.as_bytes(), hash).map_err(hash::hash_error_to_io_error)?;
+    let packet: Packet = serde_json::from_str(&metadata)?;
+    if packet.id != id {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "Location '{}' returned metadata for packet '{}' instead of '{}'",
+                remote.name, packet.id, id
+            ),
+        ));
+    }
+
+    for dependency in &packet.depends {
+        fetch_metadata(remote, &dependency.packet, available, skip, pending)?;
+    }
+
+    pending.push(PendingPacket {
+        packet,
+        metadata,
+        hash: hash.clone(),
+    });
+    Ok(())
+}
+
+/// A client for the location with the given name, which must be another outpack server.
+fn get_remote(config: &Config, location_name: &str) -> io::Result<Remote> {
+    let location = config
+        .location
+        .iter()
+        .find(|l| l.name == location_name)
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("Location '{}' does not exist", location_name),
+            )
+        })?;
+    let args = location.http_args().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "Location '{}' is not an outpack server, and cannot be pulled from",
+                location_name
+            ),
+        )
+    })?;
+    Remote::new(location_name, args)
+}
+
+/// Get the ids of every packet known to the location with the given name, which must be another
+/// outpack server.
+pub fn get_location_ids(config: &Config, location_name: &str) -> io::Result<Vec<String>> {
+    let remote = get_remote(config, location_name)?;
+    let entries: Vec<LocationEntry> = remote.get_json("metadata/list")?;
+    Ok(entries.into_iter().map(|entry| entry.packet).collect())
+}
+
+/// Pull packets from the location with the given name, which must be another outpack server.
+///
+/// If `ids` is given, only those packets and their dependencies are pulled. Otherwise every
+/// packet known to the server is. Packets which are already present are skipped.
+pub fn pull_packets(
+    root: &Path,
+    config: &Config,
+    location_name: &str,
+    ids: Option<&[String]>,
+) -> io::Result<PullResult> {
+    let remote = get_remote(config, location_name)?;
+
+    let available: HashMap<String, String> = remote
+        .get_json::<Vec<LocationEntry>>("metadata/list")?
+        .into_iter()
+        .map(|entry| (entry.packet, entry.hash))
+        .collect();
+
+    let mut wanted: Vec<String> = match ids {
+        Some(ids) => ids
+            .iter()
+            .map(metadata::get_valid_id)
+            .collect::<io::Result<Vec<String>>>()?,
+        None => available.keys().cloned().collect(),
+    };
+    wanted.sort();
+
+    let mut skip: HashSet<String> = metadata::get_ids(root, true)?.into_iter().collect();
+    let mut pending = Vec::new();
+    for id in &wanted {
+        fetch_metadata(&remote, id, &available, &mut skip, &mut pending)?;
+    }
+
+    let mut result = PullResult {
+        packets: Vec::new(),
+        files: 0,
+    };
+    for p in pending {
+        let hashes: Vec<String> = p.packet.files.iter().map(|f| f.hash.clone()).collect();
+        for hash in store::get_missing_files(root, &hashes)? {
+            remote.download_file(root, &hash)?;
+            result.files += 1;
+        }
+
+        let hash: hash::Hash = p.hash.parse().map_err(hash::hash_error_to_io_error)?;
+        metadata::add_packet(root, &p.metadata, &hash)?;
+        location::mark_packet_known(
+            &p.packet.id,
+            location_name,
+            &p.hash,
+            SystemTime::now(),
+            root,
+        )?;
+        result.packets.push(p.packet.id);
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use std::net::TcpListener;
+
+    #[test]
+    fn can_make_https_requests() {
+        // Real outpack servers are served over https, so the client must be able to start a TLS
+        // handshake rather than rejecting the url outright.
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (tx, rx) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut record_type = [0; 1];
+            stream.read_exact(&mut record_type).unwrap();
+            tx.send(record_type[0]).unwrap();
+        });
+
+        let args = HttpLocationArgs {
+            url: format!("https://127.0.0.1:{}", port),
+            token: None,
+        };
+        let remote = Remote::new("upstream", args).unwrap();
+        assert!(remote.get("").is_err());
+        // A TLS handshake record, which starts with a ClientHello.
+        let record_type = rx.recv_timeout(std::time::Duration::from_secs(10));
+        assert_eq!(record_type, Ok(0x16));
+    }
+}
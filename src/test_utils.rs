@@ -119,6 +119,16 @@ pub mod tests {
     }
 
     impl PacketBuilder {
+        pub fn id(&mut self, id: impl Into<String>) -> &mut PacketBuilder {
+            self.packet.id = id.into();
+            self
+        }
+
+        pub fn start_time(&mut self, start: f64) -> &mut PacketBuilder {
+            self.packet.time.start = start;
+            self
+        }
+
         pub fn add_file(
             &mut self,
             path: impl Into<String>,
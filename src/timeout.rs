@@ -0,0 +1,62 @@
+//! Dropping clients which stop sending the body of a request.
+//!
+//! Without a limit, a client which opens a request and then sends its body very slowly, or not at
+//! all, holds on to the request and any temporary files it uses indefinitely. Each read of the
+//! body must instead complete within the configured time. Transfers that keep making progress are
+//! never interrupted, however long they take in total.
+use axum::body::Body;
+use axum::extract::{Request, State};
+use futures::StreamExt;
+use std::error::Error;
+use std::fmt;
+use std::time::Duration;
+
+/// The error returned when reading a request body when no data has arrived for too long.
+#[derive(Debug)]
+pub struct ReadTimeout(Duration);
+
+impl fmt::Display for ReadTimeout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "No data was received for {} seconds, giving up on the request",
+            self.0.as_secs_f64()
+        )
+    }
+}
+
+impl Error for ReadTimeout {}
+
+/// Whether an error was caused, at any depth, by a request body timing out.
+pub fn is_read_timeout(e: &(dyn Error + 'static)) -> bool {
+    let mut error = Some(e);
+    while let Some(e) = error {
+        if e.is::<ReadTimeout>() {
+            return true;
+        }
+        error = e.source();
+    }
+    false
+}
+
+/// Wrap a body so that reading it fails if no data arrives within `timeout`.
+pub fn with_read_timeout(body: Body, timeout: Duration) -> Body {
+    let stream = futures::stream::unfold(Some(body.into_data_stream()), move |stream| async move {
+        let mut stream = stream?;
+        match tokio::time::timeout(timeout, stream.next()).await {
+            Ok(Some(Ok(data))) => Some((Ok(data), Some(stream))),
+            Ok(Some(Err(e))) => Some((Err(axum::BoxError::from(e)), None)),
+            Ok(None) => None,
+            Err(_) => {
+                tracing::warn!("request body timed out after {:?}", timeout);
+                Some((Err(ReadTimeout(timeout).into()), None))
+            }
+        }
+    });
+    Body::from_stream(stream)
+}
+
+/// A middleware applying a read timeout to the body of every request.
+pub async fn limit_request_body(State(timeout): State<Duration>, request: Request) -> Request {
+    request.map(|body| with_read_timeout(body, timeout))
+}
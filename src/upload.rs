@@ -1,19 +1,24 @@
 use crate::responses::OutpackError;
 use axum::body::Bytes;
 use axum::extract::{FromRequest, FromRequestParts, Request};
+use axum::http::header::CONTENT_LENGTH;
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
 use axum::Extension;
 use futures::{Stream, TryStreamExt};
+use std::fmt;
 use std::io;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use tempfile::{NamedTempFile, TempPath};
-use tokio::io::AsyncWriteExt;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio_util::io::StreamReader;
 use tower::Layer;
 
 #[derive(Clone)]
 pub struct UploadConfig {
     directory: Arc<PathBuf>,
+    max_size: Option<u64>,
 }
 
 #[derive(Clone)]
@@ -27,9 +32,37 @@ impl UploadLayer {
         UploadLayer {
             config: UploadConfig {
                 directory: Arc::new(path.into()),
+                max_size: None,
             },
         }
     }
+
+    /// Reject uploads larger than the given number of bytes with a 413 status.
+    pub fn with_max_size(mut self, max_size: Option<u64>) -> UploadLayer {
+        self.config.max_size = max_size;
+        self
+    }
+}
+
+/// The error for an upload which is larger than the configured maximum size.
+#[derive(Debug)]
+pub struct TooLarge(pub u64);
+
+impl fmt::Display for TooLarge {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Uploads may not be larger than {} bytes", self.0)
+    }
+}
+
+impl std::error::Error for TooLarge {}
+
+fn payload_too_large(max_size: u64) -> Response {
+    OutpackError::new(
+        "PAYLOAD_TOO_LARGE",
+        TooLarge(max_size).to_string(),
+        StatusCode::PAYLOAD_TOO_LARGE,
+    )
+    .into_response()
 }
 
 /// An axum `Extractor` that stores the request body as a temporary file.
@@ -71,22 +104,43 @@ impl<S> FromRequest<S> for Upload
 where
     S: Send + Sync,
 {
-    type Rejection = OutpackError;
+    type Rejection = Response;
 
-    async fn from_request(request: Request, state: &S) -> Result<Self, OutpackError> {
+    async fn from_request(request: Request, state: &S) -> Result<Self, Response> {
         let (mut parts, body) = request.into_parts();
 
         let config = Extension::<UploadConfig>::from_request_parts(&mut parts, state)
             .await
             .ok();
+        let max_size = config.as_ref().and_then(|c| c.max_size);
+
+        // Reject uploads which are known to be too large up front, without reading any of the body.
+        if let Some(max_size) = max_size {
+            let length = parts
+                .headers
+                .get(CONTENT_LENGTH)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.parse::<u64>().ok());
+            if length.is_some_and(|length| length > max_size) {
+                return Err(payload_too_large(max_size));
+            }
+        }
 
         let file = if let Some(config) = config {
-            NamedTempFile::new_in(&*config.directory)?
+            NamedTempFile::new_in(&*config.directory)
         } else {
-            NamedTempFile::new()?
-        };
+            NamedTempFile::new()
+        }
+        .map_err(|e| OutpackError::from(e).into_response())?;
 
-        stream_to_file(file.path(), body.into_data_stream()).await?;
+        // The request may not have a Content-Length, or it may be wrong, so the limit is enforced
+        // while streaming too. The temporary file is removed when it is dropped.
+        let complete = stream_to_file(file.path(), body.into_data_stream(), max_size)
+            .await
+            .map_err(|e| OutpackError::from(e).into_response())?;
+        if !complete {
+            return Err(payload_too_large(max_size.unwrap()));
+        }
 
         Ok(Upload::File(file.into_temp_path()))
     }
@@ -100,7 +154,10 @@ impl<S> Layer<S> for UploadLayer {
 }
 
 /// Stream a request body to an on-disk file.
-async fn stream_to_file<S>(path: &Path, stream: S) -> std::io::Result<()>
+///
+/// If `max_size` is given, at most one byte more than that is written. Returns false if the body
+/// was larger than `max_size`, in which case the file is incomplete.
+async fn stream_to_file<S>(path: &Path, stream: S, max_size: Option<u64>) -> std::io::Result<bool>
 where
     S: Stream<Item = Result<Bytes, axum::Error>> + Unpin,
 {
@@ -108,10 +165,19 @@ where
     let mut reader = StreamReader::new(stream);
 
     let mut file = tokio::fs::File::create(path).await?;
-    tokio::io::copy(&mut reader, &mut file).await?;
+    let complete = match max_size {
+        Some(max_size) => {
+            let written = tokio::io::copy(&mut (&mut reader).take(max_size + 1), &mut file).await?;
+            written <= max_size
+        }
+        None => {
+            tokio::io::copy(&mut reader, &mut file).await?;
+            true
+        }
+    };
     file.flush().await?;
 
-    Ok(())
+    Ok(complete)
 }
 
 impl From<&'static [u8]> for Upload {
@@ -141,6 +207,7 @@ mod tests {
         let request = Request::get("/")
             .extension(UploadConfig {
                 directory: Arc::new(upload_dir.clone()),
+                max_size: None,
             })
             .body(Body::from(data))
             .unwrap();
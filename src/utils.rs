@@ -1,6 +1,7 @@
 use cached::instant::SystemTime;
 use lazy_static::lazy_static;
 use regex::Regex;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use std::ffi::OsString;
 use std::time::UNIX_EPOCH;
 
@@ -10,7 +11,7 @@ lazy_static! {
 
 pub fn is_packet(name: &OsString) -> bool {
     let o = name.to_str();
-    o.map_or(false, is_packet_str)
+    o.is_some_and(is_packet_str)
 }
 
 pub fn is_packet_str(name: &str) -> bool {
@@ -18,7 +19,48 @@ pub fn is_packet_str(name: &str) -> bool {
 }
 
 pub fn time_as_num(time: SystemTime) -> f64 {
-    (time.duration_since(UNIX_EPOCH).unwrap().as_millis() as f64) / 1000.0
+    Timestamp::from(time).as_seconds()
+}
+
+/// A time, as a whole number of microseconds since the epoch.
+///
+/// Times are written as a number of seconds, with a fractional part. A double can hold any time
+/// to the nearest microsecond for hundreds of years to come, but not to the nanosecond, so times
+/// are rounded to the microsecond on reading. This makes them exact, so that two times compare
+/// equal whenever they were written as the same number, whichever platform wrote them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Timestamp(i64);
+
+impl Timestamp {
+    pub fn from_micros(micros: i64) -> Timestamp {
+        Timestamp(micros)
+    }
+
+    pub fn from_seconds(seconds: f64) -> Timestamp {
+        Timestamp((seconds * 1e6).round() as i64)
+    }
+
+    pub fn as_seconds(self) -> f64 {
+        self.0 as f64 / 1e6
+    }
+}
+
+impl From<SystemTime> for Timestamp {
+    fn from(time: SystemTime) -> Self {
+        Timestamp(time.duration_since(UNIX_EPOCH).unwrap().as_micros() as i64)
+    }
+}
+
+impl Serialize for Timestamp {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_f64(self.as_seconds())
+    }
+}
+
+impl<'de> Deserialize<'de> for Timestamp {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        f64::deserialize(deserializer).map(Timestamp::from_seconds)
+    }
 }
 
 #[cfg(test)]
@@ -40,4 +82,24 @@ mod tests {
         let res = time_as_num(time);
         assert_eq!(res, 1688033668.123);
     }
+
+    #[test]
+    fn timestamps_are_exact_to_the_microsecond() {
+        let time = UNIX_EPOCH + Duration::from_nanos(1662480555662301999);
+        assert_eq!(
+            Timestamp::from(time),
+            Timestamp::from_micros(1662480555662301)
+        );
+
+        let json = serde_json::to_string(&Timestamp::from(time)).unwrap();
+        assert_eq!(json, "1662480555.662301");
+        let parsed: Timestamp = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed, Timestamp::from(time));
+
+        // Times a fraction of a millisecond apart stay apart, and in order.
+        let earlier = Timestamp::from_seconds(1662480555.6623);
+        let later = Timestamp::from_seconds(1662480555.6624);
+        assert!(earlier < later);
+        assert_eq!(later, Timestamp::from_micros(1662480555662400));
+    }
 }
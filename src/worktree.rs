@@ -0,0 +1,218 @@
+//! Checking out branches of the repository's git remote into worktrees of their own.
+//!
+//! Each branch is checked out, with a detached HEAD, into a directory under a temporary directory
+//! created on first use, so that it can be served as a root in its own right alongside the main
+//! one. A worktree is moved to the latest commit of its branch whenever it is next used after a
+//! fetch. Every worktree is removed, and pruned from the repository, when `Worktrees` is dropped;
+//! any left behind by a server which did not exit cleanly are pruned by `prune_stale`.
+use git2::build::CheckoutBuilder;
+use git2::{BranchType, ErrorClass, ErrorCode, Oid, Repository, WorktreePruneOptions};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use tempfile::TempDir;
+
+use crate::git;
+
+/// The prefix of the names of the worktrees created here.
+const WORKTREE_PREFIX: &str = "outpack-";
+
+struct Worktree {
+    /// The name of the worktree in the repository's `.git/worktrees`.
+    name: String,
+    path: PathBuf,
+    commit: Oid,
+}
+
+#[derive(Default)]
+struct State {
+    directory: Option<TempDir>,
+    worktrees: HashMap<String, Worktree>,
+}
+
+pub struct Worktrees {
+    root: PathBuf,
+    state: Mutex<State>,
+}
+
+impl Worktrees {
+    pub fn new(root: &Path) -> Worktrees {
+        Worktrees {
+            root: root.to_owned(),
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    /// Check out the latest commit of the remote branch with the given name, returning the path
+    /// of its worktree and the commit it is at. The worktree is created if this is the first time
+    /// the branch is used.
+    pub fn checkout(&self, branch: &str) -> Result<(PathBuf, Oid), git2::Error> {
+        let repo = git::open_repository(&self.root)?;
+        let commit = repo
+            .find_branch(&format!("origin/{}", branch), BranchType::Remote)
+            .map_err(|_| {
+                git2::Error::new(
+                    ErrorCode::NotFound,
+                    ErrorClass::Reference,
+                    format!("Branch '{}' does not exist", branch),
+                )
+            })?
+            .get()
+            .peel_to_commit()?
+            .id();
+
+        let mut state = self.state.lock().unwrap();
+        if let Some(worktree) = state.worktrees.get_mut(branch) {
+            if worktree.commit != commit {
+                detach_at(&Repository::open(&worktree.path)?, commit)?;
+                worktree.commit = commit;
+            }
+            return Ok((worktree.path.clone(), commit));
+        }
+
+        if state.directory.is_none() {
+            let directory = TempDir::new().map_err(|e| git2::Error::from_str(&e.to_string()))?;
+            state.directory = Some(directory);
+        }
+        let name = format!("{}{}", WORKTREE_PREFIX, state.worktrees.len());
+        let path = state.directory.as_ref().unwrap().path().join(&name);
+
+        // Adding a worktree always creates a branch of the same name for it. The worktree is
+        // detached from it straight away, so that it can be deleted again.
+        let added = repo.worktree(&name, &path, None)?;
+        detach_at(&Repository::open_from_worktree(&added)?, commit)?;
+        repo.find_branch(&name, BranchType::Local)?.delete()?;
+
+        state.worktrees.insert(
+            branch.to_owned(),
+            Worktree {
+                name,
+                path: path.clone(),
+                commit,
+            },
+        );
+        Ok((path, commit))
+    }
+
+    /// Prune the worktrees, and their branches, left in the repository by an earlier server which
+    /// did not get to remove them. Their names would otherwise clash with those of new worktrees.
+    /// A root which is not a git repository has nothing to prune.
+    pub fn prune_stale(&self) -> Result<(), git2::Error> {
+        let repo = match git::open_repository(&self.root) {
+            Ok(repo) => repo,
+            Err(e) if e.code() == ErrorCode::NotFound => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        let state = self.state.lock().unwrap();
+        let names = repo.worktrees()?;
+        for name in names.iter().flatten() {
+            let in_use = state.worktrees.values().any(|w| w.name == name);
+            if !name.starts_with(WORKTREE_PREFIX) || in_use {
+                continue;
+            }
+            repo.find_worktree(name)?.prune(Some(
+                WorktreePruneOptions::new().valid(true).working_tree(true),
+            ))?;
+            if let Ok(mut branch) = repo.find_branch(name, BranchType::Local) {
+                branch.delete()?;
+            }
+        }
+        Ok(())
+    }
+
+    fn remove_all(&self) -> Result<(), git2::Error> {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        if state.worktrees.is_empty() {
+            return Ok(());
+        }
+        let repo = git::open_repository(&self.root)?;
+        for (_, worktree) in state.worktrees.drain() {
+            repo.find_worktree(&worktree.name)?.prune(Some(
+                WorktreePruneOptions::new().valid(true).working_tree(true),
+            ))?;
+        }
+        Ok(())
+    }
+}
+
+fn detach_at(repo: &Repository, commit: Oid) -> Result<(), git2::Error> {
+    repo.set_head_detached(commit)?;
+    repo.checkout_head(Some(CheckoutBuilder::new().force()))
+}
+
+impl Drop for Worktrees {
+    fn drop(&mut self) {
+        if let Err(e) = self.remove_all() {
+            tracing::warn!("failed to remove git worktrees: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_utils::initialise_git_repo;
+
+    #[test]
+    fn can_check_out_branches() {
+        let test_git = initialise_git_repo(None);
+        let local_path = test_git.dir.path().join("local");
+        git::git_fetch(&local_path, git::DEFAULT_REMOTE, None).unwrap();
+
+        let worktrees = Worktrees::new(&local_path);
+        let (master, _) = worktrees.checkout("master").unwrap();
+        let (other, _) = worktrees.checkout("other").unwrap();
+        assert_ne!(master, other);
+        assert!(master.join("new_file2").exists());
+        assert!(!master.join("new_file3").exists());
+        assert!(other.join("new_file3").exists());
+
+        // Checking out a branch again reuses its worktree.
+        assert_eq!(worktrees.checkout("other").unwrap().0, other);
+
+        // No branches are left behind, and the main working tree is untouched.
+        let repo = Repository::open(&local_path).unwrap();
+        assert!(repo.find_branch("outpack-0", BranchType::Local).is_err());
+        assert_eq!(repo.head().unwrap().shorthand(), Some("master"));
+        assert_eq!(repo.worktrees().unwrap().len(), 2);
+
+        let err = worktrees.checkout("missing").unwrap_err();
+        assert_eq!(err.code(), ErrorCode::NotFound);
+        assert!(err.message().contains("Branch 'missing' does not exist"));
+
+        drop(worktrees);
+        assert!(!master.exists());
+        assert!(!other.exists());
+        assert_eq!(repo.worktrees().unwrap().len(), 0);
+    }
+
+    #[test]
+    fn can_prune_stale_worktrees() {
+        let test_git = initialise_git_repo(None);
+        let local_path = test_git.dir.path().join("local");
+        git::git_fetch(&local_path, git::DEFAULT_REMOTE, None).unwrap();
+
+        // A worktree left behind by a server which was killed, and one which is not ours.
+        let repo = Repository::open(&local_path).unwrap();
+        let stale = test_git.dir.path().join("stale");
+        repo.worktree("outpack-0", &stale, None).unwrap();
+        let mine = test_git.dir.path().join("mine");
+        repo.worktree("mine", &mine, None).unwrap();
+
+        let worktrees = Worktrees::new(&local_path);
+        worktrees.prune_stale().unwrap();
+        assert_eq!(repo.worktrees().unwrap().len(), 1);
+        assert!(repo.find_worktree("mine").is_ok());
+        assert!(repo.find_branch("outpack-0", BranchType::Local).is_err());
+        assert!(!stale.exists());
+
+        let (master, _) = worktrees.checkout("master").unwrap();
+        assert!(master.join("new_file2").exists());
+    }
+
+    #[test]
+    fn nothing_to_prune_outside_git() {
+        let dir = tempfile::tempdir().unwrap();
+        Worktrees::new(dir.path()).prune_stale().unwrap();
+    }
+}
@@ -0,0 +1,274 @@
+//! Writing zip archives, as a single download of a whole packet.
+//!
+//! Only what is needed to stream a packet's files is supported: entries are stored without
+//! compression, and their sizes and checksums follow their contents, so that nothing needs to be
+//! known about a file before it is read. Archives are limited to 4GiB, as zip64 is not supported.
+use std::io::{self, Read, Write};
+
+const LOCAL_HEADER_SIGNATURE: u32 = 0x04034b50;
+const DATA_DESCRIPTOR_SIGNATURE: u32 = 0x08074b50;
+const CENTRAL_HEADER_SIGNATURE: u32 = 0x02014b50;
+const END_OF_CENTRAL_DIRECTORY_SIGNATURE: u32 = 0x06054b50;
+
+/// Version 2.0 of the format, the earliest with directories and data descriptors.
+const VERSION: u16 = 20;
+
+/// The sizes and checksum follow the entry's data, and its name is UTF-8.
+const FLAGS: u16 = (1 << 3) | (1 << 11);
+
+/// Entries are stored as they are, without compression.
+const METHOD_STORED: u16 = 0;
+
+/// Midnight on 1980-01-01, the earliest time a zip archive can record. Every entry is given this
+/// time, so that downloading the same packet always gives the same archive.
+const DOS_TIME: u16 = 0;
+const DOS_DATE: u16 = (1 << 5) | 1;
+
+/// The largest size of an archive, and of any file in it, without zip64.
+pub const MAX_SIZE: u64 = u32::MAX as u64;
+
+const fn crc32_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xedb88320
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+const CRC32_TABLE: [u32; 256] = crc32_table();
+
+fn crc32_update(crc: u32, bytes: &[u8]) -> u32 {
+    !bytes.iter().fold(!crc, |crc, b| {
+        CRC32_TABLE[((crc ^ *b as u32) & 0xff) as usize] ^ (crc >> 8)
+    })
+}
+
+fn too_large() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        "The archive would be larger than 4GiB, which is not supported",
+    )
+}
+
+/// Check that an entry's name is a relative path which stays within the archive, so that
+/// extracting the archive cannot write anywhere else.
+pub fn check_name(name: &str) -> io::Result<()> {
+    let bytes = name.as_bytes();
+    let drive = bytes.len() >= 2 && bytes[0].is_ascii_alphabetic() && bytes[1] == b':';
+    let absolute = name.starts_with('/') || name.starts_with('\\') || drive;
+    if name.is_empty() || absolute || name.split(['/', '\\']).any(|part| part == "..") {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "The name '{}' is not a relative path within the archive",
+                name
+            ),
+        ));
+    }
+    Ok(())
+}
+
+struct Entry {
+    name: String,
+    crc: u32,
+    size: u32,
+    offset: u32,
+}
+
+/// A zip archive being written to `W`. Entries are written as they are added, and the archive is
+/// only complete once `finish` has been called.
+pub struct ZipWriter<W: Write> {
+    inner: W,
+    written: u64,
+    entries: Vec<Entry>,
+}
+
+impl<W: Write> ZipWriter<W> {
+    pub fn new(inner: W) -> ZipWriter<W> {
+        ZipWriter {
+            inner,
+            written: 0,
+            entries: Vec::new(),
+        }
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.written += bytes.len() as u64;
+        if self.written > MAX_SIZE {
+            return Err(too_large());
+        }
+        self.inner.write_all(bytes)
+    }
+
+    fn write_u16(&mut self, value: u16) -> io::Result<()> {
+        self.write(&value.to_le_bytes())
+    }
+
+    fn write_u32(&mut self, value: u32) -> io::Result<()> {
+        self.write(&value.to_le_bytes())
+    }
+
+    /// Add an entry with the given name, containing everything read from `contents`.
+    pub fn add(&mut self, name: &str, contents: &mut impl Read) -> io::Result<()> {
+        check_name(name)?;
+        let name_length = u16::try_from(name.len()).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("The name '{}' is too long for a zip archive", name),
+            )
+        })?;
+        let offset = self.written as u32;
+
+        self.write_u32(LOCAL_HEADER_SIGNATURE)?;
+        self.write_u16(VERSION)?;
+        self.write_u16(FLAGS)?;
+        self.write_u16(METHOD_STORED)?;
+        self.write_u16(DOS_TIME)?;
+        self.write_u16(DOS_DATE)?;
+        // The checksum and sizes, which are given by the data descriptor instead.
+        self.write(&[0; 12])?;
+        self.write_u16(name_length)?;
+        self.write_u16(0)?;
+        self.write(name.as_bytes())?;
+
+        let mut crc = 0;
+        let mut size: u64 = 0;
+        let mut buffer = vec![0; 64 * 1024];
+        loop {
+            let n = match contents.read(&mut buffer) {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            crc = crc32_update(crc, &buffer[..n]);
+            size += n as u64;
+            self.write(&buffer[..n])?;
+        }
+        // The archive is no larger than MAX_SIZE, so neither is any entry in it.
+        let size = size as u32;
+
+        self.write_u32(DATA_DESCRIPTOR_SIGNATURE)?;
+        self.write_u32(crc)?;
+        self.write_u32(size)?;
+        self.write_u32(size)?;
+
+        self.entries.push(Entry {
+            name: name.to_owned(),
+            crc,
+            size,
+            offset,
+        });
+        Ok(())
+    }
+
+    /// Write the archive's central directory, which lists its entries, returning the writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        let start = self.written as u32;
+        let entries = std::mem::take(&mut self.entries);
+        for entry in &entries {
+            self.write_u32(CENTRAL_HEADER_SIGNATURE)?;
+            self.write_u16(VERSION)?;
+            self.write_u16(VERSION)?;
+            self.write_u16(FLAGS)?;
+            self.write_u16(METHOD_STORED)?;
+            self.write_u16(DOS_TIME)?;
+            self.write_u16(DOS_DATE)?;
+            self.write_u32(entry.crc)?;
+            self.write_u32(entry.size)?;
+            self.write_u32(entry.size)?;
+            self.write_u16(entry.name.len() as u16)?;
+            // The lengths of the extra field and comment, the disk number and the attributes.
+            self.write(&[0; 12])?;
+            self.write_u32(entry.offset)?;
+            self.write(entry.name.as_bytes())?;
+        }
+        let size = self.written as u32 - start;
+
+        let count = u16::try_from(entries.len()).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "A zip archive can hold at most 65535 files",
+            )
+        })?;
+        self.write_u32(END_OF_CENTRAL_DIRECTORY_SIGNATURE)?;
+        // The number of this disk, and of the disk with the central directory.
+        self.write(&[0; 4])?;
+        self.write_u16(count)?;
+        self.write_u16(count)?;
+        self.write_u32(size)?;
+        self.write_u32(start)?;
+        self.write_u16(0)?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crc32_matches_reference_value() {
+        assert_eq!(crc32_update(0, b"123456789"), 0xcbf43926);
+        assert_eq!(crc32_update(crc32_update(0, b"1234"), b"56789"), 0xcbf43926);
+        assert_eq!(crc32_update(0, b""), 0);
+    }
+
+    #[test]
+    fn can_write_archive() {
+        let mut zip = ZipWriter::new(Vec::new());
+        zip.add("a.txt", &mut "hello".as_bytes()).unwrap();
+        zip.add("dir/b.txt", &mut "".as_bytes()).unwrap();
+        let bytes = zip.finish().unwrap();
+
+        // Two local headers and data descriptors, two central headers and the end record.
+        let expected = (30 + 5 + 5 + 16) + (30 + 9 + 16) + (46 + 5) + (46 + 9) + 22;
+        assert_eq!(bytes.len(), expected);
+        assert_eq!(&bytes[..4], &LOCAL_HEADER_SIGNATURE.to_le_bytes());
+        assert_eq!(&bytes[30..40], b"a.txthello");
+
+        let end = &bytes[bytes.len() - 22..];
+        assert_eq!(&end[..4], &END_OF_CENTRAL_DIRECTORY_SIGNATURE.to_le_bytes());
+        assert_eq!(&end[10..12], &2u16.to_le_bytes());
+    }
+
+    #[test]
+    fn rejects_names_outside_archive() {
+        let mut zip = ZipWriter::new(Vec::new());
+        for name in [
+            "",
+            "/etc/passwd",
+            "\\share\\x",
+            "C:\\x",
+            "../x",
+            "a/../../x",
+            "a\\..\\x",
+        ] {
+            let err = zip.add(name, &mut "".as_bytes()).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput, "{}", name);
+        }
+        zip.add("a/..b/c..", &mut "".as_bytes()).unwrap();
+        zip.add("time:12.csv", &mut "".as_bytes()).unwrap();
+    }
+
+    #[test]
+    fn rejects_long_names() {
+        let mut zip = ZipWriter::new(Vec::new());
+        let name = "a".repeat(70000);
+        let err = zip.add(&name, &mut "".as_bytes()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+}
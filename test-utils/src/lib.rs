@@ -152,6 +152,49 @@ fn git_checkout(repo: &Repository, branch_name: &str, new_branch: bool) {
     repo.set_head(reference.unwrap().name().unwrap()).unwrap();
 }
 
-pub fn git_remote_branches(repo: &Repository) -> Branches {
+pub fn git_remote_branches(repo: &Repository) -> Branches<'_> {
     repo.branches(Some(BranchType::Remote)).unwrap()
 }
+
+/// Create a branch on top of HEAD with a single empty commit made at the given time, in seconds
+/// since the epoch.
+pub fn git_create_branch_at(repo: &Repository, branch_name: &str, message: &str, time: i64) {
+    let signature = Signature::new(
+        "Test User",
+        "test.user@example.com",
+        &git2::Time::new(time, 0),
+    )
+    .unwrap();
+    let parent_commit = repo.head().unwrap().peel_to_commit().unwrap();
+    let tree = parent_commit.tree().unwrap();
+    repo.commit(
+        Some(&format!("refs/heads/{}", branch_name)),
+        &signature,
+        &signature,
+        message,
+        &tree,
+        &[&parent_commit],
+    )
+    .unwrap();
+}
+
+/// Create a branch on top of HEAD with a single commit, containing whatever changes `change` makes
+/// to the working tree, which is passed to it. HEAD is left where it was.
+pub fn git_commit_on_branch(
+    repo: &Repository,
+    branch_name: &str,
+    message: &str,
+    change: impl FnOnce(&Path),
+) {
+    let default_branch = git_branch(repo);
+    git_checkout(repo, branch_name, true);
+    change(repo.workdir().unwrap());
+    let mut index = repo.index().unwrap();
+    index
+        .add_all(["."], git2::IndexAddOption::DEFAULT, None)
+        .unwrap();
+    index.update_all(["."], None).unwrap();
+    index.write().unwrap();
+    git_commit(repo, message);
+    git_checkout(repo, &default_branch, false);
+}
@@ -3,12 +3,14 @@ use std::fs::File;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use std::sync::Once;
+use std::time::Duration;
 
 use axum::body::Body;
-use axum::extract::Request;
-use axum::http::header::CONTENT_TYPE;
-use axum::http::StatusCode;
+use axum::extract::{ConnectInfo, Request};
+use axum::http::header::{CONTENT_DISPOSITION, CONTENT_TYPE};
+use axum::http::{Method, StatusCode};
 use axum::response::Response;
+use futures::StreamExt;
 use jsonschema::{Draft, JSONSchema, SchemaResolverError};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
@@ -22,7 +24,10 @@ use tracing_capture::{CaptureLayer, SharedStorage};
 use tracing_subscriber::{layer::SubscriberExt, Registry};
 use url::Url;
 
-use test_utils::{git_get_latest_commit, git_remote_branches, initialise_git_repo};
+use test_utils::{
+    copy_recursively, git_commit_on_branch, git_get_latest_commit, git_remote_branches,
+    initialise_git_repo,
+};
 
 static INIT: Once = Once::new();
 
@@ -47,16 +52,56 @@ fn get_test_dir() -> PathBuf {
 /// These functions are designed to keep the test code clear and concise. As an effect, they do not
 /// propagate errors and instead panic when they occur. This is acceptable in tests, but should not
 /// be copied over to production code.
-struct TestClient(axum::Router);
+struct TestClient {
+    router: axum::Router,
+    admin_token: Option<&'static str>,
+}
+
+/// The admin token configured by `TestClient::with_admin_token`.
+const ADMIN_TOKEN: &str = "admin-s3cret";
 
 impl TestClient {
     fn new(root: impl Into<PathBuf>) -> TestClient {
         let api = outpack::api::api(&root.into()).unwrap();
-        TestClient(api)
+        TestClient {
+            router: api,
+            admin_token: None,
+        }
+    }
+
+    /// A client for a server configured with an admin token, which the client sends with every
+    /// request that does not have its own `Authorization` header.
+    fn with_admin_token(root: impl Into<PathBuf>) -> TestClient {
+        let root = root.into();
+        update_config(&root, |config| {
+            config["server"]["admin_token"] = ADMIN_TOKEN.into();
+        });
+        TestClient {
+            admin_token: Some(ADMIN_TOKEN),
+            ..TestClient::new(root)
+        }
+    }
+
+    fn with_roots(roots: &[(&str, &Path)]) -> TestClient {
+        let roots = roots
+            .iter()
+            .map(|(name, root)| (name.to_string(), root.to_path_buf()))
+            .collect();
+        TestClient {
+            router: outpack::api::api_with_roots(&roots).unwrap(),
+            admin_token: None,
+        }
     }
 
-    async fn request(&mut self, request: Request) -> Response {
-        self.0.call(request).await.unwrap()
+    async fn request(&mut self, mut request: Request) -> Response {
+        if let Some(token) = self.admin_token {
+            let headers = request.headers_mut();
+            if !headers.contains_key("Authorization") {
+                let value = format!("Bearer {}", token).parse().unwrap();
+                headers.insert("Authorization", value);
+            }
+        }
+        self.router.call(request).await.unwrap()
     }
 
     async fn get(&mut self, path: impl AsRef<str>) -> Response {
@@ -77,6 +122,11 @@ impl TestClient {
         self.request(request).await
     }
 
+    async fn delete(&mut self, path: impl AsRef<str>) -> Response {
+        let request = Request::delete(path.as_ref()).body(Body::empty()).unwrap();
+        self.request(request).await
+    }
+
     async fn post_json<T: Serialize>(&mut self, path: impl AsRef<str>, data: &T) -> Response {
         self.post(
             path,
@@ -134,6 +184,46 @@ impl ResponseExt for Response {
     }
 }
 
+#[tokio::test]
+async fn can_serve_on_configured_port() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    // Find a port that is free, then release it for the server to use.
+    let port = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+
+    let config = outpack::api::ApiConfig {
+        address: "127.0.0.1".parse().unwrap(),
+        port,
+    };
+    let api = outpack::api::api_with_config(&get_test_dir(), &config)
+        .await
+        .unwrap();
+    assert_eq!(api.local_addr().unwrap().port(), port);
+
+    let (tx, rx) = tokio::sync::oneshot::channel::<()>();
+    let server = tokio::spawn(api.run(async move {
+        let _ = rx.await;
+    }));
+
+    let mut stream = tokio::net::TcpStream::connect(("127.0.0.1", port))
+        .await
+        .unwrap();
+    stream
+        .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+        .await
+        .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+    assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+
+    tx.send(()).unwrap();
+    server.await.unwrap().unwrap();
+}
+
 #[test]
 fn error_if_invalid_root() {
     let res = outpack::api::api(Path::new("bad-root"));
@@ -155,432 +245,4303 @@ async fn can_get_index() {
 }
 
 #[tokio::test]
-async fn can_get_checksum() {
+async fn responses_include_security_headers() {
     let mut client = get_default_client();
+    for path in ["/", "/file/sha256:123456", "/does-not-exist"] {
+        let response = client.get(path).await;
+        let headers = response.headers();
+        assert_eq!(headers["x-content-type-options"], "nosniff");
+        assert_eq!(headers["x-frame-options"], "DENY");
+        assert_eq!(headers["referrer-policy"], "no-referrer");
+    }
+}
 
-    let response = client.get("/checksum").await;
-
-    assert_eq!(response.status(), StatusCode::OK);
-    assert_eq!(response.content_type(), mime::APPLICATION_JSON);
-
-    let body = response.to_json().await;
-    validate_success("outpack", "hash.json", &body);
+#[tokio::test]
+async fn responses_include_api_version() {
+    let mut client = get_default_client();
+    for path in ["/", "/file/sha256:123456", "/does-not-exist", "/metrics"] {
+        let response = client.get(path).await;
+        assert_eq!(
+            response.headers()["outpack-api-version"],
+            outpack::api::API_VERSION
+        );
+    }
+}
 
-    let response = client.get("/checksum?alg=md5").await;
+#[tokio::test]
+async fn security_headers_can_be_configured() {
+    let root = get_test_dir();
+    update_config(&root, |config| {
+        config["server"] = serde_json::json!({
+            "security_headers": { "x_frame_options": null, "referrer_policy": "same-origin" }
+        });
+    });
+    let mut client = TestClient::new(&root);
 
+    let response = client.get("/").await;
     assert_eq!(response.status(), StatusCode::OK);
-    assert_eq!(response.content_type(), mime::APPLICATION_JSON);
+    let headers = response.headers();
+    assert_eq!(headers["x-content-type-options"], "nosniff");
+    assert!(headers.get("x-frame-options").is_none());
+    assert_eq!(headers["referrer-policy"], "same-origin");
 
     let body = response.to_json().await;
-    validate_success("outpack", "hash.json", &body);
-
-    let hash = body["data"].as_str().unwrap();
-    assert!(hash.starts_with("md5:"));
+    validate_success("server", "root.json", &body);
 }
 
 #[tokio::test]
-async fn can_list_location_metadata() {
-    let mut client = get_default_client();
-    let response = client.get("/metadata/list").await;
+async fn cors_preflight_responses_include_configured_max_age() {
+    let root = get_test_dir();
+    update_config(&root, |config| {
+        config["server"] = serde_json::json!({
+            "cors": { "allowed_origins": ["https://example.com"], "max_age": 3600 }
+        });
+    });
+    let mut client = TestClient::new(&root);
 
+    let request = Request::options("/metadata/list")
+        .header("Origin", "https://example.com")
+        .header("Access-Control-Request-Method", "GET")
+        .body(Body::empty())
+        .unwrap();
+    let response = client.request(request).await;
     assert_eq!(response.status(), StatusCode::OK);
-    assert_eq!(response.content_type(), mime::APPLICATION_JSON);
-
-    let body = response.to_json().await;
-    validate_success("server", "locations.json", &body);
-
-    let entries = body.get("data").unwrap().as_array().unwrap();
-    assert_eq!(entries.len(), 4);
-
+    let headers = response.headers();
     assert_eq!(
-        entries[0].get("packet").unwrap().as_str().unwrap(),
-        "20170818-164847-7574883b"
+        headers["access-control-allow-origin"],
+        "https://example.com"
     );
+    assert_eq!(headers["access-control-max-age"], "3600");
+
+    // Ordinary cross-origin requests are allowed, but are not preflights.
+    let request = Request::get("/metadata/list")
+        .header("Origin", "https://example.com")
+        .body(Body::empty())
+        .unwrap();
+    let response = client.request(request).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let headers = response.headers();
     assert_eq!(
-        entries[0].get("time").unwrap().as_f64().unwrap(),
-        1662480556.1778
+        headers["access-control-allow-origin"],
+        "https://example.com"
     );
+    assert!(headers.get("access-control-max-age").is_none());
+    // Browsers only let applications read the headers they are told about.
     assert_eq!(
-        entries[0].get("hash").unwrap().as_str().unwrap(),
-        "sha256:af3c863f96898c6c88cee4daa1a6d6cfb756025e70059f5ea4dbe4d9cc5e0e36"
+        headers["access-control-expose-headers"],
+        "outpack-api-version"
     );
+}
 
+#[tokio::test]
+async fn cors_settings_can_be_reloaded() {
+    let root = get_test_dir();
+    let mut client = TestClient::new(&root);
+    let preflight = || {
+        Request::options("/metadata/list")
+            .header("Origin", "https://example.com")
+            .header("Access-Control-Request-Method", "GET")
+            .body(Body::empty())
+            .unwrap()
+    };
+
+    let response = client.request(preflight()).await;
+    assert!(response
+        .headers()
+        .get("access-control-allow-origin")
+        .is_none());
+
+    update_config(&root, |config| {
+        config["server"] = serde_json::json!({
+            "cors": { "allowed_origins": ["https://example.com"] }
+        });
+    });
+    let response = client.post_json("/admin/reload", &()).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = client.request(preflight()).await;
     assert_eq!(
-        entries[1].get("packet").unwrap().as_str().unwrap(),
-        "20170818-164830-33e0ab01"
-    );
-    assert_eq!(
-        entries[2].get("packet").unwrap().as_str().unwrap(),
-        "20180220-095832-16a4bbed"
+        response.headers()["access-control-allow-origin"],
+        "https://example.com"
     );
+
+    // Invalid origins are rejected by the reload, and the previous settings stay in use.
+    update_config(&root, |config| {
+        config["server"]["cors"]["allowed_origins"] = serde_json::json!(["bad\norigin"]);
+    });
+    let response = client.post_json("/admin/reload", &()).await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    validate_error(&response.to_json().await, Some("Invalid CORS origin"));
+    let response = client.request(preflight()).await;
     assert_eq!(
-        entries[3].get("packet").unwrap().as_str().unwrap(),
-        "20180818-164043-7cdcde4b"
+        response.headers()["access-control-allow-origin"],
+        "https://example.com"
     );
 }
 
 #[tokio::test]
-async fn handles_location_metadata_errors() {
-    let mut client = TestClient::new("tests/bad-example");
-    let response = client.get("/metadata/list").await;
-    assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
-    assert_eq!(response.content_type(), mime::APPLICATION_JSON);
-
-    let body = response.to_json().await;
-    validate_error(&body, Some("missing field `packet`"));
+async fn cors_is_disabled_by_default() {
+    let mut client = get_default_client();
+    let request = Request::options("/metadata/list")
+        .header("Origin", "https://example.com")
+        .header("Access-Control-Request-Method", "GET")
+        .body(Body::empty())
+        .unwrap();
+    let response = client.request(request).await;
+    assert!(response
+        .headers()
+        .get("access-control-allow-origin")
+        .is_none());
+    assert!(response.headers().get("access-control-max-age").is_none());
 }
 
 #[tokio::test]
-async fn can_list_metadata() {
+async fn can_get_openapi_document() {
     let mut client = get_default_client();
-    let response = client.get("/packit/metadata").await;
-
+    let response = client.get("/openapi.json").await;
     assert_eq!(response.status(), StatusCode::OK);
     assert_eq!(response.content_type(), mime::APPLICATION_JSON);
 
-    let body = response.to_json().await;
-    print!("{}", body);
-    validate_success("server", "list.json", &body);
-
-    let entries = body.get("data").unwrap().as_array().unwrap();
-    assert_eq!(entries.len(), 4);
+    let document: Value = response.to_json().await;
+    assert_eq!(document["openapi"], "3.1.0");
+    assert!(document["info"]["title"].is_string());
+    assert!(document["info"]["version"].is_string());
+
+    let paths = document["paths"].as_object().unwrap();
+    for path in [
+        "/",
+        "/metadata/list",
+        "/metadata/{id}/json",
+        "/file/{hash}",
+        "/upload/{id}/finish",
+        "/sync/pull",
+        "/roots",
+    ] {
+        assert!(paths.contains_key(path), "Missing path '{}'", path);
+    }
+    for (path, item) in paths {
+        for (method, operation) in item.as_object().unwrap() {
+            // A path may be served somewhere other than the document's servers.
+            if method == "servers" {
+                continue;
+            }
+            assert!(
+                ["get", "head", "post", "patch", "delete"].contains(&method.as_str()),
+                "Unexpected method '{}' for '{}'",
+                method,
+                path
+            );
+            assert!(operation["responses"].is_object());
+        }
+    }
 
-    assert_eq!(
-        entries[0].get("id").unwrap().as_str().unwrap(),
-        "20170818-164830-33e0ab01"
-    );
-    assert_eq!(
-        entries[0]
-            .get("parameters")
-            .unwrap()
-            .get("disease")
-            .unwrap(),
-        "YF"
-    );
-    assert_eq!(
-        entries[0]
-            .get("parameters")
-            .unwrap()
-            .as_object()
-            .unwrap()
-            .get("disease")
-            .unwrap()
-            .as_str()
-            .unwrap(),
-        "YF"
-    );
-    assert_eq!(
-        entries[0].get("time").unwrap().get("start").unwrap(),
-        1503074938.2232
-    );
-    assert_eq!(
-        entries[0].get("time").unwrap().get("end").unwrap(),
-        1503074938.2232
-    );
-    assert_eq!(
-        entries[0]["custom"]["orderly"]["artefacts"][3]["description"],
-        "Projected Coverage for routine immunisation in PINE countries"
-    );
-    assert_eq!(
-        entries[1].get("id").unwrap().as_str().unwrap(),
-        "20170818-164847-7574883b"
-    );
-    assert_eq!(
-        entries[2].get("id").unwrap().as_str().unwrap(),
-        "20180220-095832-16a4bbed"
-    );
-    assert_eq!(
-        entries[3].get("id").unwrap().as_str().unwrap(),
-        "20180818-164043-7cdcde4b"
-    );
+    // The component schemas can be used to validate the server's responses.
+    let response = client.get("/metadata/20180818-164043-7cdcde4b/json").await;
+    let body: Value = response.to_json().await;
+    let mut schema = document.clone();
+    schema["$ref"] = Value::from("#/components/schemas/metadata");
+    let compiled = JSONSchema::options()
+        .with_draft(Draft::Draft7)
+        .compile(&schema)
+        .expect("A valid schema");
+    assert_valid(&body["data"], &compiled);
 }
 
 #[tokio::test]
-async fn can_list_metadata_from_date() {
+async fn can_get_checksum() {
     let mut client = get_default_client();
-    let response = client.get("/packit/metadata?known_since=1662480556").await;
+
+    let response = client.get("/checksum").await;
 
     assert_eq!(response.status(), StatusCode::OK);
     assert_eq!(response.content_type(), mime::APPLICATION_JSON);
 
     let body = response.to_json().await;
-    print!("{}", body);
-    validate_success("server", "list.json", &body);
+    validate_success("outpack", "hash.json", &body);
 
-    let entries = body.get("data").unwrap().as_array().unwrap();
-    assert_eq!(entries.len(), 1);
-    assert_eq!(
-        entries[0].get("id").unwrap().as_str().unwrap(),
-        "20170818-164847-7574883b"
-    );
-}
+    // The algorithm used is given as the hash's prefix, and defaults to the one configured.
+    let hash = body["data"].as_str().unwrap();
+    assert!(hash.starts_with("sha256:"));
 
-#[tokio::test]
-async fn handles_metadata_errors() {
-    let mut client = TestClient::new("tests/bad-example");
-    let response = client.get("/packit/metadata").await;
-    assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    let response = client.get("/checksum?alg=md5").await;
+
+    assert_eq!(response.status(), StatusCode::OK);
     assert_eq!(response.content_type(), mime::APPLICATION_JSON);
 
     let body = response.to_json().await;
-    validate_error(&body, Some("missing field `name`"));
+    validate_success("outpack", "hash.json", &body);
+
+    let hash = body["data"].as_str().unwrap();
+    assert!(hash.starts_with("md5:"));
+}
+
+fn example_metadata(id: &str) -> Value {
+    let path = Path::new("tests/example/.outpack/metadata/20170818-164830-33e0ab01");
+    let mut metadata: Value = serde_json::from_str(&fs::read_to_string(path).unwrap()).unwrap();
+    metadata["id"] = id.into();
+    metadata
+}
+
+/// Metadata for a packet whose only file is already in the example repository's store, and which
+/// has no dependencies, so that it can be added there.
+fn complete_metadata(id: &str) -> Value {
+    let mut metadata = example_metadata(id);
+    metadata["files"] = serde_json::json!([{
+        "path": "data.csv",
+        "size": 115,
+        "hash": "sha256:b189579a9326f585d308304bd9e03326be5d395ac71b31df359ab8bac408d248"
+    }]);
+    metadata["depends"] = serde_json::json!([]);
+    metadata
+}
+
+/// Write metadata straight into the repository, as when it is pulled from another location
+/// without the packet being unpacked.
+fn add_metadata_only(root: &Path, metadata: &Value) {
+    let id = metadata["id"].as_str().unwrap();
+    let path = root.join(".outpack").join("metadata").join(id);
+    fs::write(path, serde_json::to_string(metadata).unwrap()).unwrap();
 }
 
 #[tokio::test]
-async fn can_get_metadata_json() {
+async fn can_add_metadata() {
     let mut client = get_default_client();
-    let response = client.get("/metadata/20180818-164043-7cdcde4b/json").await;
+    let id = "20240101-120000-00000001";
+    let data = serde_json::to_string(&complete_metadata(id)).unwrap();
 
+    let response = client
+        .post("/metadata", mime::APPLICATION_JSON, data.clone())
+        .await;
     assert_eq!(response.status(), StatusCode::OK);
     assert_eq!(response.content_type(), mime::APPLICATION_JSON);
 
     let body = response.to_json().await;
-    validate_success("outpack", "metadata.json", &body);
+    validate_success("outpack", "hash.json", &body);
+    assert_eq!(
+        body["data"].as_str().unwrap(),
+        format!("sha256:{:x}", Sha256::digest(&data))
+    );
+
+    let response = client.get(format!("/metadata/{}/text", id)).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.to_string().await, data);
+
+    // The packet is available locally, like one added with POST /packet.
+    let body = serde_json::json!({ "ids": [id], "unpacked": true });
+    let response = client.post_json("/packets/missing", &body).await;
+    let body: Value = response.to_json().await;
+    assert_eq!(body["data"], serde_json::json!([]));
 }
 
 #[tokio::test]
-async fn can_get_metadata_text() {
+async fn add_metadata_requires_files_and_dependencies() {
     let mut client = get_default_client();
-    let response = client.get("/metadata/20180818-164043-7cdcde4b/text").await;
+    let id = "20240101-120000-00000001";
 
-    assert_eq!(response.status(), StatusCode::OK);
-    assert_eq!(response.content_type(), mime::TEXT_PLAIN_UTF_8);
+    let response = client.post_json("/metadata", &example_metadata(id)).await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    validate_error(&response.to_json().await, Some("as files missing"));
 
-    let expected = fs::File::open(Path::new(
-        "tests/example/.outpack/metadata/20180818-164043-7cdcde4b",
-    ))
-    .unwrap();
+    let mut metadata = complete_metadata(id);
+    let files = serde_json::json!([{ "here": "data.csv", "there": "data.csv" }]);
+    metadata["depends"] = serde_json::json!([{ "packet": "20240101-110000-00000002", "query": "latest", "files": files }]);
+    let response = client.post_json("/metadata", &metadata).await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    validate_error(&response.to_json().await, Some("as dependencies missing"));
 
-    let result: Value = response.to_json().await;
-    let expected: Value = serde_json::from_reader(expected).unwrap();
-    assert_eq!(result, expected);
+    // Neither packet is added, so the tree stays complete.
+    let response = client.get(format!("/metadata/{}/json", id)).await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    let body = serde_json::json!({ "ids": [id] });
+    let response = client.post_json("/packets/missing", &body).await;
+    let body: Value = response.to_json().await;
+    assert_eq!(body["data"], serde_json::json!([id]));
 }
 
 #[tokio::test]
-async fn returns_404_if_packet_not_found() {
+async fn add_metadata_validates_against_schema() {
     let mut client = get_default_client();
-    let response = client.get("/metadata/bad-id/json").await;
+    let id = "20240101-120000-00000001";
+    let mut metadata = example_metadata(id);
+    metadata.as_object_mut().unwrap().remove("name");
 
-    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    let response = client.post_json("/metadata", &metadata).await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
     assert_eq!(response.content_type(), mime::APPLICATION_JSON);
 
     let body = response.to_json().await;
-    validate_error(&body, Some("packet with id 'bad-id' does not exist"))
+    validate_error(
+        &body,
+        Some("Metadata does not conform to the outpack schema"),
+    );
+    let detail = body["errors"][0]["detail"].as_str().unwrap();
+    assert!(detail.contains(" - /: \"name\" is a required property"));
+
+    let response = client.post("/metadata", mime::APPLICATION_JSON, "{").await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let body = response.to_json().await;
+    validate_error(&body, Some("Metadata is not valid json"));
+
+    let response = client.get(format!("/metadata/{}/json", id)).await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
 }
 
 #[tokio::test]
-async fn can_get_file() {
+async fn can_validate_metadata_dependencies() {
     let mut client = get_default_client();
-    let hash = "sha256:b189579a9326f585d308304bd9e03326be5d395ac71b31df359ab8bac408d248";
-    let response = client.get(format!("/file/{}", hash)).await;
-
+    let id = "20240101-120000-00000001";
+    let present = "20170818-164847-7574883b";
+    let absent = "20240101-110000-00000002";
+    let mut metadata = example_metadata(id);
+    let files = serde_json::json!([{ "here": "data.csv", "there": "data.csv" }]);
+    metadata["depends"] = serde_json::json!([
+        { "packet": present, "query": "latest", "files": files },
+        { "packet": absent, "query": "latest", "files": files },
+    ]);
+
+    let response = client.post_json("/metadata/validate", &metadata).await;
     assert_eq!(response.status(), StatusCode::OK);
-    assert_eq!(response.content_type(), mime::APPLICATION_OCTET_STREAM);
-
-    let path = Path::new("tests/example/.outpack/files/sha256/b1/")
-        .join("89579a9326f585d308304bd9e03326be5d395ac71b31df359ab8bac408d248");
+    let body = response.to_json().await;
+    validate_success("server", "ids.json", &body);
+    assert_eq!(body["data"], serde_json::json!([absent]));
 
-    let expected = fs::read(path).unwrap();
+    // Nothing is added, whether or not the dependencies are all present.
+    let response = client.get(format!("/metadata/{}/json", id)).await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
 
-    assert_eq!(response.to_bytes().await, expected);
-}
+    metadata["depends"].as_array_mut().unwrap().pop();
+    let response = client.post_json("/metadata/validate", &metadata).await;
+    let body: Value = response.to_json().await;
+    assert_eq!(body["data"], serde_json::json!([]));
 
-#[tokio::test]
-async fn returns_404_if_file_not_found() {
-    let mut client = get_default_client();
-    let hash = "sha256:123456";
-    let response = client.get(format!("/file/{}", hash)).await;
+    metadata.as_object_mut().unwrap().remove("name");
+    let response = client.post_json("/metadata/validate", &metadata).await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let body = response.to_json().await;
+    validate_error(
+        &body,
+        Some("Metadata does not conform to the outpack schema"),
+    );
+}
 
-    assert_eq!(response.status(), StatusCode::NOT_FOUND);
-    assert_eq!(response.content_type(), mime::APPLICATION_JSON);
+#[tokio::test]
+async fn metadata_violations_give_json_pointers() {
+    let mut client = get_default_client();
+    let mut metadata = example_metadata("20240101-120000-00000001");
+    metadata["files"][0]["size"] = "large".into();
 
+    let response = client.post_json("/metadata/validate", &metadata).await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
     let body = response.to_json().await;
-    validate_error(&body, Some("hash 'sha256:123456' not found"))
+    validate_error(&body, Some("/files/0/size"));
+    let violations = body["errors"][0]["violations"].as_array().unwrap();
+    assert_eq!(violations.len(), 1);
+    assert_eq!(violations[0]["pointer"], "/files/0/size");
+    assert_eq!(
+        violations[0]["message"],
+        "\"large\" is not of type \"number\""
+    );
+
+    // Errors which are not from validation have no violations.
+    let response = client.get("/metadata/bad-id/json").await;
+    let body: Value = response.to_json().await;
+    assert!(body["errors"][0].get("violations").is_none());
 }
 
-#[derive(Serialize, Deserialize)]
-struct Ids {
-    ids: Vec<String>,
-    unpacked: bool,
+#[tokio::test]
+async fn sync_plan_combines_missing_packets_and_files() {
+    let root = get_empty_root();
+    let mut client = TestClient::new(&root);
+    let present = add_packet_with_file(&mut client, "20240101-120000-00000001", "a", &[]).await;
+    let known = "20240101-120000-00000002";
+    let metadata = example_metadata(known);
+    add_metadata_only(&root, &metadata);
+    let unknown = "20240101-120000-00000003";
+    let ids = vec![present.clone(), known.to_owned(), unknown.to_owned()];
+
+    let body = serde_json::json!({ "ids": ids, "unpacked": true });
+    let response = client.post_json("/sync/plan", &body).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let plan: Value = response.to_json().await;
+    validate_success("server", "sync-plan.json", &plan);
+    let plan = &plan["data"];
+
+    let response = client.post_json("/packets/missing", &body).await;
+    let missing: Value = response.to_json().await;
+    let mut missing: Vec<String> = serde_json::from_value(missing["data"].clone()).unwrap();
+    missing.sort();
+    assert_eq!(plan["packets"], serde_json::json!(missing));
+    assert_eq!(plan["packets"], serde_json::json!([known, unknown]));
+
+    let hashes: Vec<&str> = metadata["files"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|f| f["hash"].as_str().unwrap())
+        .collect();
+    let response = client
+        .post_json("/files/missing", &serde_json::json!({ "hashes": hashes }))
+        .await;
+    let missing: Value = response.to_json().await;
+    assert_eq!(plan["files"], missing["data"]);
+    assert!(!plan["files"].as_array().unwrap().is_empty());
+    assert_eq!(plan["unknown"], serde_json::json!([unknown]));
+
+    // Once a file of a present packet goes missing, it is only included when asked for.
+    let hash = format!("sha256:{:x}", Sha256::digest("a"));
+    fs::remove_file(outpack::store::file_path(&root, &hash).unwrap()).unwrap();
+    let response = client.post_json("/sync/plan", &body).await;
+    let plan: Value = response.to_json().await;
+    assert!(!plan["data"]["files"]
+        .as_array()
+        .unwrap()
+        .contains(&hash.clone().into()));
+
+    let body = serde_json::json!({ "ids": ids, "unpacked": true, "incomplete": true });
+    let response = client.post_json("/sync/plan", &body).await;
+    let plan: Value = response.to_json().await;
+    assert!(plan["data"]["files"]
+        .as_array()
+        .unwrap()
+        .contains(&hash.into()));
+    assert_eq!(plan["data"]["packets"], serde_json::json!([known, unknown]));
 }
 
 #[tokio::test]
-async fn can_get_missing_ids() {
+async fn can_get_missing_packets_recursively() {
     let mut client = get_default_client();
+
+    // The dependency of this packet is not present at all, and so is always missing.
+    let ids = vec!["20170818-164830-33e0ab01"];
     let response = client
         .post_json(
             "/packets/missing",
-            &Ids {
-                ids: vec![
-                    "20180818-164043-7cdcde4b".to_string(),
-                    "20170818-164830-33e0ab01".to_string(),
-                ],
-                unpacked: false,
-            },
+            &serde_json::json!({ "ids": ids, "unpacked": true }),
         )
         .await;
-
     assert_eq!(response.status(), StatusCode::OK);
-    assert_eq!(response.content_type(), mime::APPLICATION_JSON);
-
     let body = response.to_json().await;
     validate_success("server", "ids.json", &body);
-    let entries = body.get("data").unwrap().as_array().unwrap();
-    assert_eq!(entries.len(), 0);
-}
+    assert_eq!(
+        body["data"],
+        serde_json::json!(["20170818-164830-33e0ab01"])
+    );
 
-#[tokio::test]
-async fn can_get_missing_unpacked_ids() {
-    let mut client = get_default_client();
     let response = client
         .post_json(
             "/packets/missing",
-            &Ids {
-                ids: vec![
-                    "20170818-164847-7574883b".to_string(),
-                    "20170818-164830-33e0ab02".to_string(),
-                ],
-                unpacked: true,
-            },
+            &serde_json::json!({ "ids": ids, "unpacked": true, "recursive": true }),
         )
         .await;
     assert_eq!(response.status(), StatusCode::OK);
-    assert_eq!(response.content_type(), mime::APPLICATION_JSON);
-
     let body = response.to_json().await;
     validate_success("server", "ids.json", &body);
-    let entries = body.get("data").unwrap().as_array().unwrap();
-    assert_eq!(entries.len(), 1);
+
+    let mut missing: Vec<&str> = body["data"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|v| v.as_str().unwrap())
+        .collect();
+    missing.sort();
     assert_eq!(
-        entries.first().unwrap().as_str(),
-        Some("20170818-164830-33e0ab02")
+        missing,
+        ["20170818-164043-7cdcde4b", "20170818-164830-33e0ab01"]
     );
 }
 
 #[tokio::test]
-async fn missing_packets_propagates_errors() {
-    let mut client = get_default_client();
+async fn hashes_can_be_sent_and_received_as_multihashes() {
+    use outpack::hash::{Hash, HashEncoding};
+
+    let root = get_empty_root();
+    let mut client = TestClient::new(&root);
+    add_packet_with_file(&mut client, "20240101-000000-00000001", "contents", &[]).await;
+    let hex = format!("sha256:{:x}", Sha256::digest("contents"));
+    let hash: Hash = hex.parse().unwrap();
+    let multihash = hash.encode(HashEncoding::Multihash).unwrap();
+
+    let body: Value = client.get("/checksum").await.to_json().await;
+    let checksum = body["data"].as_str().unwrap().to_owned();
+    let body: Value = client
+        .get("/checksum?encoding=multihash")
+        .await
+        .to_json()
+        .await;
+    let encoded = body["data"].as_str().unwrap();
+    assert!(encoded.starts_with("bciq"));
+    let (decoded, encoding) = Hash::decode(encoded).unwrap();
+    assert_eq!(decoded.to_string(), checksum);
+    assert_eq!(encoding, HashEncoding::Multihash);
+
+    let response = client.get(format!("/file/{}", multihash)).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.to_bytes().await, "contents".as_bytes());
+
+    // Missing files are reported in the encoding they were asked about in.
+    let absent: Hash = format!("sha256:{:x}", Sha256::digest("absent"))
+        .parse()
+        .unwrap();
+    let absent = absent.encode(HashEncoding::Multihash).unwrap();
     let response = client
         .post_json(
-            "/packets/missing",
-            &Ids {
-                ids: vec!["badid".to_string()],
-                unpacked: true,
-            },
+            "/files/missing",
+            &serde_json::json!({ "hashes": [multihash, absent] }),
         )
         .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let body: Value = response.to_json().await;
+    validate_success("server", "encoded-hashes.json", &body);
+    assert_eq!(body["data"], serde_json::json!([absent]));
 
+    let response = client
+        .post_json(
+            "/files/missing",
+            &serde_json::json!({ "hashes": [hex, absent] }),
+        )
+        .await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
     let body = response.to_json().await;
-    validate_error(&body, Some("Invalid packet id"));
+    validate_error(&body, Some("Hashes must all be in the same encoding"));
+
+    // File info is keyed by the hashes as they were sent.
+    let response = client
+        .post_json(
+            "/files/info",
+            &serde_json::json!({ "hashes": [multihash, absent] }),
+        )
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let body: Value = response.to_json().await;
+    validate_success("server", "file-info.json", &body);
+    assert_eq!(body["data"][&multihash]["present"], true);
+    assert_eq!(body["data"][&multihash]["size"], 8);
+    assert_eq!(
+        body["data"][&absent],
+        serde_json::json!({ "present": false })
+    );
 }
 
 #[tokio::test]
-async fn missing_packets_validates_request_body() {
+async fn checksum_rejects_unsupported_algorithm() {
     let mut client = get_default_client();
-    let response = client
-        .post("/packets/missing", mime::APPLICATION_JSON, Body::empty())
-        .await;
+
+    let response = client.get("/checksum?alg=sha3-256").await;
 
     assert_eq!(response.status(), StatusCode::BAD_REQUEST);
     assert_eq!(response.content_type(), mime::APPLICATION_JSON);
 
     let body = response.to_json().await;
-    validate_error(&body, Some("EOF while parsing a value at line 1 column 0"));
+    validate_error(&body, Some("Invalid hash algorithm 'sha3-256'"));
 }
 
-#[derive(Serialize, Deserialize)]
-struct Hashes {
-    hashes: Vec<String>,
+fn update_config(root: &Path, f: impl FnOnce(&mut Value)) {
+    let path = root.join(".outpack").join("config.json");
+    let mut config: Value = serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+    f(&mut config);
+    fs::write(path, serde_json::to_string(&config).unwrap()).unwrap();
+}
+
+async fn get_first_listed_packet(client: &mut TestClient) -> String {
+    let body: Value = client.get("/metadata/list").await.to_json().await;
+    body["data"][0]["packet"].as_str().unwrap().to_owned()
 }
 
 #[tokio::test]
-async fn can_get_missing_files() {
+async fn can_reload_config() {
+    let root = get_test_dir();
+    let mut client = TestClient::new(&root);
+
+    assert_eq!(
+        get_first_listed_packet(&mut client).await,
+        "20170818-164847-7574883b"
+    );
+
+    update_config(&root, |config| {
+        config["location"].as_array_mut().unwrap().reverse();
+    });
+
+    // Changes on disk are not picked up until the configuration is reloaded.
+    assert_eq!(
+        get_first_listed_packet(&mut client).await,
+        "20170818-164847-7574883b"
+    );
+
+    let response = client.post_json("/admin/reload", &()).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = response.to_json().await;
+    validate_success("server", "null-response.json", &body);
+
+    assert_eq!(
+        get_first_listed_packet(&mut client).await,
+        "20170818-164830-33e0ab01"
+    );
+}
+
+#[tokio::test]
+async fn can_reindex_packets() {
+    let root = get_test_dir();
+    let mut client = TestClient::new(&root);
+    let body: Value = client.get("/metadata/list").await.to_json().await;
+    assert_eq!(body["data"].as_array().unwrap().len(), 4);
+
+    // Add a packet by writing its metadata directly, without going through the server.
+    let metadata = root.join(".outpack").join("metadata");
+    let contents = fs::read_to_string(metadata.join("20170818-164830-33e0ab01")).unwrap();
+    let id = "20240101-000000-00000001";
+    fs::write(
+        metadata.join(id),
+        contents.replace("20170818-164830-33e0ab01", id),
+    )
+    .unwrap();
+
+    let response = client.post_json("/admin/reindex", &()).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = response.to_json().await;
+    validate_success("server", "reindex.json", &body);
+    assert_eq!(body["data"]["packets"], 5);
+
+    let response = client.get(format!("/metadata/{}/json", id)).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let body: Value = response.to_json().await;
+    assert_eq!(body["data"]["id"], id);
+}
+
+#[tokio::test]
+async fn admin_endpoints_can_require_token() {
+    let root = get_test_dir();
+    update_config(&root, |config| {
+        config["server"] = serde_json::json!({ "admin_token": "s3cret" });
+    });
+    let mut client = TestClient::new(&root);
+
+    for path in ["/admin/reload", "/admin/reindex", "/admin/clean-temp"] {
+        let response = client.post_json(path, &()).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(response.headers()["www-authenticate"], "Bearer");
+        let body = response.to_json().await;
+        validate_error(&body, Some("requires the admin token"));
+
+        for token in ["Bearer wrong", "s3cret"] {
+            let request = Request::post(path)
+                .header(CONTENT_TYPE, "application/json")
+                .header("Authorization", token)
+                .body(Body::from("null"))
+                .unwrap();
+            let response = client.request(request).await;
+            assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        }
+
+        let request = Request::post(path)
+            .header(CONTENT_TYPE, "application/json")
+            .header("Authorization", "Bearer s3cret")
+            .body(Body::from("null"))
+            .unwrap();
+        let response = client.request(request).await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    // Other endpoints are unaffected.
+    let response = client.get("/metadata/list").await;
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn mutating_endpoints_can_require_admin_token() {
+    let root = get_test_dir();
+    update_config(&root, |config| {
+        config["server"] = serde_json::json!({ "admin_token": "s3cret" });
+    });
+    let mut client = TestClient::new(&root);
+    let hash = "sha256:b189579a9326f585d308304bd9e03326be5d395ac71b31df359ab8bac408d248";
+
+    let requests = [
+        (Method::POST, "/store/gc".to_owned()),
+        (Method::POST, format!("/file/{}/pull", hash)),
+        (Method::POST, "/locations".to_owned()),
+        (Method::DELETE, "/locations/other".to_owned()),
+        (Method::POST, "/sync/pull".to_owned()),
+        (Method::POST, "/git/fetch".to_owned()),
+    ];
+    for (method, path) in requests {
+        let request = Request::builder()
+            .method(method.clone())
+            .uri(&path)
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from("{}"))
+            .unwrap();
+        let response = client.request(request).await;
+        assert_eq!(
+            response.status(),
+            StatusCode::UNAUTHORIZED,
+            "{} {}",
+            method,
+            path
+        );
+        let body = response.to_json().await;
+        validate_error(&body, Some("requires the admin token"));
+    }
+
+    let request = Request::post("/store/gc")
+        .header("Authorization", "Bearer s3cret")
+        .body(Body::empty())
+        .unwrap();
+    let response = client.request(request).await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // Locations can still be listed without the token.
+    let response = client.get("/locations").await;
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn remote_endpoints_are_disabled_without_admin_token() {
     let mut client = get_default_client();
+    let hash = "sha256:b189579a9326f585d308304bd9e03326be5d395ac71b31df359ab8bac408d248";
+
+    let requests = [
+        (Method::POST, format!("/file/{}/pull", hash)),
+        (Method::POST, "/locations".to_owned()),
+        (Method::DELETE, "/locations/other".to_owned()),
+        (Method::POST, "/sync/pull".to_owned()),
+        (Method::POST, "/git/fetch".to_owned()),
+    ];
+    for (method, path) in requests {
+        let request = Request::builder()
+            .method(method.clone())
+            .uri(&path)
+            .header(CONTENT_TYPE, "application/json")
+            .header("Authorization", "Bearer anything")
+            .body(Body::from("{}"))
+            .unwrap();
+        let response = client.request(request).await;
+        assert_eq!(
+            response.status(),
+            StatusCode::FORBIDDEN,
+            "{} {}",
+            method,
+            path
+        );
+        let body = response.to_json().await;
+        validate_error(&body, Some("disabled unless an admin token is configured"));
+    }
+
+    // The admin endpoints need no token when none is configured.
+    let response = client.post_json("/store/gc", &()).await;
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn can_clean_old_temporary_files() {
+    let root = get_empty_root();
+    let mut client = TestClient::new(&root);
+    add_packet_with_file(&mut client, "20240101-000000-00000001", "stored", &[]).await;
+    let hash = format!("sha256:{:x}", Sha256::digest("stored"));
+
+    let store = root.join(".outpack").join("files");
+    fs::write(store.join(".tmpold123"), "abandoned").unwrap();
+    fs::write(store.join(".tmpnew123"), "in progress").unwrap();
+    let old = filetime::FileTime::from_system_time(
+        std::time::SystemTime::now() - Duration::from_secs(2 * 24 * 60 * 60),
+    );
+    filetime::set_file_mtime(store.join(".tmpold123"), old).unwrap();
+    filetime::set_file_mtime(outpack::store::file_path(&root, &hash).unwrap(), old).unwrap();
+
+    let response = client.post_json("/admin/clean-temp", &()).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let body: Value = response.to_json().await;
+    validate_success("server", "clean-temp.json", &body);
+    assert_eq!(
+        body["data"],
+        serde_json::json!({ "removed": [".outpack/files/.tmpold123"], "bytes": 9 })
+    );
+
+    assert!(!store.join(".tmpold123").exists());
+    assert!(store.join(".tmpnew123").exists());
+    let response = client.get(format!("/file/{}", hash)).await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // A shorter age can be asked for, which still leaves recent files alone.
     let response = client
-        .post_json(
-            "/files/missing",
-            &Hashes {
-                hashes: vec![
-                    "sha256:b189579a9326f585d308304bd9e03326be5d395ac71b31df359ab8bac408d248"
-                        .to_string(),
-                    "sha256:a189579a9326f585d308304bd9e03326be5d395ac71b31df359ab8bac408d247"
-                        .to_string(),
-                ],
-            },
-        )
+        .post_json("/admin/clean-temp?max_age=3600", &())
         .await;
+    let body: Value = response.to_json().await;
+    assert_eq!(body["data"]["removed"], serde_json::json!([]));
+    assert!(store.join(".tmpnew123").exists());
+}
 
+async fn list_location_names(client: &mut TestClient) -> Vec<String> {
+    let response = client.get("/locations").await;
     assert_eq!(response.status(), StatusCode::OK);
-    assert_eq!(response.content_type(), mime::APPLICATION_JSON);
-
     let body = response.to_json().await;
-    validate_success("server", "hashes.json", &body);
-    let entries = body.get("data").unwrap().as_array().unwrap();
-    assert_eq!(entries.len(), 1);
+    validate_success("server", "location-configs.json", &body);
+    body["data"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|l| l["name"].as_str().unwrap().to_owned())
+        .collect()
+}
+
+#[tokio::test]
+async fn location_changes_can_be_conditional() {
+    let mut client = TestClient::with_admin_token(get_test_dir());
+    let response = client.get("/locations").await;
+    let etag = response.headers()["etag"].to_str().unwrap().to_owned();
+
+    let add = |name: &str, etag: &str| {
+        let body = serde_json::json!({ "name": name, "url": "http://example.com/outpack" });
+        Request::post("/locations")
+            .header(CONTENT_TYPE, "application/json")
+            .header("If-Match", etag)
+            .body(Body::from(body.to_string()))
+            .unwrap()
+    };
+
+    let response = client.request(add("upstream", &etag)).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let new_etag = response.headers()["etag"].to_str().unwrap().to_owned();
+    assert_ne!(new_etag, etag);
+    let response = client.get("/locations").await;
+    assert_eq!(response.headers()["etag"], new_etag.as_str());
+
+    // A client which has not seen that change cannot make one of its own.
+    let response = client.request(add("other", &etag)).await;
+    assert_eq!(response.status(), StatusCode::PRECONDITION_FAILED);
+    validate_error(
+        &response.to_json().await,
+        Some("has changed since it was read"),
+    );
+    let request = Request::delete("/locations/upstream")
+        .header("If-Match", &etag)
+        .body(Body::empty())
+        .unwrap();
+    let response = client.request(request).await;
+    assert_eq!(response.status(), StatusCode::PRECONDITION_FAILED);
     assert_eq!(
-        entries.first().unwrap().as_str(),
-        Some("sha256:a189579a9326f585d308304bd9e03326be5d395ac71b31df359ab8bac408d247")
+        list_location_names(&mut client).await,
+        ["local", "another", "upstream"]
+    );
+
+    // Weak tags never match, but any of several tags may.
+    let response = client
+        .request(add("other", &format!("W/{}", new_etag)))
+        .await;
+    assert_eq!(response.status(), StatusCode::PRECONDITION_FAILED);
+    let response = client
+        .request(add("other", &format!("{}, {}", etag, new_etag)))
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let etag = response.headers()["etag"].to_str().unwrap().to_owned();
+
+    let request = Request::delete("/locations/upstream")
+        .header("If-Match", &etag)
+        .body(Body::empty())
+        .unwrap();
+    let response = client.request(request).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(
+        list_location_names(&mut client).await,
+        ["local", "another", "other"]
     );
 }
 
 #[tokio::test]
-async fn missing_files_propagates_errors() {
-    let mut client = get_default_client();
+async fn location_etag_changes_with_tokens() {
+    let root = get_test_dir();
+    let mut client = TestClient::with_admin_token(&root);
+    let add = |token: &str| {
+        serde_json::json!({
+            "name": "upstream",
+            "url": "http://example.com/outpack",
+            "token": token,
+        })
+    };
+    let response = client.post_json("/locations", &add("secret")).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let etag = response.headers()["etag"].to_str().unwrap().to_owned();
+    assert!(!etag.contains("secret"));
+
+    // Another client rotates the token, which is all that changes.
+    let response = client.delete("/locations/upstream").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = client.post_json("/locations", &add("rotated")).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_ne!(response.headers()["etag"], etag.as_str());
+
+    // So a client which has not seen the rotation cannot undo it.
+    let request = Request::delete("/locations/upstream")
+        .header("If-Match", &etag)
+        .body(Body::empty())
+        .unwrap();
+    let response = client.request(request).await;
+    assert_eq!(response.status(), StatusCode::PRECONDITION_FAILED);
+    let config = outpack::config::read_config(&root).unwrap();
+    assert_eq!(config.location.last().unwrap().args["token"], "rotated");
+}
+
+#[tokio::test]
+async fn can_manage_remote_locations() {
+    let root = get_test_dir();
+    let mut client = TestClient::with_admin_token(&root);
+    assert_eq!(list_location_names(&mut client).await, ["local", "another"]);
+
     let response = client
         .post_json(
-            "/files/missing",
-            &Hashes {
-                hashes: vec!["badhash".to_string()],
-            },
+            "/locations",
+            &serde_json::json!({ "name": "upstream", "url": "http://example.com/outpack" }),
         )
         .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    validate_success("server", "null-response.json", &response.to_json().await);
+
+    let response = client
+        .post_json(
+            "/locations",
+            &serde_json::json!({ "name": "private", "url": "https://example.com", "token": "secret" }),
+        )
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
 
+    assert_eq!(
+        list_location_names(&mut client).await,
+        ["local", "another", "upstream", "private"]
+    );
+    let body: Value = client.get("/locations").await.to_json().await;
+    assert_eq!(body["data"][3]["type"], "http");
+    assert_eq!(
+        body["data"][3]["args"],
+        serde_json::json!({ "url": "https://example.com" })
+    );
+
+    // Locations are persisted in the repository's configuration, including their credentials.
+    let config = outpack::config::read_config(&root).unwrap();
+    assert_eq!(config.location.len(), 4);
+    assert_eq!(config.location[3].args["token"], "secret");
+
+    let response = client.delete("/locations/upstream").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(
+        list_location_names(&mut client).await,
+        ["local", "another", "private"]
+    );
+    assert_eq!(
+        outpack::config::read_config(&root).unwrap().location.len(),
+        3
+    );
+
+    let response = client.delete("/locations/upstream").await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    validate_error(
+        &response.to_json().await,
+        Some("Location 'upstream' does not exist"),
+    );
+}
+
+#[tokio::test]
+async fn adding_location_validates_request() {
+    let mut client = TestClient::with_admin_token(get_test_dir());
+
+    let response = client
+        .post_json(
+            "/locations",
+            &serde_json::json!({ "name": "another", "url": "http://a" }),
+        )
+        .await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    validate_error(
+        &response.to_json().await,
+        Some("A location named 'another' already exists"),
+    );
+
+    let response = client
+        .post_json(
+            "/locations",
+            &serde_json::json!({ "name": "x", "url": "example.com" }),
+        )
+        .await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    validate_error(&response.to_json().await, Some("Invalid location url"));
+
+    let response = client
+        .post_json("/locations", &serde_json::json!({ "url": "http://a" }))
+        .await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+    let response = client.delete("/locations/local").await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+}
+
+fn get_empty_root() -> PathBuf {
+    let root = TempDir::new("outpack").unwrap().into_path();
+    outpack::init::create_root(&root, outpack::hash::HashAlgorithm::Sha256).unwrap();
+    root
+}
+
+#[tokio::test]
+async fn can_serve_several_roots() {
+    let example = get_test_dir();
+    let other = get_empty_root();
+    let id = add_packet_with_file(
+        &mut TestClient::new(&other),
+        "20240101-000000-00000001",
+        "contents",
+        &[],
+    )
+    .await;
+    let mut client = TestClient::with_roots(&[("example", &example), ("other", &other)]);
+
+    let response = client.get("/roots").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = response.to_json().await;
+    validate_success("server", "roots.json", &body);
+    assert_eq!(body["data"], serde_json::json!(["example", "other"]));
+
+    // Each root describes the paths under its prefix, and how to list the others.
+    let document: Value = client
+        .get("/roots/example/openapi.json")
+        .await
+        .to_json()
+        .await;
+    assert_eq!(document["servers"][1]["url"], "/roots/{root}");
+    let mut schema = document.clone();
+    schema["allOf"] = document["paths"]["/roots"]["get"]["responses"]["200"]["content"]
+        ["application/json"]["schema"]["allOf"]
+        .clone();
+    let compiled = JSONSchema::options()
+        .with_draft(Draft::Draft7)
+        .compile(&schema)
+        .expect("A valid schema");
+    assert_valid(&body, &compiled);
+
+    let list = |body: &Value| -> Vec<String> {
+        let entries = body["data"].as_array().unwrap();
+        entries
+            .iter()
+            .map(|e| e["packet"].as_str().unwrap().to_owned())
+            .collect()
+    };
+    let body = client
+        .get("/roots/example/metadata/list")
+        .await
+        .to_json()
+        .await;
+    validate_success("server", "locations.json", &body);
+    assert_eq!(list(&body).len(), 4);
+    assert!(!list(&body).contains(&id));
+
+    let body = client
+        .get("/roots/other/metadata/list/")
+        .await
+        .to_json()
+        .await;
+    validate_success("server", "locations.json", &body);
+    assert_eq!(list(&body), vec![id.clone()]);
+
+    let response = client
+        .get(format!("/roots/example/metadata/{}/json", id))
+        .await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    let response = client
+        .get(format!("/roots/other/metadata/{}/json", id))
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let response = client.get("/roots/other").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    validate_success("server", "root.json", &response.to_json().await);
+
+    for path in ["/roots/missing/metadata/list", "/metadata/list"] {
+        let response = client.get(path).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        validate_error(&response.to_json().await, Some("This route does not exist"));
+    }
+}
+
+#[test]
+fn root_names_must_be_usable_in_paths() {
+    let root = get_test_dir();
+    for name in ["", "a/b", "..", "with space"] {
+        let roots = [(name.to_owned(), root.clone())].into_iter().collect();
+        let err = outpack::api::api_with_roots(&roots).unwrap_err();
+        assert!(err.to_string().contains("Invalid root name"), "{}", err);
+    }
+}
+
+/// Add a packet with a single file to a repository through the API, returning its id.
+async fn add_packet_with_file(
+    client: &mut TestClient,
+    id: &str,
+    contents: &str,
+    depends: &[&str],
+) -> String {
+    let file_hash = format!("sha256:{:x}", Sha256::digest(contents));
+    let response = client
+        .post(
+            format!("/file/{}", file_hash),
+            mime::APPLICATION_OCTET_STREAM,
+            contents.to_owned(),
+        )
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let depends: Vec<Value> = depends
+        .iter()
+        .map(|d| serde_json::json!({ "packet": d, "query": "", "files": [] }))
+        .collect();
+    let metadata = serde_json::to_string(&serde_json::json!({
+        "schema_version": "0.0.1",
+        "name": "data",
+        "id": id,
+        "time": { "start": 1704110400.0, "end": 1704110401.0 },
+        "parameters": null,
+        "files": [{ "path": "data.txt", "size": contents.len(), "hash": file_hash }],
+        "depends": depends,
+        "script": []
+    }))
+    .unwrap();
+    let hash = format!("sha256:{:x}", Sha256::digest(&metadata));
+    let response = client
+        .post(
+            format!("/packet/{}", hash),
+            mime::TEXT_PLAIN_UTF_8,
+            metadata,
+        )
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    id.to_owned()
+}
+
+/// Serve the repository at `root` on a random local port, returning its url.
+async fn start_upstream_server(root: &Path) -> String {
+    let config = outpack::api::ApiConfig {
+        address: "127.0.0.1".parse().unwrap(),
+        port: 0,
+    };
+    let api = outpack::api::api_with_config(root, &config).await.unwrap();
+    let url = format!("http://{}", api.local_addr().unwrap());
+    tokio::spawn(api.run(std::future::pending()));
+    url
+}
+
+async fn pull(client: &mut TestClient, body: Value) -> Value {
+    let response = client.post_json("/sync/pull", &body).await;
+    let body = response.to_json().await;
+    validate_success("server", "pull.json", &body);
+    body["data"].clone()
+}
+
+#[tokio::test]
+async fn can_pull_packets_from_another_server() {
+    let upstream_root = get_empty_root();
+    let mut upstream = TestClient::new(&upstream_root);
+    let a = add_packet_with_file(&mut upstream, "20240101-120000-0000000a", "a", &[]).await;
+    let b = add_packet_with_file(&mut upstream, "20240101-120000-0000000b", "b", &[&a]).await;
+    let c = add_packet_with_file(&mut upstream, "20240101-120000-0000000c", "a", &[]).await;
+    let url = start_upstream_server(&upstream_root).await;
+
+    let root = get_empty_root();
+    let mut client = TestClient::with_admin_token(&root);
+    let response = client
+        .post_json(
+            "/locations",
+            &serde_json::json!({ "name": "upstream", "url": url }),
+        )
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // Dependencies are pulled along with the requested packets.
+    let result = pull(
+        &mut client,
+        serde_json::json!({ "location": "upstream", "ids": [b] }),
+    )
+    .await;
+    assert_eq!(result, serde_json::json!({ "packets": [a, b], "files": 2 }));
+
+    // The remaining packet's only file is already present, so it does not need downloading.
+    let result = pull(&mut client, serde_json::json!({ "location": "upstream" })).await;
+    assert_eq!(result, serde_json::json!({ "packets": [c], "files": 0 }));
+
+    // Pulling again has no effect.
+    let result = pull(&mut client, serde_json::json!({ "location": "upstream" })).await;
+    assert_eq!(result, serde_json::json!({ "packets": [], "files": 0 }));
+
+    let body: Value = client.get("/checksum").await.to_json().await;
+    let upstream_body: Value = upstream.get("/checksum").await.to_json().await;
+    assert_eq!(body["data"], upstream_body["data"]);
+
+    let response = client.get(format!("/metadata/{}/json", b)).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = client
+        .get(format!("/file/sha256:{:x}", Sha256::digest("b")))
+        .await;
+    assert_eq!(response.to_string().await, "b");
+
+    // Pulled packets are recorded as coming from the location they were pulled from.
+    assert!(root.join(".outpack/location/upstream").join(&a).exists());
+}
+
+#[tokio::test]
+async fn can_pull_file_from_another_server() {
+    let upstream_root = get_empty_root();
+    let mut upstream = TestClient::new(&upstream_root);
+    add_packet_with_file(&mut upstream, "20240101-120000-0000000a", "a", &[]).await;
+    let url = start_upstream_server(&upstream_root).await;
+    let hash = format!("sha256:{:x}", Sha256::digest("a"));
+    let file_url = format!("{}/file/{}", url, hash);
+
+    let root = get_empty_root();
+    let mut client = TestClient::with_admin_token(&root);
+    let pull_path = format!("/file/{}/pull", hash);
+    let pull_body = serde_json::json!({ "url": file_url });
+
+    // The upstream server can only be pulled from once it is one of the locations.
+    let response = client.post_json(&pull_path, &pull_body).await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let body = response.to_json().await;
+    validate_error(&body, Some("which is not a location or an allowed host"));
+
+    let response = client
+        .post_json(
+            "/locations",
+            &serde_json::json!({ "name": "upstream", "url": url }),
+        )
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let response = client.post_json(&pull_path, &pull_body).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = response.to_json().await;
+    validate_success("server", "null-response.json", &body);
+    let response = client.get(format!("/file/{}", hash)).await;
+    assert_eq!(response.to_string().await, "a");
+
+    // The downloaded file must match the hash it is pulled as.
+    let other = format!("sha256:{:x}", Sha256::digest("b"));
+    let response = client
+        .post_json(&format!("/file/{}/pull", other), &pull_body)
+        .await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let response = client.get(format!("/file/{}", other)).await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn redirects_are_not_followed_when_pulling() {
+    let upstream_root = get_empty_root();
+    let mut upstream = TestClient::new(&upstream_root);
+    add_packet_with_file(&mut upstream, "20240101-120000-0000000a", "a", &[]).await;
+    let url = start_upstream_server(&upstream_root).await;
+
+    // A server which redirects every request to the upstream one.
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let redirector = format!("http://{}", listener.local_addr().unwrap());
+    let app = axum::Router::new().fallback(move |request: Request| async move {
+        axum::response::Redirect::temporary(&format!("{}{}", url, request.uri()))
+    });
+    tokio::spawn(async move { axum::serve(listener, app).await });
+
+    let root = get_empty_root();
+    let mut client = TestClient::with_admin_token(&root);
+    let response = client
+        .post_json(
+            "/locations",
+            &serde_json::json!({ "name": "redirector", "url": redirector }),
+        )
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let hash = format!("sha256:{:x}", Sha256::digest("a"));
+    let response = client
+        .post_json(
+            &format!("/file/{}/pull", hash),
+            &serde_json::json!({ "url": format!("{}/file/{}", redirector, hash) }),
+        )
+        .await;
+    assert_ne!(response.status(), StatusCode::OK);
+    let body = response.to_json().await;
+    validate_error(&body, Some("307 Temporary Redirect"));
+    let response = client.get(format!("/file/{}", hash)).await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn can_diff_packets_with_another_server() {
+    let upstream_root = get_empty_root();
+    let mut upstream = TestClient::new(&upstream_root);
+    let a = add_packet_with_file(&mut upstream, "20240101-120000-0000000a", "a", &[]).await;
+    let b = add_packet_with_file(&mut upstream, "20240101-120000-0000000b", "b", &[]).await;
+    let url = start_upstream_server(&upstream_root).await;
+
+    let root = get_empty_root();
+    let mut client = TestClient::with_admin_token(&root);
+    add_packet_with_file(&mut client, &b, "b", &[]).await;
+    let c = add_packet_with_file(&mut client, "20240101-120000-0000000c", "c", &[]).await;
+    let response = client
+        .post_json(
+            "/locations",
+            &serde_json::json!({ "name": "upstream", "url": url }),
+        )
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let expected = serde_json::json!({ "only_local": [c], "only_remote": [a], "common": [b] });
+    for request in [
+        serde_json::json!({ "location": "upstream" }),
+        serde_json::json!({ "ids": [b, a] }),
+    ] {
+        let response = client.post_json("/sync/diff", &request).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response.to_json().await;
+        validate_success("server", "sync-diff.json", &body);
+        assert_eq!(body["data"], expected);
+    }
+
+    let response = client
+        .post_json(
+            "/sync/diff",
+            &serde_json::json!({ "ids": [a], "location": "upstream" }),
+        )
+        .await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let body = response.to_json().await;
+    validate_error(
+        &body,
+        Some("Exactly one of 'ids' and 'location' must be given"),
+    );
+}
+
+#[tokio::test]
+async fn pull_reports_errors() {
+    let upstream_root = get_empty_root();
+    let url = start_upstream_server(&upstream_root).await;
+
+    let mut client = TestClient::with_admin_token(get_empty_root());
+    client
+        .post_json(
+            "/locations",
+            &serde_json::json!({ "name": "upstream", "url": url }),
+        )
+        .await;
+
+    let response = client
+        .post_json(
+            "/sync/pull",
+            &serde_json::json!({ "location": "upstream", "ids": ["20240101-120000-0000000a"] }),
+        )
+        .await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    validate_error(
+        &response.to_json().await,
+        Some("Packet '20240101-120000-0000000a' is not available from location 'upstream'"),
+    );
+
+    let response = client
+        .post_json("/sync/pull", &serde_json::json!({ "location": "missing" }))
+        .await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    validate_error(
+        &response.to_json().await,
+        Some("Location 'missing' does not exist"),
+    );
+
+    let response = client
+        .post_json("/sync/pull", &serde_json::json!({ "location": "local" }))
+        .await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    validate_error(
+        &response.to_json().await,
+        Some("Location 'local' is not an outpack server"),
+    );
+}
+
+/// Serve a location which lists the given packets and returns the given metadata for them,
+/// whether or not it is valid, returning its url.
+async fn start_fake_upstream_server(packets: Vec<(&'static str, String)>) -> String {
+    let list: Vec<Value> = packets
+        .iter()
+        .map(|(id, metadata)| {
+            serde_json::json!({
+                "packet": id,
+                "time": 1704110400.0,
+                "hash": format!("sha256:{:x}", Sha256::digest(metadata)),
+            })
+        })
+        .collect();
+    let list = serde_json::json!({ "status": "success", "errors": null, "data": list });
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    let app = axum::Router::new()
+        .route(
+            "/metadata/list",
+            axum::routing::get(move || async move { axum::Json(list) }),
+        )
+        .route(
+            "/metadata/:id/text",
+            axum::routing::get(
+                move |axum::extract::Path(id): axum::extract::Path<String>| async move {
+                    let (_, metadata) = packets.iter().find(|(p, _)| *p == id).unwrap();
+                    metadata.clone()
+                },
+            ),
+        );
+    tokio::spawn(async move { axum::serve(listener, app).await });
+    url
+}
+
+fn packet_metadata(id: &str, depends: &[&str]) -> String {
+    let depends: Vec<Value> = depends
+        .iter()
+        .map(|d| serde_json::json!({ "packet": d, "query": "", "files": [] }))
+        .collect();
+    serde_json::to_string(&serde_json::json!({
+        "schema_version": "0.0.1",
+        "name": "data",
+        "id": id,
+        "time": { "start": 1704110400.0, "end": 1704110401.0 },
+        "parameters": null,
+        "files": [],
+        "depends": depends,
+        "script": []
+    }))
+    .unwrap()
+}
+
+#[tokio::test]
+async fn pull_rejects_invalid_ids_from_location() {
+    let a = "20240101-120000-0000000a";
+    let b = "20240101-120000-0000000b";
+    let cases = [
+        (
+            vec![("../../../escaped", packet_metadata("../../../escaped", &[]))],
+            "Location 'upstream' refers to invalid packet id '../../../escaped'",
+        ),
+        (
+            vec![(a, packet_metadata(a, &["../../../escaped"]))],
+            "Location 'upstream' refers to invalid packet id '../../../escaped'",
+        ),
+        (
+            vec![(a, packet_metadata(b, &[]))],
+            "Location 'upstream' returned metadata for packet '20240101-120000-0000000b' instead \
+             of '20240101-120000-0000000a'",
+        ),
+    ];
+
+    for (packets, message) in cases {
+        let url = start_fake_upstream_server(packets).await;
+        let root = get_empty_root();
+        let mut client = TestClient::with_admin_token(&root);
+        client
+            .post_json(
+                "/locations",
+                &serde_json::json!({ "name": "upstream", "url": url }),
+            )
+            .await;
+
+        let response = client
+            .post_json("/sync/pull", &serde_json::json!({ "location": "upstream" }))
+            .await;
+        assert_ne!(response.status(), StatusCode::OK);
+        validate_error(&response.to_json().await, Some(message));
+
+        // Nothing is written, inside the repository or out of it.
+        let outpack = root.join(".outpack");
+        assert_eq!(fs::read_dir(outpack.join("metadata")).unwrap().count(), 0);
+        assert!(!outpack.join("location").join("upstream").exists());
+        assert!(!root.parent().unwrap().join("escaped").exists());
+    }
+}
+
+#[tokio::test]
+async fn reloading_cannot_change_startup_settings() {
+    let root = get_test_dir();
+    let mut client = TestClient::new(&root);
+
+    update_config(&root, |config| {
+        config["server"] = serde_json::json!({
+            "store_layout": { "depth": 2, "width": 2 },
+            "rate_limit": { "writes": { "requests": 10, "per_seconds": 1 } },
+            "max_batch_size": 1,
+        });
+        config["location"].as_array_mut().unwrap().reverse();
+    });
+
+    let response = client.post_json("/admin/reload", &()).await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let body = response.to_json().await;
+    validate_error(
+        &body,
+        Some("The settings store_layout, rate_limit are only read at startup"),
+    );
+
+    // Files are still found where the layout the server started with puts them.
+    let hash = "sha256:b189579a9326f585d308304bd9e03326be5d395ac71b31df359ab8bac408d248";
+    let response = client.get(format!("/file/{}", hash)).await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // None of the new configuration is used.
+    assert_eq!(
+        get_first_listed_packet(&mut client).await,
+        "20170818-164847-7574883b"
+    );
+    let ids = ["20170818-164830-33e0ab01", "20170818-164847-7574883b"];
+    let response = client
+        .post_json("/metadata/batch", &serde_json::json!({ "ids": ids }))
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn reloading_invalid_config_keeps_existing_config() {
+    let root = get_test_dir();
+    let mut client = TestClient::new(&root);
+
+    update_config(&root, |config| {
+        config["core"]["hash_algorithm"] = "md5".into();
+        config["location"].as_array_mut().unwrap().reverse();
+    });
+
+    let response = client.post_json("/admin/reload", &()).await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let body = response.to_json().await;
+    validate_error(
+        &body,
+        Some("Outpack must be configured to use hash algorithm 'sha256'"),
+    );
+    check_uses_existing_config(&mut client, "20240101-120000-00000001").await;
+
+    fs::write(root.join(".outpack").join("config.json"), "{").unwrap();
+    let response = client.post_json("/admin/reload", &()).await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let body = response.to_json().await;
+    validate_error(&body, Some("Failed to read outpack config"));
+    check_uses_existing_config(&mut client, "20240101-120000-00000002").await;
+}
+
+/// Check that the server is still using the example configuration, after a reload of something
+/// else has been rejected.
+async fn check_uses_existing_config(client: &mut TestClient, id: &str) {
+    assert_eq!(
+        get_first_listed_packet(client).await,
+        "20170818-164847-7574883b"
+    );
+
+    let response = client.get("/checksum").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let body: Value = response.to_json().await;
+    assert!(body["data"].as_str().unwrap().starts_with("sha256:"));
+
+    let data = serde_json::to_string(&complete_metadata(id)).unwrap();
+    let response = client
+        .post("/metadata", mime::APPLICATION_JSON, data.clone())
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let body: Value = response.to_json().await;
+    assert_eq!(
+        body["data"].as_str().unwrap(),
+        format!("sha256:{:x}", Sha256::digest(&data))
+    );
+
+    let hash = "sha256:b189579a9326f585d308304bd9e03326be5d395ac71b31df359ab8bac408d248";
+    let response = client.get(format!("/file/{}", hash)).await;
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn can_list_location_metadata() {
+    let mut client = get_default_client();
+    let response = client.get("/metadata/list").await;
+
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.content_type(), mime::APPLICATION_JSON);
+
+    let body = response.to_json().await;
+    validate_success("server", "locations.json", &body);
+
+    let entries = body.get("data").unwrap().as_array().unwrap();
+    assert_eq!(entries.len(), 4);
+
+    assert_eq!(
+        entries[0].get("packet").unwrap().as_str().unwrap(),
+        "20170818-164847-7574883b"
+    );
+    assert_eq!(
+        entries[0].get("time").unwrap().as_f64().unwrap(),
+        1662480556.1778
+    );
+    assert_eq!(
+        entries[0].get("hash").unwrap().as_str().unwrap(),
+        "sha256:af3c863f96898c6c88cee4daa1a6d6cfb756025e70059f5ea4dbe4d9cc5e0e36"
+    );
+
+    assert_eq!(
+        entries[1].get("packet").unwrap().as_str().unwrap(),
+        "20170818-164830-33e0ab01"
+    );
+    assert_eq!(
+        entries[2].get("packet").unwrap().as_str().unwrap(),
+        "20180220-095832-16a4bbed"
+    );
+    assert_eq!(
+        entries[3].get("packet").unwrap().as_str().unwrap(),
+        "20180818-164043-7cdcde4b"
+    );
+}
+
+#[tokio::test]
+async fn can_list_location_metadata_with_file_hashes() {
+    let mut client = get_default_client();
+
+    let response = client.get("/metadata/list").await;
+    let body: Value = response.to_json().await;
+    let entries = body["data"].as_array().unwrap();
+    assert!(entries.iter().all(|e| e.get("files").is_none()));
+
+    let response = client.get("/metadata/list?files=true").await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let body: Value = response.to_json().await;
+    validate_success("server", "locations.json", &body);
+
+    let entries = body["data"].as_array().unwrap();
+    assert_eq!(entries.len(), 4);
+    assert_eq!(entries[0]["packet"], "20170818-164847-7574883b");
+
+    let files = entries[0]["files"].as_array().unwrap();
+    assert_eq!(files.len(), 21);
+    assert!(files.contains(&serde_json::json!(
+        "sha256:8916131d0bebabc5ab098ae3a34b03389768e1ef15acba74ea265deddeba579f"
+    )));
+}
+
+#[tokio::test]
+async fn returns_service_unavailable_if_root_is_removed() {
+    let root = get_test_dir();
+    let mut client = TestClient::new(&root);
+    let response = client.get("/metadata/list").await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    fs::remove_dir_all(&root).unwrap();
+
+    for path in ["/metadata/list", "/metadata/20170818-164847-7574883b/json"] {
+        let response = client.get(path).await;
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.content_type(), mime::APPLICATION_JSON);
+
+        assert_eq!(response.headers()["retry-after"], "30");
+
+        let body = response.to_json().await;
+        validate_error(&body, Some("is unavailable"));
+    }
+}
+
+#[tokio::test]
+async fn retry_after_can_be_configured() {
+    let root = get_test_dir();
+    update_config(&root, |config| {
+        config["server"] = serde_json::json!({ "retry_after": 120 });
+    });
+    let mut client = TestClient::new(&root);
+
+    // Only failures caused by the server being unavailable ask clients to retry.
+    let response = client.get("/metadata/20170818-164847-12345678/json").await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    assert!(response.headers().get("retry-after").is_none());
+
+    fs::remove_dir_all(&root).unwrap();
+    let response = client.get("/metadata/list").await;
+    assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(response.headers()["retry-after"], "120");
+}
+
+#[tokio::test]
+async fn requests_can_be_rate_limited() {
+    let root = get_test_dir();
+    update_config(&root, |config| {
+        config["server"] = serde_json::json!({
+            "rate_limit": { "reads": { "requests": 2, "per_seconds": 0.5 } }
+        });
+    });
+    let mut client = TestClient::new(&root);
+    let get_from = |ip: [u8; 4]| {
+        let mut request = Request::get("/metadata/list").body(Body::empty()).unwrap();
+        let addr = std::net::SocketAddr::from((ip, 1234));
+        request.extensions_mut().insert(ConnectInfo(addr));
+        request
+    };
+
+    for _ in 0..2 {
+        let response = client.request(get_from([10, 0, 0, 1])).await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+    let response = client.request(get_from([10, 0, 0, 1])).await;
+    assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(response.headers()["retry-after"], "1");
+    let body = response.to_json().await;
+    validate_error(&body, Some("Too many requests"));
+
+    // Other clients, and other kinds of request, are limited separately.
+    let response = client.request(get_from([10, 0, 0, 2])).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = client
+        .post_json(
+            "/packets/missing",
+            &serde_json::json!({ "ids": [], "unpacked": false }),
+        )
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // The allowance is refilled at four requests a second.
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    let response = client.request(get_from([10, 0, 0, 1])).await;
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn retry_after_can_be_disabled() {
+    let root = get_test_dir();
+    update_config(&root, |config| {
+        config["server"] = serde_json::json!({ "retry_after": null });
+    });
+    let mut client = TestClient::new(&root);
+
+    fs::remove_dir_all(&root).unwrap();
+    let response = client.get("/metadata/list").await;
+    assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert!(response.headers().get("retry-after").is_none());
+}
+
+#[tokio::test]
+async fn requests_do_not_read_config_from_disk() {
+    let root = get_test_dir();
+    let mut client = TestClient::new(&root);
+    let path = root.join(".outpack").join("config.json");
+
+    fs::remove_file(&path).unwrap();
+    let response = client.get("/packit/metadata?known_since=0").await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    fs::write(&path, "{ \"core\": ").unwrap();
+    let response = client.get("/packit/metadata?known_since=0").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = client.get("/checksum").await;
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn trailing_slashes_are_ignored() {
+    let mut client = get_default_client();
+    let expected: Value = client.get("/metadata/list").await.to_json().await;
+
+    let response = client.get("/metadata/list/").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let body: Value = response.to_json().await;
+    assert_eq!(body["data"], expected["data"]);
+
+    let response = client.get("/metadata/list/?files=true").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let body: Value = response.to_json().await;
+    assert!(body["data"][0]["files"].is_array());
+
+    let response = client.get("/").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    validate_success("server", "root.json", &response.to_json().await);
+}
+
+#[tokio::test]
+async fn handles_location_metadata_errors() {
+    let mut client = TestClient::new("tests/bad-example");
+    let response = client.get("/metadata/list").await;
+    assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    assert_eq!(response.content_type(), mime::APPLICATION_JSON);
+
+    let body = response.to_json().await;
+    validate_error(&body, Some("missing field `packet`"));
+}
+
+#[tokio::test]
+async fn packit_metadata_says_which_packets_are_local() {
+    let mut client = get_default_client();
+    let body: Value = client.get("/packit/metadata").await.to_json().await;
+    validate_success("server", "list.json", &body);
+
+    // Only one of the example packets is unpacked; the others are only known to other locations.
+    let is_local: Vec<(&str, bool)> = body["data"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|p| (p["id"].as_str().unwrap(), p["is_local"].as_bool().unwrap()))
+        .collect();
+    assert_eq!(
+        is_local,
+        vec![
+            ("20170818-164830-33e0ab01", false),
+            ("20170818-164847-7574883b", true),
+            ("20180220-095832-16a4bbed", false),
+            ("20180818-164043-7cdcde4b", false),
+        ]
+    );
+}
+
+#[tokio::test]
+async fn can_list_metadata() {
+    let mut client = get_default_client();
+    let response = client.get("/packit/metadata").await;
+
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.content_type(), mime::APPLICATION_JSON);
+
+    let body = response.to_json().await;
+    print!("{}", body);
+    validate_success("server", "list.json", &body);
+
+    let entries = body.get("data").unwrap().as_array().unwrap();
+    assert_eq!(entries.len(), 4);
+
+    assert_eq!(
+        entries[0].get("id").unwrap().as_str().unwrap(),
+        "20170818-164830-33e0ab01"
+    );
+    assert_eq!(
+        entries[0]
+            .get("parameters")
+            .unwrap()
+            .get("disease")
+            .unwrap(),
+        "YF"
+    );
+    assert_eq!(
+        entries[0]
+            .get("parameters")
+            .unwrap()
+            .as_object()
+            .unwrap()
+            .get("disease")
+            .unwrap()
+            .as_str()
+            .unwrap(),
+        "YF"
+    );
+    assert_eq!(
+        entries[0].get("time").unwrap().get("start").unwrap(),
+        1503074938.2232
+    );
+    assert_eq!(
+        entries[0].get("time").unwrap().get("end").unwrap(),
+        1503074938.2232
+    );
+    assert_eq!(
+        entries[0]["custom"]["orderly"]["artefacts"][3]["description"],
+        "Projected Coverage for routine immunisation in PINE countries"
+    );
+    assert_eq!(
+        entries[1].get("id").unwrap().as_str().unwrap(),
+        "20170818-164847-7574883b"
+    );
+    assert_eq!(
+        entries[2].get("id").unwrap().as_str().unwrap(),
+        "20180220-095832-16a4bbed"
+    );
+    assert_eq!(
+        entries[3].get("id").unwrap().as_str().unwrap(),
+        "20180818-164043-7cdcde4b"
+    );
+}
+
+/// Parse a newline-delimited JSON body, checking that every line is terminated.
+async fn ndjson_lines(response: Response) -> Vec<Value> {
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(
+        response.content_type().essence_str(),
+        "application/x-ndjson"
+    );
+    let body = response.to_string().await;
+    assert!(body.is_empty() || body.ends_with('\n'));
+    body.lines()
+        .map(|line| serde_json::from_str(line).expect("Each line is valid json"))
+        .collect()
+}
+
+#[tokio::test]
+async fn can_list_metadata_as_ndjson() {
+    let mut client = get_default_client();
+    let body: Value = client.get("/packit/metadata").await.to_json().await;
+    let expected = body["data"].as_array().unwrap();
+
+    let packets = ndjson_lines(client.get("/packit/metadata?format=ndjson").await).await;
+    assert_eq!(packets.len(), expected.len());
+    assert_eq!(&packets, expected);
+
+    let schema = get_schema("server", "list.json");
+    for packet in &packets {
+        assert_valid(&Value::Array(vec![packet.clone()]), &schema);
+    }
+
+    let request = Request::get("/packit/metadata")
+        .header("Accept", "application/x-ndjson")
+        .body(Body::empty())
+        .unwrap();
+    let packets = ndjson_lines(client.request(request).await).await;
+    assert_eq!(&packets, expected);
+}
+
+#[tokio::test]
+async fn can_list_location_metadata_as_ndjson() {
+    let mut client = get_default_client();
+    let body: Value = client.get("/metadata/list").await.to_json().await;
+    let expected = body["data"].as_array().unwrap();
+
+    let entries = ndjson_lines(client.get("/metadata/list?format=ndjson").await).await;
+    assert_eq!(&entries, expected);
+}
+
+#[tokio::test]
+async fn format_query_parameter_takes_precedence_over_accept() {
+    let mut client = get_default_client();
+    let request = Request::get("/packit/metadata?format=json")
+        .header("Accept", "application/x-ndjson")
+        .body(Body::empty())
+        .unwrap();
+    let response = client.request(request).await;
+    assert_eq!(response.content_type(), mime::APPLICATION_JSON);
+
+    let response = client.get("/packit/metadata?format=xml").await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let body = response.to_json().await;
+    validate_error(&body, Some("Unknown format 'xml'"));
+}
+
+async fn list_ids_between(client: &mut TestClient, since: &str, until: &str) -> Vec<String> {
+    let response = client
+        .get(format!(
+            "/packit/metadata?known_since={}&known_until={}",
+            since, until
+        ))
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = response.to_json().await;
+    validate_success("server", "list.json", &body);
+    body["data"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|entry| entry["id"].as_str().unwrap().to_owned())
+        .collect()
+}
+
+#[tokio::test]
+async fn can_list_metadata_in_time_window() {
+    let mut client = get_default_client();
+
+    // Two packets were inserted at exactly the lower bound, and one at exactly the upper bound.
+    assert_eq!(
+        list_ids_between(&mut client, "1662480555.6623", "1662480556.1778").await,
+        vec![
+            "20170818-164830-33e0ab01",
+            "20180220-095832-16a4bbed",
+            "20180818-164043-7cdcde4b"
+        ]
+    );
+    assert_eq!(
+        list_ids_between(&mut client, "1662480555.7", "1662480556.1778").await,
+        vec!["20170818-164830-33e0ab01"]
+    );
+    assert_eq!(
+        list_ids_between(&mut client, "1662480556.1778", "1662480557").await,
+        vec!["20170818-164847-7574883b"]
+    );
+    assert!(list_ids_between(&mut client, "1662480556", "1662480556")
+        .await
+        .is_empty());
+}
+
+#[tokio::test]
+async fn can_list_metadata_from_date() {
+    let mut client = get_default_client();
+    let response = client.get("/packit/metadata?known_since=1662480556").await;
+
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.content_type(), mime::APPLICATION_JSON);
+
+    let body = response.to_json().await;
+    print!("{}", body);
+    validate_success("server", "list.json", &body);
+
+    let entries = body.get("data").unwrap().as_array().unwrap();
+    assert_eq!(entries.len(), 1);
+    assert_eq!(
+        entries[0].get("id").unwrap().as_str().unwrap(),
+        "20170818-164847-7574883b"
+    );
+}
+
+fn assert_list_meta(body: &Value) {
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs_f64();
+    let meta = &body["meta"];
+    assert_eq!(
+        meta["count"].as_u64().unwrap() as usize,
+        body["data"].as_array().unwrap().len()
+    );
+    let server_time = meta["server_time"].as_f64().unwrap();
+    assert!(
+        (now - server_time).abs() < 60.0,
+        "{} vs {}",
+        server_time,
+        now
+    );
+}
+
+#[tokio::test]
+async fn list_responses_include_count_and_server_time() {
+    let mut client = get_default_client();
+    for path in [
+        "/metadata/list",
+        "/packit/metadata",
+        "/packit/metadata?known_since=1662480556",
+    ] {
+        let response = client.get(path).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response.to_json().await;
+        validate_success(
+            "server",
+            if path == "/metadata/list" {
+                "locations.json"
+            } else {
+                "list.json"
+            },
+            &body,
+        );
+        assert_list_meta(&body);
+    }
+
+    let body: Value = client
+        .get("/packit/metadata?known_since=1662480556")
+        .await
+        .to_json()
+        .await;
+    assert_eq!(body["meta"]["count"], 1);
+
+    // Responses which aren't lists are unchanged
+    let body: Value = client.get("/checksum").await.to_json().await;
+    assert!(body.get("meta").is_none());
+}
+
+fn http_date(secs: u64) -> String {
+    httpdate::fmt_http_date(std::time::UNIX_EPOCH + std::time::Duration::from_secs(secs))
+}
+
+async fn get_metadata_if_modified_since(
+    client: &mut TestClient,
+    path: &str,
+    since: &str,
+) -> Response {
+    let request = Request::get(path)
+        .header("If-Modified-Since", since)
+        .body(Body::empty())
+        .unwrap();
+    client.request(request).await
+}
+
+#[tokio::test]
+async fn can_list_metadata_if_modified_since() {
+    let mut client = get_default_client();
+    let response =
+        get_metadata_if_modified_since(&mut client, "/packit/metadata", &http_date(1662480556))
+            .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = response.to_json().await;
+    validate_success("server", "list.json", &body);
+    let entries = body["data"].as_array().unwrap();
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0]["id"], "20170818-164847-7574883b");
+
+    let response =
+        get_metadata_if_modified_since(&mut client, "/packit/metadata", &http_date(1662480557))
+            .await;
+    assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+    assert!(response.to_bytes().await.is_empty());
+
+    // Invalid dates are ignored
+    let response =
+        get_metadata_if_modified_since(&mut client, "/packit/metadata", "yesterday").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let body: Value = response.to_json().await;
+    assert_eq!(body["data"].as_array().unwrap().len(), 4);
+}
+
+#[tokio::test]
+async fn known_since_takes_precedence_over_if_modified_since() {
+    let mut client = get_default_client();
+    let response = get_metadata_if_modified_since(
+        &mut client,
+        "/packit/metadata?known_since=1662480555",
+        &http_date(1662480557),
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = response.to_json().await;
+    validate_success("server", "list.json", &body);
+    assert_eq!(body["data"].as_array().unwrap().len(), 4);
+
+    // An empty result is returned as usual when using the query parameter
+    let response = get_metadata_if_modified_since(
+        &mut client,
+        "/packit/metadata?known_since=1662480557",
+        &http_date(1662480555),
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let body: Value = response.to_json().await;
+    assert_eq!(body["data"], serde_json::json!([]));
+}
+
+#[tokio::test]
+async fn handles_metadata_errors() {
+    let mut client = TestClient::new("tests/bad-example");
+    let response = client.get("/packit/metadata").await;
+    assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    assert_eq!(response.content_type(), mime::APPLICATION_JSON);
+
+    let body = response.to_json().await;
+    validate_error(&body, Some("missing field `name`"));
+}
+
+#[tokio::test]
+async fn can_get_metadata_json() {
+    let mut client = get_default_client();
+    let response = client.get("/metadata/20180818-164043-7cdcde4b/json").await;
+
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.content_type(), mime::APPLICATION_JSON);
+
+    let body = response.to_json().await;
+    validate_success("outpack", "metadata.json", &body);
+}
+
+#[tokio::test]
+async fn can_pretty_print_json_responses() {
+    let mut client = get_default_client();
+    for path in [
+        "/metadata/list",
+        "/metadata/20180818-164043-7cdcde4b/json",
+        "/metadata/missing/json",
+    ] {
+        let compact = client.get(path).await.to_string().await;
+        let response = client.get(format!("{}?pretty=true", path)).await;
+        assert_eq!(response.content_type(), mime::APPLICATION_JSON);
+        let pretty = response.to_string().await;
+
+        assert!(!compact.contains('\n'));
+        assert!(pretty.contains("\n  "));
+        let mut compact: Value = serde_json::from_str(&compact).unwrap();
+        let mut pretty: Value = serde_json::from_str(&pretty).unwrap();
+        // The time a list was read differs between the two requests.
+        for body in [&mut compact, &mut pretty] {
+            if let Some(meta) = body["meta"].as_object_mut() {
+                meta.remove("server_time");
+            }
+        }
+        assert_eq!(pretty, compact);
+    }
+
+    let response = client.get("/metadata/list?pretty=false").await;
+    assert!(!response.to_string().await.contains('\n'));
+}
+
+#[tokio::test]
+async fn metadata_responses_include_hash_from_list() {
+    let mut client = get_default_client();
+    let list: Value = client.get("/metadata/list").await.to_json().await;
+    let entries = list["data"].as_array().unwrap();
+    assert!(!entries.is_empty());
+
+    for entry in entries {
+        let id = entry["packet"].as_str().unwrap();
+        for format in ["json", "text"] {
+            let response = client.get(format!("/metadata/{}/{}", id, format)).await;
+            assert_eq!(response.status(), StatusCode::OK);
+            let hash = response
+                .headers()
+                .get("outpack-metadata-hash")
+                .expect("Hash header present")
+                .to_str()
+                .unwrap();
+            assert_eq!(hash, entry["hash"].as_str().unwrap());
+        }
+    }
+}
+
+#[tokio::test]
+async fn can_get_metadata_text() {
+    let mut client = get_default_client();
+    let response = client.get("/metadata/20180818-164043-7cdcde4b/text").await;
+
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.content_type(), mime::APPLICATION_JSON);
+
+    let hash = response.headers()["outpack-metadata-hash"]
+        .to_str()
+        .unwrap();
+    assert_eq!(response.headers()["etag"], format!("\"{}\"", hash));
+
+    // The text is returned exactly as stored, rather than being re-serialised.
+    let expected = fs::read("tests/example/.outpack/metadata/20180818-164043-7cdcde4b").unwrap();
+    assert_eq!(response.to_bytes().await, expected);
+}
+
+#[tokio::test]
+async fn metadata_text_honours_if_none_match() {
+    let mut client = get_default_client();
+    let path = "/metadata/20180818-164043-7cdcde4b/text";
+    let response = client.get(path).await;
+    let etag = response.headers()["etag"].clone();
+
+    let request = Request::get(path)
+        .header("If-None-Match", etag.clone())
+        .body(Body::empty())
+        .unwrap();
+    let response = client.request(request).await;
+    assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+    assert_eq!(response.headers()["etag"], etag);
+    assert!(response.to_bytes().await.is_empty());
+
+    let request = Request::get(path)
+        .header("If-None-Match", "\"sha256:abcdef\"")
+        .body(Body::empty())
+        .unwrap();
+    let response = client.request(request).await;
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+async fn head(client: &mut TestClient, path: &str) -> Response {
+    let request = Request::head(path).body(Body::empty()).unwrap();
+    client.request(request).await
+}
+
+#[tokio::test]
+async fn head_of_metadata_matches_get_without_body() {
+    let mut client = get_default_client();
+    for format in ["json", "text"] {
+        let path = format!("/metadata/20180818-164043-7cdcde4b/{}", format);
+        let get = client.get(&path).await;
+        let response = head(&mut client, &path).await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(response.headers().contains_key("content-length"));
+        assert!(response.headers().contains_key("outpack-metadata-hash"));
+        for name in [
+            "content-type",
+            "content-length",
+            "etag",
+            "outpack-metadata-hash",
+        ] {
+            assert_eq!(
+                response.headers().get(name),
+                get.headers().get(name),
+                "{}",
+                name
+            );
+        }
+        assert!(response.to_bytes().await.is_empty());
+    }
+}
+
+#[tokio::test]
+async fn head_of_missing_metadata_is_not_found() {
+    let mut client = get_default_client();
+    for format in ["json", "text"] {
+        let path = format!("/metadata/20180818-164043-12345678/{}", format);
+        let response = head(&mut client, &path).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert!(response.to_bytes().await.is_empty());
+    }
+}
+
+#[tokio::test]
+async fn can_get_packet_size() {
+    let mut client = get_default_client();
+    let response = client.get("/metadata/20180220-095832-16a4bbed/size").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = response.to_json().await;
+    validate_success("server", "packet-size.json", &body);
+    assert_eq!(body["data"]["size"], 8159918);
+    assert_eq!(body["data"]["files"], 21);
+    assert_eq!(body["data"]["missing"].as_array().unwrap().len(), 21);
+
+    let response = client.get("/metadata/20170818-164847-12345678/size").await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    let body = response.to_json().await;
+    validate_error(
+        &body,
+        Some("packet with id '20170818-164847-12345678' does not exist"),
+    );
+}
+
+/// Read the name and contents of each entry of a zip archive, as listed by its central directory.
+fn read_zip(bytes: &[u8]) -> Vec<(String, Vec<u8>)> {
+    let u16_at = |i: usize| u16::from_le_bytes([bytes[i], bytes[i + 1]]) as usize;
+    let u32_at = |i: usize| u32::from_le_bytes(bytes[i..i + 4].try_into().unwrap()) as usize;
+
+    let end = bytes.len() - 22;
+    assert_eq!(u32_at(end), 0x06054b50);
+    let mut header = u32_at(end + 16);
+    let mut entries = Vec::new();
+    for _ in 0..u16_at(end + 10) {
+        assert_eq!(u32_at(header), 0x02014b50);
+        let size = u32_at(header + 24);
+        let name_length = u16_at(header + 28);
+        let name = &bytes[header + 46..header + 46 + name_length];
+        let local = u32_at(header + 42);
+        let data = local + 30 + u16_at(local + 26) + u16_at(local + 28);
+        entries.push((
+            String::from_utf8(name.to_vec()).unwrap(),
+            bytes[data..data + size].to_vec(),
+        ));
+        header += 46 + name_length + u16_at(header + 30) + u16_at(header + 32);
+    }
+    entries
+}
+
+#[tokio::test]
+async fn can_download_packet_as_zip() {
+    let mut client = get_default_client();
+    let id = add_packet_with_file(&mut client, "20240101-000000-00000001", "contents", &[]).await;
+
+    let response = client.get(format!("/metadata/{}/download", id)).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()[CONTENT_TYPE], "application/zip");
+    assert_eq!(
+        response.headers()["content-disposition"],
+        "attachment; filename=\"20240101-000000-00000001.zip\""
+    );
+    let entries = read_zip(&response.to_bytes().await);
+
+    let metadata = client.get(format!("/metadata/{}/text", id)).await;
+    let metadata = metadata.to_bytes().await;
+    assert_eq!(
+        entries,
+        [
+            (format!(".outpack/metadata/{}", id), metadata.to_vec()),
+            (String::from("data.txt"), b"contents".to_vec()),
+        ]
+    );
+
+    // None of the files of this packet are in the store.
+    let response = client
+        .get("/metadata/20180220-095832-16a4bbed/download")
+        .await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    let body = response.to_json().await;
+    validate_error(
+        &body,
+        Some("of packet '20180220-095832-16a4bbed' is missing"),
+    );
+
+    let response = client
+        .get("/metadata/20170818-164847-12345678/download")
+        .await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn can_get_schemas() {
+    let mut client = get_default_client();
+    for name in ["root.json", "response-success.json"] {
+        let response = client.get(format!("/schema/{}", name)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.content_type(), mime::APPLICATION_JSON);
+        let schema: Value = response.to_json().await;
+        let expected: Value = serde_json::from_str(
+            &fs::read_to_string(Path::new("schema/server").join(name)).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(schema, expected);
+    }
+
+    // Responses from the server can be validated against the schemas it serves.
+    let schema: Value = client.get("/schema/root.json").await.to_json().await;
+    let body: Value = client.get("/").await.to_json().await;
+    assert!(jsonschema::is_valid(&schema, &body["data"]));
+
+    let response = client.get("/schema/missing.json").await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    validate_error(
+        &response.to_json().await,
+        Some("Schema 'missing.json' does not exist"),
+    );
+
+    let response = client.get("/schema/..%2Foutpack%2Fconfig.json").await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    validate_error(&response.to_json().await, Some("Invalid schema name"));
+}
+
+#[tokio::test]
+async fn can_get_packet_parameters() {
+    let mut client = get_default_client();
+    let response = client
+        .get("/metadata/20180220-095832-16a4bbed/parameters")
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = response.to_json().await;
+    validate_success("server", "parameters.json", &body);
+    assert_eq!(
+        body["data"],
+        serde_json::json!({ "disease": "YF", "pull_data": true, "tolerance": 0.001, "size": 10 })
+    );
+
+    let response = client
+        .get("/metadata/20170818-164847-7574883b/parameters")
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = response.to_json().await;
+    validate_success("server", "parameters.json", &body);
+    assert_eq!(body["data"], serde_json::json!({}));
+
+    let response = client
+        .get("/metadata/20170818-164847-12345678/parameters")
+        .await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    let body = response.to_json().await;
+    validate_error(&body, Some("does not exist"));
+}
+
+#[tokio::test]
+async fn can_get_packet_dependencies() {
+    let mut client = get_default_client();
+    let a = add_packet_with_file(&mut client, "20240101-000000-00000001", "a", &[]).await;
+    let b = add_packet_with_file(&mut client, "20240101-000000-00000002", "b", &[&a]).await;
+    let c = add_packet_with_file(&mut client, "20240101-000000-00000003", "c", &[&b]).await;
+
+    let response = client.get(format!("/metadata/{}/dependencies", c)).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = response.to_json().await;
+    validate_success("server", "ids.json", &body);
+    assert_eq!(body["data"], serde_json::json!([b]));
+
+    let response = client
+        .get(format!("/metadata/{}/dependencies?recursive=true", c))
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = response.to_json().await;
+    validate_success("server", "ids.json", &body);
+    assert_eq!(body["data"], serde_json::json!([b, a]));
+
+    let response = client
+        .get("/metadata/20240101-000000-00000004/dependencies")
+        .await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    let body = response.to_json().await;
+    validate_error(&body, Some("does not exist"));
+}
+
+#[tokio::test]
+async fn can_get_metadata_batch() {
+    let mut client = get_default_client();
+    let ids = [
+        "20170818-164830-33e0ab01",
+        "20170818-164847-7574883b",
+        "20180818-164043-7cdcde4b",
+        "20180818-164043-12345678",
+    ];
+    let response = client
+        .post_json("/metadata/batch", &serde_json::json!({ "ids": ids }))
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let body: Value = response.to_json().await;
+    validate_success("server", "metadata-batch.json", &body);
+    let data = body["data"].as_object().unwrap();
+    assert_eq!(data.len(), 4);
+    for id in &ids[..3] {
+        assert_eq!(data[*id]["metadata"]["id"], *id);
+    }
+    let error = &data["20180818-164043-12345678"]["error"];
+    assert!(data["20180818-164043-12345678"].get("metadata").is_none());
+    assert!(error["detail"]
+        .as_str()
+        .unwrap()
+        .contains("packet with id '20180818-164043-12345678' does not exist"));
+}
+
+#[tokio::test]
+async fn metadata_batch_size_is_limited() {
+    let root = get_test_dir();
+    update_config(&root, |config| {
+        config["server"] = serde_json::json!({ "max_batch_size": 2 });
+    });
+    let mut client = TestClient::new(&root);
+
+    let ids = [
+        "20170818-164830-33e0ab01",
+        "20170818-164847-7574883b",
+        "20180818-164043-7cdcde4b",
+    ];
+    let response = client
+        .post_json("/metadata/batch", &serde_json::json!({ "ids": ids }))
+        .await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let body = response.to_json().await;
+    validate_error(&body, Some("at most 2 may be requested at once"));
+}
+
+#[tokio::test]
+async fn metadata_batch_rejects_invalid_ids() {
+    let mut client = get_default_client();
+    let response = client
+        .post_json(
+            "/metadata/batch",
+            &serde_json::json!({ "ids": ["20170818-164830-33e0ab01", "../config.json"] }),
+        )
+        .await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let body = response.to_json().await;
+    validate_error(&body, Some("Invalid packet id '../config.json'"));
+}
+
+#[tokio::test]
+async fn missing_checks_are_limited_in_size() {
+    let root = get_test_dir();
+    update_config(&root, |config| {
+        config["server"] = serde_json::json!({ "max_check_size": 2 });
+    });
+    let mut client = TestClient::new(&root);
+
+    let ids = [
+        "20170818-164830-33e0ab01",
+        "20170818-164847-7574883b",
+        "20180818-164043-7cdcde4b",
+    ];
+    let response = client
+        .post_json("/packets/missing", &serde_json::json!({ "ids": ids[..2] }))
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = client
+        .post_json("/packets/missing", &serde_json::json!({ "ids": ids }))
+        .await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let body = response.to_json().await;
+    validate_error(
+        &body,
+        Some("Sent 3 ids, but at most 2 may be checked at once"),
+    );
+
+    let hashes = [
+        "sha256:b189579a9326f585d308304bd9e03326be5d395ac71b31df359ab8bac408d248",
+        "sha256:a189579a9326f585d308304bd9e03326be5d395ac71b31df359ab8bac408d248",
+        "sha256:c189579a9326f585d308304bd9e03326be5d395ac71b31df359ab8bac408d248",
+    ];
+    let response = client
+        .post_json(
+            "/files/missing",
+            &serde_json::json!({ "hashes": hashes[..2] }),
+        )
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = client
+        .post_json("/files/missing", &serde_json::json!({ "hashes": hashes }))
+        .await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let body = response.to_json().await;
+    validate_error(
+        &body,
+        Some("Sent 3 hashes, but at most 2 may be checked at once"),
+    );
+
+    let response = client
+        .post_json("/files/info", &serde_json::json!({ "hashes": hashes }))
+        .await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let body = response.to_json().await;
+    validate_error(
+        &body,
+        Some("Sent 3 hashes, but at most 2 may be checked at once"),
+    );
+
+    for path in ["/sync/plan", "/sync/diff"] {
+        let response = client
+            .post_json(path, &serde_json::json!({ "ids": ids[..2] }))
+            .await;
+        assert_eq!(response.status(), StatusCode::OK, "{}", path);
+        let response = client
+            .post_json(path, &serde_json::json!({ "ids": ids }))
+            .await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST, "{}", path);
+        let body = response.to_json().await;
+        validate_error(
+            &body,
+            Some("Sent 3 ids, but at most 2 may be checked at once"),
+        );
+    }
+}
+
+#[tokio::test]
+async fn returns_404_if_packet_not_found() {
+    let mut client = get_default_client();
+    let response = client.get("/metadata/bad-id/json").await;
+
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    assert_eq!(response.content_type(), mime::APPLICATION_JSON);
+
+    let body = response.to_json().await;
+    validate_error(&body, Some("packet with id 'bad-id' does not exist"))
+}
+
+#[tokio::test]
+async fn metadata_routes_reject_paths_outside_the_metadata() {
+    let root = get_test_dir();
+    update_config(&root, |config| {
+        config["server"] = serde_json::json!({ "admin_token": "s3cret" });
+    });
+    let mut client = TestClient::new(&root);
+    for route in [
+        "json",
+        "text",
+        "parameters",
+        "dependencies",
+        "size",
+        "download",
+    ] {
+        let response = client
+            .get(format!("/metadata/..%2Fconfig.json/{}", route))
+            .await;
+        assert!(response.status().is_client_error(), "{}", route);
+        let body = response.to_string().await;
+        assert!(!body.contains("s3cret"), "{}", route);
+    }
+}
+
+#[tokio::test]
+async fn packet_routes_reject_invalid_ids() {
+    let mut client = get_default_client();
+    for route in ["parameters", "size", "download"] {
+        // A quote in the id would otherwise end up in the Content-Disposition header.
+        let response = client.get(format!("/metadata/bad%22id/{}", route)).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST, "{}", route);
+        assert!(response.headers().get("content-disposition").is_none());
+        validate_error(&response.to_json().await, Some("Invalid packet id"));
+    }
+}
+
+#[tokio::test]
+async fn can_get_file() {
+    let mut client = get_default_client();
+    let hash = "sha256:b189579a9326f585d308304bd9e03326be5d395ac71b31df359ab8bac408d248";
+    let response = client.get(format!("/file/{}", hash)).await;
+
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.content_type(), mime::APPLICATION_OCTET_STREAM);
+
+    let path = Path::new("tests/example/.outpack/files/sha256/b1/")
+        .join("89579a9326f585d308304bd9e03326be5d395ac71b31df359ab8bac408d248");
+
+    let expected = fs::read(path).unwrap();
+
+    assert_eq!(response.to_bytes().await, expected);
+}
+
+async fn get_file_accepting_gzip(client: &mut TestClient, hash: &str) -> Response {
+    let request = Request::get(format!("/file/{}", hash))
+        .header("accept-encoding", "deflate, gzip")
+        .body(Body::empty())
+        .unwrap();
+    client.request(request).await
+}
+
+#[tokio::test]
+async fn serves_compressed_copy_of_file_if_accepted() {
+    let root = get_test_dir();
+    let hash = "sha256:b189579a9326f585d308304bd9e03326be5d395ac71b31df359ab8bac408d248";
+    let path = root
+        .join(".outpack/files/sha256/b1")
+        .join("89579a9326f585d308304bd9e03326be5d395ac71b31df359ab8bac408d248");
+    // The server does not decompress the copy, so its contents need not be real gzip data.
+    fs::write(path.with_extension("gz"), b"compressed").unwrap();
+    let mut client = TestClient::new(&root);
+
+    let response = get_file_accepting_gzip(&mut client, hash).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()["content-encoding"], "gzip");
+    assert_eq!(response.headers()["etag"], format!("\"{}+gzip\"", hash));
+    assert_eq!(response.headers()["vary"], "accept-encoding");
+    assert_eq!(response.to_bytes().await, b"compressed".as_slice());
+
+    let response = client.get(format!("/file/{}", hash)).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(response.headers().get("content-encoding").is_none());
+    assert_eq!(response.headers()["etag"], format!("\"{}\"", hash));
+    assert_eq!(response.to_bytes().await, fs::read(path).unwrap());
+}
+
+#[tokio::test]
+async fn conditional_file_requests_distinguish_encodings() {
+    let root = get_test_dir();
+    let hash = "sha256:b189579a9326f585d308304bd9e03326be5d395ac71b31df359ab8bac408d248";
+    let path = root
+        .join(".outpack/files/sha256/b1")
+        .join("89579a9326f585d308304bd9e03326be5d395ac71b31df359ab8bac408d248");
+    fs::write(path.with_extension("gz"), b"compressed").unwrap();
+    let mut client = TestClient::new(&root);
+
+    let gzip = get_file_accepting_gzip(&mut client, hash).await;
+    let plain = client.get(format!("/file/{}", hash)).await;
+    let gzip_etag = gzip.headers()["etag"].to_str().unwrap().to_owned();
+    let plain_etag = plain.headers()["etag"].to_str().unwrap().to_owned();
+    assert_ne!(gzip_etag, plain_etag);
+    assert_eq!(gzip.headers()["vary"], "accept-encoding");
+    assert_eq!(plain.headers()["vary"], "accept-encoding");
+
+    let conditional = |etag: &str, accept_gzip: bool| {
+        let mut request = Request::get(format!("/file/{}", hash)).header("if-none-match", etag);
+        if accept_gzip {
+            request = request.header("accept-encoding", "gzip");
+        }
+        request.body(Body::empty()).unwrap()
+    };
+
+    for (etag, accept_gzip) in [
+        (gzip_etag.clone(), true),
+        (format!("W/{}", gzip_etag), true),
+        (plain_etag.clone(), false),
+        (format!("\"x\", {}", plain_etag), false),
+    ] {
+        let response = client.request(conditional(&etag, accept_gzip)).await;
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED, "{}", etag);
+        let expected = if accept_gzip { &gzip_etag } else { &plain_etag };
+        assert_eq!(response.headers()["etag"], expected.as_str());
+        assert_eq!(response.headers()["vary"], "accept-encoding");
+        assert!(response.to_bytes().await.is_empty());
+    }
+
+    // A tag for one encoding never validates the other.
+    let response = client.request(conditional(&plain_etag, true)).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()["etag"], gzip_etag.as_str());
+    assert_eq!(response.to_bytes().await, b"compressed".as_slice());
+
+    let response = client.request(conditional(&gzip_etag, false)).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()["etag"], plain_etag.as_str());
+}
+
+#[tokio::test]
+async fn can_download_range_of_file() {
+    let root = get_empty_root();
+    let mut client = TestClient::new(&root);
+    let content = "0123456789";
+    let hash = format!("sha256:{:x}", Sha256::digest(content));
+    let response = client
+        .post(
+            format!("/file/{}", hash),
+            mime::APPLICATION_OCTET_STREAM,
+            content,
+        )
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let response = client.get(format!("/file/{}", hash)).await;
+    assert_eq!(response.headers()["accept-ranges"], "bytes");
+
+    let ranged = |range: &str| {
+        Request::get(format!("/file/{}", hash))
+            .header("range", range)
+            .body(Body::empty())
+            .unwrap()
+    };
+    let response = client.request(ranged("bytes=2-4")).await;
+    assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+    assert_eq!(response.headers()["content-range"], "bytes 2-4/10");
+    assert_eq!(response.headers()["content-length"], "3");
+    assert_eq!(response.to_string().await, "234");
+
+    let response = client.request(ranged("bytes=-3")).await;
+    assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+    assert_eq!(response.to_string().await, "789");
+
+    let response = client.request(ranged("bytes=10-")).await;
+    assert_eq!(response.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+    assert_eq!(response.headers()["content-range"], "bytes */10");
+
+    // Several ranges are not supported, so the whole file is served.
+    let response = client.request(ranged("bytes=0-1,4-5")).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.to_string().await, content);
+}
+
+#[tokio::test]
+async fn if_range_only_serves_range_of_unchanged_file() {
+    let root = get_empty_root();
+    let mut client = TestClient::new(&root);
+    let content = "0123456789";
+    let hash = format!("sha256:{:x}", Sha256::digest(content));
+    client
+        .post(
+            format!("/file/{}", hash),
+            mime::APPLICATION_OCTET_STREAM,
+            content,
+        )
+        .await;
+    let response = client.get(format!("/file/{}", hash)).await;
+    let etag = response.headers()["etag"].to_str().unwrap().to_owned();
+    let last_modified = response.headers()["last-modified"]
+        .to_str()
+        .unwrap()
+        .to_owned();
+
+    let conditional = |if_range: &str| {
+        Request::get(format!("/file/{}", hash))
+            .header("range", "bytes=5-")
+            .header("if-range", if_range)
+            .body(Body::empty())
+            .unwrap()
+    };
+
+    for if_range in [etag.clone(), last_modified] {
+        let response = client.request(conditional(&if_range)).await;
+        assert_eq!(
+            response.status(),
+            StatusCode::PARTIAL_CONTENT,
+            "{}",
+            if_range
+        );
+        assert_eq!(response.headers()["content-range"], "bytes 5-9/10");
+        assert_eq!(response.to_string().await, "56789");
+    }
+
+    // Any other validator, including a weak version of the file's own tag, gets the whole file.
+    for if_range in [
+        String::from("\"sha256:abcdef\""),
+        format!("W/{}", etag),
+        String::from("Thu, 01 Jan 1970 00:00:00 GMT"),
+    ] {
+        let response = client.request(conditional(&if_range)).await;
+        assert_eq!(response.status(), StatusCode::OK, "{}", if_range);
+        assert!(response.headers().get("content-range").is_none());
+        assert_eq!(response.to_string().await, content);
+    }
+}
+
+#[tokio::test]
+async fn serves_plain_file_if_no_compressed_copy() {
+    let mut client = get_default_client();
+    let hash = "sha256:b189579a9326f585d308304bd9e03326be5d395ac71b31df359ab8bac408d248";
+
+    let response = get_file_accepting_gzip(&mut client, hash).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(response.headers().get("content-encoding").is_none());
+    assert_eq!(response.headers()["etag"], format!("\"{}\"", hash));
+
+    let path = Path::new("tests/example/.outpack/files/sha256/b1/")
+        .join("89579a9326f585d308304bd9e03326be5d395ac71b31df359ab8bac408d248");
+    assert_eq!(response.to_bytes().await, fs::read(path).unwrap());
+}
+
+#[tokio::test]
+async fn file_has_last_modified_time() {
+    let root = get_test_dir();
+    let mut client = TestClient::new(&root);
+    let hash = "sha256:b189579a9326f585d308304bd9e03326be5d395ac71b31df359ab8bac408d248";
+    let response = client.get(format!("/file/{}", hash)).await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let header = response.headers()["last-modified"].to_str().unwrap();
+    let modified = httpdate::parse_http_date(header).expect("A valid http date");
+    let path = root
+        .join(".outpack/files/sha256/b1")
+        .join("89579a9326f585d308304bd9e03326be5d395ac71b31df359ab8bac408d248");
+    let expected = fs::metadata(path).unwrap().modified().unwrap();
+    // Http dates only have a resolution of one second.
+    let difference = match expected.duration_since(modified) {
+        Ok(d) => d,
+        Err(e) => e.duration(),
+    };
+    assert!(difference < std::time::Duration::from_secs(1));
+}
+
+#[tokio::test]
+async fn file_content_type_is_derived_from_filename() {
+    let mut client = get_default_client();
+    let hash = "sha256:b189579a9326f585d308304bd9e03326be5d395ac71b31df359ab8bac408d248";
+
+    let response = client
+        .get(format!("/file/{}?filename=data.csv", hash))
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.content_type(), mime::TEXT_CSV);
+    assert_eq!(
+        response.headers().get(CONTENT_DISPOSITION).unwrap(),
+        "attachment; filename=\"data.csv\""
+    );
+
+    let response = client
+        .get(format!("/file/{}?filename=data.unknown", hash))
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.content_type(), mime::APPLICATION_OCTET_STREAM);
+    assert_eq!(
+        response.headers().get(CONTENT_DISPOSITION).unwrap(),
+        "attachment; filename=\"data.unknown\""
+    );
+}
+
+#[tokio::test]
+async fn returns_404_if_file_not_found() {
+    let mut client = get_default_client();
+    let hash = "sha256:123456";
+    let response = client.get(format!("/file/{}", hash)).await;
+
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    assert_eq!(response.content_type(), mime::APPLICATION_JSON);
+
+    let body = response.to_json().await;
+    validate_error(&body, Some("hash 'sha256:123456' not found"))
+}
+
+#[tokio::test]
+async fn returns_404_if_well_formed_hash_is_not_stored() {
+    let mut client = get_default_client();
+    let hash = format!("sha256:{:x}", Sha256::digest("not in the store"));
+    let response = client.get(format!("/file/{}", hash)).await;
+
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    let body: Value = response.to_json().await;
+    validate_error(&body, Some(&format!("hash '{}' not found", hash)));
+    assert_eq!(body["errors"][0]["error"], "NOT_FOUND");
+}
+
+#[derive(Serialize, Deserialize)]
+struct Ids {
+    ids: Vec<String>,
+    unpacked: bool,
+}
+
+#[tokio::test]
+async fn can_get_missing_ids() {
+    let mut client = get_default_client();
+    let response = client
+        .post_json(
+            "/packets/missing",
+            &Ids {
+                ids: vec![
+                    "20180818-164043-7cdcde4b".to_string(),
+                    "20170818-164830-33e0ab01".to_string(),
+                ],
+                unpacked: false,
+            },
+        )
+        .await;
+
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.content_type(), mime::APPLICATION_JSON);
+
+    let body = response.to_json().await;
+    validate_success("server", "ids.json", &body);
+    let entries = body.get("data").unwrap().as_array().unwrap();
+    assert_eq!(entries.len(), 0);
+}
+
+#[tokio::test]
+async fn can_get_missing_unpacked_ids() {
+    let mut client = get_default_client();
+    let response = client
+        .post_json(
+            "/packets/missing",
+            &Ids {
+                ids: vec![
+                    "20170818-164847-7574883b".to_string(),
+                    "20170818-164830-33e0ab02".to_string(),
+                ],
+                unpacked: true,
+            },
+        )
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.content_type(), mime::APPLICATION_JSON);
+
+    let body = response.to_json().await;
+    validate_success("server", "ids.json", &body);
+    let entries = body.get("data").unwrap().as_array().unwrap();
+    assert_eq!(entries.len(), 1);
+    assert_eq!(
+        entries.first().unwrap().as_str(),
+        Some("20170818-164830-33e0ab02")
+    );
+}
+
+#[tokio::test]
+async fn missing_packets_only_checks_metadata_by_default() {
+    let root = get_empty_root();
+    let id = "20240101-120000-00000001";
+    add_metadata_only(&root, &example_metadata(id));
+    let absent = "20240101-120000-00000002";
+
+    let missing = |body: Value| {
+        let mut client = TestClient::new(&root);
+        async move {
+            let response = client.post_json("/packets/missing", &body).await;
+            assert_eq!(response.status(), StatusCode::OK);
+            let body: Value = response.to_json().await;
+            validate_success("server", "ids.json", &body);
+            let mut ids: Vec<String> = serde_json::from_value(body["data"].clone()).unwrap();
+            ids.sort();
+            ids
+        }
+    };
+    // The metadata of the first packet is present, but the packet is not unpacked.
+    let ids = [id, absent];
+    assert_eq!(missing(serde_json::json!({ "ids": ids })).await, [absent]);
+    assert_eq!(
+        missing(serde_json::json!({ "ids": ids, "unpacked": false })).await,
+        [absent]
+    );
+    assert_eq!(
+        missing(serde_json::json!({ "ids": ids, "unpacked": true })).await,
+        [id, absent]
+    );
+}
+
+#[tokio::test]
+async fn missing_packets_propagates_errors() {
+    let mut client = get_default_client();
+    let response = client
+        .post_json(
+            "/packets/missing",
+            &Ids {
+                ids: vec!["badid".to_string()],
+                unpacked: true,
+            },
+        )
+        .await;
+
+    let body = response.to_json().await;
+    validate_error(&body, Some("Invalid packet id"));
+}
+
+#[tokio::test]
+async fn missing_packets_validates_request_body() {
+    let mut client = get_default_client();
+    let response = client
+        .post("/packets/missing", mime::APPLICATION_JSON, Body::empty())
+        .await;
+
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(response.content_type(), mime::APPLICATION_JSON);
+
+    let body = response.to_json().await;
+    validate_error(&body, Some("EOF while parsing a value at line 1 column 0"));
+}
+
+#[tokio::test]
+async fn missing_packets_rejects_malformed_json() {
+    let mut client = get_default_client();
+    let response = client
+        .post("/packets/missing", mime::APPLICATION_JSON, "{\"ids\": [")
+        .await;
+
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(response.content_type(), mime::APPLICATION_JSON);
+    let body = response.to_json().await;
+    validate_error(&body, None);
+
+    let response = client
+        .post("/packets/missing", mime::TEXT_PLAIN, "{}")
+        .await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(response.content_type(), mime::APPLICATION_JSON);
+    let body = response.to_json().await;
+    validate_error(
+        &body,
+        Some("Expected request with `Content-Type: application/json`"),
+    );
+}
+
+#[tokio::test]
+async fn oversized_metadata_gives_json_error() {
+    let mut client = get_default_client();
+    // Larger than axum's default limit on the size of a request body read into memory.
+    let metadata = vec![b' '; 3 * 1024 * 1024];
+    let response = client.post("/metadata", mime::TEXT_PLAIN, metadata).await;
+
+    assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    assert_eq!(response.content_type(), mime::APPLICATION_JSON);
+    let body: Value = response.to_json().await;
+    validate_error(&body, None);
+    assert_eq!(body["errors"][0]["error"], "PAYLOAD_TOO_LARGE");
+}
+
+#[tokio::test]
+async fn oversized_json_gives_json_error() {
+    let root = get_test_dir();
+    update_config(&root, |config| {
+        config["server"] = serde_json::json!({ "max_json_size": 1024 });
+    });
+    let mut client = TestClient::new(&root);
+
+    let hashes: Vec<String> = (0..100)
+        .map(|i| format!("sha256:{:x}", Sha256::digest(i.to_string())))
+        .collect();
+    let response = client.post_json("/files/missing", &Hashes { hashes }).await;
+    assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    assert_eq!(response.content_type(), mime::APPLICATION_JSON);
+    let body = response.to_json().await;
+    validate_error(&body, Some("length limit exceeded"));
+
+    // Smaller requests are unaffected, as are file uploads larger than the limit.
+    let hashes = vec![format!("sha256:{:x}", Sha256::digest("0"))];
+    let response = client.post_json("/files/missing", &Hashes { hashes }).await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let content = "x".repeat(2048);
+    let hash = format!("sha256:{:x}", Sha256::digest(&content));
+    let response = client
+        .post(
+            format!("/file/{}", hash),
+            mime::APPLICATION_OCTET_STREAM,
+            content,
+        )
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn invalid_query_parameters_give_json_errors() {
+    let mut client = get_default_client();
+    let response = client.get("/files?limit=lots").await;
+
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(response.content_type(), mime::APPLICATION_JSON);
+    assert!(response.headers().contains_key("x-request-id"));
+    let body: Value = response.to_json().await;
+    validate_error(&body, Some("invalid digit found in string"));
+    assert_eq!(body["errors"][0]["error"], "BAD_REQUEST");
+}
+
+#[derive(Serialize, Deserialize)]
+struct Hashes {
+    hashes: Vec<String>,
+}
+
+#[tokio::test]
+async fn can_get_latest_packet_by_name() {
+    let mut client = get_default_client();
+
+    // Every packet of this name was started at the same time, so the latest is the greatest id.
+    let response = client
+        .get("/packets/latest?name=modup-201707-queries1")
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = response.to_json().await;
+    validate_success("server", "packet-id.json", &body);
+    assert_eq!(body["data"], "20180818-164043-7cdcde4b");
+
+    let response = client
+        .get("/packets/latest?name=modup-201707-params1")
+        .await;
+    let body: Value = response.to_json().await;
+    assert_eq!(body["data"], "20180220-095832-16a4bbed");
+
+    let response = client.get("/packets/latest?name=unknown").await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    let body = response.to_json().await;
+    validate_error(&body, Some("No packets named 'unknown'"));
+
+    let response = client.get("/packets/latest").await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn can_search_metadata_fields() {
+    let mut client = get_default_client();
+    let body = serde_json::json!({ "fields": ["name", "parameters.disease"] });
+    let response = client.post_json("/metadata/search", &body).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let body: Value = response.to_json().await;
+    validate_success("server", "metadata-search.json", &body);
+    assert_eq!(
+        body["data"],
+        serde_json::json!([
+            { "name": "modup-201707-queries1", "parameters.disease": "YF" },
+            { "name": "modup-201707-queries1", "parameters.disease": null },
+            { "name": "modup-201707-params1", "parameters.disease": "YF" },
+            { "name": "modup-201707-queries1", "parameters.disease": "YF" }
+        ])
+    );
+
+    let body = serde_json::json!({
+        "query": "parameter:size == 10",
+        "fields": ["id", "parameters.size"]
+    });
+    let response = client.post_json("/metadata/search", &body).await;
+    let body: Value = response.to_json().await;
+    assert_eq!(
+        body["data"],
+        serde_json::json!([{ "id": "20180220-095832-16a4bbed", "parameters.size": 10 }])
+    );
+
+    let body = serde_json::json!({ "query": "name ==", "fields": ["id"] });
+    let response = client.post_json("/metadata/search", &body).await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let body = response.to_json().await;
+    validate_error(&body, Some("Failed to parse query"));
+
+    let body = serde_json::json!({ "fields": [] });
+    let response = client.post_json("/metadata/search", &body).await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn can_summarise_packets_by_name() {
+    let mut client = get_default_client();
+
+    let response = client.get("/packets/summary").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = response.to_json().await;
+    validate_success("server", "packet-summary.json", &body);
+    assert_eq!(body["meta"]["count"], 2);
+    assert_eq!(
+        body["data"],
+        serde_json::json!([
+            {
+                "name": "modup-201707-params1",
+                "count": 1,
+                "latest_id": "20180220-095832-16a4bbed",
+                "latest_time": 1519120570.2232
+            },
+            {
+                "name": "modup-201707-queries1",
+                "count": 3,
+                "latest_id": "20180818-164043-7cdcde4b",
+                "latest_time": 1503074938.2232
+            }
+        ])
+    );
+
+    let response = client.get("/packets/summary?sort=count&limit=1").await;
+    let body: Value = response.to_json().await;
+    assert_eq!(body["data"].as_array().unwrap().len(), 1);
+    assert_eq!(body["data"][0]["name"], "modup-201707-queries1");
+    assert_eq!(body["data"][0]["count"], 3);
+
+    let response = client.get("/packets/summary?sort=latest&offset=1").await;
+    let body: Value = response.to_json().await;
+    assert_eq!(body["data"].as_array().unwrap().len(), 1);
+    assert_eq!(body["data"][0]["name"], "modup-201707-queries1");
+
+    let response = client.get("/packets/summary?sort=size").await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+}
+
+async fn get_signed_url(client: &mut TestClient, hash: &str, expires_in: u64) -> String {
+    let response = client
+        .get(format!(
+            "/file/{}/signed-url?expires_in={}",
+            hash, expires_in
+        ))
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = response.to_json().await;
+    validate_success("server", "signed-url.json", &body);
+    body["data"]["url"].as_str().unwrap().to_owned()
+}
+
+#[tokio::test]
+async fn can_download_file_with_signed_url() {
+    let root = get_test_dir();
+    update_config(&root, |config| {
+        config["server"] = serde_json::json!({ "url_signing_secret": "s3cret" });
+    });
+    let mut client = TestClient::new(&root);
+    let hash = "sha256:b189579a9326f585d308304bd9e03326be5d395ac71b31df359ab8bac408d248";
+
+    let url = get_signed_url(&mut client, hash, 600).await;
+    assert!(url.starts_with(&format!("/file/{}?exp=", hash)));
+    let response = client.get(&url).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.content_type(), mime::APPLICATION_OCTET_STREAM);
+
+    // A url which expires immediately can never be used.
+    let url = get_signed_url(&mut client, hash, 0).await;
+    let response = client.get(&url).await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    let body = response.to_json().await;
+    validate_error(&body, Some("The signed url has expired"));
+
+    // Neither the signature nor the expiry can be changed.
+    let url = get_signed_url(&mut client, hash, 600).await;
+    let replacement = if url.ends_with('0') { '1' } else { '0' };
+    let tampered = format!("{}{}", &url[..url.len() - 1], replacement);
+    let response = client.get(&tampered).await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    let body = response.to_json().await;
+    validate_error(&body, Some("The signature of the url is invalid"));
+
+    let (_, query) = url.split_once("?exp=").unwrap();
+    let (exp, sig) = query.split_once("&sig=").unwrap();
+    let extended = exp.parse::<u64>().unwrap() + 1;
+    let response = client
+        .get(format!("/file/{}?exp={}&sig={}", hash, extended, sig))
+        .await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+    let response = client.get(format!("/file/{}?sig={}", hash, sig)).await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+    // A multihash gets the same url, which can also be used with the multihash.
+    let multihash = hash
+        .parse::<outpack::hash::Hash>()
+        .unwrap()
+        .encode(outpack::hash::HashEncoding::Multihash)
+        .unwrap();
+    let url = get_signed_url(&mut client, &multihash, 600).await;
+    assert!(url.starts_with(&format!("/file/{}?exp=", hash)));
+    let response = client.get(&url).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = client.get(url.replace(hash, &multihash)).await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // Urls cannot be signed for files that don't exist.
+    let other = "sha256:a189579a9326f585d308304bd9e03326be5d395ac71b31df359ab8bac408d247";
+    let response = client.get(format!("/file/{}/signed-url", other)).await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn signed_urls_are_limited_in_expiry() {
+    let root = get_test_dir();
+    update_config(&root, |config| {
+        config["server"] = serde_json::json!({
+            "url_signing_secret": "s3cret",
+            "max_signed_url_expiry": 600,
+        });
+    });
+    let mut client = TestClient::new(&root);
+    let hash = "sha256:b189579a9326f585d308304bd9e03326be5d395ac71b31df359ab8bac408d248";
+
+    get_signed_url(&mut client, hash, 600).await;
+    for expires_in in [601, u64::MAX] {
+        let response = client
+            .get(format!(
+                "/file/{}/signed-url?expires_in={}",
+                hash, expires_in
+            ))
+            .await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let body = response.to_json().await;
+        validate_error(&body, Some("may be valid for at most 600 seconds"));
+    }
+}
+
+#[tokio::test]
+async fn signed_urls_lead_back_to_their_root() {
+    let root = get_test_dir();
+    update_config(&root, |config| {
+        config["server"] = serde_json::json!({ "url_signing_secret": "s3cret" });
+    });
+    let other = get_test_dir();
+    let mut client = TestClient::with_roots(&[("a", &root), ("b", &other)]);
+    let hash = "sha256:b189579a9326f585d308304bd9e03326be5d395ac71b31df359ab8bac408d248";
+
+    let response = client
+        .get(format!("/roots/a/file/{}/signed-url", hash))
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let body: Value = response.to_json().await;
+    let url = body["data"]["url"].as_str().unwrap();
+    assert!(url.starts_with(&format!("/roots/a/file/{}?exp=", hash)));
+    let response = client.get(url).await;
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn signed_urls_require_a_secret() {
+    let mut client = get_default_client();
+    let hash = "sha256:b189579a9326f585d308304bd9e03326be5d395ac71b31df359ab8bac408d248";
+
+    let response = client.get(format!("/file/{}/signed-url", hash)).await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    let body = response.to_json().await;
+    validate_error(&body, Some("Signed urls are not enabled"));
+
+    let response = client
+        .get(format!("/file/{}?exp=9999999999&sig=abc", hash))
+        .await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+    // Requests without a signature are unaffected.
+    let response = client.get(format!("/file/{}", hash)).await;
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn can_get_file_info() {
+    let mut client = get_default_client();
+    let present = "sha256:b189579a9326f585d308304bd9e03326be5d395ac71b31df359ab8bac408d248";
+    let absent = "sha256:a189579a9326f585d308304bd9e03326be5d395ac71b31df359ab8bac408d247";
+    let response = client
+        .post_json(
+            "/files/info",
+            &Hashes {
+                hashes: vec![present.to_string(), absent.to_string()],
+            },
+        )
+        .await;
+
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = response.to_json().await;
+    validate_success("server", "file-info.json", &body);
+
+    let size = std::fs::metadata(
+        "tests/example/.outpack/files/sha256/b1/89579a9326f585d308304bd9e03326be5d395ac71b31df359ab8bac408d248",
+    )
+        .unwrap()
+        .len();
+    assert_eq!(body["data"][present]["present"], true);
+    assert_eq!(body["data"][present]["size"], size);
+    assert!(body["data"][present]["modified"].as_f64().unwrap() > 0.0);
+    assert_eq!(
+        body["data"][absent],
+        serde_json::json!({ "present": false })
+    );
+
+    let response = client
+        .post_json(
+            "/files/info",
+            &Hashes {
+                hashes: vec!["sha256:notahash".to_string()],
+            },
+        )
+        .await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let body = response.to_json().await;
+    validate_error(&body, None);
+}
+
+#[tokio::test]
+async fn can_get_missing_files() {
+    let mut client = get_default_client();
+    let response = client
+        .post_json(
+            "/files/missing",
+            &Hashes {
+                hashes: vec![
+                    "sha256:b189579a9326f585d308304bd9e03326be5d395ac71b31df359ab8bac408d248"
+                        .to_string(),
+                    "sha256:a189579a9326f585d308304bd9e03326be5d395ac71b31df359ab8bac408d247"
+                        .to_string(),
+                ],
+            },
+        )
+        .await;
+
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.content_type(), mime::APPLICATION_JSON);
+
+    let body = response.to_json().await;
+    validate_success("server", "hashes.json", &body);
+    let entries = body.get("data").unwrap().as_array().unwrap();
+    assert_eq!(entries.len(), 1);
+    assert_eq!(
+        entries.first().unwrap().as_str(),
+        Some("sha256:a189579a9326f585d308304bd9e03326be5d395ac71b31df359ab8bac408d247")
+    );
+}
+
+#[tokio::test]
+async fn can_verify_missing_files() {
+    let root = get_test_dir();
+    let mut client = TestClient::new(&root);
+
+    let intact = "sha256:b189579a9326f585d308304bd9e03326be5d395ac71b31df359ab8bac408d248";
+    let truncated = format!("sha256:{:x}", Sha256::digest("truncated"));
+    let absent = "sha256:a189579a9326f585d308304bd9e03326be5d395ac71b31df359ab8bac408d247";
+
+    let response = client
+        .post(
+            format!("/file/{}", truncated),
+            mime::APPLICATION_OCTET_STREAM,
+            "truncated",
+        )
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let value = truncated.strip_prefix("sha256:").unwrap();
+    let path = root
+        .join(".outpack/files/sha256")
+        .join(&value[..2])
+        .join(&value[2..]);
+    fs::write(path, "trunc").unwrap();
+
+    let hashes = Hashes {
+        hashes: vec![intact.to_string(), truncated.clone(), absent.to_string()],
+    };
+
+    let response = client
+        .post_json("/files/missing?verify=true", &hashes)
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.content_type(), mime::APPLICATION_JSON);
+
+    let body = response.to_json().await;
+    validate_success("server", "file-check.json", &body);
+    assert_eq!(body["data"]["missing"], serde_json::json!([absent]));
+    assert_eq!(body["data"]["corrupt"], serde_json::json!([truncated]));
+
+    // Without verification, the truncated file is considered present.
+    let response = client.post_json("/files/missing", &hashes).await;
+    let body = response.to_json().await;
+    validate_success("server", "hashes.json", &body);
+    assert_eq!(body["data"], serde_json::json!([absent]));
+}
+
+#[tokio::test]
+async fn can_verify_store() {
+    let root = get_test_dir();
+    let mut client = TestClient::new(&root);
+
+    let response = client.get("/store/verify").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = response.to_json().await;
+    validate_success("server", "invalid-files.json", &body);
+    assert_eq!(body["data"], serde_json::json!([]));
+
+    let hash = format!("sha256:{:x}", Sha256::digest("good"));
+    let value = hash.strip_prefix("sha256:").unwrap();
+    let directory = root.join(".outpack/files/sha256").join(&value[..2]);
+    fs::create_dir_all(&directory).unwrap();
+    fs::write(directory.join(&value[2..]), "bad").unwrap();
+
+    let response = client.get("/store/verify").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = response.to_json().await;
+    validate_success("server", "invalid-files.json", &body);
+
+    let entries = body["data"].as_array().unwrap();
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0]["expected"], hash);
+    assert_eq!(
+        entries[0]["found"],
+        format!("sha256:{:x}", Sha256::digest("bad"))
+    );
+}
+
+#[tokio::test]
+async fn missing_files_propagates_errors() {
+    let mut client = get_default_client();
+    let response = client
+        .post_json(
+            "/files/missing",
+            &Hashes {
+                hashes: vec!["badhash".to_string()],
+            },
+        )
+        .await;
+
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let body = response.to_json().await;
+    validate_error(&body, Some("Invalid hash format 'badhash'"));
+}
+
+#[tokio::test]
+async fn missing_files_validates_request_body() {
+    let mut client = get_default_client();
+    let response = client
+        .post("/files/missing", mime::APPLICATION_JSON, Body::empty())
+        .await;
+
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(response.content_type(), mime::APPLICATION_JSON);
+
+    let body = response.to_json().await;
+    validate_error(&body, Some("EOF while parsing a value at line 1 column 0"));
+}
+
+#[tokio::test]
+async fn can_post_file() {
+    let mut client = get_default_client();
+    let content = "test";
+    let hash = format!("sha256:{:x}", Sha256::digest(content));
+    let response = client
+        .post(
+            format!("/file/{}", hash),
+            mime::APPLICATION_OCTET_STREAM,
+            content,
+        )
+        .await;
+
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.content_type(), mime::APPLICATION_JSON);
+
+    let body = response.to_json().await;
+    validate_success("server", "null-response.json", &body);
+
+    body.get("data")
+        .expect("Data property present")
+        .as_null()
+        .expect("Null data");
+
+    // check file now exists on server
+    let get_file_response = client.get(format!("/file/{}", hash)).await;
+    assert_eq!(get_file_response.status(), StatusCode::OK);
+    assert_eq!(get_file_response.to_string().await, "test");
+}
+
+#[tokio::test]
+async fn posting_existing_file_is_a_no_op() {
+    let root = get_test_dir();
+    let mut client = TestClient::new(&root);
+    let content = "test";
+    let hash = format!("sha256:{:x}", Sha256::digest(content));
+
+    for _ in 0..2 {
+        let response = client
+            .post(
+                format!("/file/{}", hash),
+                mime::APPLICATION_OCTET_STREAM,
+                content,
+            )
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    let path = outpack::store::file_path(&root, &hash).unwrap();
+    assert_eq!(std::fs::read_to_string(path).unwrap(), content);
+}
+
+#[tokio::test]
+async fn posting_file_over_corrupt_copy_is_a_conflict() {
+    let root = get_test_dir();
+    let mut client = TestClient::new(&root);
+    let content = "test";
+    let hash = format!("sha256:{:x}", Sha256::digest(content));
+
+    let path = outpack::store::file_path(&root, &hash).unwrap();
+    std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+    std::fs::write(&path, "corrupted").unwrap();
+
+    let response = client
+        .post(
+            format!("/file/{}?verify=true", hash),
+            mime::APPLICATION_OCTET_STREAM,
+            content,
+        )
+        .await;
+    assert_eq!(response.status(), StatusCode::CONFLICT);
+    assert_eq!(response.content_type(), mime::APPLICATION_JSON);
+
+    let body = response.to_json().await;
+    validate_error(&body, Some("its contents do not match its hash"));
+
+    // The corrupt copy is left in place, so that it can be investigated.
+    assert_eq!(std::fs::read_to_string(path).unwrap(), "corrupted");
+}
+
+#[tokio::test]
+async fn can_gc_store() {
+    let root = get_test_dir();
+    let mut client = TestClient::new(&root);
+    add_packet_with_file(&mut client, "20240101-120000-00000001", "kept", &[]).await;
+    let kept = format!("sha256:{:x}", Sha256::digest("kept"));
+    let orphan = format!("sha256:{:x}", Sha256::digest("orphan"));
+    let response = client
+        .post(
+            format!("/file/{}", orphan),
+            mime::APPLICATION_OCTET_STREAM,
+            "orphan",
+        )
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // Files which were only just added might be part of a packet which is being pushed.
+    let response = client.post("/store/gc", mime::TEXT_PLAIN, "").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let body: Value = response.to_json().await;
+    let removed = body["data"]["removed"].as_array().unwrap();
+    assert!(!removed.contains(&Value::from(orphan.clone())));
+    assert!(outpack::store::file_exists(&root, &orphan).unwrap());
+
+    update_config(&root, |config| {
+        config["server"] = serde_json::json!({ "gc_grace_period": 0 });
+    });
+    let mut client = TestClient::new(&root);
+    let response = client
+        .post("/store/gc?dry_run=true", mime::TEXT_PLAIN, "")
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let body: Value = response.to_json().await;
+    validate_success("server", "gc.json", &body);
+    let removed = body["data"]["removed"].as_array().unwrap();
+    assert!(removed.contains(&Value::from(orphan.clone())));
+    assert!(!removed.contains(&Value::from(kept.clone())));
+    assert!(outpack::store::file_exists(&root, &orphan).unwrap());
+
+    let response = client.post("/store/gc", mime::TEXT_PLAIN, "").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let body: Value = response.to_json().await;
+    validate_success("server", "gc.json", &body);
+    assert_eq!(body["data"]["removed"].as_array().unwrap(), removed);
+    assert!(!outpack::store::file_exists(&root, &orphan).unwrap());
+    assert!(outpack::store::file_exists(&root, &kept).unwrap());
+}
+
+#[tokio::test]
+async fn gc_keeps_files_uploaded_again() {
+    let root = get_empty_root();
+    update_config(&root, |config| {
+        config["server"] = serde_json::json!({ "gc_grace_period": 60 * 60 });
+    });
+    let mut client = TestClient::new(&root);
+    let hash = format!("sha256:{:x}", Sha256::digest("orphan"));
+    let response = client
+        .post(
+            format!("/file/{}", hash),
+            mime::APPLICATION_OCTET_STREAM,
+            "orphan",
+        )
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let a_day_ago = std::time::SystemTime::now() - Duration::from_secs(24 * 60 * 60);
+    let path = outpack::store::file_path(&root, &hash).unwrap();
+    filetime::set_file_mtime(&path, filetime::FileTime::from_system_time(a_day_ago)).unwrap();
+
+    // The file is already stored, so its body is not read, but it is about to be used by a packet
+    // and so must not be collected.
+    let response = client
+        .post(
+            format!("/file/{}", hash),
+            mime::APPLICATION_OCTET_STREAM,
+            "orphan",
+        )
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = client.post("/store/gc", mime::TEXT_PLAIN, "").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let body: Value = response.to_json().await;
+    assert_eq!(body["data"]["removed"], serde_json::json!([]));
+    assert!(outpack::store::file_exists(&root, &hash).unwrap());
+
+    // The file is still served as having been added when it was first uploaded.
+    let response = client.get(format!("/file/{}", hash)).await;
+    assert_eq!(
+        response.headers()["last-modified"],
+        httpdate::fmt_http_date(a_day_ago)
+    );
+}
+
+#[tokio::test]
+async fn gc_keeps_files_reported_present() {
+    let root = get_empty_root();
+    update_config(&root, |config| {
+        config["server"] = serde_json::json!({ "gc_grace_period": 60 * 60 });
+    });
+    let mut client = TestClient::new(&root);
+    let hash = format!("sha256:{:x}", Sha256::digest("orphan"));
+    let response = client
+        .post(
+            format!("/file/{}", hash),
+            mime::APPLICATION_OCTET_STREAM,
+            "orphan",
+        )
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let a_day_ago = filetime::FileTime::from_system_time(
+        std::time::SystemTime::now() - Duration::from_secs(24 * 60 * 60),
+    );
+    let path = outpack::store::file_path(&root, &hash).unwrap();
+    filetime::set_file_mtime(&path, a_day_ago).unwrap();
+
+    // A client told that the file is present will not upload it before adding its packet.
+    let response = client
+        .post_json("/files/missing", &serde_json::json!({ "hashes": [hash] }))
+        .await;
+    let body: Value = response.to_json().await;
+    assert_eq!(body["data"], serde_json::json!([]));
+    let response = client.post("/store/gc", mime::TEXT_PLAIN, "").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let body: Value = response.to_json().await;
+    assert_eq!(body["data"]["removed"], serde_json::json!([]));
+    assert!(outpack::store::file_exists(&root, &hash).unwrap());
+}
+
+#[tokio::test]
+async fn can_count_store_references() {
+    let root = get_empty_root();
+    let mut client = TestClient::new(&root);
+    add_packet_with_file(&mut client, "20240101-120000-00000001", "shared", &[]).await;
+    add_packet_with_file(&mut client, "20240101-120000-00000002", "shared", &[]).await;
+    add_packet_with_file(&mut client, "20240101-120000-00000003", "single", &[]).await;
+    let shared = format!("sha256:{:x}", Sha256::digest("shared"));
+    let single = format!("sha256:{:x}", Sha256::digest("single"));
+    let orphan = format!("sha256:{:x}", Sha256::digest("orphan"));
+    let response = client
+        .post(
+            format!("/file/{}", orphan),
+            mime::APPLICATION_OCTET_STREAM,
+            "orphan",
+        )
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let response = client.get("/store/references").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let body: Value = response.to_json().await;
+    validate_success("server", "references.json", &body);
+    assert_eq!(
+        body["data"]["counts"],
+        serde_json::json!({ shared.clone(): 2, single.clone(): 1, orphan: 0 })
+    );
+    assert_eq!(body["data"]["orphans"], 1);
+    assert_eq!(body["data"]["bytes_saved"], 6);
+    assert_eq!(
+        body["data"]["most_referenced"],
+        serde_json::json!([
+            { "hash": shared, "packets": 2, "size": 6 },
+            { "hash": single, "packets": 1, "size": 6 }
+        ])
+    );
+
+    let response = client.get("/store/references?top=1").await;
+    let body: Value = response.to_json().await;
+    assert_eq!(body["data"]["most_referenced"].as_array().unwrap().len(), 1);
+}
+
+async fn dry_run_file(client: &mut TestClient, hash: &str, content: &'static str) -> Value {
+    let response = client
+        .post(
+            format!("/file/{}?dry_run=true", hash),
+            mime::APPLICATION_OCTET_STREAM,
+            content,
+        )
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = response.to_json().await;
+    validate_success("server", "hash-check.json", &body);
+    body
+}
+
+fn count_uploads(root: &Path) -> usize {
+    let files = root.join(".outpack").join("files");
+    walkdir::WalkDir::new(files)
+        .into_iter()
+        .filter(|e| e.as_ref().unwrap().file_type().is_file())
+        .count()
+}
+
+#[tokio::test]
+async fn can_list_files() {
+    let root = get_empty_root();
+    let mut client = TestClient::new(&root);
+
+    let mut hashes = Vec::new();
+    for content in ["one", "two", "three"] {
+        let hash = format!("sha256:{:x}", Sha256::digest(content));
+        let response = client
+            .post(
+                format!("/file/{}", hash),
+                mime::APPLICATION_OCTET_STREAM,
+                content,
+            )
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        hashes.push(hash);
+    }
+    hashes.sort();
+
+    let response = client.get("/files").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let body: Value = response.to_json().await;
+    validate_success("server", "hashes.json", &body);
+    assert_eq!(body["data"], serde_json::json!(hashes));
+
+    let body: Value = client.get("/files?limit=2").await.to_json().await;
+    assert_eq!(body["data"], serde_json::json!(hashes[..2]));
+    let body: Value = client.get("/files?limit=2&offset=2").await.to_json().await;
+    assert_eq!(body["data"], serde_json::json!(hashes[2..]));
+    assert_list_meta(&body);
+}
+
+#[tokio::test]
+async fn can_dry_run_file_upload() {
+    let root = get_test_dir();
+    let mut client = TestClient::new(&root);
+    let before = count_uploads(&root);
+
+    let content = "dry run";
+    let hash = format!("sha256:{:x}", Sha256::digest(content));
+    let body = dry_run_file(&mut client, &hash, content).await;
+    assert_eq!(body["data"]["matches"], true);
+    assert_eq!(body["data"]["found"], hash);
+
+    let wrong = format!("sha256:{:x}", Sha256::digest("something else"));
+    let body = dry_run_file(&mut client, &wrong, content).await;
+    assert_eq!(body["data"]["matches"], false);
+    assert_eq!(body["data"]["expected"], wrong);
+    assert_eq!(body["data"]["found"], hash);
+
+    // Neither the file nor any temporary copy of it is left behind.
+    assert_eq!(count_uploads(&root), before);
+    let response = client.get(format!("/file/{}", hash)).await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+/// A request body which sends the given chunks, waiting `delay` before each one, and then stalls
+/// forever if `stall` is true.
+fn slow_body(chunks: Vec<&'static str>, delay: Duration, stall: bool) -> Body {
+    let chunks = futures::stream::iter(chunks).then(move |chunk| async move {
+        tokio::time::sleep(delay).await;
+        Ok::<_, std::io::Error>(chunk)
+    });
+    let stalled = futures::stream::pending();
+    if stall {
+        Body::from_stream(chunks.chain(stalled))
+    } else {
+        Body::from_stream(chunks)
+    }
+}
+
+#[tokio::test]
+async fn uploading_present_file_skips_reading_body() {
+    let root = get_test_dir();
+    let mut client = TestClient::new(&root);
+    let content = "test";
+    let hash = format!("sha256:{:x}", Sha256::digest(content));
+    let response = client
+        .post(
+            format!("/file/{}", hash),
+            mime::APPLICATION_OCTET_STREAM,
+            content,
+        )
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let head = |hash: &str| {
+        Request::head(format!("/file/{}", hash))
+            .body(Body::empty())
+            .unwrap()
+    };
+    assert_eq!(client.request(head(&hash)).await.status(), StatusCode::OK);
+    let absent = format!("sha256:{:x}", Sha256::digest("absent"));
+    let response = client.request(head(&absent)).await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+    // The body never finishes, so the upload can only succeed if none of it is read.
+    let request = Request::post(format!("/file/{}", hash))
+        .header(CONTENT_TYPE, mime::APPLICATION_OCTET_STREAM.as_ref())
+        .body(slow_body(vec!["not the contents"], Duration::ZERO, true))
+        .unwrap();
+    let response = tokio::time::timeout(Duration::from_secs(5), client.request(request))
+        .await
+        .expect("upload of a present file returned without reading its body");
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = response.to_json().await;
+    validate_success("server", "null-response.json", &body);
+
+    // When asked to verify, the body is read and checked as usual.
+    let response = client
+        .post(
+            format!("/file/{}?verify=true", hash),
+            mime::APPLICATION_OCTET_STREAM,
+            "not the contents",
+        )
+        .await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+    let path = outpack::store::file_path(&root, &hash).unwrap();
+    assert_eq!(std::fs::read_to_string(path).unwrap(), content);
+}
+
+#[tokio::test]
+async fn stalled_uploads_time_out() {
+    let root = get_test_dir();
+    update_config(&root, |config| {
+        config["server"] = serde_json::json!({ "read_timeout": 1 });
+    });
+    let mut client = TestClient::new(&root);
+
+    let hash = format!("sha256:{:x}", Sha256::digest("never finished"));
+    let request = Request::post(format!("/file/{}", hash))
+        .header(CONTENT_TYPE, mime::APPLICATION_OCTET_STREAM.as_ref())
+        .body(slow_body(vec!["never "], Duration::ZERO, true))
+        .unwrap();
+    let response = tokio::time::timeout(Duration::from_secs(10), client.request(request))
+        .await
+        .expect("stalled upload was terminated");
+    assert_eq!(response.status(), StatusCode::REQUEST_TIMEOUT);
+    let body = response.to_json().await;
+    validate_error(&body, Some("No data was received for 1 seconds"));
+
+    // Json bodies are limited in the same way.
+    let request = Request::post("/files/missing")
+        .header(CONTENT_TYPE, mime::APPLICATION_JSON.as_ref())
+        .body(slow_body(vec!["{\"hashes\": "], Duration::ZERO, true))
+        .unwrap();
+    let response = tokio::time::timeout(Duration::from_secs(10), client.request(request))
+        .await
+        .expect("stalled request was terminated");
+    assert_eq!(response.status(), StatusCode::REQUEST_TIMEOUT);
+}
+
+#[tokio::test]
+async fn slow_uploads_making_progress_are_not_timed_out() {
+    let root = get_test_dir();
+    update_config(&root, |config| {
+        config["server"] = serde_json::json!({ "read_timeout": 1 });
+    });
+    let mut client = TestClient::new(&root);
+
+    // The whole upload takes longer than the timeout, but no single chunk does.
+    let chunks = vec!["slow ", "and ", "steady ", "upload"];
+    let hash = format!("sha256:{:x}", Sha256::digest(chunks.concat()));
+    let request = Request::post(format!("/file/{}", hash))
+        .header(CONTENT_TYPE, mime::APPLICATION_OCTET_STREAM.as_ref())
+        .body(slow_body(chunks.clone(), Duration::from_millis(400), false))
+        .unwrap();
+    let response = client.request(request).await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let response = client.get(format!("/file/{}", hash)).await;
+    assert_eq!(response.to_string().await, chunks.concat());
+}
+
+#[tokio::test]
+async fn can_configure_upload_directory() {
+    let root = get_test_dir();
+    update_config(&root, |config| {
+        config["server"] = serde_json::json!({ "upload_dir": "scratch/uploads" });
+    });
+    let mut client = TestClient::new(&root);
+    assert!(root.join("scratch/uploads").is_dir());
+
+    let content = "uploaded via a custom directory";
+    let hash = format!("sha256:{:x}", Sha256::digest(content));
+    let response = client
+        .post(
+            format!("/file/{}", hash),
+            mime::APPLICATION_OCTET_STREAM,
+            content,
+        )
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let response = client.get(format!("/file/{}", hash)).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.to_string().await, content);
+
+    // Once moved into the store, no temporary files are left behind, either in the upload
+    // directory or in the root.
+    assert_eq!(
+        fs::read_dir(root.join("scratch/uploads")).unwrap().count(),
+        0
+    );
+    assert!(!fs::read_dir(&root).unwrap().any(|e| e
+        .unwrap()
+        .file_name()
+        .to_string_lossy()
+        .starts_with(".tmp")));
+
+    // The server cannot start if the directory cannot be created.
+    fs::write(root.join("not-a-directory"), "").unwrap();
+    update_config(&root, |config| {
+        config["server"] = serde_json::json!({ "upload_dir": "not-a-directory" });
+    });
+    let err = outpack::api::api(&root).unwrap_err();
+    assert!(err
+        .to_string()
+        .contains("Could not create upload directory"));
+}
+
+#[cfg(unix)]
+#[test]
+fn upload_directory_must_be_on_the_same_filesystem_as_the_store() {
+    use std::os::unix::fs::MetadataExt;
+    let root = get_test_dir();
+    let other = Path::new("/dev/shm");
+    let device = |path: &Path| path.metadata().map(|m| m.dev()).ok();
+    if device(other).is_none() || device(other) == device(&root) {
+        // There is no other filesystem to test with.
+        return;
+    }
+
+    let upload_dir = TempDir::new_in(other, "outpack").unwrap();
+    update_config(&root, |config| {
+        config["server"] = serde_json::json!({ "upload_dir": upload_dir.path() });
+    });
+    let err = outpack::api::api(&root).unwrap_err();
+    assert!(
+        err.to_string().contains("must be on the same filesystem"),
+        "{:#}",
+        err
+    );
+    assert_eq!(fs::read_dir(upload_dir.path()).unwrap().count(), 0);
+}
+
+#[tokio::test]
+async fn rejects_files_larger_than_upload_limit() {
+    let root = get_test_dir();
+    update_config(&root, |config| {
+        config["server"] = serde_json::json!({ "max_upload_size": 8 });
+    });
+    let mut client = TestClient::new(&root);
+
+    let content = "123456789";
+    let hash = format!("sha256:{:x}", Sha256::digest(content));
+
+    // Without a Content-Length header, the limit is only detected while reading the body.
+    let response = client
+        .post(
+            format!("/file/{}", hash),
+            mime::APPLICATION_OCTET_STREAM,
+            content,
+        )
+        .await;
+    assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    let body = response.to_json().await;
+    validate_error(&body, Some("Uploads may not be larger than 8 bytes"));
+
+    let request = Request::post(format!("/file/{}", hash))
+        .header(CONTENT_TYPE, mime::APPLICATION_OCTET_STREAM.as_ref())
+        .header("content-length", content.len())
+        .body(Body::from(content))
+        .unwrap();
+    let response = client.request(request).await;
+    assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    let body = response.to_json().await;
+    validate_error(&body, Some("Uploads may not be larger than 8 bytes"));
+
+    let response = client.get(format!("/file/{}", hash)).await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+    // Files up to the limit are still accepted.
+    let content = "12345678";
+    let hash = format!("sha256:{:x}", Sha256::digest(content));
+    let response = client
+        .post(
+            format!("/file/{}", hash),
+            mime::APPLICATION_OCTET_STREAM,
+            content,
+        )
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+async fn append_upload(
+    client: &mut TestClient,
+    id: &str,
+    offset: u64,
+    data: &'static str,
+) -> Response {
+    let request = Request::patch(format!("/upload/{}", id))
+        .header("upload-offset", offset)
+        .header(CONTENT_TYPE, mime::APPLICATION_OCTET_STREAM.as_ref())
+        .body(Body::from(data))
+        .unwrap();
+    client.request(request).await
+}
+
+#[tokio::test]
+async fn can_upload_file_in_chunks() {
+    let mut client = get_default_client();
+    let hash = format!("sha256:{:x}", Sha256::digest("Hello, World!"));
+
+    let response = client
+        .post("/upload", mime::APPLICATION_JSON, Body::empty())
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = response.to_json().await;
+    validate_success("server", "upload.json", &body);
+    let id = body["data"]["id"].as_str().unwrap().to_owned();
+
+    let response = append_upload(&mut client, &id, 0, "Hello, ").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = response.to_json().await;
+    validate_success("server", "upload.json", &body);
+    assert_eq!(body["data"]["offset"], 7);
+
+    let response = append_upload(&mut client, &id, 0, "World!").await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let body = response.to_json().await;
+    validate_error(&body, Some("starts at offset 0, but expected offset 7"));
+
+    let response = append_upload(&mut client, &id, 7, "World!").await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let response = client.get(format!("/upload/{}", id)).await;
+    let body = response.to_json().await;
+    validate_success("server", "upload.json", &body);
+    assert_eq!(body["data"]["offset"], 13);
+
+    let response = client
+        .post_json(
+            format!("/upload/{}/finish", id),
+            &serde_json::json!({ "hash": hash }),
+        )
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
     let body = response.to_json().await;
-    validate_error(&body, Some("Invalid hash format 'badhash'"));
+    validate_success("server", "null-response.json", &body);
+
+    let response = client.get(format!("/file/{}", hash)).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.to_string().await, "Hello, World!");
+
+    let response = client.get(format!("/upload/{}", id)).await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
 }
 
 #[tokio::test]
-async fn missing_files_validates_request_body() {
-    let mut client = get_default_client();
+async fn chunked_uploads_are_limited_in_size() {
+    let root = get_test_dir();
+    update_config(&root, |config| {
+        config["server"] = serde_json::json!({ "max_upload_size": 8 });
+    });
+    let mut client = TestClient::new(&root);
     let response = client
-        .post("/files/missing", mime::APPLICATION_JSON, Body::empty())
+        .post("/upload", mime::APPLICATION_JSON, Body::empty())
         .await;
+    let body: Value = response.to_json().await;
+    let id = body["data"]["id"].as_str().unwrap().to_owned();
 
-    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
-    assert_eq!(response.content_type(), mime::APPLICATION_JSON);
-
+    let response = append_upload(&mut client, &id, 0, "123456789").await;
+    assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
     let body = response.to_json().await;
-    validate_error(&body, Some("EOF while parsing a value at line 1 column 0"));
+    validate_error(&body, Some("Uploads may not be larger than 8 bytes"));
+
+    // The limit applies to the whole upload, not just to each chunk.
+    let response = append_upload(&mut client, &id, 0, "12345").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = append_upload(&mut client, &id, 5, "6789").await;
+    assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    let response = append_upload(&mut client, &id, 5, "678").await;
+    assert_eq!(response.status(), StatusCode::OK);
 }
 
 #[tokio::test]
-async fn can_post_file() {
+async fn chunked_upload_validates_hash() {
     let mut client = get_default_client();
-    let content = "test";
-    let hash = format!("sha256:{:x}", Sha256::digest(content));
+    let hash = format!("sha256:{:x}", Sha256::digest("Hello, World!"));
+
     let response = client
-        .post(
-            format!("/file/{}", hash),
-            mime::APPLICATION_OCTET_STREAM,
-            content,
-        )
+        .post("/upload", mime::APPLICATION_JSON, Body::empty())
         .await;
+    let body: Value = response.to_json().await;
+    let id = body["data"]["id"].as_str().unwrap().to_owned();
 
+    let response = append_upload(&mut client, &id, 0, "Hello").await;
     assert_eq!(response.status(), StatusCode::OK);
-    assert_eq!(response.content_type(), mime::APPLICATION_JSON);
 
+    let response = client
+        .post_json(
+            format!("/upload/{}/finish", id),
+            &serde_json::json!({ "hash": hash }),
+        )
+        .await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
     let body = response.to_json().await;
-    validate_success("server", "null-response.json", &body);
-
-    body.get("data")
-        .expect("Data property present")
-        .as_null()
-        .expect("Null data");
+    validate_error(&body, Some("Expected hash"));
 
-    // check file now exists on server
-    let get_file_response = client.get(format!("/file/{}", hash)).await;
-    assert_eq!(get_file_response.status(), StatusCode::OK);
-    assert_eq!(get_file_response.to_string().await, "test");
+    let response = client.get(format!("/file/{}", hash)).await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
 }
 
 #[tokio::test]
@@ -640,6 +4601,56 @@ async fn can_post_metadata() {
     assert_eq!(get_metadata_response.status(), StatusCode::OK);
 }
 
+#[tokio::test]
+async fn unsupported_hash_algorithm_is_reported() {
+    let root = get_empty_root();
+    let mut client = TestClient::new(&root);
+    update_config(&root, |config| {
+        config["core"]["hash_algorithm"] = "sha3".into();
+    });
+    let expected =
+        "unsupported hash algorithm 'sha3', expected one of md5, sha1, sha256, sha384, sha512";
+
+    // The configuration is checked when it is read, whether by reloading it or at startup.
+    let response = client.post_json("/admin/reload", &()).await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let body = response.to_json().await;
+    validate_error(&body, Some(expected));
+
+    let err = outpack::api::api(&root).unwrap_err();
+    assert!(format!("{:#}", err).contains(expected), "{:#}", err);
+}
+
+#[tokio::test]
+async fn metadata_round_trips_byte_for_byte() {
+    let root = get_empty_root();
+    let mut client = TestClient::new(&root);
+    let id = "20240101-120000-00000001";
+
+    // Keys in an unusual order, with irregular whitespace and escapes, none of which would
+    // survive being parsed and serialised again.
+    let content = r#"{ "time":{"end": 1682608108.4309, "start":1682608108.4139},
+	"id": "20240101-120000-00000001", "schema_version" : "0.0.1",
+  "name":"r\u00e9sum\u00e9", "parameters":null,"files":[ ],
+  "depends":[],"script":["orderly.R"]  }
+"#;
+    let hash = format!("sha256:{:x}", Sha256::digest(content));
+    let response = client
+        .post(format!("/packet/{}", hash), mime::TEXT_PLAIN_UTF_8, content)
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let response = client.get(format!("/metadata/{}/text", id)).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()["outpack-metadata-hash"], hash.as_str());
+    let served = response.to_string().await;
+    assert_eq!(served, content);
+    assert_eq!(format!("sha256:{:x}", Sha256::digest(&served)), hash);
+
+    let body: Value = client.get("/metadata/list").await.to_json().await;
+    assert_eq!(body["data"][0]["hash"], hash);
+}
+
 #[tokio::test]
 async fn catches_arbitrary_404() {
     let mut client = get_default_client();
@@ -671,6 +4682,61 @@ async fn exposes_metrics_endpoint() {
         .any(|line| line.starts_with("http_requests_total")));
 }
 
+/// Find the value of a metric with the given labels in Prometheus' text exposition format.
+fn get_metric_value(metrics: &str, name: &str, labels: &[(&str, &str)]) -> Option<f64> {
+    metrics.lines().find_map(|line| {
+        let (series, value) = line.rsplit_once(' ')?;
+        let (series_name, series_labels) = match series.split_once('{') {
+            Some((n, l)) => (n, l.trim_end_matches('}')),
+            None => (series, ""),
+        };
+        let matches = series_name == name
+            && labels.iter().all(|(k, v)| {
+                series_labels
+                    .split(',')
+                    .any(|label| label == format!("{}=\"{}\"", k, v))
+            });
+        matches.then(|| value.parse().unwrap())
+    })
+}
+
+#[tokio::test]
+async fn metrics_count_requests() {
+    let mut client = get_default_client();
+
+    client.get("/").await;
+    client.get("/").await;
+    client.get("/metadata/bad-id/json").await;
+
+    let response = client.get("/metrics").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let metrics = response.to_string().await;
+
+    let requests = |endpoint, status| {
+        get_metric_value(
+            &metrics,
+            "http_requests_total",
+            &[
+                ("endpoint", endpoint),
+                ("method", "GET"),
+                ("status", status),
+            ],
+        )
+    };
+    assert_eq!(requests("/", "200"), Some(2.));
+    assert_eq!(requests("/metadata/:id/json", "404"), Some(1.));
+
+    let durations = get_metric_value(
+        &metrics,
+        "http_requests_duration_seconds_count",
+        &[("endpoint", "/"), ("method", "GET"), ("status", "200")],
+    );
+    assert_eq!(durations, Some(2.));
+
+    let file_size = get_metric_value(&metrics, "outpack_server_file_size_bytes_total", &[]);
+    assert!(file_size.unwrap() > 0.);
+}
+
 #[tokio::test]
 async fn generates_request_id() {
     let mut client = get_default_client();
@@ -728,11 +4794,65 @@ async fn request_id_is_logged() {
         .single(&message(eq("finished processing request")));
 }
 
+#[tokio::test]
+async fn request_id_is_returned_on_errors() {
+    let mut client = get_default_client();
+    let response = client.get("/badurl").await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    assert!(!response.headers()["x-request-id"].is_empty());
+
+    let mut client = TestClient::new("tests/bad-example");
+    let request = Request::get("/metadata/list")
+        .header("x-request-id", "foobar123")
+        .body(Body::empty())
+        .unwrap();
+    let response = client.request(request).await;
+    assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    assert_eq!(response.headers()["x-request-id"], "foobar123");
+}
+
+#[tokio::test]
+async fn response_status_is_logged() {
+    use predicates::ord::eq;
+    use tracing_capture::predicates::{message, name, ScanExt};
+
+    // See request_id_is_logged for why this is needed.
+    let _dont_drop_me = tracing::Dispatch::new(tracing::subscriber::NoSubscriber::new());
+
+    let storage = SharedStorage::default();
+    let subscriber = Registry::default().with(CaptureLayer::new(&storage));
+
+    let f = async {
+        let mut client = TestClient::new("tests/bad-example");
+        client.get("/metadata/list").await
+    };
+
+    let response = f.with_subscriber(subscriber).await;
+    assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+
+    let storage = storage.lock();
+    let span = storage.scan_spans().single(&name(eq("request")));
+    assert!(span
+        .value("method")
+        .unwrap()
+        .is_debug(&tracing::field::display("GET")));
+    assert!(span
+        .value("uri")
+        .unwrap()
+        .is_debug(&tracing::field::display("/metadata/list")));
+
+    let event = span
+        .scan_events()
+        .single(&message(eq("finished processing request")));
+    assert_eq!(event.value("status").unwrap().as_int(), Some(500));
+    assert!(event.value("latency").is_some());
+}
+
 #[tokio::test]
 async fn can_fetch_git() {
     let test_dir = get_test_dir();
     let test_git = initialise_git_repo(Some(&test_dir));
-    let mut client = TestClient::new(test_git.dir.path().join("local"));
+    let mut client = TestClient::with_admin_token(test_git.dir.path().join("local"));
 
     let remote_ref = git_get_latest_commit(&test_git.remote, "HEAD");
     let initial_ref = git_get_latest_commit(&test_git.local, "refs/remotes/origin/HEAD");
@@ -751,12 +4871,12 @@ async fn can_fetch_git() {
     assert_eq!(response.content_type(), mime::APPLICATION_JSON);
 
     let body = response.to_json().await;
-    validate_success("server", "null-response.json", &body);
-
-    body.get("data")
-        .expect("Data property present")
-        .as_null()
-        .expect("Null data");
+    validate_success("server", "git-fetch.json", &body);
+    assert!(body["data"]["objects_received"].as_u64().unwrap() > 0);
+    assert_eq!(
+        body["data"]["refs_updated"],
+        serde_json::json!(["refs/remotes/origin/master", "refs/remotes/origin/other"])
+    );
 
     let post_fetch_ref = git_get_latest_commit(&test_git.local, "refs/remotes/origin/HEAD");
     assert_eq!(
@@ -769,12 +4889,285 @@ async fn can_fetch_git() {
 }
 
 #[tokio::test]
-async fn can_list_git_branches() {
+async fn can_fetch_git_on_schedule() {
+    let test_dir = get_test_dir();
+    let test_git = initialise_git_repo(Some(&test_dir));
+    let local = test_git.dir.path().join("local");
+    update_config(&local, |config| {
+        config["server"] = serde_json::json!({
+            "git_fetch_schedule": { "interval": 1, "checkout": "other" }
+        });
+    });
+    let remote_ref = git_get_latest_commit(&test_git.remote, "HEAD");
+
+    // The client is kept alive while waiting, as the fetches stop once the API is dropped.
+    let _client = TestClient::new(&local);
+    let mut fetched = false;
+    for _ in 0..100 {
+        let head = git_get_latest_commit(&test_git.local, "refs/remotes/origin/HEAD");
+        if head.message() == remote_ref.message() && test_git.local.worktrees().unwrap().len() == 1
+        {
+            fetched = true;
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    assert!(fetched);
+    assert_eq!(git_remote_branches(&test_git.local).count(), 3);
+}
+
+/// Serve the git repositories under `root` over HTTP, by running `git http-backend` for each
+/// request. libgit2 only makes shallow fetches from remotes reached over the network.
+async fn start_git_http_server(root: &Path) -> String {
+    let root = root.to_owned();
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    let app = axum::Router::new().fallback(move |request: Request| {
+        let root = root.clone();
+        async move {
+            let (parts, body) = request.into_parts();
+            let body = axum::body::to_bytes(body, usize::MAX).await.unwrap();
+            let content_type = parts
+                .headers
+                .get(CONTENT_TYPE)
+                .map(|value| value.to_str().unwrap().to_owned())
+                .unwrap_or_default();
+            let output = tokio::task::spawn_blocking(move || {
+                use std::io::Write;
+                use std::process::{Command, Stdio};
+                let mut child = Command::new("git")
+                    .arg("http-backend")
+                    .env("GIT_PROJECT_ROOT", &root)
+                    .env("GIT_HTTP_EXPORT_ALL", "1")
+                    .env("REQUEST_METHOD", parts.method.as_str())
+                    .env("PATH_INFO", parts.uri.path())
+                    .env("QUERY_STRING", parts.uri.query().unwrap_or(""))
+                    .env("CONTENT_TYPE", content_type)
+                    .env("CONTENT_LENGTH", body.len().to_string())
+                    .stdin(Stdio::piped())
+                    .stdout(Stdio::piped())
+                    .stderr(Stdio::null())
+                    .spawn()
+                    .unwrap();
+                child.stdin.take().unwrap().write_all(&body).unwrap();
+                child.wait_with_output().unwrap().stdout
+            })
+            .await
+            .unwrap();
+
+            // The output is a CGI response: headers, a blank line and then the body.
+            let split = output.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+            let mut response = Response::builder();
+            for line in std::str::from_utf8(&output[..split]).unwrap().split("\r\n") {
+                let (name, value) = line.split_once(": ").unwrap();
+                if name.eq_ignore_ascii_case("Status") {
+                    response = response.status(&value[..3]);
+                } else {
+                    response = response.header(name, value);
+                }
+            }
+            response
+                .body(Body::from(output[split + 4..].to_vec()))
+                .unwrap()
+        }
+    });
+    tokio::spawn(async move { axum::serve(listener, app).await });
+    url
+}
+
+#[tokio::test]
+async fn git_fetch_can_be_shallow() {
+    let test_dir = get_test_dir();
+    let test_git = initialise_git_repo(Some(&test_dir));
+    let url = start_git_http_server(test_git.dir.path()).await;
+
+    // A repository with no history yet, fetching from the remote over HTTP.
+    let shallow_path = test_git.dir.path().join("shallow");
+    copy_recursively(&test_dir, &shallow_path).unwrap();
+    let shallow = git2::Repository::init(&shallow_path).unwrap();
+    shallow
+        .remote("origin", &format!("{}/remote", url))
+        .unwrap();
+    update_config(&shallow_path, |config| {
+        config["server"] = serde_json::json!({ "git_fetch_depth": 1 });
+    });
+    let mut client = TestClient::with_admin_token(&shallow_path);
+
+    let response = client
+        .post("/git/fetch", mime::APPLICATION_JSON, Body::empty())
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = response.to_json().await;
+    validate_success("server", "git-fetch.json", &body);
+    assert!(shallow.is_shallow());
+
+    // Only the tip of each branch was fetched, not its parents.
+    let tip = git_get_latest_commit(&shallow, "refs/remotes/origin/master");
+    assert_eq!(tip.message().unwrap(), "Second commit");
+    assert!(shallow.find_commit(tip.parent_id(0).unwrap()).is_err());
+}
+
+#[tokio::test]
+async fn can_git_fetch_from_named_remote() {
     let test_dir = get_test_dir();
     let test_git = initialise_git_repo(Some(&test_dir));
+    let remote_path = test_git.dir.path().join("remote");
+    test_git
+        .local
+        .remote("upstream", remote_path.to_str().unwrap())
+        .unwrap();
+    let mut client = TestClient::with_admin_token(test_git.dir.path().join("local"));
+
+    let response = client
+        .post(
+            "/git/fetch?remote=upstream",
+            mime::APPLICATION_JSON,
+            Body::empty(),
+        )
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = response.to_json().await;
+    validate_success("server", "git-fetch.json", &body);
+    assert_eq!(
+        body["data"]["refs_updated"],
+        serde_json::json!([
+            "refs/remotes/upstream/master",
+            "refs/remotes/upstream/other"
+        ])
+    );
+
+    let response = client
+        .post(
+            "/git/fetch?remote=missing",
+            mime::APPLICATION_JSON,
+            Body::empty(),
+        )
+        .await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    let body = response.to_json().await;
+    validate_error(
+        &body,
+        Some("Remote 'missing' does not exist; available remotes are 'origin', 'upstream'"),
+    );
+}
+
+#[tokio::test]
+async fn can_serve_branches_from_worktrees() {
+    let test_dir = get_test_dir();
+    let test_git = initialise_git_repo(Some(&test_dir));
+    let removed = "20180220-095832-16a4bbed";
+    git_commit_on_branch(&test_git.remote, "fewer", "Remove a packet", |path| {
+        let outpack = path.join(".outpack");
+        fs::remove_file(outpack.join("metadata").join(removed)).unwrap();
+        fs::remove_file(outpack.join("location").join("another").join(removed)).unwrap();
+    });
+    let local = test_git.dir.path().join("local");
+    let mut client = TestClient::with_admin_token(&local);
+    let response = client
+        .post("/git/fetch", mime::APPLICATION_JSON, Body::empty())
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
 
+    let list = |body: Value| -> Vec<String> {
+        let entries = body["data"].as_array().unwrap();
+        entries
+            .iter()
+            .map(|e| e["packet"].as_str().unwrap().to_owned())
+            .collect()
+    };
+
+    let response = client.get("/git/master/metadata/list").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = response.to_json().await;
+    validate_success("server", "locations.json", &body);
+    let master = list(body);
+    assert_eq!(master.len(), 4);
+    assert!(master.iter().any(|id| id == removed));
+
+    let response = client.get("/git/fewer/metadata/list").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let fewer = list(response.to_json().await);
+    assert_eq!(fewer.len(), 3);
+    assert!(!fewer.iter().any(|id| id == removed));
+
+    // Query strings are passed on, and the branch's packets can be read individually.
+    let response = client
+        .get(format!("/git/fewer/metadata/{}/json", removed))
+        .await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    let response = client
+        .get(format!("/git/master/metadata/{}/json?format=json", removed))
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // Escaped characters are passed on as they were sent, to be handled as the main root would.
+    let response = client.get("/git/master/metadata/a%20b/json").await;
+    let status = response.status();
+    let body: Value = response.to_json().await;
+    let expected = client.get("/metadata/a%20b/json").await;
+    assert_eq!(status, expected.status());
+    assert_eq!(body, expected.to_json::<Value>().await);
+
+    // The main root is unaffected.
+    let main = list(client.get("/metadata/list").await.to_json().await);
+    assert_eq!(main.len(), 4);
+
+    let response = client.get("/git/missing/metadata/list").await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    let body = response.to_json().await;
+    validate_error(&body, Some("Branch 'missing' does not exist"));
+
+    // Worktrees are removed along with the server.
+    let repo = git2::Repository::open(&local).unwrap();
+    assert_eq!(repo.worktrees().unwrap().len(), 2);
+    drop(client);
+    assert_eq!(repo.worktrees().unwrap().len(), 0);
+}
+
+#[tokio::test]
+async fn can_get_git_status() {
+    let test_dir = get_test_dir();
+    let test_git = initialise_git_repo(Some(&test_dir));
     let mut client = TestClient::new(test_git.dir.path().join("local"));
 
+    let response = client.get("/git/status").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = response.to_json().await;
+    validate_success("server", "git-status.json", &body);
+
+    let head = git_get_latest_commit(&test_git.local, "HEAD");
+    assert_eq!(body["data"]["commit_hash"], head.id().to_string());
+    assert_eq!(body["data"]["branch"], "master");
+    assert_eq!(body["data"]["dirty"], false);
+}
+
+#[tokio::test]
+async fn git_endpoints_require_a_git_repository() {
+    let mut client = TestClient::with_admin_token(get_test_dir());
+
+    let response = client.get("/git/branches").await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    let body = response.to_json().await;
+    validate_error(&body, Some("is not a git repository"));
+
+    let response = client.get("/git/status").await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    let body = response.to_json().await;
+    validate_error(&body, Some("is not a git repository"));
+
+    let response = client
+        .post("/git/fetch", mime::APPLICATION_JSON, Body::empty())
+        .await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn can_list_git_branches() {
+    let test_dir = get_test_dir();
+    let test_git = initialise_git_repo(Some(&test_dir));
+
+    let mut client = TestClient::with_admin_token(test_git.dir.path().join("local"));
+
     let response_fetch = client
         .post("/git/fetch", mime::APPLICATION_JSON, Body::empty())
         .await;
@@ -799,16 +5192,33 @@ async fn can_list_git_branches() {
         "master"
     );
     assert_eq!(
-        *branch_list[0].get("message").unwrap().as_array().unwrap(),
-        vec!["Second commit"]
+        branch_list[0]["message"],
+        serde_json::json!({ "subject": "Second commit", "body": null })
     );
     assert_eq!(
         branch_list[1].get("name").unwrap().as_str().unwrap(),
         "other"
     );
-    assert_eq!(
-        *branch_list[1].get("message").unwrap().as_array().unwrap(),
-        vec!["Third commit"]
+    assert_eq!(branch_list[1]["message"]["subject"], "Third commit");
+
+    assert_eq!(branch_list[0]["current"], true);
+    assert_eq!(branch_list[1]["current"], false);
+
+    // Every branch was committed to just now, so none are excluded.
+    let response = client.get("/git/branches?max_age_days=1").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = response.to_json().await;
+    validate_success("server", "branch-response.json", &body);
+    assert_eq!(body["data"]["branches"].as_array().unwrap().len(), 2);
+
+    let response = client
+        .get(format!("/git/branches?max_age_days={}", u64::MAX))
+        .await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let body = response.to_json().await;
+    validate_error(
+        &body,
+        Some(&format!("max_age_days of {} is too large", u64::MAX)),
     );
 }
 
@@ -7,3 +7,107 @@ fn prints_usage_if_args_invalid() {
     let mut cmd = Command::cargo_bin("outpack").unwrap();
     cmd.assert().stderr(predicate::str::contains("Usage:"));
 }
+
+#[test]
+fn can_query_repository() {
+    let mut cmd = Command::cargo_bin("outpack").unwrap();
+    cmd.args(["query", "--root", "tests/example", "parameter:size == 10"]);
+    cmd.assert().success().stdout("20180220-095832-16a4bbed\n");
+}
+
+#[test]
+fn can_query_repository_as_json() {
+    let mut cmd = Command::cargo_bin("outpack").unwrap();
+    cmd.args([
+        "query",
+        "--root",
+        "tests/example",
+        "--json",
+        r#"name == "modup-201707-queries1""#,
+    ]);
+    cmd.assert().success().stdout(
+        "[\"20170818-164830-33e0ab01\",\"20170818-164847-7574883b\",\"20180818-164043-7cdcde4b\"]\n",
+    );
+
+    let mut cmd = Command::cargo_bin("outpack").unwrap();
+    cmd.args([
+        "query",
+        "--root",
+        "tests/example",
+        "--json",
+        r#"name == "x""#,
+    ]);
+    cmd.assert().success().stdout("[]\n");
+}
+
+#[test]
+fn query_reports_invalid_syntax() {
+    let mut cmd = Command::cargo_bin("outpack").unwrap();
+    cmd.args(["query", "--root", "tests/example", "invalid"]);
+    cmd.assert()
+        .failure()
+        .stderr(predicate::str::contains("Failed to parse query"));
+}
+
+#[test]
+fn can_init_new_repository() {
+    let tmp = tempfile::TempDir::new().unwrap();
+    let mut cmd = Command::cargo_bin("outpack").unwrap();
+    cmd.args(["init", "--hash-algorithm", "sha512"])
+        .arg(tmp.path());
+    cmd.assert().success();
+
+    let config = outpack::config::read_config(tmp.path()).unwrap();
+    assert!(config.core.use_file_store);
+    assert_eq!(config.core.hash_algorithm.to_string(), "sha512");
+
+    let mut cmd = Command::cargo_bin("outpack").unwrap();
+    cmd.args(["init", "--hash-algorithm", "whirlpool"])
+        .arg(tmp.path());
+    cmd.assert().failure().stderr(predicate::str::contains(
+        "Invalid hash algorithm 'whirlpool'",
+    ));
+}
+
+#[test]
+fn init_honours_storage_options() {
+    // A complete tree needs a file store, which is not assumed once any storage option is given.
+    let tmp = tempfile::TempDir::new().unwrap();
+    let mut cmd = Command::cargo_bin("outpack").unwrap();
+    cmd.args(["init", "--require-complete-tree"])
+        .arg(tmp.path());
+    cmd.assert().failure().stderr(predicate::str::contains(
+        "If 'path_archive' is None, then use_file_store must be true",
+    ));
+    assert!(!tmp.path().join(".outpack").exists());
+
+    let mut cmd = Command::cargo_bin("outpack").unwrap();
+    cmd.args(["init", "--use-file-store"]).arg(tmp.path());
+    cmd.assert().success();
+    let config = outpack::config::read_config(tmp.path()).unwrap();
+    assert!(config.core.use_file_store);
+    assert!(!config.core.require_complete_tree);
+}
+
+#[test]
+fn can_verify_store() {
+    let tmp = tempfile::TempDir::new().unwrap();
+    let mut cmd = Command::cargo_bin("outpack").unwrap();
+    cmd.arg("init").arg(tmp.path());
+    cmd.assert().success();
+
+    let mut cmd = Command::cargo_bin("outpack").unwrap();
+    cmd.args(["verify-store", "--root"]).arg(tmp.path());
+    cmd.assert().success().stdout("");
+
+    let directory = tmp.path().join(".outpack").join("files").join("sha3");
+    std::fs::create_dir_all(&directory).unwrap();
+    std::fs::write(directory.join("data"), b"data").unwrap();
+
+    let mut cmd = Command::cargo_bin("outpack").unwrap();
+    cmd.args(["verify-store", "--root"]).arg(tmp.path());
+    cmd.assert()
+        .failure()
+        .stdout("sha3/data: not a valid location in the store\n")
+        .stderr(predicate::str::contains("Found 1 invalid files"));
+}
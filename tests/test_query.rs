@@ -9,7 +9,7 @@ pub fn test_query(root: &Path, query: &str, result: &str) {
 #[test]
 fn locates_latest_packet() {
     let root_path = Path::new("tests/example");
-    test_query(root_path, "latest", "20180818-164043-7cdcde4b");
+    test_query(root_path, "latest", "20180220-095832-16a4bbed");
 }
 
 #[test]
@@ -85,7 +85,7 @@ fn can_get_packet_by_parameter() {
     test_query(
         root_path,
         r#"latest(parameter:disease == "YF")"#,
-        "20180818-164043-7cdcde4b",
+        "20180220-095832-16a4bbed",
     );
     test_query(
         root_path,
@@ -170,6 +170,39 @@ fn can_get_packet_by_numeric_parameter() {
     test_query(root_path, r#"parameter:size == "10""#, "Found no packets");
 }
 
+#[test]
+fn can_get_packet_by_parameter_inequality() {
+    let root_path = Path::new("tests/example");
+    test_query(root_path, "parameter:size != 5", "20180220-095832-16a4bbed");
+    test_query(root_path, "parameter:size != 10", "Found no packets");
+    test_query(
+        root_path,
+        r#"parameter:disease != "YF""#,
+        "Found no packets",
+    );
+    test_query(
+        root_path,
+        r#"parameter:disease != "HepB""#,
+        "20170818-164830-33e0ab01\n20180220-095832-16a4bbed\n20180818-164043-7cdcde4b",
+    );
+    // Packets without the parameter are not matched by either comparison
+    test_query(
+        root_path,
+        "parameter:tolerance != 0.5",
+        "20180220-095832-16a4bbed",
+    );
+}
+
+#[test]
+fn parameters_of_different_type_never_match() {
+    let root_path = Path::new("tests/example");
+    test_query(root_path, r#"parameter:size == "10""#, "Found no packets");
+    test_query(root_path, r#"parameter:size != "10""#, "Found no packets");
+    test_query(root_path, "parameter:disease != 10", "Found no packets");
+    test_query(root_path, "parameter:disease > 10", "Found no packets");
+    test_query(root_path, "parameter:pull_data != 1", "Found no packets");
+}
+
 #[test]
 fn no_packets_returned_incompatible_types() {
     let root_path = Path::new("tests/example");
@@ -238,17 +271,17 @@ fn query_supports_groupings() {
     test_query(
         root_path,
         r#"(parameter:tolerance < 0.002) || id == "20170818-164847-7574883b""#,
-        "20180220-095832-16a4bbed\n20170818-164847-7574883b",
+        "20170818-164847-7574883b\n20180220-095832-16a4bbed",
     );
     test_query(
         root_path,
         r#"(parameter:tolerance < 0.002)||id == "20170818-164847-7574883b""#,
-        "20180220-095832-16a4bbed\n20170818-164847-7574883b",
+        "20170818-164847-7574883b\n20180220-095832-16a4bbed",
     );
     test_query(
         root_path,
         r#"(parameter:tolerance < 0.002) || (id == "20170818-164847-7574883b")"#,
-        "20180220-095832-16a4bbed\n20170818-164847-7574883b",
+        "20170818-164847-7574883b\n20180220-095832-16a4bbed",
     );
 
     test_query(
@@ -285,6 +318,78 @@ fn query_and_is_highest_precedence() {
     );
 }
 
+#[test]
+fn query_negation_binds_tighter_than_boolean_operators() {
+    let root_path = Path::new("tests/example");
+    // Parsed as (!A) && B, rather than !(A && B)
+    test_query(
+        root_path,
+        r#"!name == "modup-201707-queries1" && parameter:disease == "YF""#,
+        "20180220-095832-16a4bbed",
+    );
+    test_query(
+        root_path,
+        r#"!(name == "modup-201707-queries1" && parameter:disease == "YF")"#,
+        "20170818-164847-7574883b\n20180220-095832-16a4bbed",
+    );
+    test_query(
+        root_path,
+        r#"!!(name == "modup-201707-params1")"#,
+        "20180220-095832-16a4bbed",
+    );
+}
+
+#[test]
+fn query_supports_nested_groupings() {
+    let root_path = Path::new("tests/example");
+    test_query(
+        root_path,
+        r#"((id == "20170818-164847-7574883b" || (parameter:size == 10)) && !(name == "x" || (id == "20170818-164847-7574883b")))"#,
+        "20180220-095832-16a4bbed",
+    );
+    test_query(
+        root_path,
+        r#"(id == "20170818-164847-7574883b" || id == "20180220-095832-16a4bbed") && (parameter:size == 10 || parameter:pull_data != true)"#,
+        "20180220-095832-16a4bbed",
+    );
+}
+
+#[test]
+fn query_results_are_in_index_order() {
+    let root_path = Path::new("tests/example");
+    test_query(
+        root_path,
+        r#"id == "20180818-164043-7cdcde4b" || id == "20170818-164830-33e0ab01" || id == "20180220-095832-16a4bbed""#,
+        "20170818-164830-33e0ab01\n20180220-095832-16a4bbed\n20180818-164043-7cdcde4b",
+    );
+    test_query(
+        root_path,
+        r#"parameter:disease == "YF" || parameter:disease != "YF""#,
+        "20170818-164830-33e0ab01\n20180220-095832-16a4bbed\n20180818-164043-7cdcde4b",
+    );
+}
+
+#[test]
+fn boolean_operators_short_circuit() {
+    let root_path = Path::new("tests/example");
+    // The right hand side would fail if it were evaluated, as it matches more than one packet
+    let rhs = r#"single(name == "modup-201707-queries1")"#;
+    test_query(
+        root_path,
+        &format!(r#"id == "unknown" && {}"#, rhs),
+        "Found no packets",
+    );
+    test_query(
+        root_path,
+        &format!(r#"!(id == "unknown") || {}"#, rhs),
+        "20170818-164830-33e0ab01\n20170818-164847-7574883b\n\
+        20180220-095832-16a4bbed\n20180818-164043-7cdcde4b",
+    );
+    let e = outpack::query::run_query(root_path, &format!(r#"id != "unknown" && {}"#, rhs))
+        .unwrap_err();
+    assert!(matches!(e, QueryError::EvalError(..)));
+}
+
 #[test]
 fn query_functions_can_be_nested() {
     let root_path = Path::new("tests/example");